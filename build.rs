@@ -0,0 +1,251 @@
+// Generates the decoder opcode maps from tables/opcodes.txt,
+// see the comment at the top of that file for the format.
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const TABLE: &'static str = "tables/opcodes.txt";
+
+const MAPS: [(&'static str, &'static str); 4] = [
+    ("1", "ONE_BYTE_MAP"),
+    ("0F", "TWO_BYTE_MAP"),
+    ("0F38", "THREE_BYTE_38_MAP"),
+    ("0F3A", "THREE_BYTE_3A_MAP"),
+];
+
+struct Row {
+    map: usize,
+    opcode: u8,
+    entry: String,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", TABLE);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut table = String::new();
+    File::open(TABLE)
+        .and_then(|mut file| file.read_to_string(&mut table))
+        .expect("Cannot read opcode table");
+
+    let mut rows = Vec::new();
+    for (number, line) in table.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.is_empty() {
+            continue;
+        }
+        if columns.len() != 9 {
+            panic!("{}:{}: expected 9 columns, got {}", TABLE, number + 1, columns.len());
+        }
+        match parse_row(&columns) {
+            Ok(mut parsed) => rows.append(&mut parsed),
+            Err(message) => panic!("{}:{}: {}", TABLE, number + 1, message),
+        }
+    }
+
+    let mut output = String::new();
+    writeln!(output, "// Generated by build.rs from {}, do not edit.", TABLE).unwrap();
+    for (map, &(_, name)) in MAPS.iter().enumerate() {
+        writeln!(output, "\npub static {}: [&'static [OpcodeEntry]; 256] = [", name).unwrap();
+        for opcode in 0..256 {
+            let entries: Vec<&str> = rows.iter()
+                .filter(|row| row.map == map && row.opcode as usize == opcode)
+                .map(|row| row.entry.as_str())
+                .collect();
+            if entries.is_empty() {
+                writeln!(output, "    &[],").unwrap();
+            } else {
+                writeln!(output, "    &[{}],", entries.join(", ")).unwrap();
+            }
+        }
+        writeln!(output, "];").unwrap();
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut file = File::create(Path::new(&out_dir).join("opcode_maps.rs")).unwrap();
+    file.write_all(output.as_bytes()).unwrap();
+}
+
+fn parse_row(columns: &[&str]) -> Result<Vec<Row>, String> {
+    let map = match MAPS.iter().position(|&(map, _)| map == columns[0]) {
+        Some(map) => map,
+        None => return Err(format!("unknown opcode map {}", columns[0])),
+    };
+
+    let (opcode, register_in_opcode) = if columns[1].ends_with("+r") {
+        (&columns[1][..columns[1].len() - 2], true)
+    } else {
+        (columns[1], false)
+    };
+    let opcode = u8::from_str_radix(opcode, 16).map_err(|_| format!("invalid opcode {}", columns[1]))?;
+    if register_in_opcode && opcode & 0b111 != 0 {
+        return Err(format!("register opcode {} must be a multiple of 8", columns[1]));
+    }
+
+    let (extension, modrm) = match columns[2] {
+        "-" => ("None".to_string(), "None".to_string()),
+        ext if ext.len() == 2 && ext.starts_with('/') && ext.as_bytes()[1] >= b'0' && ext.as_bytes()[1] <= b'7' => {
+            (format!("Some({})", &ext[1..]), "None".to_string())
+        }
+        ext => {
+            let modrm = u8::from_str_radix(ext, 16).map_err(|_| format!("invalid opcode extension {}", ext))?;
+            ("None".to_string(), format!("Some(0x{:02X})", modrm))
+        }
+    };
+
+    let prefix = match columns[3] {
+        "-" => "None",
+        "66" => "OperandSize",
+        "F2" => "RepeatNotEqual",
+        "F3" => "RepeatEqual",
+        prefix => return Err(format!("invalid mandatory prefix {}", prefix)),
+    };
+
+    let vex = if columns[4] == "-" {
+        "None".to_string()
+    } else {
+        parse_vex(columns[4])?
+    };
+
+    let explicit = columns[6].starts_with(|c: char| c.is_ascii_uppercase());
+    if explicit && (columns[7] != "-" || columns[8] != "-") {
+        return Err(format!("operand list {} needs - as size and immediate", columns[6]));
+    }
+
+    let operands = if explicit {
+        parse_operand_list(columns[6])?
+    } else {
+        match columns[6] {
+            "-" => "None",
+            "modrm" => "ModRm",
+            "rm" => "Rm",
+            "rm_nosize" => "RmNoSize",
+            "rm,reg" => "RmReg",
+            "reg,rm" => "RegRm",
+            "reg,rm8" => "RegRm8",
+            "reg,rm16" => "RegRm16",
+            "reg,rm32" => "RegRm32",
+            "reg,rm,imm" => "RegRmImm",
+            "rm,1" => "RmOne",
+            "rm,cl" => "RmCl",
+            "rm,cr" => "RmCr",
+            "cr,rm" => "CrRm",
+            "m" => "Memory",
+            "al,imm" => "AlImm",
+            "rax,imm" => "RaxImm",
+            "rax,reg" => "RaxReg",
+            "reg" => "Reg",
+            "reg,imm" => "RegImm",
+            "imm" => "Imm",
+            "rcx,imm" => "RcxImm",
+            "enter" => "Enter",
+            "cbw" => "Cbw",
+            "cwd" => "Cwd",
+            "string" => "String",
+            "sreg" => "SegmentRegister",
+            "ptr" => "FarPointer",
+            "port" => "Port",
+            operands => return Err(format!("unknown operand encoding {}", operands)),
+        }.to_string()
+    };
+    let register_operand = operands == "Reg" || operands == "RegImm" || operands == "RaxReg";
+    if register_in_opcode != register_operand {
+        return Err(format!("operand encoding {} does not match opcode {}", columns[6], columns[1]));
+    }
+
+    let size = match columns[7] {
+        "-" => "None",
+        "s" => "Segment",
+        size => parse_size(size)?,
+    };
+
+    let immediate = match columns[8] {
+        "-" => "None",
+        "ib" => "Bit8",
+        "ub" => "UnsignedBit8",
+        "uw" => "UnsignedBit16",
+        "iz" => "Bit16Or32",
+        "id" => "Bit32",
+        "iv" => "Full",
+        "jz" => "Relative",
+        immediate => return Err(format!("invalid immediate {}", immediate)),
+    };
+
+    let entry = format!("OpcodeEntry {{ instruction: Instruction::{}, extension: {}, modrm: {}, \
+                         prefix: MandatoryPrefix::{}, vex: {}, operands: Operands::{}, size: OperandSize::{}, \
+                         immediate: Immediate::{} }}",
+                        columns[5], extension, modrm, prefix, vex, operands, size, immediate);
+
+    let count = if register_in_opcode { 8 } else { 1 };
+    Ok((0..count).map(|register| Row {
+        map: map,
+        opcode: opcode + register,
+        entry: entry.clone(),
+    }).collect())
+}
+
+fn parse_vex(vex: &str) -> Result<String, String> {
+    let parts: Vec<&str> = vex.split('.').collect();
+    if parts.len() != 3 {
+        return Err(format!("invalid vex encoding {}", vex));
+    }
+    let evex = match parts[0] {
+        "VEX" => false,
+        "EVEX" => true,
+        _ => return Err(format!("invalid vex encoding {}", vex)),
+    };
+    let length = match parts[1] {
+        "LIG" => "Ignored",
+        "128" => "Bit128",
+        "256" => "Bit256",
+        "512" if evex => "Bit512",
+        _ => return Err(format!("invalid vector length in {}", vex)),
+    };
+    let w = match parts[2] {
+        "WIG" => "None",
+        "W0" => "Some(false)",
+        "W1" => "Some(true)",
+        _ => return Err(format!("invalid W bit in {}", vex)),
+    };
+    Ok(format!("Some(VexEncoding {{ evex: {}, length: VectorLength::{}, w: {} }})", evex, length, w))
+}
+
+fn parse_size(size: &str) -> Result<&'static str, String> {
+    Ok(match size {
+        "b" => "Byte",
+        "w" => "Word",
+        "d" => "Dword",
+        "q" => "Qword",
+        "v" => "Full",
+        "v64" => "Full64",
+        "dq" => "DoubleQuadword",
+        "qq" => "QuadQuadword",
+        "x" => "Vector",
+        "y" => "DwordOrQword",
+        size => return Err(format!("invalid operand size {}", size)),
+    })
+}
+
+/// Vx,Hx,Wx becomes Explicit(&[OperandSpec { kind: OperandKind::V, size: OperandSize::Vector }, ...])
+fn parse_operand_list(operands: &str) -> Result<String, String> {
+    let mut specs = Vec::new();
+    for operand in operands.split(',') {
+        if operand.is_empty() || !"GVHBERWUMI".contains(&operand[..1]) {
+            return Err(format!("unknown operand {}", operand));
+        }
+        let (kind, size) = operand.split_at(1);
+        let size = match size {
+            "" if kind == "M" => "None",
+            size => parse_size(size)?,
+        };
+        specs.push(format!("OperandSpec {{ kind: OperandKind::{}, size: OperandSize::{} }}", kind, size));
+    }
+    Ok(format!("Explicit(&[{}])", specs.join(", ")))
+}
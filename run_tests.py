@@ -3,6 +3,9 @@ import os
 import sys
 from glob import glob
 
+# differ on the host: time stamp counter and xcr0 of the host cpu, undefined
+# flags
+DIFFTEST_SKIP = ['avx.S', 'rdtsc.S', 'shr.S']
 
 for f in glob('./test/decoder/*.asm'):
     command = './test/decoder/test.sh {}'.format(f)
@@ -16,6 +19,62 @@ for f in glob('./test/execution/*.S'):
     if os.system(command) != 0:
         sys.exit(1)
 
+for f in glob('./test/execution/*.S'):
+    if os.path.basename(f) in DIFFTEST_SKIP:
+        continue
+    command = './test/difftest/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/boot/*.S'):
+    command = './test/boot/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/multiboot/*.S'):
+    command = './test/multiboot/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/memory_map/*.S'):
+    command = './test/memory_map/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/linux/*.S'):
+    command = './test/linux/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/pci/*.S'):
+    command = './test/pci/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/virtio_blk/*.S'):
+    command = './test/virtio_blk/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/virtio_net/*.S'):
+    command = './test/virtio_net/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
+for f in glob('./test/virtio_console/*.S'):
+    command = './test/virtio_console/test.sh {}'.format(f)
+    print(command)
+    if os.system(command) != 0:
+        sys.exit(1)
+
 for f in glob('./test/c_execution/*.c'):
     command = './test/c_execution/test.sh {}'.format(f)
     print(command)
@@ -0,0 +1,124 @@
+use machine_state::MachineState;
+use memory_map::RegionType;
+use pci::ECAM_BASE;
+
+/* ACPI tables for guests with a memory map. Guests search the BIOS area from
+ * 0xE0000 to 1 MB for the RSDP on a 16 byte boundary, it points to the RSDT
+ * with 32 bit and the XSDT with 64 bit table addresses. With a PCI bus both
+ * list an MCFG with the ECAM window of bus 0 to 255.
+ *
+ * The tables go into the first ROM without file or reserved region of the
+ * BIOS area, where the guest cannot overwrite them and which the memory map
+ * already reports as not usable. ROM files are the firmware of the guest,
+ * which brings tables of its own, so without such a region there are none.
+ */
+
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+const HEADER_SIZE: usize = 36;
+const RSDP_SIZE: usize = 36;
+
+/// The byte which makes all bytes of a table add up to zero
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte))
+}
+
+fn push_le(table: &mut Vec<u8>, value: u64, size: usize) {
+    table.extend((0..size).map(|index| (value >> (index * 8)) as u8));
+}
+
+/// A system description table: the common header and body
+fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(HEADER_SIZE + body.len());
+    table.extend_from_slice(signature);
+    push_le(&mut table, (HEADER_SIZE + body.len()) as u64, 4);
+    table.push(revision);
+    table.push(0);
+    table.extend_from_slice(b"X86EMU");
+    table.extend_from_slice(b"X86EMU  ");
+    push_le(&mut table, 1, 4);
+    table.extend_from_slice(b"X86E");
+    push_le(&mut table, 1, 4);
+    table.extend_from_slice(body);
+    table[9] = checksum(&table);
+    table
+}
+
+/// The root system description pointer of ACPI 2.0
+fn rsdp(rsdt: u64, xsdt: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(RSDP_SIZE);
+    rsdp.extend_from_slice(b"RSD PTR ");
+    rsdp.push(0);
+    rsdp.extend_from_slice(b"X86EMU");
+    rsdp.push(2);
+    push_le(&mut rsdp, rsdt, 4);
+    push_le(&mut rsdp, RSDP_SIZE as u64, 4);
+    push_le(&mut rsdp, xsdt, 8);
+    rsdp.extend_from_slice(&[0; 4]);
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// The PCI express memory mapped configuration space of segment 0
+fn mcfg() -> Vec<u8> {
+    let mut body = vec![0; 8];
+    push_le(&mut body, ECAM_BASE, 8);
+    push_le(&mut body, 0, 2);
+    body.push(0);
+    body.push(255);
+    body.extend_from_slice(&[0; 4]);
+    table(b"MCFG", 1, &body)
+}
+
+/// Where the tables go, base and size of the free part of the BIOS area
+fn table_area(machine_state: &MachineState) -> Option<(u64, u64)> {
+    machine_state.memory_map.e820().iter()
+        .filter(|region| match region.region_type {
+            RegionType::Rom => region.file.is_none(),
+            RegionType::Reserved => true,
+            _ => false,
+        })
+        .filter_map(|region| {
+            let base = (region.base.max(BIOS_AREA_START) + 15) & !15;
+            let end = region.end().min(BIOS_AREA_END);
+            if base < end { Some((base, end - base)) } else { None }
+        })
+        .next()
+}
+
+/// Writes the tables to the BIOS area, pci tells whether there is a PCI bus
+pub fn build(machine_state: &mut MachineState, pci: bool) {
+    let (base, size) = match table_area(machine_state) {
+        Some(area) => area,
+        None => return,
+    };
+    let tables = if pci { vec![mcfg()] } else { Vec::new() };
+
+    // RSDP, XSDT (8 byte aligned), RSDT, then the tables
+    let xsdt_address = base + RSDP_SIZE as u64 + 4;
+    let rsdt_address = xsdt_address + (HEADER_SIZE + 8 * tables.len()) as u64;
+    let mut address = rsdt_address + (HEADER_SIZE + 4 * tables.len()) as u64;
+    let mut addresses = Vec::new();
+    for table in &tables {
+        addresses.push(address);
+        address += table.len() as u64;
+    }
+    if address - base > size {
+        panic!("The ACPI tables need {:#x} bytes, the area at {:#x} only has {:#x}", address - base, base, size);
+    }
+
+    let mut xsdt_body = Vec::new();
+    let mut rsdt_body = Vec::new();
+    for &address in &addresses {
+        push_le(&mut xsdt_body, address, 8);
+        push_le(&mut rsdt_body, address, 4);
+    }
+    machine_state.load_rom(base, &rsdp(rsdt_address, xsdt_address));
+    machine_state.load_rom(xsdt_address, &table(b"XSDT", 1, &xsdt_body));
+    machine_state.load_rom(rsdt_address, &table(b"RSDT", 1, &rsdt_body));
+    for (table, &address) in tables.iter().zip(addresses.iter()) {
+        machine_state.load_rom(address, table);
+    }
+}
@@ -0,0 +1,89 @@
+extern crate clap;
+use clap::{App, Arg};
+
+use std::fs::File;
+use std::io::Read;
+
+extern crate xmas_elf;
+use xmas_elf::{ElfFile, program};
+
+extern crate x86emu;
+use x86emu::disassembler::disassemble;
+use x86emu::formatter::{InstructionFormatter, formatter_for_syntax};
+use x86emu::cpu::mode::CodeSize;
+
+fn main() {
+    let matches = App::new("x86dis")
+        .about("x86_64 disassembler using the x86emu decoder")
+        .arg(Arg::with_name("file").required(true))
+        .arg(Arg::with_name("section")
+            .help("elf section to disassemble (default .text)")
+            .long("section")
+            .short("j")
+            .takes_value(true))
+        .arg(Arg::with_name("raw")
+            .help("treat the file as raw machine code, even if it is an elf file")
+            .long("raw"))
+        .arg(Arg::with_name("address")
+            .help("load address of raw machine code (hex)")
+            .long("address")
+            .takes_value(true))
+        .arg(Arg::with_name("syntax")
+            .help("assembler syntax (default att)")
+            .long("syntax")
+            .takes_value(true)
+            .possible_values(&["att", "nasm", "masm"]))
+        .arg(Arg::with_name("bits")
+            .help("decode 16, 32 or 64 bit code (default 64)")
+            .long("bits")
+            .takes_value(true)
+            .possible_values(&["16", "32", "64"]))
+        .get_matches();
+
+    let filename = matches.value_of("file").unwrap();
+    let formatter = formatter_for_syntax(matches.value_of("syntax").unwrap_or("att"));
+    let code_size = match matches.value_of("bits").unwrap_or("64") {
+        "16" => CodeSize::Bit16,
+        "32" => CodeSize::Bit32,
+        "64" => CodeSize::Bit64,
+        _ => unreachable!("Values already validated by clap"),
+    };
+    let mut file = File::open(filename).expect("Cannot open file");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).expect("Failed to read file.");
+
+    if !matches.is_present("raw") && buffer.starts_with(b"\x7fELF") {
+        let elf_file = ElfFile::new(&buffer);
+        let section_name = matches.value_of("section").unwrap_or(".text");
+        let section = elf_file.find_section_by_name(section_name)
+            .expect("section not found");
+        let address = section_address(&elf_file, section.offset());
+        print_disassembly(section.raw_data(&elf_file), address, formatter, code_size);
+    } else {
+        let address = matches.value_of("address")
+            .map_or(0, |address| {
+                u64::from_str_radix(address.trim_start_matches("0x"), 16).expect("Invalid address")
+            });
+        print_disassembly(&buffer, address, formatter, code_size);
+    }
+}
+
+/// Virtual address of a file offset, the load segment containing it decides.
+fn section_address(elf_file: &ElfFile, offset: u64) -> u64 {
+    for segment in elf_file.program_iter() {
+        if let Ok(program::Type::Load) = segment.get_type() {
+            if offset >= segment.offset() && offset < segment.offset() + segment.file_size() {
+                return segment.virtual_addr() + offset - segment.offset();
+            }
+        }
+    }
+    // object files are not loaded, addresses are section offsets
+    0
+}
+
+fn print_disassembly(code: &[u8], address: u64, formatter: Box<dyn InstructionFormatter>, code_size: CodeSize) {
+    for instruction in disassemble(code, address).formatter(formatter).code_size(code_size) {
+        let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{:>8x}:\t{:<21}\t{}", instruction.address, bytes.join(" "), instruction.text().trim_end());
+    }
+}
@@ -0,0 +1,33 @@
+extern crate clap;
+use clap::{App, Arg};
+
+use std::process;
+
+extern crate x86emu;
+use x86emu::difftest::{difftest, DEFAULT_FLAGS_MASK};
+
+fn main() {
+    let matches = App::new("x86emu-difftest")
+        .about("runs a static binary natively (ptrace single step) and in the emulator, \
+                compares all registers after every instruction")
+        .arg(Arg::with_name("file").required(true))
+        .arg(Arg::with_name("flags-mask")
+            .help("rflags bits to compare (hex), default: CF PF ZF SF DF OF")
+            .long("flags-mask")
+            .takes_value(true))
+        .arg(Arg::with_name("verbose")
+            .help("print every executed instruction")
+            .long("verbose")
+            .short("v"))
+        .get_matches();
+
+    let filename = matches.value_of("file").unwrap();
+    let flags_mask = match matches.value_of("flags-mask") {
+        Some(mask) => u64::from_str_radix(mask.trim_start_matches("0x"), 16).expect("Invalid flags mask"),
+        None => DEFAULT_FLAGS_MASK,
+    };
+
+    if !difftest(filename, flags_mask, matches.is_present("verbose")) {
+        process::exit(1);
+    }
+}
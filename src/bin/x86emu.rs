@@ -1,10 +1,35 @@
 extern crate clap;
 use clap::{App, Arg};
 
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::rc::Rc;
+use std::cell::RefCell;
+
 extern crate x86emu;
 use x86emu::loader::elf::elf;
 use x86emu::loader::linux::linux;
 use x86emu::loader::dump::dump;
+use x86emu::loader::boot::boot;
+use x86emu::loader::multiboot::multiboot;
+use x86emu::loader::options::BootOptions;
+use x86emu::memory_map::MemoryConfig;
+use x86emu::pci::PciFunction;
+use x86emu::pci::test_device::TestDevice;
+use x86emu::virtio::pci::VirtioPci;
+use x86emu::virtio::disk::Disk;
+use x86emu::virtio::block::BlockDevice;
+use x86emu::virtio::net::{NetDevice, DEFAULT_MAC};
+use x86emu::virtio::net_backend::{NetBackend, PcapBackend, SocketBackend};
+use x86emu::virtio::console::{ConsoleDevice, ConsolePort};
+use x86emu::virtio::rng::RngDevice;
+use x86emu::trace::{TraceSink, TextTraceWriter, JsonTraceWriter, BinaryTraceWriter, TraceFilter};
+use x86emu::coverage::{DrcovWriter, LcovWriter};
+use x86emu::profiler::Profiler;
+use x86emu::formatter::formatter_for_syntax;
+use x86emu::cpu::clock::ClockSource;
+use x86emu::cpu::options::CpuOptions;
+use x86emu::cpu::model::CpuModel;
 
 fn main() {
     let matches = App::new("x86emu")
@@ -19,7 +44,7 @@ fn main() {
             .long("loader")
             .short("l")
             .takes_value(true)
-            .possible_values(&["linux", "elf", "dump"]))
+            .possible_values(&["linux", "elf", "dump", "boot", "multiboot"]))
         .arg(Arg::with_name("debug")
             .help("run in debug mode (print all registers after every instruction)")
             .long("debug")
@@ -32,6 +57,133 @@ fn main() {
             .help("print every executed instruction")
             .long("print-instructions")
             .short("p"))
+        .arg(Arg::with_name("trace")
+            .help("write an execution trace to this file")
+            .long("trace")
+            .takes_value(true))
+        .arg(Arg::with_name("trace-format")
+            .help("format of the execution trace")
+            .long("trace-format")
+            .takes_value(true)
+            .possible_values(&["text", "jsonl", "binary"]))
+        .arg(Arg::with_name("trace-range")
+            .help("only trace instructions in this address range (hex, start:end)")
+            .long("trace-range")
+            .takes_value(true))
+        .arg(Arg::with_name("trace-window")
+            .help("only trace instructions with an instruction count in this window (first:last)")
+            .long("trace-window")
+            .takes_value(true))
+        .arg(Arg::with_name("coverage")
+            .help("write the code coverage to this file")
+            .long("coverage")
+            .takes_value(true))
+        .arg(Arg::with_name("coverage-format")
+            .help("format of the code coverage (lcov needs DWARF debug information)")
+            .long("coverage-format")
+            .takes_value(true)
+            .possible_values(&["drcov", "lcov"]))
+        .arg(Arg::with_name("profile")
+            .help("profile the guest, write collapsed stacks for flamegraph.pl to this file")
+            .long("profile")
+            .takes_value(true))
+        .arg(Arg::with_name("profile-top")
+            .help("number of functions and instructions in the profile summary (default 20)")
+            .long("profile-top")
+            .takes_value(true))
+        .arg(Arg::with_name("syntax")
+            .help("assembler syntax of printed and traced instructions (default att)")
+            .long("syntax")
+            .takes_value(true)
+            .possible_values(&["att", "nasm", "masm"]))
+        .arg(Arg::with_name("clock")
+            .help("source of the time stamp counter read by rdtsc (default instructions)")
+            .long("clock")
+            .takes_value(true)
+            .possible_values(&["instructions", "realtime"]))
+        .arg(Arg::with_name("cpu")
+            .help("cpu model: minimal-x86-64, x86-64-v2, host-like, max or a .toml/.json file (default max)")
+            .long("cpu")
+            .takes_value(true))
+        .arg(Arg::with_name("seed")
+            .help("seed of the random numbers returned by rdrand, rdseed and virtio-rng (default 0)")
+            .long("seed")
+            .takes_value(true))
+        .arg(Arg::with_name("append")
+            .help("kernel command line")
+            .long("append")
+            .takes_value(true))
+        .arg(Arg::with_name("initrd")
+            .help("initial ramdisk of a linux kernel")
+            .long("initrd")
+            .takes_value(true))
+        .arg(Arg::with_name("memory")
+            .help("RAM of a PC memory map, in MB or with a K/M/G suffix (default unlimited, reported as 128M)")
+            .long("memory")
+            .takes_value(true)
+            .validator(|value| memory_config(&value).map(|_| ())))
+        .arg(Arg::with_name("memory-map")
+            .help("physical memory map with RAM, ROM, reserved and MMIO regions from a .toml/.json file")
+            .long("memory-map")
+            .takes_value(true)
+            .conflicts_with("memory"))
+        .arg(Arg::with_name("module")
+            .help("multiboot module, the file name can be followed by the module command line")
+            .long("module")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("pci")
+            .help("PCI host bridge with configuration ports 0xCF8/0xCFC and ECAM")
+            .long("pci"))
+        .arg(Arg::with_name("pci-test-device")
+            .help("PCI function with scratch memory, i/o ports and capabilities for testing, implies --pci")
+            .long("pci-test-device"))
+        .arg(Arg::with_name("disk")
+            .help("raw disk image of a virtio-blk device, implies --pci")
+            .long("disk")
+            .takes_value(true))
+        .arg(Arg::with_name("disk-read-only")
+            .help("the virtio-blk device is read only")
+            .long("disk-read-only")
+            .requires("disk"))
+        .arg(Arg::with_name("disk-overlay")
+            .help("copy-on-write overlay file for the disk image, created if it does not exist")
+            .long("disk-overlay")
+            .takes_value(true)
+            .requires("disk"))
+        .arg(Arg::with_name("net-mac")
+            .help("MAC address of a virtio-net device (default 52:54:00:12:34:56), implies --pci")
+            .long("net-mac")
+            .takes_value(true))
+        .arg(Arg::with_name("net-replay")
+            .help("pcap file with the frames the virtio-net device receives")
+            .long("net-replay")
+            .takes_value(true))
+        .arg(Arg::with_name("net-capture")
+            .help("pcap file for the frames the virtio-net device sends")
+            .long("net-capture")
+            .takes_value(true))
+        .arg(Arg::with_name("net-socket")
+            .help("Unix datagram socket the virtio-net device sends and receives frames on")
+            .long("net-socket")
+            .takes_value(true)
+            .requires("net-peer")
+            .conflicts_with_all(&["net-replay", "net-capture"]))
+        .arg(Arg::with_name("net-peer")
+            .help("socket the frames from --net-socket go to, e.g. the --net-socket of another x86emu")
+            .long("net-peer")
+            .takes_value(true)
+            .requires("net-socket"))
+        .arg(Arg::with_name("console-port")
+            .help("port of a virtio-console as name:output[:input] with host files or pipes, output - is stdout, the first port is the console, implies --pci")
+            .long("console-port")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("rng")
+            .help("virtio-rng device with random numbers from --seed, implies --pci")
+            .long("rng"))
         .get_matches();
 
     let symbol = matches.value_of("symbol").unwrap_or("main");
@@ -41,16 +193,137 @@ fn main() {
     let benchmark = matches.is_present("benchmark");
     let print_instructions = matches.is_present("print-instructions");
 
+    let trace_range = matches.value_of("trace-range").map(|range| parse_range(range, 16));
+    let trace_window = matches.value_of("trace-window").map(|window| parse_range(window, 10));
+
+    let mut trace_sinks: Vec<Box<dyn TraceSink>> = Vec::new();
+    if print_instructions {
+        let sink = Box::new(TextTraceWriter::new(io::stdout()));
+        trace_sinks.push(Box::new(TraceFilter::new(sink, trace_range, trace_window)));
+    }
+    if let Some(trace_file) = matches.value_of("trace") {
+        let writer = BufWriter::new(File::create(trace_file).expect("Cannot create trace file"));
+        let sink: Box<dyn TraceSink> = match matches.value_of("trace-format").unwrap_or("jsonl") {
+            "text" => Box::new(TextTraceWriter::new(writer)),
+            "jsonl" => Box::new(JsonTraceWriter::new(writer)),
+            "binary" => Box::new(BinaryTraceWriter::new(writer)),
+            _ => unreachable!("Values already validated by clap"),
+        };
+        trace_sinks.push(Box::new(TraceFilter::new(sink, trace_range, trace_window)));
+    }
+    if let Some(coverage_file) = matches.value_of("coverage") {
+        let writer = BufWriter::new(File::create(coverage_file).expect("Cannot create coverage file"));
+        let sink: Box<dyn TraceSink> = match matches.value_of("coverage-format").unwrap_or("drcov") {
+            "drcov" => Box::new(DrcovWriter::new(writer, filename)),
+            "lcov" => Box::new(LcovWriter::new(writer, filename)),
+            _ => unreachable!("Values already validated by clap"),
+        };
+        trace_sinks.push(sink);
+    }
+    if let Some(profile_file) = matches.value_of("profile") {
+        let writer = BufWriter::new(File::create(profile_file).expect("Cannot create profile file"));
+        let top = matches.value_of("profile-top")
+            .map_or(20, |top| top.parse().expect("Invalid number for --profile-top"));
+        trace_sinks.push(Box::new(Profiler::new(writer, filename, top)));
+    }
+
+    let formatter = formatter_for_syntax(matches.value_of("syntax").unwrap_or("att"));
+
+    let mut pci_functions: Vec<Rc<RefCell<dyn PciFunction>>> = Vec::new();
+    if matches.is_present("pci-test-device") {
+        pci_functions.push(Rc::new(RefCell::new(TestDevice::new())));
+    }
+    if let Some(disk) = matches.value_of("disk") {
+        let disk = Disk::open(disk, matches.is_present("disk-read-only"), matches.value_of("disk-overlay"));
+        pci_functions.push(Rc::new(RefCell::new(VirtioPci::new(BlockDevice::new(disk)))));
+    }
+    if ["net-mac", "net-replay", "net-capture", "net-socket"].iter().any(|name| matches.is_present(name)) {
+        let mac = matches.value_of("net-mac").map_or(DEFAULT_MAC, NetDevice::parse_mac);
+        let backend: Box<dyn NetBackend> = match matches.value_of("net-socket") {
+            Some(socket) => Box::new(SocketBackend::new(socket, matches.value_of("net-peer").unwrap())
+                                   .expect("Cannot bind network socket")),
+            None => Box::new(PcapBackend::new(matches.value_of("net-replay"), matches.value_of("net-capture"))),
+        };
+        pci_functions.push(Rc::new(RefCell::new(VirtioPci::new(NetDevice::new(mac, backend)))));
+    }
+    if let Some(ports) = matches.values_of("console-port") {
+        let ports = ports.map(ConsolePort::from_spec).collect();
+        pci_functions.push(Rc::new(RefCell::new(VirtioPci::new(ConsoleDevice::new(ports)))));
+    }
+    let seed = matches.value_of("seed").map_or(0, |seed| seed.parse().expect("Invalid number for --seed"));
+    if matches.is_present("rng") {
+        pci_functions.push(Rc::new(RefCell::new(VirtioPci::new(RngDevice::new(seed)))));
+    }
+
+    let cpu_options = CpuOptions {
+        clock: ClockSource::from_name(matches.value_of("clock").unwrap_or("instructions")),
+        seed: seed,
+        model: matches.value_of("cpu").map_or_else(CpuModel::default, CpuModel::from_name),
+        msr_handlers: Vec::new(),
+        memory: match (matches.value_of("memory"), matches.value_of("memory-map")) {
+            (Some(size), _) => Some(memory_config(size).expect("Values already validated by clap")),
+            (_, Some(filename)) => Some(MemoryConfig::from_file(filename)),
+            _ => None,
+        },
+        mmio_handlers: Vec::new(),
+        port_handlers: Vec::new(),
+        pci: matches.is_present("pci"),
+        pci_functions: pci_functions,
+    };
+
+    let boot_options = BootOptions {
+        command_line: matches.value_of("append").unwrap_or("").to_string(),
+        modules: matches.values_of("module").map_or_else(Vec::new, |modules| modules.map(String::from).collect()),
+        initrd: matches.value_of("initrd").map(String::from),
+    };
+
     match loader {
         "linux" => {
-            linux(filename, print_instructions, debug);
+            linux(filename, &boot_options, trace_sinks, formatter, &cpu_options, debug);
         }
         "elf" => {
-            elf(filename, symbol, print_instructions, debug, benchmark);
+            elf(filename, symbol, trace_sinks, formatter, &cpu_options, debug, benchmark);
         }
         "dump" => {
-            dump(filename, print_instructions, debug);
+            dump(filename, trace_sinks, formatter, &cpu_options, debug);
+        }
+        "boot" => {
+            boot(filename, trace_sinks, formatter, &cpu_options, debug);
+        }
+        "multiboot" => {
+            multiboot(filename, &boot_options, trace_sinks, formatter, &cpu_options, debug);
         }
         _ => unreachable!("Values already validated by clap"),
     }
 }
+
+fn parse_range(value: &str, radix: u32) -> (u64, u64) {
+    let parts: Vec<&str> = value.split(':').collect();
+    if parts.len() != 2 {
+        panic!("Invalid range: {}, expected start:end", value);
+    }
+    let parse = |part: &str| {
+        let part = part.trim_start_matches("0x");
+        u64::from_str_radix(part, radix).expect("Invalid number in range")
+    };
+    (parse(parts[0]), parse(parts[1]))
+}
+
+fn parse_memory_size(value: &str) -> Result<u64, String> {
+    let (number, shift) = match value.chars().last().map(|unit| unit.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        _ => (value, 20),
+    };
+    let number = number.parse::<u64>().map_err(|_| format!("{} is not a number", value))?;
+    if number.leading_zeros() < shift {
+        return Err(format!("{} does not fit into 64 bits", value));
+    }
+    Ok(number << shift)
+}
+
+/// The PC memory map of --memory
+fn memory_config(value: &str) -> Result<MemoryConfig, String> {
+    parse_memory_size(value).and_then(MemoryConfig::pc)
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+use xmas_elf::{ElfFile, program};
+
+use dwarf::{parse_debug_line, LineTable, StringSections};
+use trace::{TraceSink, TraceRecord};
+
+/// Executed instructions and basic blocks of a guest program.
+#[derive(Default)]
+pub struct CoverageMap {
+    /// execution count per instruction address
+    hits: BTreeMap<u64, u64>,
+    /// start address -> size of all executed basic blocks
+    blocks: BTreeMap<u64, u64>,
+    /// block we are currently in, start and end address
+    current_block: Option<(u64, u64)>,
+}
+
+impl CoverageMap {
+    pub fn record(&mut self, rip: u64, size: u64) {
+        *self.hits.entry(rip).or_insert(0) += 1;
+        match self.current_block {
+            // drcov stores the block size as u16
+            Some((start, end)) if end == rip && end + size - start <= 0xffff => {
+                self.current_block = Some((start, end + size));
+            }
+            _ => {
+                self.end_block();
+                self.current_block = Some((rip, rip + size));
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        if let Some((start, end)) = self.current_block.take() {
+            let size = self.blocks.entry(start).or_insert(0);
+            if end - start > *size {
+                *size = end - start;
+            }
+        }
+    }
+}
+
+/// The loaded image, drcov calls this a module.
+struct Module {
+    base: u64,
+    end: u64,
+    entry: u64,
+    path: String,
+}
+
+fn read_program(program: &str) -> Vec<u8> {
+    let mut file = File::open(program).expect("Cannot open file");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).expect("Failed to read file.");
+    buffer
+}
+
+fn is_elf(buffer: &[u8]) -> bool {
+    buffer.starts_with(b"\x7fELF")
+}
+
+fn module(program: &str, coverage: &CoverageMap) -> Module {
+    let path = fs::canonicalize(program)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or(program.to_string());
+    let buffer = read_program(program);
+    if is_elf(&buffer) {
+        let elf_file = ElfFile::new(&buffer);
+        let mut base = u64::max_value();
+        let mut end = 0;
+        for sect in elf_file.program_iter() {
+            if let Ok(program::Type::Load) = sect.get_type() {
+                base = base.min(sect.virtual_addr() & !0xfff);
+                end = end.max(sect.virtual_addr() + sect.mem_size());
+            }
+        }
+        let entry = elf_file.header.pt2.map(|header| header.entry_point()).unwrap_or(base);
+        Module {
+            base: base,
+            end: end,
+            entry: entry,
+            path: path,
+        }
+    } else {
+        // raw images (linux kernel, memory dumps): everything that was executed
+        let base = coverage.blocks.keys().next().cloned().unwrap_or(0);
+        let end = coverage.blocks.iter().map(|(start, size)| start + size).max().unwrap_or(0);
+        Module {
+            base: base,
+            end: end,
+            entry: base,
+            path: path,
+        }
+    }
+}
+
+/// drcov format (version 2) as written by DynamoRIO, readable by Lighthouse and bncov.
+pub struct DrcovWriter<W: Write> {
+    writer: W,
+    program: String,
+    coverage: CoverageMap,
+}
+
+impl<W: Write> DrcovWriter<W> {
+    pub fn new(writer: W, program: &str) -> DrcovWriter<W> {
+        DrcovWriter {
+            writer: writer,
+            program: program.to_string(),
+            coverage: CoverageMap::default(),
+        }
+    }
+}
+
+impl<W: Write> TraceSink for DrcovWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.coverage.record(record.rip, record.bytes.len() as u64);
+    }
+
+    fn finish(&mut self) {
+        self.coverage.end_block();
+        let module = module(&self.program, &self.coverage);
+        let blocks: Vec<(&u64, &u64)> = self.coverage
+            .blocks
+            .iter()
+            .filter(|&(&start, _)| start >= module.base && start < module.end)
+            .collect();
+
+        write!(self.writer,
+               "DRCOV VERSION: 2\n\
+                DRCOV FLAVOR: x86emu\n\
+                Module Table: version 2, count 1\n\
+                Columns: id, base, end, entry, checksum, timestamp, path\n\
+                {:2}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, {:#010x}, {}\n\
+                BB Table: {} bbs\n",
+               0,
+               module.base,
+               module.end,
+               module.entry,
+               0,
+               0,
+               module.path,
+               blocks.len())
+            .expect("Failed to write coverage");
+        for (&start, &size) in blocks {
+            // struct bb_entry_t { uint start; ushort size; ushort mod_id; }
+            let offset = (start - module.base) as u32;
+            let mut entry = [0; 8];
+            for i in 0..4 {
+                entry[i] = (offset >> (i * 8)) as u8;
+            }
+            entry[4] = size as u8;
+            entry[5] = (size >> 8) as u8;
+            self.writer.write_all(&entry).expect("Failed to write coverage");
+        }
+        self.writer.flush().expect("Failed to write coverage");
+    }
+}
+
+/// lcov tracefile with line coverage, addresses are mapped to source lines with the DWARF line table.
+pub struct LcovWriter<W: Write> {
+    writer: W,
+    program: String,
+    coverage: CoverageMap,
+}
+
+impl<W: Write> LcovWriter<W> {
+    pub fn new(writer: W, program: &str) -> LcovWriter<W> {
+        LcovWriter {
+            writer: writer,
+            program: program.to_string(),
+            coverage: CoverageMap::default(),
+        }
+    }
+}
+
+fn line_table(program: &str) -> LineTable {
+    let buffer = read_program(program);
+    if !is_elf(&buffer) {
+        panic!("lcov coverage needs an elf file with debug information");
+    }
+    let elf_file = ElfFile::new(&buffer);
+    let section_data = |name| elf_file.find_section_by_name(name).map_or(&[][..], |s| s.raw_data(&elf_file));
+    let debug_line = elf_file.find_section_by_name(".debug_line")
+        .expect(".debug_line section not found, was the program compiled with -g?");
+    let strings = StringSections {
+        debug_str: section_data(".debug_str"),
+        debug_line_str: section_data(".debug_line_str"),
+    };
+    parse_debug_line(debug_line.raw_data(&elf_file), &strings)
+}
+
+impl<W: Write> TraceSink for LcovWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.coverage.record(record.rip, record.bytes.len() as u64);
+    }
+
+    fn finish(&mut self) {
+        let table = line_table(&self.program);
+
+        // file -> line -> execution count, every line in the line table is instrumented
+        let mut lines: BTreeMap<&str, BTreeMap<u64, u64>> = BTreeMap::new();
+        for rows in table.rows.windows(2) {
+            let (row, next) = (rows[0], rows[1]);
+            if row.end_sequence || row.line == 0 {
+                continue;
+            }
+            let count = if next.address > row.address {
+                self.coverage.hits.range(row.address..next.address).map(|(_, &count)| count).max()
+            } else {
+                None
+            };
+            let line = lines.entry(&table.files[row.file])
+                .or_insert_with(BTreeMap::new)
+                .entry(row.line)
+                .or_insert(0);
+            *line = (*line).max(count.unwrap_or(0));
+        }
+
+        let mut output = String::new();
+        for (file, lines) in lines {
+            output.push_str(&format!("TN:\nSF:{}\n", file));
+            for (line, count) in &lines {
+                output.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            output.push_str(&format!("LF:{}\n", lines.len()));
+            output.push_str(&format!("LH:{}\n", lines.values().filter(|&&count| count > 0).count()));
+            output.push_str("end_of_record\n");
+        }
+        self.writer.write_all(output.as_bytes()).expect("Failed to write coverage");
+        self.writer.flush().expect("Failed to write coverage");
+    }
+}
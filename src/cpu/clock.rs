@@ -0,0 +1,97 @@
+use time::precise_time_ns;
+
+use instruction_set::Register;
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+
+/* The time stamp counter read by rdtsc and rdtscp. It either counts executed
+ * instructions, which makes every run of a program see the same times, or runs
+ * at 1 GHz with the monotonic clock of the host.
+ */
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    /// one tick per executed instruction
+    Instructions,
+    /// nanoseconds of the host's monotonic clock since the start of the emulation
+    Realtime,
+}
+
+impl ClockSource {
+    pub fn from_name(name: &str) -> ClockSource {
+        match name {
+            "instructions" => ClockSource::Instructions,
+            "realtime" => ClockSource::Realtime,
+            _ => panic!("Unknown clock source: {}", name),
+        }
+    }
+}
+
+pub struct Clock {
+    source: ClockSource,
+    instructions: u64,
+    start: u64,
+    /// added to the counter, set by writes of the IA32_TIME_STAMP_COUNTER MSR
+    offset: u64,
+}
+
+impl Clock {
+    pub fn new(source: ClockSource) -> Clock {
+        Clock {
+            source: source,
+            instructions: 0,
+            start: precise_time_ns(),
+            offset: 0,
+        }
+    }
+
+    /// Called by the decoder for every executed instruction
+    pub fn tick(&mut self) {
+        self.instructions += 1;
+    }
+
+    fn ticks(&self) -> u64 {
+        match self.source {
+            ClockSource::Instructions => self.instructions,
+            ClockSource::Realtime => precise_time_ns() - self.start,
+        }
+    }
+
+    pub fn time_stamp_counter(&self) -> u64 {
+        self.ticks().wrapping_add(self.offset)
+    }
+
+    /// The counter continues from value
+    pub fn set_time_stamp_counter(&mut self, value: u64) {
+        self.offset = value.wrapping_sub(self.ticks());
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new(ClockSource::Instructions)
+    }
+}
+
+impl EmulationCPU {
+    fn read_time_stamp_counter(&self, machine_state: &mut MachineState) {
+        // CR4.TSD restricts the time stamp counter to the kernel
+        if machine_state.cr4 & 1 << 2 != 0 && !machine_state.check_privileged("rdtsc with CR4.TSD set") {
+            return;
+        }
+        let tsc = machine_state.clock.time_stamp_counter();
+        machine_state.set_register_value(&Register::EAX, tsc as u32 as i64);
+        machine_state.set_register_value(&Register::EDX, (tsc >> 32) as i64);
+    }
+
+    pub fn rdtsc(&self, machine_state: &mut MachineState) {
+        self.read_time_stamp_counter(machine_state);
+    }
+
+    /// rdtsc and the IA32_TSC_AUX MSR, which the kernel sets to the cpu number
+    pub fn rdtscp(&self, machine_state: &mut MachineState) {
+        self.read_time_stamp_counter(machine_state);
+        let tsc_aux = machine_state.msrs.tsc_aux;
+        machine_state.set_register_value(&Register::ECX, tsc_aux as i64);
+    }
+}
@@ -0,0 +1,353 @@
+use instruction_set::{InstructionArgument, InstructionArguments, Register, Flags, ArgumentSize};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::msr::EFER_SCE;
+use cpu::mode::{CpuMode, CodeSize, SegmentDescriptor};
+use cpu::protection::Exception;
+
+/* Control flow instructions beyond the near call, jmp and ret: loop, jrcxz, enter,
+ * far call, jmp and ret, iret, syscall/sysret and hlt.
+ *
+ * Far transfers load cs (and ss when returning to an outer level) through the
+ * GDT, see cpu/mode.rs. There are no call gates or task switches, so far calls
+ * and jumps stay at the current privilege level. The privilege level is the RPL
+ * of cs (cpu/protection.rs), the elf loader starts user space programs at level 3
+ * with the selectors of Linux.
+ */
+
+/// rflags bits restored by iret: CF, PF, AF, ZF, SF, TF, IF, DF, OF, IOPL, NT, RF, AC and ID
+const IRET_FLAGS: i64 = 0x257FD5;
+
+/// rflags bits restored by sysret from r11, bit 1 is always set
+const SYSRET_FLAGS: i64 = 0x3C7FD7;
+
+const INTERRUPT_FLAG: i64 = 1 << 9;
+const IOPL: i64 = 3 << 12;
+
+/// Raises #UD and returns false if EFER.SCE is clear
+fn check_syscall_enabled(machine_state: &mut MachineState, instruction: &str) -> bool {
+    if machine_state.msrs.efer & EFER_SCE == 0 {
+        machine_state.raise(Exception::invalid_opcode(format!("{} with EFER.SCE clear", instruction)));
+        return false;
+    }
+    true
+}
+
+/// Size in bytes of the values pushed and popped by far transfers
+fn operand_bytes(size: ArgumentSize) -> u64 {
+    match size {
+        ArgumentSize::Bit16 => 2,
+        ArgumentSize::Bit32 => 4,
+        ArgumentSize::Bit64 => 8,
+        _ => panic!("Unsupported operand size for far transfers"),
+    }
+}
+
+fn to_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |result, &byte| result << 8 | byte as u64)
+}
+
+fn push(machine_state: &mut MachineState, value: u64, bytes: u64) {
+    let data: Vec<u8> = (0..bytes).map(|index| (value >> (index * 8)) as u8).collect();
+    machine_state.stack_push(&data);
+}
+
+fn pop(machine_state: &mut MachineState, bytes: u64) -> u64 {
+    let data = machine_state.stack_pop_bytes(bytes);
+    to_u64(&data)
+}
+
+/// Reads the offset and the selector of a far pointer, either in memory or
+/// in the instruction (ptr16:16 and ptr16:32 outside of 64 bit mode)
+fn far_pointer(machine_state: &mut MachineState, arg: &InstructionArguments) -> Result<(u64, u16), Exception> {
+    let (offset, selector) = match (&arg.first_argument, &arg.second_argument) {
+        (&Some(InstructionArgument::Immediate { immediate: offset }),
+         &Some(InstructionArgument::Immediate { immediate: selector })) => (offset as u64, selector as u16),
+        _ => {
+            let bytes = operand_bytes(arg.size());
+            let address = machine_state.linear_address(arg.get_one_argument());
+            let data = machine_state.mem_read(address, bytes + 2);
+            (to_u64(&data[..bytes as usize]), to_u64(&data[bytes as usize..]) as u16)
+        }
+    };
+    // real mode has no null selector
+    if machine_state.cpu_mode() == CpuMode::Real {
+        return Ok((offset, selector));
+    }
+    if selector & !3 == 0 {
+        return Err(Exception::general_protection(0, "far transfer to the null selector".to_string()));
+    }
+    let cpl = machine_state.cpl() as u16;
+    if selector & 3 > cpl {
+        let reason = format!("far transfer to selector {:#x} at privilege level {}", selector, cpl);
+        return Err(Exception::general_protection(selector as u64 & !3, reason));
+    }
+    Ok((offset, selector & !3 | cpl))
+}
+
+/// #GP unless a far return goes to the same or an outer privilege level
+fn check_return_privilege(machine_state: &MachineState, cs: u16, instruction: &str) -> Result<(), Exception> {
+    let cpl = machine_state.cpl() as u16;
+    if cs & 3 < cpl && machine_state.cpu_mode() != CpuMode::Real {
+        let reason = format!("{} to privilege level {} from {}", instruction, cs & 3, cpl);
+        return Err(Exception::general_protection(cs as u64 & !3, reason));
+    }
+    Ok(())
+}
+
+fn far_call(machine_state: &mut MachineState, arg: &InstructionArguments) -> Result<(), Exception> {
+    let (offset, selector) = far_pointer(machine_state, arg)?;
+    let bytes = operand_bytes(arg.size());
+    let cs = machine_state.cs as u64;
+    let rip = machine_state.rip as u64;
+    machine_state.load_segment(Register::CS, selector)?;
+    push(machine_state, cs, bytes);
+    push(machine_state, rip, bytes);
+    machine_state.rip = offset as i64;
+    Ok(())
+}
+
+fn far_jump(machine_state: &mut MachineState, arg: &InstructionArguments) -> Result<(), Exception> {
+    let (offset, selector) = far_pointer(machine_state, arg)?;
+    machine_state.load_segment(Register::CS, selector)?;
+    machine_state.rip = offset as i64;
+    Ok(())
+}
+
+/// cs and ss are both checked before either is loaded, a fault leaves the
+/// segments of the caller
+fn far_return(machine_state: &mut MachineState, arg: &InstructionArguments) -> Result<(), Exception> {
+    let bytes = operand_bytes(arg.size());
+    let rip = pop(machine_state, bytes);
+    let cs = pop(machine_state, bytes) as u16;
+    // lret imm16 releases the arguments on the stack of the caller
+    let release = match arg.first_argument {
+        Some(ref first_argument) => machine_state.get_value(first_argument, ArgumentSize::Bit64) as u64,
+        None => 0,
+    };
+    let stack_pointer = machine_state.stack_pointer().wrapping_add(release);
+    machine_state.set_stack_pointer(stack_pointer);
+    check_return_privilege(machine_state, cs, "lret")?;
+
+    let privilege_level = machine_state.cpl();
+    let code = machine_state.segment_descriptor(Register::CS, cs, privilege_level)?;
+    if cs & 3 > privilege_level as u16 && machine_state.cpu_mode() != CpuMode::Real {
+        // return to an outer privilege level, the stack of the caller follows
+        let rsp = pop(machine_state, bytes);
+        let ss = pop(machine_state, bytes) as u16;
+        let stack = machine_state.segment_descriptor(Register::SS, ss, (cs & 3) as u8)?;
+        machine_state.set_segment(Register::CS, cs, code);
+        machine_state.set_segment(Register::SS, ss, stack);
+        machine_state.set_stack_pointer(rsp.wrapping_add(release));
+        machine_state.null_inaccessible_segments();
+    } else {
+        machine_state.set_segment(Register::CS, cs, code);
+    }
+    machine_state.rip = rip as i64;
+    Ok(())
+}
+
+/// In 64 bit mode iret always pops rip, cs, rflags, rsp and ss, in the other
+/// modes rsp and ss only on a return to an outer privilege level
+fn interrupt_return(machine_state: &mut MachineState, arg: &InstructionArguments) -> Result<(), Exception> {
+    let bytes = operand_bytes(arg.size());
+    let rip = pop(machine_state, bytes);
+    let cs = pop(machine_state, bytes) as u16;
+    let rflags = pop(machine_state, bytes) as i64;
+
+    let mode = machine_state.cpu_mode();
+    let privilege_level = machine_state.cpl();
+    let outer = mode != CpuMode::Real && cs & 3 > privilege_level as u16;
+    let stack = if mode == CpuMode::Long || outer {
+        let rsp = pop(machine_state, bytes);
+        let ss = pop(machine_state, bytes) as u16;
+        Some((rsp, ss))
+    } else {
+        None
+    };
+
+    check_return_privilege(machine_state, cs, "iret")?;
+    let code = machine_state.segment_descriptor(Register::CS, cs, privilege_level)?;
+    let stack = match stack {
+        Some((rsp, ss)) => Some((rsp, ss, machine_state.segment_descriptor(Register::SS, ss, (cs & 3) as u8)?)),
+        None => None,
+    };
+
+    let mut mask = match bytes {
+        2 => IRET_FLAGS & 0xFFFF,
+        _ => IRET_FLAGS,
+    };
+    // only the kernel changes the i/o privilege level and the interrupt flag
+    if privilege_level > 0 {
+        mask &= !IOPL;
+    }
+    if privilege_level as i64 > (machine_state.rflags & IOPL) >> 12 {
+        mask &= !INTERRUPT_FLAG;
+    }
+    machine_state.rflags = machine_state.rflags & !mask | rflags & mask | 2;
+    machine_state.set_segment(Register::CS, cs, code);
+    if let Some((rsp, ss, descriptor)) = stack {
+        machine_state.set_segment(Register::SS, ss, descriptor);
+        machine_state.rsp = rsp as i64;
+    }
+    if outer {
+        machine_state.null_inaccessible_segments();
+    }
+    machine_state.rip = rip as i64;
+    Ok(())
+}
+
+fn canonical(address: i64) -> bool {
+    (address << 16) >> 16 == address
+}
+
+impl EmulationCPU {
+    /// Decrements rcx, or ecx with the address size prefix, without changing the flags.
+    /// Returns true if the counter is not 0 afterwards.
+    fn decrement_counter(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let (_, second_argument) = arg.get_two_arguments();
+        let counter = match *second_argument {
+            InstructionArgument::Register { register } => register,
+            _ => panic!("Loop without counter register"),
+        };
+        let value = machine_state.get_register_value(&counter).wrapping_sub(1);
+        machine_state.set_register_value(&counter, value);
+        machine_state.get_register_value(&counter) != 0
+    }
+
+    fn relative_jump(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (first_argument, _) = arg.get_two_arguments();
+        machine_state.rip += machine_state.get_value(first_argument, ArgumentSize::Bit64);
+    }
+
+    pub fn loop_rcx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if self.decrement_counter(machine_state, arg) {
+            self.relative_jump(machine_state, arg);
+        }
+    }
+
+    pub fn loope(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if self.decrement_counter(machine_state, arg) && machine_state.get_flag(Flags::Zero) {
+            self.relative_jump(machine_state, arg);
+        }
+    }
+
+    pub fn loopne(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if self.decrement_counter(machine_state, arg) && !machine_state.get_flag(Flags::Zero) {
+            self.relative_jump(machine_state, arg);
+        }
+    }
+
+    pub fn jrcxz(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (_, second_argument) = arg.get_two_arguments();
+        if machine_state.get_value(second_argument, ArgumentSize::Bit64) == 0 {
+            self.relative_jump(machine_state, arg);
+        }
+    }
+
+    /// The pushed frame pointers have the operand size, the stack size (SS.B outside
+    /// of 64 bit mode) selects sp/esp/rsp and bp/ebp/rbp, like in the SDM
+    pub fn enter(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (first_argument, second_argument) = arg.get_two_arguments();
+        let size = machine_state.get_value(first_argument, ArgumentSize::Bit64) as u64;
+        let level = machine_state.get_value(second_argument, ArgumentSize::Bit64) % 32;
+        let bytes = operand_bytes(arg.size());
+        let (stack_pointer, frame_pointer) = match machine_state.stack_size() {
+            CodeSize::Bit16 => (Register::SP, Register::BP),
+            CodeSize::Bit32 => (Register::ESP, Register::EBP),
+            CodeSize::Bit64 => (Register::RSP, Register::RBP),
+        };
+
+        let rbp = machine_state.rbp as u64;
+        push(machine_state, rbp, bytes);
+        let frame = machine_state.get_register_value(&stack_pointer) as u64;
+        if level > 0 {
+            // copy the frame pointers of the enclosing procedures, then add our own
+            let mut address = machine_state.get_register_value(&frame_pointer) as u64;
+            for _ in 1..level {
+                address = address.wrapping_sub(bytes);
+                machine_state.set_register_value(&frame_pointer, address as i64);
+                let linear = machine_state.stack_linear_address(address);
+                let data = machine_state.mem_read(linear, bytes);
+                machine_state.stack_push(&data);
+            }
+            push(machine_state, frame, bytes);
+        }
+        machine_state.set_register_value(&frame_pointer, frame as i64);
+        let rsp = (machine_state.get_register_value(&stack_pointer) as u64).wrapping_sub(size);
+        machine_state.set_register_value(&stack_pointer, rsp as i64);
+    }
+
+    pub fn lcall(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Err(exception) = far_call(machine_state, arg) {
+            machine_state.raise(exception);
+        }
+    }
+
+    pub fn ljmp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Err(exception) = far_jump(machine_state, arg) {
+            machine_state.raise(exception);
+        }
+    }
+
+    pub fn lret(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Err(exception) = far_return(machine_state, arg) {
+            machine_state.raise(exception);
+        }
+    }
+
+    pub fn iret(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Err(exception) = interrupt_return(machine_state, arg) {
+            machine_state.raise(exception);
+        }
+    }
+
+    pub fn syscall(&self, machine_state: &mut MachineState) {
+        if !check_syscall_enabled(machine_state, "syscall") {
+            return;
+        }
+        // without a kernel entry point the system calls of user space programs are emulated
+        if machine_state.msrs.lstar == 0 {
+            self.linux_syscall(machine_state);
+            return;
+        }
+        machine_state.rcx = machine_state.rip;
+        machine_state.r11 = machine_state.rflags;
+        // STAR[47:32] is the kernel code segment, the stack segment follows it
+        let selector = (machine_state.msrs.star >> 32) as u16 & !3;
+        machine_state.load_flat_segments(selector, SegmentDescriptor::code64(0), selector + 8);
+        machine_state.rflags &= !(machine_state.msrs.fmask as i64);
+        machine_state.rip = machine_state.msrs.lstar as i64;
+    }
+
+    pub fn sysret(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if !check_syscall_enabled(machine_state, "sysret") || !machine_state.check_privileged("sysret") {
+            return;
+        }
+        // STAR[63:48] is the 32 bit user code segment, followed by the stack and the 64 bit code segment
+        let selector = (machine_state.msrs.star >> 48) as u16;
+        match arg.size() {
+            ArgumentSize::Bit64 => {
+                let rcx = machine_state.get_register_value(&Register::RCX);
+                if !canonical(rcx) {
+                    let reason = format!("sysret to non canonical address {:x}", rcx);
+                    machine_state.raise(Exception::general_protection(0, reason));
+                    return;
+                }
+                machine_state.load_flat_segments((selector + 16) | 3, SegmentDescriptor::code64(3), (selector + 8) | 3);
+                machine_state.rip = rcx;
+            }
+            _ => {
+                machine_state.load_flat_segments(selector | 3, SegmentDescriptor::code32(3), (selector + 8) | 3);
+                machine_state.rip = machine_state.get_register_value(&Register::ECX) as u32 as i64;
+            }
+        }
+        machine_state.rflags = machine_state.r11 & SYSRET_FLAGS | 2;
+    }
+
+    pub fn hlt(&self, machine_state: &mut MachineState) {
+        if machine_state.check_privileged("hlt") {
+            machine_state.halted = true;
+        }
+    }
+}
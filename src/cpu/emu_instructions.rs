@@ -1,4 +1,3 @@
-use std::process;
 use std::u64;
 
 use extprim::u128::u128;
@@ -6,7 +5,9 @@ use extprim::u128::u128;
 use instruction_set::{InstructionArgument, InstructionArguments, Register, Flags};
 use machine_state::{MachineState};
 use instruction_set::{ArgumentSize, get_register_size};
-use utils::{convert_i32_to_u8vec, convert_i64_to_u8vec};
+use utils::{convert_i16_to_u8vec, convert_i32_to_u8vec, convert_i64_to_u8vec};
+use cpu::mode::CodeSize;
+use cpu::protection::Exception;
 
 pub struct EmulationCPU;
 
@@ -23,7 +24,8 @@ impl EmulationCPU {
         }
     }
 
-    fn sub_impl2(&self, machine_state: &mut MachineState, value1: i64, value2: i64, argument_size: ArgumentSize) -> i64 {
+    /// value2 - value1 with the flags of sub and cmp
+    pub fn sub_impl2(&self, machine_state: &mut MachineState, value1: i64, value2: i64, argument_size: ArgumentSize) -> i64 {
         let (result, carry, overflow) = match argument_size {
             ArgumentSize::Bit8 => {
                 let (result, carry) = (value2 as u8).overflowing_sub(value1 as u8);
@@ -45,6 +47,7 @@ impl EmulationCPU {
                 let (_, overflow) = (value2 as i64).overflowing_sub(value1 as i64);
                 (result as i64, carry, overflow)
             }
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by sub"),
         };
         machine_state.set_flag(Flags::Carry, carry);
         machine_state.set_flag(Flags::Overflow, overflow);
@@ -84,83 +87,13 @@ impl EmulationCPU {
         machine_state.set_value(value, second_argument, argument_size);
     }
 
-    // different instructions with same opcode
-    pub fn arithmetic(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let opcode = match arg.opcode {
-            Some(opcode) => opcode,
-            None => panic!("Unsupported argument type for arithmetic"),
-        };
-        match opcode {
-            0 => self.add(machine_state, arg),
-            1 => self.or(machine_state, arg),
-            2 => self.adc(machine_state, arg),
-            3 => self.sbb(machine_state, arg),
-            4 => self.and(machine_state, arg),
-            5 => self.sub(machine_state, arg),
-            6 => self.xor(machine_state, arg),
-            7 => self.cmp(machine_state, arg),
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn register_operation(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let opcode = match arg.opcode {
-            Some(opcode) => opcode,
-            None => panic!("Unsupported argument type for register_operation"),
-        };
-        match opcode {
-            0 => self.inc(machine_state, arg),
-            1 => self.dec(machine_state, arg),
-            2 => self.call(machine_state, arg),
-            3 => self.call(machine_state, arg), // far call
-            4 => self.jmp(machine_state, arg),
-            5 => self.jmp(machine_state, arg), // far jmp
-            6 => self.push(machine_state, arg),
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn compare_mul_operation(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let opcode = match arg.opcode {
-            Some(opcode) => opcode,
-            None => panic!("Unsupported argument type for compare_mul_operation"),
-        };
-        match opcode {
-            0 => self.test(machine_state, arg),
-            1 => self.test(machine_state, arg),
-            2 => self.not(machine_state, arg),
-            3 => self.neg(machine_state, arg),
-            4 => self.mul(machine_state, arg),
-            5 => self.imul(machine_state, arg),
-            6 => self.div(machine_state, arg),
-            7 => self.idiv(machine_state, arg),
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn shift_rotate(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let opcode = match arg.opcode {
-            Some(opcode) => opcode,
-            None => panic!("Unsupported argument type for shift_rotate"),
-        };
-        match opcode {
-            0 => self.rol(machine_state, arg),
-            1 => self.ror(machine_state, arg),
-            2 => self.rcl(machine_state, arg),
-            3 => self.rcr(machine_state, arg),
-            4 => self.shl(machine_state, arg),
-            5 => self.shr(machine_state, arg),
-            6 => self.shl(machine_state, arg), // sal and shl are the same
-            7 => self.sar(machine_state, arg),
-            _ => unreachable!(),
-        }
-    }
-
-    // all other instructions
     pub fn push(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("push", &arg);
         let first_argument = arg.get_one_argument();
         let vector = match arg.size() {
+            ArgumentSize::Bit16 => {
+                convert_i16_to_u8vec(machine_state.get_value(&first_argument,
+                                                             ArgumentSize::Bit16) as i16)
+            }
             ArgumentSize::Bit32 => {
                 convert_i32_to_u8vec(machine_state.get_value(&first_argument,
                                                              ArgumentSize::Bit32) as i32)
@@ -175,25 +108,53 @@ impl EmulationCPU {
     }
 
     pub fn pop(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("pop", &arg);
         let first_argument = arg.get_one_argument();
-        let value = machine_state.stack_pop();
-        machine_state.set_value(value, &first_argument, arg.size());
+        let size = arg.size();
+        let data = machine_state.stack_pop_bytes(size.bytes());
+        let value = data.iter().rev().fold(0, |value, &byte| value << 8 | byte as i64);
+        machine_state.set_value(value, &first_argument, size);
     }
 
     pub fn mov(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("mov", &arg);
-        self.mov_impl(machine_state, arg);
+        let (first_argument, second_argument) = arg.get_two_arguments();
+        let control_register = is_control_register(first_argument) || is_control_register(second_argument);
+        if control_register && !machine_state.check_privileged("mov from or to a control register") {
+            return;
+        }
+        match *second_argument {
+            InstructionArgument::Register { register } if control_register => {
+                self.write_control_register(machine_state, arg, register)
+            }
+            _ => self.mov_impl(machine_state, arg),
+        }
+    }
+
+    /// Outside of 64 bit mode control registers get zero extended 32 bit values,
+    /// the upper half of CR0 and CR4 is reserved
+    fn write_control_register(&self, machine_state: &mut MachineState, arg: &InstructionArguments, register: Register) {
+        let value = machine_state.get_value(arg.get_two_arguments().0, arg.size());
+        let value = match machine_state.code_size() {
+            CodeSize::Bit64 => value,
+            _ => value as u32 as i64,
+        };
+        let reserved = match register {
+            Register::CR0 | Register::CR4 => value as u64 >> 32 != 0,
+            _ => false,
+        };
+        if reserved {
+            let reason = format!("reserved bits set in {:?} value {:x}", register, value);
+            machine_state.raise(Exception::general_protection(0, reason));
+            return;
+        }
+        machine_state.set_register_value(&register, value);
     }
 
     pub fn movsx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg_no_size("movsx", &arg);
         // normal mov already does the sign extension
         self.mov_impl(machine_state, arg);
     }
 
     pub fn movzx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg_no_size("movzx", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let value = machine_state.get_value(&first_argument, argument_size);
@@ -215,6 +176,7 @@ impl EmulationCPU {
             ArgumentSize::Bit16 => value as u16 as u64,
             ArgumentSize::Bit32 => value as u32 as u64,
             ArgumentSize::Bit64 => value as u64 as u64,
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by movzx"),
         };
 
         // ArgumentSize::Bit64 is not used because target is always a register
@@ -222,7 +184,6 @@ impl EmulationCPU {
     }
 
     pub fn add(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("add", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let value1 = machine_state.get_value(&first_argument, argument_size);
@@ -253,6 +214,7 @@ impl EmulationCPU {
                 let (_, overflow) = (value2 as i64).overflowing_add(value1 as i64);
                 (result as i64, carry, overflow)
             }
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by add"),
         };
         machine_state.set_flag(Flags::Carry, carry);
         machine_state.set_flag(Flags::Overflow, overflow);
@@ -262,62 +224,58 @@ impl EmulationCPU {
     }
 
     pub fn or(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("or", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let value1 = machine_state.get_value(&first_argument, argument_size);
         let value2 = machine_state.get_value(&second_argument, argument_size);
         let result = value1 | value2;
         machine_state.compute_flags(result, argument_size);
+        machine_state.set_flag(Flags::Carry, false);
+        machine_state.set_flag(Flags::Overflow, false);
         machine_state.set_value(result, &second_argument, argument_size);
     }
 
-    pub fn adc(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("adc", &arg);
+    pub fn adc(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
     pub fn sbb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("sbb", &arg);
         self.sub_impl(machine_state, arg, true);
         // TODO: SBB implemented without carry
     }
 
     pub fn and(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("and", &arg);
         self.and_impl(machine_state, arg, true);
     }
 
     pub fn sub(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("sub", &arg);
         self.sub_impl(machine_state, arg, true);
     }
 
     pub fn xor(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("xor", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let value1 = machine_state.get_value(&first_argument, argument_size);
         let value2 = machine_state.get_value(&second_argument, argument_size);
         let result = value1 ^ value2;
         machine_state.compute_flags(result, argument_size);
+        machine_state.set_flag(Flags::Carry, false);
+        machine_state.set_flag(Flags::Overflow, false);
         machine_state.set_value(result, &second_argument, argument_size);
     }
 
     pub fn cmp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmp", &arg);
         self.sub_impl(machine_state, arg, false);
     }
 
     pub fn call(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("call", &arg);
+        let bytes = machine_state.stack_operand_bytes() as usize;
         let rip = convert_i64_to_u8vec(machine_state.rip);
-        machine_state.stack_push(&rip);
+        machine_state.stack_push(&rip[..bytes]);
         self.jmp_iml(machine_state, arg);
     }
 
     pub fn lea(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("lea", &arg);
         let (first_argument, second_argument) = arg.get_two_arguments();
         let argument_size = arg.size();
         match *first_argument {
@@ -335,111 +293,95 @@ impl EmulationCPU {
     }
 
     pub fn test(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("test", &arg);
         // TODO:  test not fully implemented
         self.and_impl(machine_state, arg, false);
     }
 
     pub fn cmovo(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovo", &arg);
         if machine_state.get_flag(Flags::Overflow) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovno(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovno", &arg);
         if !machine_state.get_flag(Flags::Overflow) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovb", &arg);
         if machine_state.get_flag(Flags::Carry) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovae(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovae", &arg);
         if !machine_state.get_flag(Flags::Carry) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmove(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmove", &arg);
         if machine_state.get_flag(Flags::Zero) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovne(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovne", &arg);
         if !machine_state.get_flag(Flags::Zero) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovbe(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovbe", &arg);
         if machine_state.get_flag(Flags::Carry) || machine_state.get_flag(Flags::Zero) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmova(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmova", &arg);
         if !machine_state.get_flag(Flags::Carry) && !machine_state.get_flag(Flags::Zero) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovs(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovs", &arg);
         if machine_state.get_flag(Flags::Sign) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovns(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovns", &arg);
         if !machine_state.get_flag(Flags::Sign) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovp", &arg);
         if machine_state.get_flag(Flags::Parity) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovnp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovnp", &arg);
         if !machine_state.get_flag(Flags::Parity) {
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovl", &arg);
         if machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow){
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovge(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovge", &arg);
         if machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow){
             self.mov_impl(machine_state, arg);
         }
     }
 
     pub fn cmovle(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovle", &arg);
         if machine_state.get_flag(Flags::Zero) ||
                 (machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow)) {
             self.mov_impl(machine_state, arg);
@@ -447,35 +389,29 @@ impl EmulationCPU {
     }
 
     pub fn cmovg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmovg", &arg);
         if !machine_state.get_flag(Flags::Zero) &&
                 (machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow)) {
             self.mov_impl(machine_state, arg);
         }
     }
 
-    pub fn rol(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("rol", &arg);
+    pub fn rol(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
-    pub fn ror(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("rol", &arg);
+    pub fn ror(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
-    pub fn rcl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("rcl", &arg);
+    pub fn rcl(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
-    pub fn rcr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("rcr", &arg);
+    pub fn rcr(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
     pub fn shl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("shl", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let mut value1 = machine_state.get_value(&first_argument, argument_size);
@@ -545,6 +481,7 @@ impl EmulationCPU {
                     (result as i64, carry, overflow)
                 }
             }
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by shl"),
         };
 
         if value1 == 1 {
@@ -558,7 +495,6 @@ impl EmulationCPU {
     }
 
     pub fn shr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("shr", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let mut value1 = machine_state.get_value(&first_argument, argument_size);
@@ -616,6 +552,7 @@ impl EmulationCPU {
                     (result as i64, carry, value2 as u64 & 0x8000000000000000 == 0x8000000000000000)
                 }
             }
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by shr"),
         };
 
         if value1 == 1 {
@@ -629,7 +566,6 @@ impl EmulationCPU {
     }
 
     pub fn sar(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("sar", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let mut value1 = machine_state.get_value(&first_argument, argument_size);
@@ -687,6 +623,7 @@ impl EmulationCPU {
                     (result as i64, carry)
                 }
             }
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by sar"),
         };
 
         if value1 == 1 {
@@ -700,7 +637,6 @@ impl EmulationCPU {
     }
 
     pub fn inc(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("inc", &arg);
         let first_argument = arg.get_one_argument();
         let argument_size = arg.size();
         let value = machine_state.get_value(&first_argument, argument_size);
@@ -711,7 +647,6 @@ impl EmulationCPU {
     }
 
     pub fn dec(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("dec", &arg);
         let first_argument = arg.get_one_argument();
         let argument_size = arg.size();
         let value = machine_state.get_value(&first_argument, argument_size);
@@ -722,7 +657,6 @@ impl EmulationCPU {
     }
 
     pub fn div(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("div", &arg);
         let argument_size = arg.size();
         let divisor = arg.get_one_argument();
         let divisor = u128::new(machine_state.get_value(&divisor, argument_size) as u64);
@@ -732,6 +666,7 @@ impl EmulationCPU {
             ArgumentSize::Bit16 => (Register::AX, Register::DX),
             ArgumentSize::Bit32 => (Register::EAX, Register::EDX),
             ArgumentSize::Bit64 => (Register::RAX, Register::RDX),
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by div"),
         };
 
         let dividend = u128::from_parts(machine_state.get_register_value(&reg_upper) as u64,
@@ -749,18 +684,15 @@ impl EmulationCPU {
         // todo: set flags (including floating point error flags)
     }
 
-    pub fn idiv(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("idiv", &arg);
+    pub fn idiv(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
-    pub fn mul(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("mul", &arg);
+    pub fn mul(&self, _machine_state: &mut MachineState, _arg: &InstructionArguments) {
         panic!("Not implemented");
     }
 
     pub fn imul(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("imul", &arg);
         // TODO: implement one argument version
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
@@ -780,7 +712,6 @@ impl EmulationCPU {
     }
 
     pub fn not(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("not", &arg);
         let first_argument = arg.get_one_argument();
         let argument_size = arg.size();
         let value = machine_state.get_value(&first_argument, argument_size);
@@ -789,7 +720,6 @@ impl EmulationCPU {
     }
 
     pub fn neg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("neg", &arg);
         let first_argument = arg.get_one_argument();
         let argument_size = arg.size();
         let value = machine_state.get_value(&first_argument, argument_size);
@@ -797,226 +727,109 @@ impl EmulationCPU {
         machine_state.set_value(result, &first_argument, argument_size);
     }
 
-    pub fn ret(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("ret");
-        let value = machine_state.stack_pop();
-        machine_state.rip = value;
-    }
-
-    pub fn lret(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("lret");
+    pub fn ret(&self, machine_state: &mut MachineState, arg: Option<&InstructionArguments>) {
         let value = machine_state.stack_pop();
-        machine_state.stack_pop(); // Code segment
         machine_state.rip = value;
+        // ret imm16 also releases the arguments of the callee
+        if let Some(arg) = arg {
+            let bytes = machine_state.get_value(arg.get_one_argument(), ArgumentSize::Bit64) as u64;
+            let stack_pointer = machine_state.stack_pointer().wrapping_add(bytes);
+            machine_state.set_stack_pointer(stack_pointer);
+        }
     }
 
     pub fn leave(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("leave");
-        let value = machine_state.get_register_value(&Register::RBP);
-        machine_state.set_register_value(&Register::RSP, value);
+        let (stack_pointer, frame_pointer) = match machine_state.stack_size() {
+            CodeSize::Bit16 => (Register::SP, Register::BP),
+            CodeSize::Bit32 => (Register::ESP, Register::EBP),
+            CodeSize::Bit64 => (Register::RSP, Register::RBP),
+        };
+        let value = machine_state.get_register_value(&frame_pointer);
+        machine_state.set_register_value(&stack_pointer, value);
         let value = machine_state.stack_pop();
-        machine_state.set_register_value(&Register::RBP, value);
+        machine_state.set_register_value(&frame_pointer, value);
     }
 
     pub fn pushf(&self, machine_state: &mut MachineState) {
+        let bytes = machine_state.stack_operand_bytes() as usize;
         let vector = convert_i64_to_u8vec(machine_state.rflags);
-        machine_state.stack_push(&vector);
+        machine_state.stack_push(&vector[..bytes]);
     }
 
     pub fn popf(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("popf");
         let value = machine_state.stack_pop();
-        machine_state.rflags = value;
+        // 16 and 32 bit code only replaces the low bits
+        machine_state.rflags = match machine_state.stack_operand_bytes() {
+            2 => machine_state.rflags & !0xFFFF | value,
+            _ => value,
+        };
     }
 
     pub fn std(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("std");
         machine_state.set_flag(Flags::Direction, true);
     }
 
     pub fn cld(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("cld");
         machine_state.set_flag(Flags::Direction, false);
     }
 
-    pub fn stos(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let to =
-            machine_state.get_value(&InstructionArgument::Register { register: Register::RDI },
-                                    ArgumentSize::Bit64);
-        if arg.repeat_equal {
-            let length =
-                machine_state.get_value(&InstructionArgument::Register { register: Register::RCX },
-                                        ArgumentSize::Bit64);
-            let length = match arg.explicit_size.unwrap() {
-                ArgumentSize::Bit8 => {
-                    machine_state.print_instr("rep stos %al,%es:(%rdi)");
-                    length
-                },
-                ArgumentSize::Bit16 => {
-                    machine_state.print_instr("rep stos %ax,%es:(%rdi)");
-                    length * 2
-                },
-                ArgumentSize::Bit32 => {
-                    machine_state.print_instr("rep stos %eax,%es:(%rdi)");
-                    length * 4
-                },
-                ArgumentSize::Bit64 => {
-                    machine_state.print_instr("rep stos %rax,%es:(%rdi)");
-                    length * 8
-                },
-            };
-
-            if machine_state.get_flag(Flags::Direction) {
-                panic!("stos NOOP");
-            } else {
-                // TODO: actually do something
-                machine_state.set_register_value(&Register::RDI, to + length);
-                machine_state.set_register_value(&Register::RCX, 0);
-            }
-        } else {
-            machine_state.print_instr("stos %ds:(%rsi),%es:(%rdi)");
-            panic!("stos NOOP");
-            // TODO:  stos: NOOP
-        }
-    }
-
-
-    pub fn movs(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let mut from =
-            machine_state.get_value(&InstructionArgument::Register { register: Register::RSI },
-                                    ArgumentSize::Bit64);
-        let mut to =
-            machine_state.get_value(&InstructionArgument::Register { register: Register::RDI },
-                                    ArgumentSize::Bit64);
-        let bytes_per_mov = match arg.explicit_size.expect("movs need an explicit_size") {
-            ArgumentSize::Bit64 => 8,
-            ArgumentSize::Bit32 => 4,
-            ArgumentSize::Bit16 => 2,
-            ArgumentSize::Bit8 => 1,
-        };
-        if arg.repeat_equal {
-            machine_state.print_instr("rep movs %ds:(%rsi),%es:(%rdi)");
-            let mut length =
-                machine_state.get_value(&InstructionArgument::Register { register: Register::RCX },
-                                        ArgumentSize::Bit64);
-            length *= bytes_per_mov;
-            if machine_state.get_flag(Flags::Direction) {
-                // TODO:  address calculation could be incorrect
-                from -= length;
-                to -= length;
-                let data = machine_state.mem_read(from as u64, length as u64);
-                machine_state.mem_write(to as u64, &data);
-                machine_state.set_register_value(&Register::RSI, from);
-                machine_state.set_register_value(&Register::RDI, to);
-            } else {
-                let data = machine_state.mem_read(from as u64, length as u64);
-                machine_state.mem_write(to as u64, &data);
-                // TODO:  rsi and rdi not set
-                // TODO: set rsi, rdi registers
-            }
-            machine_state.set_register_value(&Register::RCX, 0);
-        } else {
-            machine_state.print_instr("movs %ds:(%rsi),%es:(%rdi)");
-            let data = machine_state.mem_read(from as u64, bytes_per_mov as u64);
-            machine_state.mem_write(to as u64, &data);
-        }
-    }
-
-    fn scas_step(&self, machine_state: &mut MachineState, source: i64, needle: i64, argument_size: ArgumentSize) {
-        self.sub_impl2(machine_state, source, needle, argument_size);
-
-        let mut source_address = machine_state.get_register_value(&Register::RDI);
-        if machine_state.get_flag(Flags::Direction) {
-            source_address -= 1;
-        } else {
-            source_address += 1;
+    pub fn cli(&self, machine_state: &mut MachineState) {
+        if machine_state.check_iopl("cli") {
+            machine_state.set_flag(Flags::Interrupt, false);
         }
-        machine_state.set_register_value(&Register::RDI, source_address);
     }
 
-    pub fn scas(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("scas", &arg);
-        let argument_size = arg.size();
-        match argument_size {
-            ArgumentSize::Bit8 => (),
-            _ => panic!("scas: only 8bit values supported")
-        }
-        let (source_arg, needle) = arg.get_two_arguments();
-        let mut source = machine_state.get_value(&source_arg, argument_size);
-        let needle = machine_state.get_value(&needle, argument_size);
-
-        if arg.repeat_not_equal {
-            let mut i = machine_state.get_register_value(&Register::RCX) as u64;
-            loop {
-                if i == 0 {
-                    break;
-                }
-                i -= 1;
-                self.scas_step(machine_state, source, needle, argument_size);
-
-                if machine_state.get_flag(Flags::Zero) {
-                    break;
-                }
-
-                source = machine_state.get_value(&source_arg, argument_size);
+    pub fn sti(&self, machine_state: &mut MachineState) {
+        if machine_state.check_iopl("sti") {
+            // interrupts wait for the next instruction, sti; hlt cannot miss one
+            if !machine_state.get_flag(Flags::Interrupt) {
+                machine_state.interrupt_shadow = true;
             }
-            machine_state.set_register_value(&Register::RCX, i as i64);
-        } else if arg.repeat_equal {
-            panic!("repe scas not supported");
-        } else {
-            self.scas_step(machine_state, source, needle, argument_size);
+            machine_state.set_flag(Flags::Interrupt, true);
         }
     }
 
     pub fn jmp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jmp", &arg);
         self.jmp_iml(machine_state, arg);
     }
 
     pub fn jo(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jo", &arg);
         if machine_state.get_flag(Flags::Overflow) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jno(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jno", &arg);
         if !machine_state.get_flag(Flags::Overflow) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jb", &arg);
         if machine_state.get_flag(Flags::Carry) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jae(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jae", &arg);
         if !machine_state.get_flag(Flags::Carry) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn je(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("je", &arg);
         if machine_state.get_flag(Flags::Zero) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jne(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jne", &arg);
         if !machine_state.get_flag(Flags::Zero) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jbe(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jbe", &arg);
         // CF=1 OR ZF=1
         if machine_state.get_flag(Flags::Carry) || machine_state.get_flag(Flags::Zero) {
             self.jmp_iml(machine_state, arg);
@@ -1024,7 +837,6 @@ impl EmulationCPU {
     }
 
     pub fn ja(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("ja", &arg);
         // CF=0 AND ZF=0
         if !machine_state.get_flag(Flags::Carry) && !machine_state.get_flag(Flags::Zero) {
             self.jmp_iml(machine_state, arg);
@@ -1032,28 +844,24 @@ impl EmulationCPU {
     }
 
     pub fn js(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("js", &arg);
         if machine_state.get_flag(Flags::Sign) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jns(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jns", &arg);
         if !machine_state.get_flag(Flags::Sign) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jp", &arg);
         if machine_state.get_flag(Flags::Parity) {
             self.jmp_iml(machine_state, arg);
         }
     }
 
     pub fn jnp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("jnp", &arg);
         if !machine_state.get_flag(Flags::Parity) {
             self.jmp_iml(machine_state, arg);
         }
@@ -1061,7 +869,6 @@ impl EmulationCPU {
 
     pub fn jl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
         // SF!=OF
-        machine_state.print_instr_arg("jl", &arg);
         if machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow){
             self.jmp_iml(machine_state, arg);
         }
@@ -1069,7 +876,6 @@ impl EmulationCPU {
 
     pub fn jge(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
         // SF=OF
-        machine_state.print_instr_arg("jge", &arg);
         if machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow){
             self.jmp_iml(machine_state, arg);
         }
@@ -1077,7 +883,6 @@ impl EmulationCPU {
 
     pub fn jle(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
         // (ZF=1) OR (SF!=OF)
-        machine_state.print_instr_arg("jle", &arg);
         if machine_state.get_flag(Flags::Zero) ||
                 (machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow)) {
             self.jmp_iml(machine_state, arg);
@@ -1086,7 +891,6 @@ impl EmulationCPU {
 
     pub fn jg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
         // (ZF=0) AND (SF=OF)
-        machine_state.print_instr_arg("jg", &arg);
         if !machine_state.get_flag(Flags::Zero) &&
                 (machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow)) {
             self.jmp_iml(machine_state, arg);
@@ -1103,145 +907,117 @@ impl EmulationCPU {
     }
 
     pub fn seto(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("seto", &arg);
         let set = machine_state.get_flag(Flags::Overflow);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setno(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setno", &arg);
         let set = !machine_state.get_flag(Flags::Overflow);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setb", &arg);
         let set = machine_state.get_flag(Flags::Carry);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setae(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setae", &arg);
         let set = !machine_state.get_flag(Flags::Carry);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn sete(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("sete", &arg);
         let set = machine_state.get_flag(Flags::Zero);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setne(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setne", &arg);
         let set = !machine_state.get_flag(Flags::Zero);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setbe(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setbe", &arg);
         let set = machine_state.get_flag(Flags::Carry) || machine_state.get_flag(Flags::Zero);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn seta(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("seta", &arg);
         let set = !machine_state.get_flag(Flags::Carry) && !machine_state.get_flag(Flags::Zero);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn sets(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("sets", &arg);
         let set = machine_state.get_flag(Flags::Sign);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setns(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setns", &arg);
         let set = !machine_state.get_flag(Flags::Sign);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setp", &arg);
         let set = machine_state.get_flag(Flags::Parity);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setnp(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setnp", &arg);
         let set = !machine_state.get_flag(Flags::Parity);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setl", &arg);
         let set = machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setge(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setge", &arg);
         let set = machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow);
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setle(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setle", &arg);
         let set = machine_state.get_flag(Flags::Zero) ||
                 (machine_state.get_flag(Flags::Sign) != machine_state.get_flag(Flags::Overflow));
         self.set_byte(machine_state, arg, set);
     }
 
     pub fn setg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("setg", &arg);
         let set = !machine_state.get_flag(Flags::Zero) &&
                 (machine_state.get_flag(Flags::Sign) == machine_state.get_flag(Flags::Overflow));
         self.set_byte(machine_state, arg, set);
     }
 
-    pub fn out(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("out   %al,(%dx)");
-        let al = machine_state.get_register_value(&Register::AL);
-        let dx = machine_state.get_register_value(&Register::DX);
-        println!("AL: {:x}, DX: {:x}", al as u8, dx);
-
-        // TODO:  out not implemented
-    }
-
-    pub fn wrmsr(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("wrmsr");
-        // save_machine_state(machine_state, "machine_state.bin");
-        // panic!("machine state saved!");
-        // todo: implement instruction
+    /// The port of in and out, an 8 bit immediate or dx
+    fn port(&self, machine_state: &mut MachineState, argument: &InstructionArgument) -> u16 {
+        match *argument {
+            InstructionArgument::Immediate { immediate } => immediate as u8 as u16,
+            InstructionArgument::Register { ref register } => machine_state.get_register_value(register) as u16,
+            _ => panic!("Invalid port argument"),
+        }
     }
 
-    pub fn rdmsr(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("rdmsr");
-        let ecx = machine_state.get_register_value(&Register::RCX);
-        match ecx {
-            0xC0000080 => {
-                machine_state.set_register_value(&Register::RAX, 0x500);
-                machine_state.set_register_value(&Register::RDX, 0x0);
-            }
-            _ => {
-                panic!("RDMSR: unsupported operand: {:x}", ecx);
-            }
+    pub fn in_port(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let bytes = argument_size.bytes();
+        let port = self.port(machine_state, arg.first_argument.as_ref().unwrap());
+        if !machine_state.check_io_privilege(port, bytes) {
+            return;
         }
+        let value = machine_state.io_ports.read(port, bytes);
+        machine_state.set_value(value as i64, arg.second_argument.as_ref().unwrap(), argument_size);
     }
 
-    pub fn bit_manipulation(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        let opcode = match arg.opcode {
-            Some(opcode) => opcode,
-            None => panic!("Unsupported argument type for arithmetic"),
-        };
-        match opcode {
-            4 => self.bt(machine_state, arg),
-            5 => self.bts(machine_state, arg),
-            6 => self.btr(machine_state, arg),
-            7 => self.btc(machine_state, arg),
-            _ => panic!("Invalid opcode for bt instructions"),
+    pub fn out(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let bytes = argument_size.bytes();
+        let port = self.port(machine_state, arg.second_argument.as_ref().unwrap());
+        if !machine_state.check_io_privilege(port, bytes) {
+            return;
         }
+        let value = machine_state.get_value(arg.first_argument.as_ref().unwrap(), argument_size);
+        machine_state.io_ports.write(port, bytes, value as u64);
+        machine_state.process_pci_functions();
     }
 
     /// normalize bit_position,
@@ -1252,6 +1028,7 @@ impl EmulationCPU {
             ArgumentSize::Bit16 => bit_position % 16,
             ArgumentSize::Bit32 => bit_position % 32,
             ArgumentSize::Bit64 => bit_position % 64,
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by bt"),
         };
 
         let bit = ((arg >> bit_position) & 1) == 1;
@@ -1259,7 +1036,6 @@ impl EmulationCPU {
     }
 
     pub fn bt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("bt", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let bit_position = machine_state.get_value(&first_argument, argument_size);
@@ -1292,22 +1068,283 @@ impl EmulationCPU {
     }
 
     pub fn bts(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("bts", &arg);
         self.btx_impl(machine_state, arg, | _ | true);
     }
 
     pub fn btr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("btr", &arg);
         self.btx_impl(machine_state, arg, | _ | false);
     }
 
     pub fn btc(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("btc", &arg);
         self.btx_impl(machine_state, arg, | b | !b);
     }
 
+    pub fn bsf(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        // the destination is left unchanged if the source is zero
+        let index = if value == 0 { 0 } else { value.trailing_zeros() };
+        self.bit_scan_flags(machine_state, value, index);
+        if value != 0 {
+            machine_state.set_value(index as i64, destination, argument_size);
+        }
+    }
+
+    pub fn bsr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        let index = if value == 0 { 0 } else { 63 - value.leading_zeros() };
+        self.bit_scan_flags(machine_state, value, index);
+        if value != 0 {
+            machine_state.set_value(index as i64, destination, argument_size);
+        }
+    }
+
+    /// ZF tells whether the source is zero. The other flags are undefined, intel
+    /// cpus set PF for the index and clear the rest.
+    fn bit_scan_flags(&self, machine_state: &mut MachineState, value: u64, index: u32) {
+        machine_state.set_flag(Flags::Zero, value == 0);
+        machine_state.set_flag(Flags::Parity, index.count_ones() & 1 == 0);
+        machine_state.set_flag(Flags::Carry, false);
+        machine_state.set_flag(Flags::Overflow, false);
+        machine_state.set_flag(Flags::Sign, false);
+        machine_state.set_flag(Flags::Adjust, false);
+    }
+
+    /// CF tells whether the source is zero and ZF whether the count is. The other
+    /// flags are undefined, intel cpus clear them.
+    fn count_flags(&self, machine_state: &mut MachineState, value: u64, count: u32) {
+        machine_state.set_flag(Flags::Carry, value == 0);
+        machine_state.set_flag(Flags::Zero, count == 0);
+        machine_state.set_flag(Flags::Overflow, false);
+        machine_state.set_flag(Flags::Sign, false);
+        machine_state.set_flag(Flags::Adjust, false);
+        machine_state.set_flag(Flags::Parity, false);
+    }
+
+    pub fn bswap(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let register = arg.get_one_argument();
+        let value = machine_state.get_value(register, argument_size);
+        let value = match argument_size {
+            ArgumentSize::Bit32 => (value as u32).swap_bytes() as i64,
+            ArgumentSize::Bit64 => value.swap_bytes(),
+            // undefined according to the Intel manual, real cpus clear the register
+            ArgumentSize::Bit16 => 0,
+            _ => panic!("Invalid argument size for bswap"),
+        };
+        machine_state.set_value(value, register, argument_size);
+    }
+
+    pub fn popcnt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        machine_state.set_value(value.count_ones() as i64, destination, argument_size);
+        machine_state.set_flag(Flags::Zero, value == 0);
+        machine_state.set_flag(Flags::Carry, false);
+        machine_state.set_flag(Flags::Overflow, false);
+        machine_state.set_flag(Flags::Sign, false);
+        machine_state.set_flag(Flags::Adjust, false);
+        machine_state.set_flag(Flags::Parity, false);
+    }
+
+    pub fn lzcnt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        let count = value.leading_zeros() - (64 - bit_count(argument_size));
+        machine_state.set_value(count as i64, destination, argument_size);
+        self.count_flags(machine_state, value, count);
+    }
+
+    pub fn tzcnt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        let count = if value == 0 {
+            bit_count(argument_size)
+        } else {
+            value.trailing_zeros()
+        };
+        machine_state.set_value(count as i64, destination, argument_size);
+        self.count_flags(machine_state, value, count);
+    }
+
+    // BMI1 and BMI2, all of them are VEX encoded with the destination as the last argument
+    fn bmi_result(&self,
+                  machine_state: &mut MachineState,
+                  result: u64,
+                  destination: &InstructionArgument,
+                  argument_size: ArgumentSize) {
+        let result = zero_extend(result as i64, argument_size);
+        machine_state.set_value(result as i64, destination, argument_size);
+        machine_state.set_flag(Flags::Zero, result == 0);
+        machine_state.set_flag(Flags::Sign, result >> (bit_count(argument_size) - 1) == 1);
+        machine_state.set_flag(Flags::Overflow, false);
+        // undefined, intel cpus clear them
+        machine_state.set_flag(Flags::Adjust, false);
+        machine_state.set_flag(Flags::Parity, false);
+    }
+
+    pub fn andn(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source2, source1, destination) = arg.get_three_arguments();
+        let value1 = machine_state.get_value(source1, argument_size);
+        let value2 = machine_state.get_value(source2, argument_size);
+        self.bmi_result(machine_state, (!value1 & value2) as u64, destination, argument_size);
+        machine_state.set_flag(Flags::Carry, false);
+    }
+
+    pub fn bextr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (control, source, destination) = arg.get_three_arguments();
+        let control = machine_state.get_value(control, argument_size);
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        let start = (control & 0xFF) as u32;
+        let length = ((control >> 8) & 0xFF) as u32;
+        let value = if start >= 64 { 0 } else { value >> start };
+        let result = if length >= 64 { value } else { value & ((1 << length) - 1) };
+        self.bmi_result(machine_state, result, destination, argument_size);
+        machine_state.set_flag(Flags::Carry, false);
+    }
+
+    pub fn blsi(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        self.bmi_result(machine_state, value & value.wrapping_neg(), destination, argument_size);
+        machine_state.set_flag(Flags::Carry, value != 0);
+    }
+
+    pub fn blsmsk(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        self.bmi_result(machine_state, value ^ value.wrapping_sub(1), destination, argument_size);
+        machine_state.set_flag(Flags::Carry, value == 0);
+        machine_state.set_flag(Flags::Zero, false);
+    }
+
+    pub fn blsr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, destination) = arg.get_two_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        self.bmi_result(machine_state, value & value.wrapping_sub(1), destination, argument_size);
+        machine_state.set_flag(Flags::Carry, value == 0);
+    }
+
+    pub fn bzhi(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (index, source, destination) = arg.get_three_arguments();
+        let index = (machine_state.get_value(index, argument_size) & 0xFF) as u32;
+        let value = machine_state.get_value(source, argument_size) as u64;
+        let bits = bit_count(argument_size);
+        let result = if index < bits { value & ((1 << index) - 1) } else { value };
+        self.bmi_result(machine_state, result, destination, argument_size);
+        machine_state.set_flag(Flags::Carry, index > bits - 1);
+    }
+
+    pub fn pdep(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (mask, source, destination) = arg.get_three_arguments();
+        let mut mask = zero_extend(machine_state.get_value(mask, argument_size), argument_size);
+        let value = machine_state.get_value(source, argument_size) as u64;
+        // deposits the low bits of the source at the set bits of the mask
+        let mut result = 0;
+        let mut bit = 0;
+        while mask != 0 {
+            let lowest = mask & mask.wrapping_neg();
+            if (value >> bit) & 1 == 1 {
+                result |= lowest;
+            }
+            mask ^= lowest;
+            bit += 1;
+        }
+        machine_state.set_value(result as i64, destination, argument_size);
+    }
+
+    pub fn pext(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (mask, source, destination) = arg.get_three_arguments();
+        let mut mask = zero_extend(machine_state.get_value(mask, argument_size), argument_size);
+        let value = machine_state.get_value(source, argument_size) as u64;
+        // extracts the bits of the source at the set bits of the mask to the low bits
+        let mut result = 0;
+        let mut bit = 0;
+        while mask != 0 {
+            let lowest = mask & mask.wrapping_neg();
+            if value & lowest != 0 {
+                result |= 1 << bit;
+            }
+            mask ^= lowest;
+            bit += 1;
+        }
+        machine_state.set_value(result as i64, destination, argument_size);
+    }
+
+    pub fn mulx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (source, low, high) = arg.get_three_arguments();
+        let value = zero_extend(machine_state.get_value(source, argument_size), argument_size);
+        let (low_result, high_result) = match argument_size {
+            ArgumentSize::Bit32 => {
+                let result = value * zero_extend(machine_state.get_register_value(&Register::EDX), argument_size);
+                (result as u32 as u64, result >> 32)
+            }
+            ArgumentSize::Bit64 => {
+                let result = u128::new(value) * u128::new(machine_state.get_register_value(&Register::RDX) as u64);
+                (result.low64(), result.high64())
+            }
+            _ => panic!("Invalid argument size for mulx"),
+        };
+        // the high half wins if both destinations are the same register
+        machine_state.set_value(low_result as i64, low, argument_size);
+        machine_state.set_value(high_result as i64, high, argument_size);
+    }
+
+    fn shiftx_impl<F>(&self, machine_state: &mut MachineState, arg: &InstructionArguments, shift: F)
+        where F: FnOnce(i64, u32, ArgumentSize) -> i64
+    {
+        let argument_size = arg.size();
+        let (count, source, destination) = arg.get_three_arguments();
+        let count = machine_state.get_value(count, argument_size) as u32 & (bit_count(argument_size) - 1);
+        let value = machine_state.get_value(source, argument_size);
+        let result = shift(value, count, argument_size);
+        machine_state.set_value(result, destination, argument_size);
+    }
+
+    pub fn sarx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        // get_value sign extends, an arithmetic shift of the 64 bit value is enough
+        self.shiftx_impl(machine_state, arg, |value, count, _| value >> count);
+    }
+
+    pub fn shlx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.shiftx_impl(machine_state, arg, |value, count, _| value << count);
+    }
+
+    pub fn shrx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.shiftx_impl(machine_state, arg, |value, count, argument_size| {
+            (zero_extend(value, argument_size) >> count) as i64
+        });
+    }
+
+    pub fn rorx(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (count, source, destination) = arg.get_three_arguments();
+        let count = machine_state.get_value(count, ArgumentSize::Bit8) as u32 & (bit_count(argument_size) - 1);
+        let value = machine_state.get_value(source, argument_size);
+        let result = match argument_size {
+            ArgumentSize::Bit32 => (value as u32).rotate_right(count) as i64,
+            ArgumentSize::Bit64 => (value as u64).rotate_right(count) as i64,
+            _ => panic!("Invalid argument size for rorx"),
+        };
+        machine_state.set_value(result, destination, argument_size);
+    }
+
     pub fn cmpxchg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("cmpxchg", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let source = machine_state.get_value(&first_argument, argument_size);
@@ -1318,20 +1355,69 @@ impl EmulationCPU {
             ArgumentSize::Bit16 => Register::AX,
             ArgumentSize::Bit32 => Register::EAX,
             ArgumentSize::Bit64 => Register::RAX,
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by cmpxchg"),
         };
         let accumulator = machine_state.get_register_value(&accumulator_type);
 
-        if accumulator == destination {
-            machine_state.set_flag(Flags::Zero, true);
+        // flags are set like cmp of the accumulator and the destination
+        self.sub_impl2(machine_state, destination, accumulator, argument_size);
+        if machine_state.get_flag(Flags::Zero) {
             machine_state.set_value(source, &second_argument, argument_size);
         } else {
-            machine_state.set_flag(Flags::Zero, false);
             machine_state.set_register_value(&accumulator_type, destination);
         }
     }
 
+    /// cmpxchg8b compares edx:eax with the memory operand, cmpxchg16b (64 bit operand) rdx:rax
+    pub fn cmpxchg8b(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let address = machine_state.linear_address(arg.get_one_argument());
+        let (low, high, new_low, new_high, half) = match arg.size() {
+            ArgumentSize::Bit32 => (Register::EAX, Register::EDX, Register::EBX, Register::ECX, 4),
+            ArgumentSize::Bit64 => {
+                if address % 16 != 0 {
+                    let reason = format!("cmpxchg16b operand at {:x} is not 16 byte aligned", address);
+                    machine_state.raise(Exception::general_protection(0, reason));
+                    return;
+                }
+                (Register::RAX, Register::RDX, Register::RBX, Register::RCX, 8)
+            }
+            _ => panic!("Invalid operand size for cmpxchg8b"),
+        };
+        let mask = if half == 4 { 0xFFFFFFFF } else { u64::MAX };
+        let memory = machine_state.mem_read(address, half as u64 * 2);
+        let read = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |result, &byte| result << 8 | byte as u64);
+        let memory_low = read(&memory[..half]);
+        let memory_high = read(&memory[half..]);
+
+        let low_value = machine_state.get_register_value(&low) as u64 & mask;
+        let high_value = machine_state.get_register_value(&high) as u64 & mask;
+        if low_value == memory_low && high_value == memory_high {
+            machine_state.set_flag(Flags::Zero, true);
+            let new_low = machine_state.get_register_value(&new_low) as u64;
+            let new_high = machine_state.get_register_value(&new_high) as u64;
+            let bytes: Vec<u8> = (0..half).map(|index| (new_low >> (index * 8)) as u8)
+                .chain((0..half).map(|index| (new_high >> (index * 8)) as u8))
+                .collect();
+            machine_state.mem_write(address, &bytes);
+        } else {
+            machine_state.set_flag(Flags::Zero, false);
+            machine_state.set_register_value(&low, memory_low as i64);
+            machine_state.set_register_value(&high, memory_high as i64);
+        }
+    }
+
+    pub fn xadd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let (first_argument, second_argument) = arg.get_two_arguments();
+        let source = machine_state.get_value(&first_argument, argument_size);
+        let destination = machine_state.get_value(&second_argument, argument_size);
+        let sum = self.add_impl(machine_state, source, destination, argument_size);
+        // the destination is written last, xadd %eax,%eax keeps the sum
+        machine_state.set_value(destination, &first_argument, argument_size);
+        machine_state.set_value(sum, &second_argument, argument_size);
+    }
+
     pub fn xchg(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg("xchg", &arg);
         let argument_size = arg.size();
         let (first_argument, second_argument) = arg.get_two_arguments();
         let arg1 = machine_state.get_value(&first_argument, argument_size);
@@ -1341,7 +1427,9 @@ impl EmulationCPU {
         machine_state.set_value(arg1, &second_argument, argument_size);
     }
 
-    pub fn syscall(&self, machine_state: &mut MachineState) {
+    /// Emulates the Linux system calls of user space programs, used by syscall
+    /// if no kernel has set up an entry point.
+    pub fn linux_syscall(&self, machine_state: &mut MachineState) {
         let rax = machine_state.get_register_value(&Register::RAX);
 
         let p1 = machine_state.get_register_value(&Register::RDI) as u64;
@@ -1365,122 +1453,65 @@ impl EmulationCPU {
             /* sys_ioctl */ 16 => (),
             /* sys_writev */ 20 => (),
             /* sys_exit */ 60 => {
-                process::exit(0);
+                machine_state.stopped = true;
             },
             /* arch_prctl */ 158 => (),
+            /* sys_iopl */ 172 => {
+                machine_state.rflags = machine_state.rflags & !(3 << 12) | ((p1 & 3) << 12) as i64;
+                machine_state.set_register_value(&Register::RAX, 0);
+            }
             /* sys_set_tid_address */ 218 => (),
             /* sys_exit_group */ 231 => (),
             _ => panic!("unsupported syscall: {}", rax),
         }
     }
 
-    pub fn lgdt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg_no_size("lgdt", &arg);
-        let first_argument = arg.get_one_argument();
-        machine_state.gdt = machine_state.get_value(first_argument, ArgumentSize::Bit64);
+    pub fn cpuid(&self, machine_state: &mut MachineState) {
+        let leaf = machine_state.get_register_value(&Register::EAX) as u32;
+        let subleaf = machine_state.get_register_value(&Register::ECX) as u32;
+        let mut registers = machine_state.cpu_model.cpuid(leaf, subleaf);
+        match (leaf, subleaf) {
+            (1, _) => {
+                // OSXSAVE mirrors CR4.OSXSAVE if the cpu has XSAVE
+                let osxsave = (machine_state.cr4 >> 18) as u32 & 1 & registers[2] >> 26;
+                registers[2] = registers[2] & !(1 << 27) | osxsave << 27;
+            }
+            (0xD, 0) if registers[0] != 0 => {
+                // size of the save area for the state components enabled in XCR0 and for all of them
+                registers[1] = self.xsave_size(machine_state.xcr0) as u32;
+                registers[2] = self.xsave_size(registers[0] as u64) as u32;
+            }
+            _ => (),
+        }
+        machine_state.set_register_value(&Register::EAX, registers[0] as i64);
+        machine_state.set_register_value(&Register::EBX, registers[1] as i64);
+        machine_state.set_register_value(&Register::ECX, registers[2] as i64);
+        machine_state.set_register_value(&Register::EDX, registers[3] as i64);
     }
+}
 
-    pub fn lidt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
-        machine_state.print_instr_arg_no_size("lidt", &arg);
-        let first_argument = arg.get_one_argument();
-        machine_state.idt = machine_state.get_value(first_argument, ArgumentSize::Bit64);
+/// value as returned by get_value without the sign extension
+fn zero_extend(value: i64, argument_size: ArgumentSize) -> u64 {
+    match argument_size {
+        ArgumentSize::Bit8 => value as u8 as u64,
+        ArgumentSize::Bit16 => value as u16 as u64,
+        ArgumentSize::Bit32 => value as u32 as u64,
+        ArgumentSize::Bit64 => value as u64,
+        ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not general purpose values"),
     }
+}
 
-    pub fn cpuid(&self, machine_state: &mut MachineState) {
-        machine_state.print_instr("cpuid");
-        let value = machine_state.get_register_value(&Register::RAX);
-        match value {
-            0 => {
-                machine_state.set_register_value(&Register::EAX, 1000);
-                machine_state.set_register_value(&Register::EBX, 0x756e6547);
-                machine_state.set_register_value(&Register::EDX, 0x49656e69);
-                machine_state.set_register_value(&Register::ECX, 0x6c65746e);
-            },
-            1 => {
-                let edx = 1 << 0 | // Onboard x87 FPU
-                          0 << 1 | // Virtual 8086 mode extensions (such as VIF, VIP, PIV)
-                          0 << 2 | // Debugging extensions (CR4 bit 3)
-                          1 << 3 | // Page Size Extension
-                          0 << 4 | // Time Stamp Counter
-                          1 << 5 | // Model-specific registers
-                          1 << 6 | // Physical Address Extension
-                          0 << 7 | // Machine Check Exception
-                          1 << 8 | // CMPXCHG8 (compare-and-swap) instruction
-                          1 << 9 | // Onboard Advanced Programmable Interrupt Controller
-                          0 << 10 | // Reserved
-                          0 << 11 | // SYSENTER and SYSEXIT instructions
-                          0 << 12 | // Memory Type Range Registers
-                          0 << 13 | // Page Global Enable bit in CR4
-                          0 << 14 | // Machine check architecture
-                          1 << 15 | // Conditional move and FCMOV instructions
-                          0 << 16 | // Page Attribute Table
-                          0 << 17 | // 36-bit page size extension
-                          0 << 18 | // Processor Serial Number
-                          0 << 19 | // CLFLUSH instruction (SSE2)
-                          0 << 20 | // Reserved
-                          0 << 21 | // Debug store: save trace of executed jumps
-                          0 << 22 | // Onboard thermal control MSRs for ACPI
-                          0 << 23 | // MMX instructions
-                          1 << 24 | // FXSAVE, FXRESTOR instructions, CR4 bit 9
-                          1 << 25 | // SSE instructions (a.k.a. Katmai New Instructions)
-                          1 << 26 | // SSE2 instructions
-                          0 << 27 | // CPU cache supports self-snoop
-                          0 << 28 | // Hyper-threading
-                          0 << 29 | // Thermal monitor automatically limits temperature
-                          0 << 30 | // IA64 processor emulating x86
-                          0 << 31; // Pending Break Enable (PBE# pin) wakeup support
-
-                let ecx = 0 << 0 | // Prescott New Instructions-SSE3 (PNI)
-                          0 << 1 | // PCLMULQDQ support
-                          0 << 2 | // 64-bit debug store (edx bit 21)
-                          0 << 3 | // MONITOR and MWAIT instructions (SSE3)
-                          0 << 4 | // CPL qualified debug store
-                          0 << 5 | // Virtual Machine eXtensions
-                          0 << 6 | // Safer Mode Extensions (LaGrande)
-                          0 << 7 | // Enhanced SpeedStep
-                          0 << 8 | // Thermal Monitor 2
-                          0 << 9 | // Supplemental SSE3 instructions
-                          0 << 10 | // L1 Context ID
-                          0 << 11 | // Silicon Debug interface
-                          0 << 12 | // Fused multiply-add (FMA3)
-                          0 << 13 | // CMPXCHG16B instruction
-                          0 << 14 | // Can disable sending task priority messages
-                          0 << 15 | // Perfmon & debug capability
-                          0 << 16 | //
-                          0 << 17 | // Process context identifiers (CR4 bit 17)
-                          0 << 18 | // Direct cache access for DMA writes[12][13]
-                          0 << 19 | // SSE4.1 instructions
-                          0 << 20 | // SSE4.2 instructions
-                          0 << 21 | // x2APIC support
-                          0 << 22 | // MOVBE instruction (big-endian)
-                          0 << 23 | // POPCNT instruction
-                          0 << 24 | // APIC supports one-shot operation using a TSC deadline value
-                          0 << 25 | // AES instruction set
-                          0 << 26 | // XSAVE, XRESTOR, XSETBV, XGETBV
-                          0 << 27 | // XSAVE enabled by OS
-                          0 << 28 | // Advanced Vector Extensions
-                          0 << 29 | // F16C (half-precision) FP support
-                          0 << 30 | // RDRAND (on-chip random number generator) support
-                          0 << 31; // Running on a hypervisor (always 0 on a real CPU, but also with some hypervisors)
-
-                machine_state.set_register_value(&Register::EAX, 0);
-                machine_state.set_register_value(&Register::EBX, 0);
-                machine_state.set_register_value(&Register::ECX, ecx);
-                machine_state.set_register_value(&Register::EDX, edx);
-            },
-            0x80000000 => {
-                machine_state.set_register_value(&Register::EAX, 0x80000001);
-            },
-            0x80000001 => {
-                // let edx = 1 << 29 | // Long mode
-                //           1 << 31;  // 3DNow!
-                // machine_state.set_register_value(&Register::EDX, edx);
-                machine_state.set_register_value(&Register::RAX, 0x663);
-                machine_state.set_register_value(&Register::RBX, 0x0);
-                machine_state.set_register_value(&Register::RCX, 0x5);
-                machine_state.set_register_value(&Register::RDX, 0x2193fbfd);
-            }
-            _ => panic!("CPUID: unsupported input: {:x}", value),
-        }
+fn bit_count(argument_size: ArgumentSize) -> u32 {
+    argument_size.bytes() as u32 * 8
+}
+
+fn is_control_register(arg: &InstructionArgument) -> bool {
+    match *arg {
+        InstructionArgument::Register { register: Register::CR0 } |
+        InstructionArgument::Register { register: Register::CR2 } |
+        InstructionArgument::Register { register: Register::CR3 } |
+        InstructionArgument::Register { register: Register::CR4 } |
+        InstructionArgument::Register { register: Register::CR8 } => true,
+        _ => false,
     }
 }
@@ -1,2 +1,11 @@
 pub mod emu_instructions;
-pub mod emu_debug;
+pub mod vector;
+pub mod string;
+pub mod control_flow;
+pub mod clock;
+pub mod random;
+pub mod options;
+pub mod model;
+pub mod msr;
+pub mod mode;
+pub mod protection;
@@ -0,0 +1,654 @@
+use instruction_set::{InstructionArgument, InstructionArguments, Register, ArgumentSize};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::msr::{EFER_LME, EFER_LMA};
+use cpu::protection::Exception;
+use utils::convert_i64_to_u8vec;
+
+/* Operating modes and segmentation.
+ *
+ * CR0.PE switches from real mode to protected mode. Setting CR0.PG with EFER.LME
+ * set activates long mode (EFER.LMA), in long mode the L bit of the code segment
+ * selects 64 bit mode or compatibility mode. Outside of 64 bit mode the D bit of
+ * the code segment is the default operand and address size, the B bit of the
+ * stack segment the width of the stack pointer.
+ *
+ * Every segment register caches the descriptor of its selector. In real mode the
+ * base is the selector times 16, in protected mode the descriptor comes from the
+ * global or the local descriptor table (cpu/protection.rs). In 64 bit mode the
+ * bases of cs, ds, es and ss are ignored, fs and gs always use the FS_BASE and
+ * GS_BASE MSRs.
+ */
+
+pub const CR0_PE: i64 = 1 << 0;
+pub const CR0_ET: i64 = 1 << 4;
+pub const CR0_PG: i64 = 1 << 31;
+pub const CR4_PSE: i64 = 1 << 4;
+pub const CR4_PAE: i64 = 1 << 5;
+
+/// P, S, DPL and type of a segment descriptor
+const ACCESS_PRESENT: u8 = 1 << 7;
+const ACCESS_DPL_SHIFT: u8 = 5;
+/// code or data segment, system segments (TSS, LDT, gates) have it cleared
+const ACCESS_CODE_OR_DATA: u8 = 1 << 4;
+const ACCESS_CODE: u8 = 1 << 3;
+/// code segments which can be called from outer privilege levels without changing the CPL
+const ACCESS_CONFORMING: u8 = 1 << 2;
+/// writable for data segments, readable for code segments
+const ACCESS_READ_WRITE: u8 = 1 << 1;
+const ACCESS_ACCESSED: u8 = 1 << 0;
+
+/// the flags nibble of a segment descriptor
+const FLAG_LONG: u8 = 1 << 1;
+const FLAG_DEFAULT_32BIT: u8 = 1 << 2;
+const FLAG_GRANULARITY: u8 = 1 << 3;
+
+/// Linux keeps the GDT in the cpu entry area, out of reach of user space
+const LINUX_GDT_ADDRESS: u64 = 0xfffffe0000001000;
+/// null, kernel code 32, kernel code 64, kernel data, user code 32, user data and user code 64
+const LINUX_GDT: [u64; 7] = [
+    0,
+    0x00cf9b000000ffff,
+    0x00af9b000000ffff,
+    0x00cf93000000ffff,
+    0x00cffb000000ffff,
+    0x00cff3000000ffff,
+    0x00affb000000ffff,
+];
+const LINUX_USER_CS: u16 = 0x33;
+const LINUX_USER_DS: u16 = 0x2b;
+
+/// selectors of the segments set up without a GDT
+const FLAT_CODE_SELECTOR: u16 = 0x08;
+const FLAT_DATA_SELECTOR: u16 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuMode {
+    Real,
+    Protected,
+    /// long mode with a 16 or 32 bit code segment
+    Compatibility,
+    /// long mode with a 64 bit code segment
+    Long,
+}
+
+/// Default operand and address size of the code, selected by the code segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodeSize {
+    Bit16,
+    Bit32,
+    Bit64,
+}
+
+/// GDTR or IDTR, or the base and limit cached for LDTR and TR
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct DescriptorTableRegister {
+    pub base: u64,
+    pub limit: u16,
+}
+
+/// The part of a segment register loaded from the descriptor
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SegmentDescriptor {
+    pub base: u64,
+    /// in bytes, the granularity is already applied
+    pub limit: u32,
+    /// P, DPL, S and type
+    pub access: u8,
+    /// G, D/B, L and AVL
+    pub flags: u8,
+}
+
+impl SegmentDescriptor {
+    /// A segment as loaded in real mode: 64 KB at selector * 16
+    pub fn real_mode(selector: u16, code: bool) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: (selector as u64) << 4,
+            limit: 0xFFFF,
+            access: if code { 0x9B } else { 0x93 },
+            flags: 0,
+        }
+    }
+
+    /// Flat 64 bit code segment
+    pub fn code64(dpl: u8) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: 0,
+            limit: 0xFFFFFFFF,
+            access: 0x9B | dpl << ACCESS_DPL_SHIFT,
+            flags: FLAG_GRANULARITY | FLAG_LONG,
+        }
+    }
+
+    /// Flat 32 bit code segment
+    pub fn code32(dpl: u8) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: 0,
+            limit: 0xFFFFFFFF,
+            access: 0x9B | dpl << ACCESS_DPL_SHIFT,
+            flags: FLAG_GRANULARITY | FLAG_DEFAULT_32BIT,
+        }
+    }
+
+    /// Flat writable data segment with a 32 bit stack pointer
+    pub fn data(dpl: u8) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: 0,
+            limit: 0xFFFFFFFF,
+            access: 0x93 | dpl << ACCESS_DPL_SHIFT,
+            flags: FLAG_GRANULARITY | FLAG_DEFAULT_32BIT,
+        }
+    }
+
+    /// What a null selector loads into a data segment register
+    pub fn null() -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: 0,
+            limit: 0,
+            access: 0,
+            flags: 0,
+        }
+    }
+
+    /// Decodes an 8 byte descriptor of the GDT
+    pub fn from_raw(raw: u64) -> SegmentDescriptor {
+        let limit = (raw & 0xFFFF | (raw >> 32) & 0xF0000) as u32;
+        let flags = (raw >> 52) as u8 & 0xF;
+        SegmentDescriptor {
+            base: (raw >> 16) & 0xFFFFFF | (raw >> 32) & 0xFF000000,
+            limit: if flags & FLAG_GRANULARITY != 0 { limit << 12 | 0xFFF } else { limit },
+            access: (raw >> 40) as u8,
+            flags: flags,
+        }
+    }
+
+    pub fn present(&self) -> bool {
+        self.access & ACCESS_PRESENT != 0
+    }
+
+    pub fn dpl(&self) -> u8 {
+        (self.access >> ACCESS_DPL_SHIFT) & 3
+    }
+
+    pub fn is_code(&self) -> bool {
+        self.access & (ACCESS_CODE_OR_DATA | ACCESS_CODE) == ACCESS_CODE_OR_DATA | ACCESS_CODE
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.access & (ACCESS_CODE_OR_DATA | ACCESS_CODE) == ACCESS_CODE_OR_DATA
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.access & ACCESS_CODE_OR_DATA == 0
+    }
+
+    /// Type of a system descriptor (LDT, TSS or gate), see cpu/protection.rs
+    pub fn system_type(&self) -> u8 {
+        self.access & 0xF
+    }
+
+    pub fn conforming(&self) -> bool {
+        self.is_code() && self.access & ACCESS_CONFORMING != 0
+    }
+
+    /// writable data or readable code
+    pub fn read_write(&self) -> bool {
+        self.access & ACCESS_READ_WRITE != 0
+    }
+
+    pub fn long_mode(&self) -> bool {
+        self.flags & FLAG_LONG != 0
+    }
+
+    pub fn default_32bit(&self) -> bool {
+        self.flags & FLAG_DEFAULT_32BIT != 0
+    }
+}
+
+/// Index of a segment register in MachineState::segments, the order of the encoding
+pub fn segment_index(register: Register) -> usize {
+    match register {
+        Register::ES => 0,
+        Register::CS => 1,
+        Register::SS => 2,
+        Register::DS => 3,
+        Register::FS => 4,
+        Register::GS => 5,
+        _ => panic!("{:?} is not a segment register", register),
+    }
+}
+
+/// Memory operands based on the stack or frame pointer use ss, all others ds
+fn default_segment(arg: &InstructionArgument) -> Register {
+    match *arg {
+        InstructionArgument::EffectiveAddress { base: Some(Register::RSP), .. } |
+        InstructionArgument::EffectiveAddress { base: Some(Register::RBP), .. } |
+        InstructionArgument::EffectiveAddress { base: Some(Register::ESP), .. } |
+        InstructionArgument::EffectiveAddress { base: Some(Register::EBP), .. } |
+        InstructionArgument::EffectiveAddress { base: Some(Register::SP), .. } |
+        InstructionArgument::EffectiveAddress { base: Some(Register::BP), .. } => Register::SS,
+        _ => Register::DS,
+    }
+}
+
+impl MachineState {
+    pub fn cpu_mode(&self) -> CpuMode {
+        if self.cr0 & CR0_PE == 0 {
+            CpuMode::Real
+        } else if self.msrs.efer & EFER_LMA == 0 {
+            CpuMode::Protected
+        } else if self.segment(Register::CS).long_mode() {
+            CpuMode::Long
+        } else {
+            CpuMode::Compatibility
+        }
+    }
+
+    pub fn code_size(&self) -> CodeSize {
+        match self.cpu_mode() {
+            CpuMode::Real => CodeSize::Bit16,
+            CpuMode::Long => CodeSize::Bit64,
+            _ if self.segment(Register::CS).default_32bit() => CodeSize::Bit32,
+            _ => CodeSize::Bit16,
+        }
+    }
+
+    /// Width of the stack pointer: rsp in 64 bit mode, otherwise esp or sp depending on ss
+    pub fn stack_size(&self) -> CodeSize {
+        match self.cpu_mode() {
+            CpuMode::Long => CodeSize::Bit64,
+            CpuMode::Protected | CpuMode::Compatibility if self.segment(Register::SS).default_32bit() => CodeSize::Bit32,
+            _ => CodeSize::Bit16,
+        }
+    }
+
+    /// Size of return addresses and flags pushed by call, pushf and friends
+    pub fn stack_operand_bytes(&self) -> u64 {
+        match self.code_size() {
+            CodeSize::Bit16 => 2,
+            CodeSize::Bit32 => 4,
+            CodeSize::Bit64 => 8,
+        }
+    }
+
+    /// sp, esp or rsp depending on the stack size, zero extended
+    pub fn stack_pointer(&self) -> u64 {
+        match self.stack_size() {
+            CodeSize::Bit16 => self.rsp as u64 & 0xFFFF,
+            CodeSize::Bit32 => self.rsp as u64 & 0xFFFFFFFF,
+            CodeSize::Bit64 => self.rsp as u64,
+        }
+    }
+
+    /// Writes sp, esp or rsp depending on the stack size
+    pub fn set_stack_pointer(&mut self, value: u64) {
+        let register = match self.stack_size() {
+            CodeSize::Bit16 => Register::SP,
+            CodeSize::Bit32 => Register::ESP,
+            CodeSize::Bit64 => Register::RSP,
+        };
+        self.set_register_value(&register, value as i64);
+    }
+
+    pub fn stack_push(&mut self, data: &[u8]) {
+        let stack_pointer = self.stack_pointer().wrapping_sub(data.len() as u64);
+        self.set_stack_pointer(stack_pointer);
+        let address = self.stack_address();
+        self.mem_write(address, data);
+    }
+
+    /// Pops a value of the stack operand size, zero extended
+    pub fn stack_pop(&mut self) -> i64 {
+        let bytes = self.stack_operand_bytes();
+        let data = self.stack_pop_bytes(bytes);
+        data.iter().rev().fold(0, |value, &byte| value << 8 | byte as i64)
+    }
+
+    pub fn stack_pop_bytes(&mut self, bytes: u64) -> Vec<u8> {
+        let address = self.stack_address();
+        let data = self.mem_read(address, bytes);
+        let stack_pointer = self.stack_pointer().wrapping_add(bytes);
+        self.set_stack_pointer(stack_pointer);
+        data
+    }
+
+    /// Linear address of the top of the stack
+    pub fn stack_address(&self) -> u64 {
+        let stack_pointer = self.stack_pointer();
+        self.stack_linear_address(stack_pointer)
+    }
+
+    /// Linear address of an offset in the stack segment
+    pub fn stack_linear_address(&self, offset: u64) -> u64 {
+        let address = offset.wrapping_add(self.segment_base(Register::SS));
+        self.truncate_linear_address(address)
+    }
+
+    pub fn segment(&self, register: Register) -> &SegmentDescriptor {
+        &self.segments[segment_index(register)]
+    }
+
+    pub fn segment_base(&self, register: Register) -> u64 {
+        match register {
+            Register::FS => self.msrs.fs_base,
+            Register::GS => self.msrs.gs_base,
+            _ if self.cpu_mode() == CpuMode::Long => 0,
+            _ => self.segment(register).base,
+        }
+    }
+
+    /// The effective address of a memory operand plus the base of its segment
+    pub fn linear_address(&self, arg: &InstructionArgument) -> u64 {
+        let segment = self.segment_override.unwrap_or_else(|| default_segment(arg));
+        let address = self.calculate_effective_address(arg).wrapping_add(self.segment_base(segment));
+        self.truncate_linear_address(address)
+    }
+
+    /// Linear addresses have 32 bits outside of 64 bit mode
+    pub fn truncate_linear_address(&self, address: u64) -> u64 {
+        match self.cpu_mode() {
+            CpuMode::Long => address,
+            _ => address & 0xFFFFFFFF,
+        }
+    }
+
+    /// Linear address of the next instruction
+    pub fn instruction_address(&self) -> u64 {
+        let address = (self.rip as u64).wrapping_add(self.segment_base(Register::CS));
+        self.truncate_linear_address(address)
+    }
+
+    fn set_selector(&mut self, register: Register, selector: u16) {
+        match register {
+            Register::ES => self.es = selector,
+            Register::CS => self.cs = selector,
+            Register::SS => self.ss = selector,
+            Register::DS => self.ds = selector,
+            Register::FS => self.fs = selector,
+            _ => self.gs = selector,
+        }
+    }
+
+    /// Current privilege level, the RPL of cs outside of real mode
+    pub fn cpl(&self) -> u8 {
+        match self.cpu_mode() {
+            CpuMode::Real => 0,
+            _ => (self.cs & 3) as u8,
+        }
+    }
+
+    /// Loads a segment register with the descriptor of selector. Faults with #GP
+    /// for selectors outside of the descriptor table and descriptors which do
+    /// not fit the register or the privilege level, with #NP or #SS for
+    /// segments which are not present.
+    pub fn load_segment(&mut self, register: Register, selector: u16) -> Result<(), Exception> {
+        let cpl = self.cpl();
+        let descriptor = self.segment_descriptor(register, selector, cpl)?;
+        self.set_segment(register, selector, descriptor);
+        Ok(())
+    }
+
+    /// The descriptor a segment register gets for selector at privilege level
+    /// cpl. Far returns check cs and the stack of the outer level before they
+    /// load either.
+    pub fn segment_descriptor(&mut self, register: Register, selector: u16, cpl: u8)
+                              -> Result<SegmentDescriptor, Exception> {
+        let mode = self.cpu_mode();
+        // only the base changes, the limit and attributes stay (unreal mode)
+        if mode == CpuMode::Real {
+            return Ok(SegmentDescriptor {
+                base: (selector as u64) << 4,
+                ..*self.segment(register)
+            });
+        }
+        if selector & !3 == 0 {
+            return match register {
+                Register::CS => Err(Exception::general_protection(0, "null selector loaded into cs".to_string())),
+                // 64 bit kernels run with a null stack segment
+                Register::SS if mode != CpuMode::Long || selector & 3 == 3 => {
+                    Err(Exception::general_protection(0, "null selector loaded into ss".to_string()))
+                }
+                _ => Ok(SegmentDescriptor::null()),
+            };
+        }
+        let error_code = selector as u64 & !3;
+        let descriptor = self.read_descriptor(selector)?;
+        let valid = match register {
+            Register::CS => descriptor.is_code(),
+            Register::SS => descriptor.is_data() && descriptor.read_write(),
+            _ => descriptor.is_data() || descriptor.is_code() && descriptor.read_write(),
+        };
+        if !valid {
+            let reason = format!("descriptor {:#x} (access {:#x}) cannot be loaded into {:?}",
+                                 selector, descriptor.access, register);
+            return Err(Exception::general_protection(error_code, reason));
+        }
+        self.check_segment_privilege(register, selector, &descriptor, cpl)?;
+        if !descriptor.present() {
+            return Err(match register {
+                Register::SS => Exception::stack_fault(error_code, format!("segment {:#x} not present", selector)),
+                _ => Exception::segment_not_present(error_code, format!("segment {:#x} loaded into {:?}", selector, register)),
+            });
+        }
+        Ok(descriptor)
+    }
+
+    /// Loads a segment register with a descriptor from segment_descriptor
+    pub fn set_segment(&mut self, register: Register, selector: u16, descriptor: SegmentDescriptor) {
+        match register {
+            Register::FS => self.msrs.fs_base = descriptor.base,
+            Register::GS => self.msrs.gs_base = descriptor.base,
+            _ => (),
+        }
+        self.segments[segment_index(register)] = descriptor;
+        self.set_selector(register, selector);
+    }
+
+    /// The RPL of a code selector is the new CPL, far calls and jumps keep the
+    /// CPL (there are no call gates), lret and iret only return to outer levels.
+    /// The stack always has the DPL of the code, data segments need a DPL
+    /// at least as large as the CPL and the RPL.
+    fn check_segment_privilege(&self, register: Register, selector: u16, descriptor: &SegmentDescriptor, cpl: u8)
+                               -> Result<(), Exception> {
+        let rpl = (selector & 3) as u8;
+        let valid = match register {
+            Register::CS if descriptor.conforming() => descriptor.dpl() <= rpl,
+            Register::CS => descriptor.dpl() == rpl,
+            Register::SS => descriptor.dpl() == rpl && rpl == cpl,
+            _ if descriptor.conforming() => true,
+            _ => descriptor.dpl() >= cpl && descriptor.dpl() >= rpl,
+        };
+        if !valid {
+            let reason = format!("segment {:#x} with DPL {} loaded into {:?} at privilege level {}",
+                                 selector, descriptor.dpl(), register, cpl);
+            return Err(Exception::general_protection(selector as u64 & !3, reason));
+        }
+        Ok(())
+    }
+
+    /// Returning to an outer privilege level nulls the data segment registers
+    /// the new CPL has no access to. The fs and gs bases stay.
+    pub fn null_inaccessible_segments(&mut self) {
+        let cpl = self.cpl();
+        for &register in [Register::ES, Register::DS, Register::FS, Register::GS].iter() {
+            let descriptor = *self.segment(register);
+            if (descriptor.is_data() || descriptor.is_code() && !descriptor.conforming()) && descriptor.dpl() < cpl {
+                self.segments[segment_index(register)] = SegmentDescriptor {
+                    base: descriptor.base,
+                    ..SegmentDescriptor::null()
+                };
+                self.set_selector(register, 0);
+            }
+        }
+    }
+
+    /// Linear address of the descriptor of a selector in the GDT or the LDT.
+    /// System descriptors in long mode take 16 bytes. Selectors outside of the
+    /// table fault with #GP.
+    pub fn descriptor_address(&self, selector: u16, bytes: u64) -> Result<u64, Exception> {
+        let error_code = selector as u64 & !3;
+        let table = if selector & 4 != 0 {
+            if self.ldtr & !3 == 0 {
+                let reason = format!("selector {:#x} refers to the local descriptor table, none is loaded", selector);
+                return Err(Exception::general_protection(error_code, reason));
+            }
+            &self.ldt
+        } else {
+            &self.gdtr
+        };
+        let offset = (selector & !7) as u64;
+        if offset + bytes - 1 > table.limit as u64 {
+            let reason = format!("selector {:#x} is outside of the descriptor table (limit {:#x})", selector, table.limit);
+            return Err(Exception::general_protection(error_code, reason));
+        }
+        Ok(table.base + offset)
+    }
+
+    /// Reads the code or data segment descriptor of a selector
+    fn read_descriptor(&mut self, selector: u16) -> Result<SegmentDescriptor, Exception> {
+        let address = self.descriptor_address(selector, 8)?;
+        let data = self.mem_read(address, 8);
+        let raw = data.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64);
+        let descriptor = SegmentDescriptor::from_raw(raw);
+        // the cpu marks descriptors as used
+        if descriptor.access & ACCESS_ACCESSED == 0 && descriptor.access & ACCESS_CODE_OR_DATA != 0 {
+            let access = descriptor.access | ACCESS_ACCESSED;
+            self.mem_write(address + 5, &[access]);
+        }
+        Ok(SegmentDescriptor {
+            access: descriptor.access | ACCESS_ACCESSED,
+            ..descriptor
+        })
+    }
+
+    /// Loads cs and ss with fixed descriptors like syscall and sysret do,
+    /// without reading the GDT
+    pub fn load_flat_segments(&mut self, cs: u16, code: SegmentDescriptor, ss: u16) {
+        let dpl = (cs & 3) as u8;
+        self.segments[segment_index(Register::CS)] = code;
+        self.segments[segment_index(Register::SS)] = SegmentDescriptor::data(dpl);
+        self.cs = cs;
+        self.ss = ss;
+    }
+
+    /// Writes CR0. Paging with EFER.LME set activates long mode, which needs
+    /// CR4.PAE, disabling paging leaves long mode. Invalid combinations raise #GP.
+    pub fn set_cr0(&mut self, value: i64) {
+        if value & CR0_PG != 0 && value & CR0_PE == 0 {
+            self.raise(Exception::general_protection(0, "CR0.PG set without CR0.PE".to_string()));
+            return;
+        }
+        let paging = self.cr0 & CR0_PG != 0;
+        if !paging && value & CR0_PG != 0 && self.msrs.efer & EFER_LME != 0 {
+            if self.cr4 & CR4_PAE == 0 {
+                self.raise(Exception::general_protection(0, "long mode activated without CR4.PAE".to_string()));
+                return;
+            }
+            self.msrs.efer |= EFER_LMA;
+        }
+        if paging && value & CR0_PG == 0 && self.msrs.efer & EFER_LMA != 0 {
+            if self.cpu_mode() == CpuMode::Long {
+                self.raise(Exception::general_protection(0, "paging disabled in 64 bit mode".to_string()));
+                return;
+            }
+            self.msrs.efer &= !EFER_LMA;
+        }
+        if (value ^ self.cr0) & CR0_PG != 0 {
+            self.invalidate_instructions();
+        }
+        self.cr0 = value;
+    }
+
+    /// The state after reset: real mode with 64 KB segments at 0 and neither
+    /// paging nor long mode. The caller sets cs:ip.
+    pub fn reset_to_real_mode(&mut self) {
+        self.cr0 = CR0_ET;
+        self.cr3 = 0;
+        self.cr4 = 0;
+        self.msrs.efer = 0;
+        self.msrs.fs_base = 0;
+        self.msrs.gs_base = 0;
+        self.invalidate_instructions();
+        self.gdtr = DescriptorTableRegister { base: 0, limit: 0xFFFF };
+        self.idtr = DescriptorTableRegister { base: 0, limit: 0x3FF };
+        self.ldtr = 0;
+        self.tr = 0;
+        self.ldt = DescriptorTableRegister { base: 0, limit: 0xFFFF };
+        self.tss = DescriptorTableRegister { base: 0, limit: 0xFFFF };
+        let registers = [(Register::ES, false), (Register::CS, true), (Register::SS, false),
+                         (Register::DS, false), (Register::FS, false), (Register::GS, false)];
+        for &(register, code) in registers.iter() {
+            self.segments[segment_index(register)] = SegmentDescriptor::real_mode(0, code);
+            self.set_selector(register, 0);
+        }
+        self.rflags = 2;
+    }
+
+    /// 32 bit protected mode with flat 4 GB segments and without paging, the
+    /// state multiboot kernels start in. The GDT is empty, kernels load their own.
+    pub fn set_up_flat_protected_mode(&mut self) {
+        self.reset_to_real_mode();
+        self.cr0 = CR0_PE | CR0_ET;
+        for &register in [Register::ES, Register::SS, Register::DS, Register::FS, Register::GS].iter() {
+            self.segments[segment_index(register)] = SegmentDescriptor::data(0);
+            self.set_selector(register, FLAT_DATA_SELECTOR);
+        }
+        self.segments[segment_index(Register::CS)] = SegmentDescriptor::code32(0);
+        self.set_selector(Register::CS, FLAT_CODE_SELECTOR);
+    }
+
+    /// Sets up the GDT of Linux and the segments user space programs start with
+    pub fn set_up_linux_user_segments(&mut self) {
+        for (index, descriptor) in LINUX_GDT.iter().enumerate() {
+            self.mem_write(LINUX_GDT_ADDRESS + index as u64 * 8, &convert_i64_to_u8vec(*descriptor as i64));
+        }
+        self.gdtr = DescriptorTableRegister {
+            base: LINUX_GDT_ADDRESS,
+            limit: (LINUX_GDT.len() * 8 - 1) as u16,
+        };
+        for &(register, selector) in [(Register::CS, LINUX_USER_CS), (Register::SS, LINUX_USER_DS)].iter() {
+            if let Err(exception) = self.load_segment(register, selector) {
+                panic!("{}", exception);
+            }
+        }
+    }
+}
+
+impl EmulationCPU {
+    /// Reads the limit and base of a descriptor table, the base has 64 bits in
+    /// 64 bit mode and 24 bits with a 16 bit operand size. None outside of ring 0.
+    fn descriptor_table_register(&self, machine_state: &mut MachineState, arg: &InstructionArguments)
+                                 -> Option<DescriptorTableRegister> {
+        if !machine_state.check_privileged("descriptor table load") {
+            return None;
+        }
+        let address = machine_state.linear_address(arg.get_one_argument());
+        let base_bytes = match machine_state.code_size() {
+            CodeSize::Bit64 => 8,
+            _ => 4,
+        };
+        let data = machine_state.mem_read(address, 2 + base_bytes);
+        let limit = data[0] as u16 | (data[1] as u16) << 8;
+        let base = data[2..].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64);
+        let base = match arg.size() {
+            ArgumentSize::Bit16 if base_bytes == 4 => base & 0xFFFFFF,
+            _ => base,
+        };
+        Some(DescriptorTableRegister {
+            base: base,
+            limit: limit,
+        })
+    }
+
+    pub fn lgdt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Some(gdtr) = self.descriptor_table_register(machine_state, arg) {
+            machine_state.gdtr = gdtr;
+        }
+    }
+
+    pub fn lidt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        if let Some(idtr) = self.descriptor_table_register(machine_state, arg) {
+            machine_state.idtr = idtr;
+        }
+    }
+}
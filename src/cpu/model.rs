@@ -0,0 +1,462 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde_json;
+use toml;
+
+use cpu::msr::{MSR_TSC_AUX, x86_64_msrs};
+use cpu::vector::XCR0_SUPPORTED;
+
+/* The cpu the emulator pretends to be: what cpuid returns and which MSRs exist.
+ * Leaf 0 (vendor and highest leaf), leaf 0x80000000 (highest extended leaf),
+ * the signature in leaf 1 eax and the brand string are generated from the model,
+ * everything else comes from the list of leaves. Leaves which are not in the list
+ * return zeros. The decoder raises #UD for instructions whose feature flag is not
+ * set.
+ *
+ * Models are presets or TOML/JSON files with the fields of CpuModel, register
+ * values are numbers or hex strings:
+ *
+ *     vendor = "GenuineIntel"
+ *     family = 6
+ *     model = 61
+ *     stepping = 4
+ *     msrs = ["0x10", "0xC0000080"]
+ *
+ *     [[leaves]]
+ *     leaf = 1
+ *     ecx = "0x00802000"
+ *     edx = "0x078bfbff"
+ *
+ * The presets follow the x86-64 psABI feature levels. Instructions of advertised
+ * features the emulator does not implement still stop the emulation as unknown
+ * instructions.
+ */
+
+const EAX: usize = 0;
+const EBX: usize = 1;
+const ECX: usize = 2;
+const EDX: usize = 3;
+
+/// Instruction set extensions the decoder checks before executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    Tsc,
+    Msr,
+    Cx8,
+    Cmov,
+    Cx16,
+    Popcnt,
+    Xsave,
+    Avx,
+    Rdrand,
+    Bmi1,
+    Avx2,
+    Bmi2,
+    Rdseed,
+    Lzcnt,
+    Rdtscp,
+}
+
+impl Feature {
+    /// Leaf, subleaf, register and bit of the feature flag
+    fn cpuid_bit(&self) -> (u32, u32, usize, u32) {
+        match *self {
+            Feature::Tsc => (1, 0, EDX, 4),
+            Feature::Msr => (1, 0, EDX, 5),
+            Feature::Cx8 => (1, 0, EDX, 8),
+            Feature::Cmov => (1, 0, EDX, 15),
+            Feature::Cx16 => (1, 0, ECX, 13),
+            Feature::Popcnt => (1, 0, ECX, 23),
+            Feature::Xsave => (1, 0, ECX, 26),
+            Feature::Avx => (1, 0, ECX, 28),
+            Feature::Rdrand => (1, 0, ECX, 30),
+            Feature::Bmi1 => (7, 0, EBX, 3),
+            Feature::Avx2 => (7, 0, EBX, 5),
+            Feature::Bmi2 => (7, 0, EBX, 8),
+            Feature::Rdseed => (7, 0, EBX, 18),
+            Feature::Lzcnt => (0x80000001, 0, ECX, 5),
+            Feature::Rdtscp => (0x80000001, 0, EDX, 27),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CpuidLeaf {
+    #[serde(deserialize_with = "number")]
+    pub leaf: u32,
+    /// None if the leaf has no subleaves
+    #[serde(default, deserialize_with = "optional_number")]
+    pub subleaf: Option<u32>,
+    #[serde(default, deserialize_with = "number")]
+    pub eax: u32,
+    #[serde(default, deserialize_with = "number")]
+    pub ebx: u32,
+    #[serde(default, deserialize_with = "number")]
+    pub ecx: u32,
+    #[serde(default, deserialize_with = "number")]
+    pub edx: u32,
+}
+
+impl CpuidLeaf {
+    fn new(leaf: u32, subleaf: Option<u32>, registers: [u32; 4]) -> CpuidLeaf {
+        CpuidLeaf {
+            leaf: leaf,
+            subleaf: subleaf,
+            eax: registers[EAX],
+            ebx: registers[EBX],
+            ecx: registers[ECX],
+            edx: registers[EDX],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CpuModel {
+    /// 12 characters, e.g. GenuineIntel or AuthenticAMD
+    pub vendor: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// returned by the leaves 0x80000002 to 0x80000004
+    #[serde(default)]
+    pub brand: Option<String>,
+    pub leaves: Vec<CpuidLeaf>,
+    /// MSRs rdmsr and wrmsr accept
+    #[serde(deserialize_with = "number_list")]
+    pub msrs: Vec<u32>,
+}
+
+impl CpuModel {
+    /// The preset with the given name, or None
+    pub fn preset(name: &str) -> Option<CpuModel> {
+        match name {
+            "minimal-x86-64" => Some(CpuModel::minimal()),
+            "x86-64-v2" => Some(CpuModel::v2()),
+            "host-like" => Some(CpuModel::host_like()),
+            "max" => Some(CpuModel::max()),
+            _ => None,
+        }
+    }
+
+    /// A preset or a model file
+    pub fn from_name(name: &str) -> CpuModel {
+        if let Some(model) = CpuModel::preset(name) {
+            return model;
+        }
+        match Path::new(name).extension().and_then(|extension| extension.to_str()) {
+            Some("toml") | Some("json") => CpuModel::from_file(name),
+            _ => panic!("Unknown cpu model: {}, expected minimal-x86-64, x86-64-v2, host-like, max or a .toml/.json file", name),
+        }
+    }
+
+    /// Loads a model from a .toml or .json file
+    pub fn from_file(filename: &str) -> CpuModel {
+        let mut file = File::open(filename).expect("Cannot open cpu model file");
+        let mut content = String::new();
+        file.read_to_string(&mut content).expect("Failed to read cpu model file");
+        match Path::new(filename).extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&content).unwrap_or_else(|error| panic!("Invalid cpu model {}: {}", filename, error)),
+            Some("json") => serde_json::from_str(&content).unwrap_or_else(|error| panic!("Invalid cpu model {}: {}", filename, error)),
+            _ => panic!("Unknown cpu model file format: {}, expected .toml or .json", filename),
+        }
+    }
+
+    /// The registers eax, ebx, ecx and edx returned by cpuid
+    pub fn cpuid(&self, leaf: u32, subleaf: u32) -> [u32; 4] {
+        match leaf {
+            0 => {
+                let vendor = string_to_registers(&self.vendor, 12);
+                let highest_leaf = self.leaves.iter().map(|entry| entry.leaf).filter(|&leaf| leaf < 0x80000000).max();
+                [highest_leaf.unwrap_or(0).max(1), vendor[0], vendor[2], vendor[1]]
+            }
+            0x80000000 => {
+                let highest_leaf = self.leaves.iter().map(|entry| entry.leaf).filter(|&leaf| leaf > 0x80000000).max();
+                let highest_leaf = match self.brand {
+                    Some(_) => highest_leaf.unwrap_or(0).max(0x80000004),
+                    None => highest_leaf.unwrap_or(0x80000000),
+                };
+                [highest_leaf, 0, 0, 0]
+            }
+            0x80000002...0x80000004 if self.brand.is_some() => {
+                let brand = string_to_registers(self.brand.as_ref().unwrap(), 48);
+                let first = (leaf - 0x80000002) as usize * 4;
+                [brand[first], brand[first + 1], brand[first + 2], brand[first + 3]]
+            }
+            _ => {
+                let entry = self.leaves.iter().find(|entry| {
+                    entry.leaf == leaf && (entry.subleaf.is_none() || entry.subleaf == Some(subleaf))
+                });
+                let mut registers = match entry {
+                    Some(entry) => [entry.eax, entry.ebx, entry.ecx, entry.edx],
+                    None => [0; 4],
+                };
+                if leaf == 1 {
+                    registers[EAX] = self.signature();
+                }
+                registers
+            }
+        }
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        let (leaf, subleaf, register, bit) = feature.cpuid_bit();
+        self.cpuid(leaf, subleaf)[register] & 1 << bit != 0
+    }
+
+    /// State components xsetbv can enable in XCR0
+    pub fn xcr0_supported(&self) -> u64 {
+        self.cpuid(0xD, 0)[EAX] as u64 & XCR0_SUPPORTED
+    }
+
+    pub fn has_msr(&self, msr: u32) -> bool {
+        self.msrs.contains(&msr)
+    }
+
+    /// Family, model and stepping as encoded in leaf 1 eax
+    fn signature(&self) -> u32 {
+        let (family, extended_family) = if self.family > 15 {
+            (15, self.family - 15)
+        } else {
+            (self.family, 0)
+        };
+        let (model, extended_model) = if self.family == 6 || self.family >= 15 {
+            (self.model & 0xF, self.model >> 4)
+        } else {
+            (self.model, 0)
+        };
+        extended_family << 20 | extended_model << 16 | family << 8 | model << 4 | self.stepping
+    }
+
+    /// The first x86-64 cpus: x87, MMX, SSE and SSE2, cmov, cmpxchg8b and syscall
+    fn minimal() -> CpuModel {
+        let edx = 1 << 0 | // x87 FPU
+                  1 << 3 | // page size extension
+                  1 << 4 | // time stamp counter
+                  1 << 5 | // model specific registers
+                  1 << 6 | // physical address extension
+                  1 << 8 | // cmpxchg8b
+                  1 << 9 | // APIC
+                  1 << 13 | // page global enable
+                  1 << 15 | // cmov
+                  1 << 16 | // page attribute table
+                  1 << 19 | // clflush
+                  1 << 23 | // MMX
+                  1 << 24 | // fxsave and fxrstor
+                  1 << 25 | // SSE
+                  1 << 26; // SSE2
+        let extended_edx = 1 << 11 | // syscall and sysret
+                           1 << 20 | // no-execute pages
+                           1 << 29; // long mode
+        CpuModel {
+            vendor: "GenuineIntel".to_string(),
+            family: 15,
+            model: 4,
+            stepping: 1,
+            brand: None,
+            leaves: vec![
+                CpuidLeaf::new(1, None, [0, 0, 0, edx]),
+                CpuidLeaf::new(0x80000001, None, [0, 0, 0, extended_edx]),
+            ],
+            msrs: x86_64_msrs(),
+        }
+    }
+
+    /// minimal-x86-64 with SSE3 to SSE4.2, cmpxchg16b, popcnt, lahf/sahf and rdtscp
+    fn v2() -> CpuModel {
+        let mut model = CpuModel::minimal();
+        model.family = 6;
+        model.model = 0x1A;
+        model.stepping = 5;
+        model.leaves[0].ecx = 1 << 0 | // SSE3
+                              1 << 9 | // SSSE3
+                              1 << 13 | // cmpxchg16b
+                              1 << 19 | // SSE4.1
+                              1 << 20 | // SSE4.2
+                              1 << 23; // popcnt
+        model.leaves[1].ecx = 1 << 0; // lahf and sahf in long mode
+        model.leaves[1].edx |= 1 << 27; // rdtscp
+        model.msrs.push(MSR_TSC_AUX);
+        model
+    }
+
+    /// Everything the emulator implements
+    fn max() -> CpuModel {
+        let edx = 1 << 0 | // x87 FPU
+                  1 << 3 | // page size extension
+                  1 << 4 | // time stamp counter
+                  1 << 5 | // model specific registers
+                  1 << 6 | // physical address extension
+                  1 << 8 | // cmpxchg8b
+                  1 << 9 | // APIC
+                  1 << 15 | // cmov
+                  1 << 24 | // fxsave and fxrstor
+                  1 << 25 | // SSE
+                  1 << 26; // SSE2
+        // bit 27 (OSXSAVE) mirrors CR4.OSXSAVE
+        let ecx = 1 << 13 | // cmpxchg16b
+                  1 << 23 | // popcnt
+                  1 << 26 | // xsave, xrstor, xgetbv and xsetbv
+                  1 << 28 | // AVX
+                  1 << 30; // rdrand
+        let structured_ebx = 1 << 3 | // BMI1
+                             1 << 5 | // AVX2
+                             1 << 8 | // BMI2
+                             1 << 18; // rdseed
+        let extended_ecx = 1 << 5; // lzcnt
+        let extended_edx = 1 << 11 | // syscall and sysret
+                           1 << 20 | // no-execute pages
+                           1 << 27 | // rdtscp
+                           1 << 29; // long mode
+        let mut msrs = x86_64_msrs();
+        msrs.push(MSR_TSC_AUX);
+        CpuModel {
+            vendor: "GenuineIntel".to_string(),
+            family: 6,
+            model: 0x3D,
+            stepping: 4,
+            brand: None,
+            leaves: vec![
+                CpuidLeaf::new(1, None, [0, 0, ecx, edx]),
+                CpuidLeaf::new(7, Some(0), [0, structured_ebx, 0, 0]),
+                // XSAVE state components, the sizes of the save area are filled in by cpuid
+                CpuidLeaf::new(0xD, Some(0), [XCR0_SUPPORTED as u32, 0, 0, 0]),
+                // size and offset of the AVX state
+                CpuidLeaf::new(0xD, Some(2), [256, 576, 0, 0]),
+                CpuidLeaf::new(0x80000001, None, [0, 0, extended_ecx, extended_edx]),
+            ],
+            msrs: msrs,
+        }
+    }
+
+    /// max with the vendor, signature and brand string of the host, limited to
+    /// the features the host has
+    #[cfg(target_arch = "x86_64")]
+    fn host_like() -> CpuModel {
+        use std::arch::x86_64::__cpuid_count;
+
+        let highest_leaf = __cpuid_count(0, 0).eax;
+        let highest_extended_leaf = __cpuid_count(0x80000000, 0).eax;
+        let host = |leaf: u32, subleaf: u32| {
+            if leaf > highest_extended_leaf || (leaf < 0x80000000 && leaf > highest_leaf) {
+                return [0; 4];
+            }
+            let result = __cpuid_count(leaf, subleaf);
+            [result.eax, result.ebx, result.ecx, result.edx]
+        };
+
+        let mut model = CpuModel::max();
+        let vendor = host(0, 0);
+        model.vendor = registers_to_string(&[vendor[EBX], vendor[EDX], vendor[ECX]]);
+        let signature = host(1, 0)[EAX];
+        model.family = (signature >> 8) & 0xF;
+        model.model = (signature >> 4) & 0xF;
+        model.stepping = signature & 0xF;
+        if model.family == 6 || model.family == 15 {
+            model.model |= (signature >> 16 & 0xF) << 4;
+        }
+        if model.family == 15 {
+            model.family += (signature >> 20) & 0xFF;
+        }
+        if highest_extended_leaf >= 0x80000004 {
+            let brand: Vec<u32> = (0x80000002..0x80000005).flat_map(|leaf| host(leaf, 0).to_vec()).collect();
+            model.brand = Some(registers_to_string(&brand).trim().to_string());
+        }
+
+        for entry in model.leaves.iter_mut() {
+            let host_registers = host(entry.leaf, entry.subleaf.unwrap_or(0));
+            match (entry.leaf, entry.subleaf) {
+                (0xD, Some(0)) => entry.eax &= host_registers[EAX],
+                (0xD, _) => (),
+                _ => {
+                    entry.ecx &= host_registers[ECX];
+                    entry.edx &= host_registers[EDX];
+                    if entry.leaf == 7 {
+                        entry.ebx &= host_registers[EBX];
+                    }
+                }
+            }
+        }
+        model
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn host_like() -> CpuModel {
+        CpuModel::max()
+    }
+}
+
+impl Default for CpuModel {
+    fn default() -> CpuModel {
+        CpuModel::max()
+    }
+}
+
+/// Splits a string into little endian registers, padded with zeros to length bytes
+fn string_to_registers(value: &str, length: usize) -> Vec<u32> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.resize(length, 0);
+    bytes.chunks(4).map(|chunk| chunk.iter().rev().fold(0, |result, &byte| result << 8 | byte as u32)).collect()
+}
+
+fn registers_to_string(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers.iter()
+        .flat_map(|register| (0..4).map(move |index| (register >> (index * 8)) as u8))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A 32 bit number in a model file, either a number or a hex string ("0x1A")
+struct Number(u32);
+
+struct NumberVisitor;
+
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = Number;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a 32 bit number or a hex string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Number, E> {
+        if value > u32::MAX as u64 {
+            return Err(E::custom(format!("{} does not fit into 32 bits", value)));
+        }
+        Ok(Number(value as u32))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Number, E> {
+        if value < 0 {
+            return Err(E::custom(format!("{} is negative", value)));
+        }
+        self.visit_u64(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Number, E> {
+        let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+        u32::from_str_radix(digits, 16).map(Number).map_err(|_| E::custom(format!("invalid hex number: {}", value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Number, D::Error> {
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    Number::deserialize(deserializer).map(|number| number.0)
+}
+
+fn optional_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    number(deserializer).map(Some)
+}
+
+fn number_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    let numbers: Vec<Number> = Deserialize::deserialize(deserializer)?;
+    Ok(numbers.into_iter().map(|number| number.0).collect())
+}
@@ -0,0 +1,377 @@
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use fnv::FnvHashMap;
+
+use instruction_set::Register;
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::mode::CR0_PG;
+use cpu::protection::Exception;
+
+/* Model specific registers, read and written by rdmsr and wrmsr at privilege level 0.
+ * The cpu model decides which MSRs exist, rdmsr and wrmsr of any other MSR raise
+ * #GP. MSRs of the model without a meaning to the emulator hold the written value.
+ *
+ * Embedders can virtualise MSRs with an MsrHandler, handlers see every access
+ * before the register file and may also add MSRs the cpu model does not have.
+ */
+
+pub const MSR_TIME_STAMP_COUNTER: u32 = 0x10;
+pub const MSR_APIC_BASE: u32 = 0x1B;
+pub const MSR_MTRR_CAPABILITIES: u32 = 0xFE;
+pub const MSR_MISC_ENABLE: u32 = 0x1A0;
+/// IA32_MTRR_PHYSBASE0, followed by PHYSMASK0, PHYSBASE1... up to PHYSMASK7
+pub const MSR_MTRR_PHYS_BASE0: u32 = 0x200;
+pub const MSR_MTRR_FIX64K_00000: u32 = 0x250;
+pub const MSR_MTRR_FIX16K_80000: u32 = 0x258;
+pub const MSR_MTRR_FIX16K_A0000: u32 = 0x259;
+/// IA32_MTRR_FIX4K_C0000, followed by the 4K ranges up to F8000
+pub const MSR_MTRR_FIX4K_C0000: u32 = 0x268;
+pub const MSR_PAT: u32 = 0x277;
+pub const MSR_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const MSR_EFER: u32 = 0xC0000080;
+pub const MSR_STAR: u32 = 0xC0000081;
+pub const MSR_LSTAR: u32 = 0xC0000082;
+pub const MSR_CSTAR: u32 = 0xC0000083;
+pub const MSR_FMASK: u32 = 0xC0000084;
+pub const MSR_FS_BASE: u32 = 0xC0000100;
+pub const MSR_GS_BASE: u32 = 0xC0000101;
+pub const MSR_KERNEL_GS_BASE: u32 = 0xC0000102;
+pub const MSR_TSC_AUX: u32 = 0xC0000103;
+
+/// EFER: syscall enable, long mode enable, long mode active and no-execute enable
+pub const EFER_SCE: u64 = 1 << 0;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
+
+const VARIABLE_MTRRS: u32 = 8;
+const FIXED_MTRRS: usize = 11;
+
+/// APIC at the default address, enabled, this is the bootstrap processor
+const APIC_BASE_DEFAULT: u64 = 0xFEE00000 | 1 << 11 | 1 << 8;
+const APIC_BASE_BSP: u64 = 1 << 8;
+/// 8 variable ranges, fixed ranges and write combining
+const MTRR_CAPABILITIES: u64 = VARIABLE_MTRRS as u64 | 1 << 8 | 1 << 10;
+/// write back, write through, uncached minus and uncached, twice
+const PAT_DEFAULT: u64 = 0x0007040600070406;
+/// fast strings, no branch trace store and no PEBS
+const MISC_ENABLE_DEFAULT: u64 = 1 << 0 | 1 << 11 | 1 << 12;
+/// physical addresses have at most 52 bits
+const PHYSICAL_ADDRESS_MASK: u64 = 0x000FFFFFFFFFF000;
+
+/// MSRs every x86-64 cpu has, except IA32_TSC_AUX which comes with rdtscp
+pub fn x86_64_msrs() -> Vec<u32> {
+    let mut msrs = vec![MSR_TIME_STAMP_COUNTER, MSR_APIC_BASE, MSR_MTRR_CAPABILITIES, MSR_MISC_ENABLE,
+                        MSR_MTRR_FIX64K_00000, MSR_MTRR_FIX16K_80000, MSR_MTRR_FIX16K_A0000, MSR_PAT,
+                        MSR_MTRR_DEF_TYPE, MSR_EFER, MSR_STAR, MSR_LSTAR, MSR_CSTAR, MSR_FMASK, MSR_FS_BASE,
+                        MSR_GS_BASE, MSR_KERNEL_GS_BASE];
+    msrs.extend(MSR_MTRR_PHYS_BASE0..MSR_MTRR_PHYS_BASE0 + 2 * VARIABLE_MTRRS);
+    msrs.extend(MSR_MTRR_FIX4K_C0000..MSR_MTRR_FIX4K_C0000 + 8);
+    msrs
+}
+
+/// Virtualises MSRs for an embedder, added with CpuOptions::msr_handlers. The
+/// embedder keeps a reference to the handler to look at it after the emulation.
+pub trait MsrHandler {
+    /// The value of the MSR, None if the handler does not virtualise it
+    fn read(&mut self, msr: u32) -> Option<u64>;
+    /// Returns false if the handler does not virtualise the MSR
+    fn write(&mut self, msr: u32, value: u64) -> bool;
+}
+
+type MsrHandlers = Vec<Rc<RefCell<dyn MsrHandler>>>;
+
+#[derive(Serialize, Deserialize)]
+pub struct Msrs {
+    pub efer: u64,
+    /// syscall/sysret: segment selectors, 64 and 32 bit entry points and rflags mask
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub fmask: u64,
+    /// bases of the fs and gs segments, swapgs exchanges gs_base and kernel_gs_base
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub kernel_gs_base: u64,
+    pub apic_base: u64,
+    pub pat: u64,
+    pub mtrr_def_type: u64,
+    /// base and mask of the variable range MTRRs
+    pub mtrr_variable: [u64; 16],
+    pub mtrr_fixed: [u64; FIXED_MTRRS],
+    pub misc_enable: u64,
+    /// returned by rdtscp
+    pub tsc_aux: u32,
+    /// MSRs of the cpu model the emulator does not know
+    pub other: FnvHashMap<u32, u64>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    handlers: MsrHandlers,
+}
+
+impl Msrs {
+    pub fn new() -> Msrs {
+        Msrs {
+            // the emulator starts in long mode, with syscall and no-execute pages enabled like Linux
+            efer: EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE,
+            star: 0,
+            lstar: 0,
+            cstar: 0,
+            fmask: 0,
+            fs_base: 0,
+            gs_base: 0,
+            kernel_gs_base: 0,
+            apic_base: APIC_BASE_DEFAULT,
+            pat: PAT_DEFAULT,
+            mtrr_def_type: 0,
+            mtrr_variable: [0; 16],
+            mtrr_fixed: [0; FIXED_MTRRS],
+            misc_enable: MISC_ENABLE_DEFAULT,
+            tsc_aux: 0,
+            other: FnvHashMap::default(),
+            handlers: Vec::new(),
+        }
+    }
+}
+
+impl Default for Msrs {
+    fn default() -> Msrs {
+        Msrs::new()
+    }
+}
+
+fn general_protection_fault(msr: u32, value: u64, reason: &str) -> Exception {
+    Exception::general_protection(0, format!("wrmsr of {:x} to MSR {:x}, {}", value, msr, reason))
+}
+
+fn canonical(value: u64) -> bool {
+    ((value << 16) as i64 >> 16) as u64 == value
+}
+
+/// Memory types of MTRRs: uncached, write combining, write through, write protected and write back
+fn valid_mtrr_type(memory_type: u64) -> bool {
+    matches!(memory_type, 0 | 1 | 4 | 5 | 6)
+}
+
+/// Fixed range MTRRs hold 8 memory types
+fn valid_fixed_mtrr(value: u64) -> bool {
+    (0..8).all(|index| valid_mtrr_type(value >> (index * 8) & 0xFF))
+}
+
+/// PAT entries are MTRR memory types or uncached minus (7)
+fn valid_pat(value: u64) -> bool {
+    (0..8).all(|index| {
+        let memory_type = value >> (index * 8) & 0xFF;
+        valid_mtrr_type(memory_type) || memory_type == 7
+    })
+}
+
+fn variable_mtrr(msr: u32) -> bool {
+    (MSR_MTRR_PHYS_BASE0..MSR_MTRR_PHYS_BASE0 + 2 * VARIABLE_MTRRS).contains(&msr)
+}
+
+fn fixed_mtrr_index(msr: u32) -> Option<usize> {
+    match msr {
+        MSR_MTRR_FIX64K_00000 => Some(0),
+        MSR_MTRR_FIX16K_80000 => Some(1),
+        MSR_MTRR_FIX16K_A0000 => Some(2),
+        _ if (MSR_MTRR_FIX4K_C0000..MSR_MTRR_FIX4K_C0000 + 8).contains(&msr) => {
+            Some((msr - MSR_MTRR_FIX4K_C0000) as usize + 3)
+        }
+        _ => None,
+    }
+}
+
+impl MachineState {
+    /// Lets handler virtualise MSRs, handlers added first see the accesses first
+    pub fn add_msr_handler(&mut self, handler: Rc<RefCell<dyn MsrHandler>>) {
+        self.msrs.handlers.push(handler);
+    }
+
+    /// The value of an MSR or #GP for MSRs the cpu model does not have
+    pub fn read_msr(&mut self, msr: u32) -> Result<u64, Exception> {
+        for handler in &self.msrs.handlers {
+            if let Some(value) = handler.borrow_mut().read(msr) {
+                return Ok(value);
+            }
+        }
+        if !self.cpu_model.has_msr(msr) {
+            let reason = format!("rdmsr of MSR {:x}, which the cpu model does not have", msr);
+            return Err(Exception::general_protection(0, reason));
+        }
+        let msrs = &self.msrs;
+        let value = match msr {
+            MSR_TIME_STAMP_COUNTER => self.clock.time_stamp_counter(),
+            MSR_APIC_BASE => msrs.apic_base,
+            MSR_MTRR_CAPABILITIES => MTRR_CAPABILITIES,
+            MSR_MISC_ENABLE => msrs.misc_enable,
+            MSR_PAT => msrs.pat,
+            MSR_MTRR_DEF_TYPE => msrs.mtrr_def_type,
+            MSR_EFER => msrs.efer,
+            MSR_STAR => msrs.star,
+            MSR_LSTAR => msrs.lstar,
+            MSR_CSTAR => msrs.cstar,
+            MSR_FMASK => msrs.fmask,
+            MSR_FS_BASE => msrs.fs_base,
+            MSR_GS_BASE => msrs.gs_base,
+            MSR_KERNEL_GS_BASE => msrs.kernel_gs_base,
+            MSR_TSC_AUX => msrs.tsc_aux as u64,
+            _ if variable_mtrr(msr) => {
+                msrs.mtrr_variable[(msr - MSR_MTRR_PHYS_BASE0) as usize]
+            }
+            _ => {
+                match fixed_mtrr_index(msr) {
+                    Some(index) => msrs.mtrr_fixed[index],
+                    None => *msrs.other.get(&msr).unwrap_or(&0),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    /// Writes an MSR, #GP for MSRs the cpu model does not have and invalid values
+    pub fn write_msr(&mut self, msr: u32, value: u64) -> Result<(), Exception> {
+        for handler in &self.msrs.handlers {
+            if handler.borrow_mut().write(msr, value) {
+                return Ok(());
+            }
+        }
+        if !self.cpu_model.has_msr(msr) {
+            let reason = format!("wrmsr to MSR {:x}, which the cpu model does not have", msr);
+            return Err(Exception::general_protection(0, reason));
+        }
+        match msr {
+            MSR_TIME_STAMP_COUNTER => self.clock.set_time_stamp_counter(value),
+            MSR_APIC_BASE => {
+                // the enable bit and the base address, there is no x2APIC mode
+                if value & !(PHYSICAL_ADDRESS_MASK | 1 << 11 | APIC_BASE_BSP) != 0 {
+                    return Err(general_protection_fault(msr, value, "reserved bits are set"));
+                }
+                self.msrs.apic_base = value & !APIC_BASE_BSP | self.msrs.apic_base & APIC_BASE_BSP;
+            }
+            MSR_MTRR_CAPABILITIES => return Err(general_protection_fault(msr, value, "the MSR is read only")),
+            MSR_MISC_ENABLE => self.msrs.misc_enable = value,
+            MSR_PAT => {
+                if !valid_pat(value) {
+                    return Err(general_protection_fault(msr, value, "invalid memory type"));
+                }
+                self.msrs.pat = value;
+            }
+            MSR_MTRR_DEF_TYPE => {
+                // default memory type, fixed range enable and MTRR enable
+                if value & !(0xFF | 1 << 10 | 1 << 11) != 0 || !valid_mtrr_type(value & 0xFF) {
+                    return Err(general_protection_fault(msr, value, "invalid default memory type"));
+                }
+                self.msrs.mtrr_def_type = value;
+            }
+            MSR_EFER => self.write_efer(value)?,
+            MSR_STAR => self.msrs.star = value,
+            MSR_LSTAR | MSR_CSTAR | MSR_FS_BASE | MSR_GS_BASE | MSR_KERNEL_GS_BASE => {
+                if !canonical(value) {
+                    return Err(general_protection_fault(msr, value, "the address is not canonical"));
+                }
+                match msr {
+                    MSR_LSTAR => self.msrs.lstar = value,
+                    MSR_CSTAR => self.msrs.cstar = value,
+                    MSR_FS_BASE => self.msrs.fs_base = value,
+                    MSR_GS_BASE => self.msrs.gs_base = value,
+                    _ => self.msrs.kernel_gs_base = value,
+                }
+            }
+            MSR_FMASK => self.msrs.fmask = value & 0xFFFFFFFF,
+            MSR_TSC_AUX => {
+                if value >> 32 != 0 {
+                    return Err(general_protection_fault(msr, value, "reserved bits are set"));
+                }
+                self.msrs.tsc_aux = value as u32;
+            }
+            _ if variable_mtrr(msr) => {
+                let index = (msr - MSR_MTRR_PHYS_BASE0) as usize;
+                // PHYSBASEn: memory type and base, PHYSMASKn: valid bit and mask
+                let valid = if index & 1 == 0 {
+                    value & !(PHYSICAL_ADDRESS_MASK | 0xFF) == 0 && valid_mtrr_type(value & 0xFF)
+                } else {
+                    value & !(PHYSICAL_ADDRESS_MASK | 1 << 11) == 0
+                };
+                if !valid {
+                    return Err(general_protection_fault(msr, value, "invalid variable range"));
+                }
+                self.msrs.mtrr_variable[index] = value;
+            }
+            _ => {
+                match fixed_mtrr_index(msr) {
+                    Some(index) => {
+                        if !valid_fixed_mtrr(value) {
+                            return Err(general_protection_fault(msr, value, "invalid memory type"));
+                        }
+                        self.msrs.mtrr_fixed[index] = value;
+                    }
+                    None => {
+                        self.msrs.other.insert(msr, value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// LMA is set by the processor when it enters long mode, writes do not change it
+    fn write_efer(&mut self, value: u64) -> Result<(), Exception> {
+        let msr = MSR_EFER;
+        if value & !(EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE) != 0 {
+            return Err(general_protection_fault(msr, value, "reserved bits are set"));
+        }
+        let paging = self.cr0 & CR0_PG != 0;
+        if paging && (value ^ self.msrs.efer) & EFER_LME != 0 {
+            return Err(general_protection_fault(msr, value, "long mode cannot be changed while paging is enabled"));
+        }
+        // the execute disable bits of the cached instructions change meaning
+        if (value ^ self.msrs.efer) & EFER_NXE != 0 {
+            self.invalidate_instructions();
+        }
+        self.msrs.efer = value & !EFER_LMA | self.msrs.efer & EFER_LMA;
+        Ok(())
+    }
+}
+
+impl EmulationCPU {
+    pub fn wrmsr(&self, machine_state: &mut MachineState) {
+        if !machine_state.check_privileged("wrmsr") {
+            return;
+        }
+        let ecx = machine_state.get_register_value(&Register::ECX) as u32;
+        let eax = machine_state.get_register_value(&Register::EAX) as u32 as u64;
+        let edx = machine_state.get_register_value(&Register::EDX) as u32 as u64;
+        if let Err(exception) = machine_state.write_msr(ecx, edx << 32 | eax) {
+            machine_state.raise(exception);
+        }
+    }
+
+    pub fn rdmsr(&self, machine_state: &mut MachineState) {
+        if !machine_state.check_privileged("rdmsr") {
+            return;
+        }
+        let ecx = machine_state.get_register_value(&Register::ECX) as u32;
+        let value = match machine_state.read_msr(ecx) {
+            Ok(value) => value,
+            Err(exception) => {
+                machine_state.raise(exception);
+                return;
+            }
+        };
+        machine_state.set_register_value(&Register::EAX, value as u32 as i64);
+        machine_state.set_register_value(&Register::EDX, (value >> 32) as i64);
+    }
+
+    /// Exchanges the gs base with IA32_KERNEL_GS_BASE on the way into and out of the kernel
+    pub fn swapgs(&self, machine_state: &mut MachineState) {
+        if !machine_state.check_privileged("swapgs") {
+            return;
+        }
+        let msrs = &mut machine_state.msrs;
+        mem::swap(&mut msrs.gs_base, &mut msrs.kernel_gs_base);
+    }
+}
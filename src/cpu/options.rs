@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use machine_state::MachineState;
+use cpu::clock::{Clock, ClockSource};
+use cpu::random::Random;
+use cpu::model::CpuModel;
+use cpu::msr::MsrHandler;
+use memory_map::{MemoryConfig, MemoryMap, MmioHandlers};
+use io_ports::PortHandlers;
+use pci::{PciBus, PciFunction};
+use pic::Pic;
+use acpi;
+
+/// Settings of the emulated cpu and its memory chosen on the command line
+pub struct CpuOptions {
+    /// source of the time stamp counter
+    pub clock: ClockSource,
+    /// seed of the numbers returned by rdrand and rdseed
+    pub seed: u64,
+    /// what cpuid returns and which instructions and MSRs exist
+    pub model: CpuModel,
+    /// embedder hooks which virtualise MSRs, consulted before the MSRs of the model
+    pub msr_handlers: Vec<Rc<RefCell<dyn MsrHandler>>>,
+    /// physical memory map, None makes all of the address space RAM
+    pub memory: Option<MemoryConfig>,
+    /// embedder hooks for the MMIO windows of the memory map, by window name
+    pub mmio_handlers: MmioHandlers,
+    /// embedder hooks for i/o ports
+    pub port_handlers: PortHandlers,
+    /// a PCI host bridge, implied by pci_functions
+    pub pci: bool,
+    /// devices on PCI bus 0, in the order of their device numbers
+    pub pci_functions: Vec<Rc<RefCell<dyn PciFunction>>>,
+}
+
+impl CpuOptions {
+    pub fn apply(&self, machine_state: &mut MachineState) {
+        machine_state.clock = Clock::new(self.clock);
+        machine_state.random = Random::new(self.seed);
+        machine_state.cpu_model = self.model.clone();
+        for handler in &self.msr_handlers {
+            machine_state.add_msr_handler(handler.clone());
+        }
+        machine_state.memory_map = MemoryMap::new(self.memory.clone());
+        for (name, handler) in &self.mmio_handlers {
+            machine_state.memory_map.connect(name, handler.clone());
+        }
+        for (base, size, filename) in machine_state.memory_map.rom_files() {
+            let mut file = File::open(&filename).expect("Cannot open ROM file");
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).expect("Failed to read ROM file");
+            if content.len() as u64 > size {
+                panic!("ROM file {} has {:#x} bytes, the ROM at {:#x} only {:#x}", filename, content.len(), base, size);
+            }
+            machine_state.load_rom(base, &content);
+        }
+        for &(first, count, ref handler) in &self.port_handlers {
+            machine_state.io_ports.add(first, count, handler.clone());
+        }
+        let pci = self.pci || !self.pci_functions.is_empty();
+        if pci {
+            let mut bus = PciBus::new();
+            for function in &self.pci_functions {
+                bus.add(function.clone());
+            }
+            PciBus::attach(Rc::new(RefCell::new(bus)), machine_state);
+        }
+        if self.memory.is_some() {
+            acpi::build(machine_state, pci);
+        }
+        // a PC, its devices interrupt through the 8259s
+        if self.memory.is_some() || pci {
+            Pic::attach(Rc::new(RefCell::new(Pic::new())), machine_state);
+        }
+    }
+}
+
+impl Default for CpuOptions {
+    fn default() -> CpuOptions {
+        CpuOptions {
+            clock: ClockSource::Instructions,
+            seed: 0,
+            model: CpuModel::default(),
+            msr_handlers: Vec::new(),
+            memory: None,
+            mmio_handlers: Vec::new(),
+            port_handlers: Vec::new(),
+            pci: false,
+            pci_functions: Vec::new(),
+        }
+    }
+}
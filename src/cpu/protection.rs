@@ -0,0 +1,458 @@
+use std::fmt;
+
+use instruction_set::{InstructionArgument, InstructionArguments, Register, ArgumentSize};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::mode::{CpuMode, DescriptorTableRegister, SegmentDescriptor};
+use cpu::msr::EFER_LMA;
+use utils::convert_i64_to_u8vec;
+
+/* Privilege levels, the LDT and the TSS.
+ *
+ * The CPL is the RPL of cs, it only changes when a far return, iret, an
+ * interrupt or syscall/sysret loads cs. Instructions which manage the machine
+ * (descriptor tables, control registers, MSRs, hlt) raise #GP outside of ring 0,
+ * cli, sti and port i/o need a CPL not above rflags.IOPL, port i/o also works
+ * with the ports enabled in the i/o permission bitmap of the TSS.
+ *
+ * lldt and ltr load the LDT and TSS descriptors from the GDT, in long mode
+ * these system descriptors take 16 bytes to hold a 64 bit base. The 64 bit TSS
+ * holds the stack pointers for ring 0 to 2 (RSP0-RSP2) and the seven interrupt
+ * stacks (IST1-IST7), interrupts through a 64 bit IDT switch to them.
+ *
+ * Instructions which fault raise an exception and return, the decoder resets
+ * rip and rsp to the instruction and delivers the exception through the IDT
+ * like an interrupt with an error code. Segment loads fault with #GP, #NP or
+ * #SS and the selector as error code. A fault while delivering it becomes a double
+ * fault, another one ends the emulation. Without an IDT the exception ends the
+ * emulation right away.
+ */
+
+/// exception vectors
+pub const INVALID_OPCODE: u8 = 6;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+
+/// system descriptor types
+const SYSTEM_LDT: u8 = 0x2;
+const SYSTEM_TSS_AVAILABLE: u8 = 0x9;
+const SYSTEM_TSS_BUSY: u8 = 0xB;
+const SYSTEM_INTERRUPT_GATE: u8 = 0xE;
+const SYSTEM_TRAP_GATE: u8 = 0xF;
+
+/// offsets in the 64 bit TSS
+const TSS_RSP0: u64 = 4;
+const TSS_IST1: u64 = 36;
+const TSS_IO_MAP_BASE: u64 = 102;
+
+const TRAP_FLAG: i64 = 1 << 8;
+const INTERRUPT_FLAG: i64 = 1 << 9;
+const NESTED_TASK: i64 = 1 << 14;
+const RESUME_FLAG: i64 = 1 << 16;
+
+/// A fault of an instruction or of the delivery of an interrupt
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    /// why the instruction faulted, for the panic if nothing handles it
+    pub reason: String,
+}
+
+impl Exception {
+    pub fn new(vector: u8, error_code: Option<u64>, reason: String) -> Exception {
+        Exception {
+            vector: vector,
+            error_code: error_code,
+            reason: reason,
+        }
+    }
+
+    pub fn invalid_opcode(reason: String) -> Exception {
+        Exception::new(INVALID_OPCODE, None, reason)
+    }
+
+    pub fn general_protection(error_code: u64, reason: String) -> Exception {
+        Exception::new(GENERAL_PROTECTION, Some(error_code), reason)
+    }
+
+    pub fn segment_not_present(error_code: u64, reason: String) -> Exception {
+        Exception::new(SEGMENT_NOT_PRESENT, Some(error_code), reason)
+    }
+
+    pub fn stack_fault(error_code: u64, reason: String) -> Exception {
+        Exception::new(STACK_FAULT, Some(error_code), reason)
+    }
+
+    pub fn invalid_tss(error_code: u64, reason: String) -> Exception {
+        Exception::new(INVALID_TSS, Some(error_code), reason)
+    }
+
+    /// CR2 holds the linear address
+    pub fn page_fault(error_code: u64, reason: String) -> Exception {
+        Exception::new(PAGE_FAULT, Some(error_code), reason)
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.vector {
+            INVALID_OPCODE => "Invalid opcode (#UD)",
+            DOUBLE_FAULT => "Double fault (#DF)",
+            INVALID_TSS => "Invalid TSS (#TS)",
+            SEGMENT_NOT_PRESENT => "Segment not present (#NP)",
+            STACK_FAULT => "Stack fault (#SS)",
+            GENERAL_PROTECTION => "General protection fault",
+            PAGE_FAULT => "Page fault (#PF)",
+            _ => "Exception",
+        };
+        write!(f, "{}: {}", name, self.reason)
+    }
+}
+
+/// An interrupt or trap gate of the 64 bit IDT
+struct Gate {
+    offset: u64,
+    selector: u16,
+    ist: u8,
+    gate_type: u8,
+    dpl: u8,
+    present: bool,
+}
+
+impl Gate {
+    fn from_raw(low: u64, high: u64) -> Gate {
+        Gate {
+            offset: low & 0xFFFF | (low >> 32) & 0xFFFF0000 | high << 32,
+            selector: (low >> 16) as u16,
+            ist: (low >> 32) as u8 & 7,
+            gate_type: (low >> 40) as u8 & 0xF,
+            dpl: (low >> 45) as u8 & 3,
+            present: low & 1 << 47 != 0,
+        }
+    }
+}
+
+fn read_u64(machine_state: &mut MachineState, address: u64) -> u64 {
+    let data = machine_state.mem_read(address, 8);
+    data.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+impl MachineState {
+    /// Ends the current instruction with an exception, the first one counts
+    pub fn raise(&mut self, exception: Exception) {
+        if self.exception.is_none() {
+            self.exception = Some(exception);
+        }
+    }
+
+    /// Raises #GP and returns false if the current privilege level is not 0
+    pub fn check_privileged(&mut self, instruction: &str) -> bool {
+        let cpl = self.cpl();
+        if cpl != 0 {
+            self.raise(Exception::general_protection(0, format!("{} at privilege level {}", instruction, cpl)));
+            return false;
+        }
+        true
+    }
+
+    /// Raises #GP and returns false if the CPL is above rflags.IOPL
+    pub fn check_iopl(&mut self, instruction: &str) -> bool {
+        let cpl = self.cpl();
+        let iopl = (self.rflags >> 12) & 3;
+        if cpl as i64 > iopl {
+            self.raise(Exception::general_protection(0, format!("{} at privilege level {} with IOPL {}", instruction, cpl, iopl)));
+            return false;
+        }
+        true
+    }
+
+    /// Raises #GP and returns false unless the CPL may access the ports
+    /// port..port + bytes, through rflags.IOPL or the i/o permission bitmap of the TSS
+    pub fn check_io_privilege(&mut self, port: u16, bytes: u64) -> bool {
+        let cpl = self.cpl() as i64;
+        if cpl <= (self.rflags >> 12) & 3 {
+            return true;
+        }
+        if self.tr & !3 != 0 && self.tss.limit as u64 > TSS_IO_MAP_BASE {
+            let map_base = self.mem_read(self.tss.base + TSS_IO_MAP_BASE, 2);
+            let map_base = (map_base[0] as u64 | (map_base[1] as u64) << 8) + port as u64 / 8;
+            // the ports can span two bytes of the bitmap
+            if map_base < self.tss.limit as u64 {
+                let map = self.mem_read(self.tss.base + map_base, 2);
+                let map = (map[0] as u64 | (map[1] as u64) << 8) >> (port % 8);
+                if map & ((1 << bytes) - 1) == 0 {
+                    return true;
+                }
+            }
+        }
+        self.raise(Exception::general_protection(0, format!("access to port {:#x} at privilege level {}", port, cpl)));
+        false
+    }
+
+    /// Reads a system descriptor (LDT or TSS) of the GDT, returns the descriptor
+    /// and its address or raises #GP for selectors of the LDT or outside of the GDT
+    fn read_system_descriptor(&mut self, selector: u16, instruction: &str) -> Option<(SegmentDescriptor, u64)> {
+        if selector & 4 != 0 {
+            let reason = format!("{} of selector {:#x} in the local descriptor table", instruction, selector);
+            self.raise(Exception::general_protection(selector as u64 & !3, reason));
+            return None;
+        }
+        let long_mode = self.msrs.efer & EFER_LMA != 0;
+        let address = match self.descriptor_address(selector, if long_mode { 16 } else { 8 }) {
+            Ok(address) => address,
+            Err(exception) => {
+                self.raise(exception);
+                return None;
+            }
+        };
+        let raw = read_u64(self, address);
+        let mut descriptor = SegmentDescriptor::from_raw(raw);
+        if long_mode {
+            descriptor.base |= read_u64(self, address + 8) << 32;
+        }
+        Some((descriptor, address))
+    }
+
+    /// Stack pointer for ring 0 to 2 from the TSS
+    pub fn tss_stack_pointer(&mut self, privilege_level: u8) -> Result<u64, Exception> {
+        self.read_tss(TSS_RSP0 + privilege_level as u64 * 8)
+    }
+
+    /// Interrupt stack 1 to 7 from the TSS
+    pub fn tss_interrupt_stack(&mut self, ist: u8) -> Result<u64, Exception> {
+        self.read_tss(TSS_IST1 + (ist as u64 - 1) * 8)
+    }
+
+    fn read_tss(&mut self, offset: u64) -> Result<u64, Exception> {
+        if self.tr & !3 == 0 || offset + 7 > self.tss.limit as u64 {
+            let reason = format!("offset {:#x} is outside of the TSS (selector {:#x}, limit {:#x})",
+                                 offset, self.tr, self.tss.limit);
+            return Err(Exception::invalid_tss(self.tr as u64 & !3, reason));
+        }
+        let address = self.tss.base + offset;
+        Ok(read_u64(self, address))
+    }
+
+    /// The gate of a vector, None outside of long mode and the IDT
+    fn read_gate(&mut self, vector: u8) -> Option<Gate> {
+        let vector = vector as u64;
+        if self.msrs.efer & EFER_LMA == 0 || vector * 16 + 15 > self.idtr.limit as u64 {
+            return None;
+        }
+        let address = self.idtr.base + vector * 16;
+        let low = read_u64(self, address);
+        let high = read_u64(self, address + 8);
+        Some(Gate::from_raw(low, high))
+    }
+
+    /// Delivers an interrupt through the 64 bit IDT: switches to the stack from
+    /// the TSS, pushes ss, rsp, rflags, cs, rip and the error code and jumps to
+    /// the handler. Software interrupts (int n) need a gate DPL of at least the
+    /// CPL. Returns the fault if the delivery fails, before anything changed.
+    pub fn interrupt(&mut self, vector: u8, error_code: Option<u64>, software: bool) -> Result<(), Exception> {
+        // faults of the delivery refer to the IDT entry, bit 0 marks events
+        // from outside of the program
+        let external = if software { 0 } else { 1 };
+        let idt_error = vector as u64 * 8 + 2 + external;
+        let gate = match self.read_gate(vector) {
+            Some(gate) => gate,
+            None => {
+                let reason = format!("vector {:#x} is outside of the IDT", vector);
+                return Err(Exception::general_protection(idt_error, reason));
+            }
+        };
+        if !gate.present {
+            return Err(Exception::segment_not_present(idt_error, format!("IDT entry {:#x}", vector)));
+        }
+        if gate.gate_type != SYSTEM_INTERRUPT_GATE && gate.gate_type != SYSTEM_TRAP_GATE {
+            let reason = format!("IDT entry {:#x} has type {:#x}", vector, gate.gate_type);
+            return Err(Exception::general_protection(idt_error, reason));
+        }
+        let cpl = self.cpl();
+        // user space may only use the gates meant for it
+        if software && gate.dpl < cpl {
+            let reason = format!("int {:#x} through a gate with DPL {} at privilege level {}", vector, gate.dpl, cpl);
+            return Err(Exception::general_protection(idt_error, reason));
+        }
+        let target_cs = self.descriptor_address(gate.selector, 8)?;
+        let target = SegmentDescriptor::from_raw(read_u64(self, target_cs));
+        if !target.is_code() || !target.long_mode() || target.dpl() > cpl {
+            let reason = format!("IDT entry {:#x} refers to segment {:#x} (access {:#x})",
+                                 vector, gate.selector, target.access);
+            return Err(Exception::general_protection(gate.selector as u64 & !3 | external, reason));
+        }
+        let new_cpl = if target.conforming() { cpl } else { target.dpl() };
+
+        let ss = self.ss as u64;
+        let rsp = self.rsp as u64;
+        let rflags = self.rflags;
+        let cs = self.cs as u64;
+        let rip = self.rip as u64;
+        let stack = if gate.ist != 0 {
+            Some(self.tss_interrupt_stack(gate.ist)?)
+        } else if new_cpl < cpl {
+            Some(self.tss_stack_pointer(new_cpl)?)
+        } else {
+            None
+        };
+
+        self.load_segment(Register::CS, gate.selector & !3 | new_cpl as u16)?;
+        if new_cpl < cpl {
+            // the stack segment of an inner level is null in 64 bit mode
+            self.load_segment(Register::SS, new_cpl as u16)?;
+        }
+        if let Some(stack) = stack {
+            self.rsp = stack as i64;
+        }
+        self.rsp &= !0xF;
+        for value in [ss, rsp, rflags as u64, cs, rip].iter() {
+            self.stack_push(&convert_i64_to_u8vec(*value as i64));
+        }
+        if let Some(error_code) = error_code {
+            self.stack_push(&convert_i64_to_u8vec(error_code as i64));
+        }
+
+        let mut mask = TRAP_FLAG | NESTED_TASK | RESUME_FLAG;
+        if gate.gate_type == SYSTEM_INTERRUPT_GATE {
+            mask |= INTERRUPT_FLAG;
+        }
+        self.rflags &= !mask;
+        self.rip = gate.offset as i64;
+        Ok(())
+    }
+
+    /// Delivers an exception raised by an instruction, rip points to the instruction
+    pub fn deliver_exception(&mut self, exception: Exception) {
+        if self.msrs.efer & EFER_LMA == 0 || self.idtr.limit == 0 {
+            panic!("{}", exception);
+        }
+        if let Err(fault) = self.interrupt(exception.vector, exception.error_code, false) {
+            if exception.vector == DOUBLE_FAULT {
+                panic!("Triple fault: {}, delivering it raised {}", exception, fault);
+            }
+            let reason = format!("{}, delivering it raised {}", exception, fault);
+            self.deliver_exception(Exception::new(DOUBLE_FAULT, Some(0), reason));
+        }
+    }
+}
+
+impl EmulationCPU {
+    fn check_protected_mode(&self, machine_state: &MachineState, instruction: &str) {
+        if machine_state.cpu_mode() == CpuMode::Real {
+            panic!("Invalid opcode (#UD): {} in real mode", instruction);
+        }
+    }
+
+    /// sldt and str zero extend the selector into registers, memory operands get 16 bits
+    fn store_selector(&self, machine_state: &mut MachineState, arg: &InstructionArguments, selector: u16) {
+        let argument = arg.get_one_argument();
+        let size = match *argument {
+            InstructionArgument::Register { .. } => arg.size(),
+            _ => ArgumentSize::Bit16,
+        };
+        machine_state.set_value(selector as i64, argument, size);
+    }
+
+    fn load_selector(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> u16 {
+        machine_state.get_value(arg.get_one_argument(), ArgumentSize::Bit16) as u16
+    }
+
+    pub fn sldt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.check_protected_mode(machine_state, "sldt");
+        let selector = machine_state.ldtr;
+        self.store_selector(machine_state, arg, selector);
+    }
+
+    pub fn str(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.check_protected_mode(machine_state, "str");
+        let selector = machine_state.tr;
+        self.store_selector(machine_state, arg, selector);
+    }
+
+    /// A null selector leaves the LDT unusable
+    pub fn lldt(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.check_protected_mode(machine_state, "lldt");
+        if !machine_state.check_privileged("lldt") {
+            return;
+        }
+        let selector = self.load_selector(machine_state, arg);
+        if selector & !3 == 0 {
+            machine_state.ldtr = selector;
+            machine_state.ldt = DescriptorTableRegister::default();
+            return;
+        }
+        let descriptor = match machine_state.read_system_descriptor(selector, "lldt") {
+            Some((descriptor, _)) => descriptor,
+            None => return,
+        };
+        if !descriptor.is_system() || descriptor.system_type() != SYSTEM_LDT {
+            let reason = format!("lldt of selector {:#x}, which is not an LDT (access {:#x})", selector, descriptor.access);
+            machine_state.raise(Exception::general_protection(selector as u64 & !3, reason));
+            return;
+        }
+        if !descriptor.present() {
+            machine_state.raise(Exception::segment_not_present(selector as u64 & !3, format!("LDT {:#x}", selector)));
+            return;
+        }
+        machine_state.ldtr = selector;
+        machine_state.ldt = DescriptorTableRegister {
+            base: descriptor.base,
+            limit: descriptor.limit as u16,
+        };
+    }
+
+    /// Loads the task register and marks the TSS busy
+    pub fn ltr(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.check_protected_mode(machine_state, "ltr");
+        if !machine_state.check_privileged("ltr") {
+            return;
+        }
+        let selector = self.load_selector(machine_state, arg);
+        if selector & !3 == 0 {
+            machine_state.raise(Exception::general_protection(0, "ltr of the null selector".to_string()));
+            return;
+        }
+        let (descriptor, address) = match machine_state.read_system_descriptor(selector, "ltr") {
+            Some(descriptor) => descriptor,
+            None => return,
+        };
+        if !descriptor.is_system() || descriptor.system_type() != SYSTEM_TSS_AVAILABLE {
+            let reason = format!("ltr of selector {:#x}, which is not an available TSS (access {:#x})",
+                                 selector, descriptor.access);
+            machine_state.raise(Exception::general_protection(selector as u64 & !3, reason));
+            return;
+        }
+        if !descriptor.present() {
+            machine_state.raise(Exception::segment_not_present(selector as u64 & !3, format!("TSS {:#x}", selector)));
+            return;
+        }
+        let access = descriptor.access & !0xF | SYSTEM_TSS_BUSY;
+        machine_state.mem_write(address + 5, &[access]);
+        machine_state.tr = selector;
+        machine_state.tss = DescriptorTableRegister {
+            base: descriptor.base,
+            limit: descriptor.limit as u16,
+        };
+    }
+
+    /// int n through the IDT in long mode. Vectors without a present gate end
+    /// the emulation like int always did, test programs exit with int $0x80.
+    pub fn int(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let vector = machine_state.get_value(arg.get_one_argument(), ArgumentSize::Bit8) as u8;
+        match machine_state.read_gate(vector) {
+            Some(ref gate) if gate.present => (),
+            _ => {
+                machine_state.stopped = true;
+                return;
+            }
+        }
+        if let Err(exception) = machine_state.interrupt(vector, None, true) {
+            machine_state.raise(exception);
+        }
+    }
+}
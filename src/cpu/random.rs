@@ -0,0 +1,54 @@
+use instruction_set::{InstructionArguments, Flags};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+
+/* rdrand and rdseed return numbers of a seeded pseudo random number generator
+ * (splitmix64), so a program sees the same numbers in every run with the same
+ * seed. They never fail, the carry flag is always set.
+ */
+
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new(0)
+    }
+}
+
+impl EmulationCPU {
+    fn random_impl(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let argument_size = arg.size();
+        let value = machine_state.random.next_u64() as i64;
+        machine_state.set_value(value, arg.get_one_argument(), argument_size);
+        machine_state.set_flag(Flags::Carry, true);
+        machine_state.set_flag(Flags::Overflow, false);
+        machine_state.set_flag(Flags::Sign, false);
+        machine_state.set_flag(Flags::Zero, false);
+        machine_state.set_flag(Flags::Adjust, false);
+        machine_state.set_flag(Flags::Parity, false);
+    }
+
+    pub fn rdrand(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.random_impl(machine_state, arg);
+    }
+
+    pub fn rdseed(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.random_impl(machine_state, arg);
+    }
+}
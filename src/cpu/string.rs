@@ -0,0 +1,316 @@
+use instruction_set::{InstructionArgument, InstructionArguments, Register, Flags, ArgumentSize};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::mode::CodeSize;
+
+/* String instructions: movs, cmps, stos, lods, scas, ins and outs.
+ *
+ * The decoder stores the (%rsi), (%rdi), accumulator and (%dx) operands in AT&T
+ * order, with a 32 bit address size the memory operands are (%esi) and (%edi)
+ * and ecx is the counter, with a 16 bit address size si, di and cx. The source
+ * is in ds (or the segment override), the destination always in es. Every function returns false if a rep prefixed
+ * instruction is not finished yet: at most ITERATIONS_PER_STEP elements are
+ * processed per call and rsi, rdi and rcx are updated after each of them, the
+ * decoder then executes the instruction again. Like on a real cpu a long rep movs
+ * can be interrupted between two elements and resumed from the registers.
+ */
+
+const ITERATIONS_PER_STEP: u64 = 4096;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Element size and address size of one string instruction.
+struct StringOperation {
+    size: ArgumentSize,
+    element_size: u64,
+    /// selects rsi, rdi and rcx, esi, edi and ecx or si, di and cx
+    address_size: CodeSize,
+}
+
+impl StringOperation {
+    fn new(arg: &InstructionArguments) -> StringOperation {
+        let size = arg.size();
+        let element_size = match size {
+            ArgumentSize::Bit8 => 1,
+            ArgumentSize::Bit16 => 2,
+            ArgumentSize::Bit32 => 4,
+            ArgumentSize::Bit64 => 8,
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Vector operands are not supported by string instructions"),
+        };
+        let address_size = [&arg.first_argument, &arg.second_argument].iter().filter_map(|argument| match **argument {
+            Some(InstructionArgument::EffectiveAddress { base: Some(Register::ESI), .. }) |
+            Some(InstructionArgument::EffectiveAddress { base: Some(Register::EDI), .. }) => Some(CodeSize::Bit32),
+            Some(InstructionArgument::EffectiveAddress { base: Some(Register::SI), .. }) |
+            Some(InstructionArgument::EffectiveAddress { base: Some(Register::DI), .. }) => Some(CodeSize::Bit16),
+            _ => None,
+        }).next().unwrap_or(CodeSize::Bit64);
+        StringOperation {
+            size: size,
+            element_size: element_size,
+            address_size: address_size,
+        }
+    }
+
+    fn register(&self, register: Register) -> Register {
+        match (self.address_size, register) {
+            (CodeSize::Bit64, _) => register,
+            (CodeSize::Bit32, Register::RSI) => Register::ESI,
+            (CodeSize::Bit32, Register::RDI) => Register::EDI,
+            (CodeSize::Bit32, Register::RCX) => Register::ECX,
+            (CodeSize::Bit16, Register::RSI) => Register::SI,
+            (CodeSize::Bit16, Register::RDI) => Register::DI,
+            (CodeSize::Bit16, Register::RCX) => Register::CX,
+            _ => panic!("{:?} is not used by string instructions", register),
+        }
+    }
+
+    fn read(&self, machine_state: &MachineState, register: Register) -> u64 {
+        let value = machine_state.get_register_value(&self.register(register)) as u64;
+        match self.address_size {
+            CodeSize::Bit16 => value & 0xFFFF,
+            CodeSize::Bit32 => value & 0xFFFFFFFF,
+            CodeSize::Bit64 => value,
+        }
+    }
+
+    fn write(&self, machine_state: &mut MachineState, register: Register, value: u64) {
+        // writing esi, edi or ecx wraps around at 4 GiB, si, di and cx at 64 KiB
+        machine_state.set_register_value(&self.register(register), value as i64);
+    }
+
+    /// Linear address of the element at rsi or rdi
+    fn address(&self, machine_state: &MachineState, register: Register) -> u64 {
+        let segment = match register {
+            Register::RDI => Register::ES,
+            _ => machine_state.segment_override.unwrap_or(Register::DS),
+        };
+        let address = self.read(machine_state, register).wrapping_add(machine_state.segment_base(segment));
+        machine_state.truncate_linear_address(address)
+    }
+
+    /// Moves rsi or rdi count elements forward, or backward if the direction flag is set.
+    fn advance(&self, machine_state: &mut MachineState, register: Register, count: u64) {
+        let distance = count.wrapping_mul(self.element_size);
+        let value = self.read(machine_state, register);
+        let value = if machine_state.get_flag(Flags::Direction) {
+            value.wrapping_sub(distance)
+        } else {
+            value.wrapping_add(distance)
+        };
+        self.write(machine_state, register, value);
+    }
+
+    fn read_element(&self, machine_state: &mut MachineState, register: Register) -> u64 {
+        let address = self.address(machine_state, register);
+        let bytes = machine_state.mem_read(address, self.element_size);
+        bytes.iter().rev().fold(0, |result, &byte| result << 8 | byte as u64)
+    }
+
+    fn write_element(&self, machine_state: &mut MachineState, register: Register, value: u64) {
+        let address = self.address(machine_state, register);
+        let bytes = self.to_bytes(value);
+        machine_state.mem_write(address, &bytes);
+    }
+
+    fn to_bytes(&self, value: u64) -> Vec<u8> {
+        (0..self.element_size).map(|index| (value >> (index * 8)) as u8).collect()
+    }
+
+    /// How many elements starting at address fit into its page
+    fn elements_in_page(&self, address: u64) -> u64 {
+        (PAGE_SIZE - address % PAGE_SIZE) / self.element_size
+    }
+
+    /// Number of elements for a bulk copy or fill of the destination (and source),
+    /// None if the elements have to be processed one by one.
+    fn bulk_count(&self, machine_state: &MachineState, arg: &InstructionArguments, source: Option<u64>) -> Option<u64> {
+        // traced instructions record every memory access of every element
+        if !repeated(arg) || machine_state.trace.record_memory || machine_state.get_flag(Flags::Direction) {
+            return None;
+        }
+        // si and di could wrap around in the middle of a page
+        if self.address_size == CodeSize::Bit16 {
+            return None;
+        }
+        // stay on the current pages, the mmu only translates the first address of an access
+        let destination = self.address(machine_state, Register::RDI);
+        let mut count = self.read(machine_state, Register::RCX)
+            .min(ITERATIONS_PER_STEP)
+            .min(self.elements_in_page(destination));
+        if let Some(source) = source {
+            count = count.min(self.elements_in_page(source));
+            let length = count * self.element_size;
+            // overlapping movs repeats a pattern, only the element by element copy does that
+            if source < destination + length && destination < source + length {
+                return None;
+            }
+        }
+        if count == 0 {
+            None
+        } else {
+            Some(count)
+        }
+    }
+
+    /// Updates the registers after count elements were processed at once.
+    fn finish_bulk(&self, machine_state: &mut MachineState, registers: &[Register], count: u64) -> bool {
+        for register in registers {
+            self.advance(machine_state, *register, count);
+        }
+        let counter = self.read(machine_state, Register::RCX) - count;
+        self.write(machine_state, Register::RCX, counter);
+        counter == 0
+    }
+}
+
+fn repeated(arg: &InstructionArguments) -> bool {
+    arg.repeat_equal || arg.repeat_not_equal
+}
+
+/// Runs element once, or with a rep prefix until rcx is 0 or element returns false.
+/// Returns false if the instruction has to be executed again.
+fn repeat<F>(machine_state: &mut MachineState, arg: &InstructionArguments, operation: &StringOperation, mut element: F)
+             -> bool
+    where F: FnMut(&mut MachineState) -> bool
+{
+    if !repeated(arg) {
+        element(machine_state);
+        return true;
+    }
+    let mut counter = operation.read(machine_state, Register::RCX);
+    for _ in 0..ITERATIONS_PER_STEP {
+        if counter == 0 {
+            return true;
+        }
+        let next = element(machine_state);
+        counter -= 1;
+        operation.write(machine_state, Register::RCX, counter);
+        if !next {
+            return true;
+        }
+    }
+    counter == 0
+}
+
+/// repe/repz continues while the elements are equal, repne/repnz while they differ
+fn compare_continues(machine_state: &MachineState, arg: &InstructionArguments) -> bool {
+    let equal = machine_state.get_flag(Flags::Zero);
+    if arg.repeat_not_equal {
+        !equal
+    } else {
+        equal
+    }
+}
+
+fn accumulator(argument: &InstructionArgument) -> Register {
+    match *argument {
+        InstructionArgument::Register { register } => register,
+        _ => panic!("String instruction without accumulator operand"),
+    }
+}
+
+impl EmulationCPU {
+    pub fn movs(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let source = operation.address(machine_state, Register::RSI);
+        if let Some(count) = operation.bulk_count(machine_state, arg, Some(source)) {
+            let destination = operation.address(machine_state, Register::RDI);
+            let data = machine_state.mem_read(source, count * operation.element_size);
+            machine_state.mem_write(destination, &data);
+            return operation.finish_bulk(machine_state, &[Register::RSI, Register::RDI], count);
+        }
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let value = operation.read_element(machine_state, Register::RSI);
+            operation.write_element(machine_state, Register::RDI, value);
+            operation.advance(machine_state, Register::RSI, 1);
+            operation.advance(machine_state, Register::RDI, 1);
+            true
+        })
+    }
+
+    pub fn cmps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let source = operation.read_element(machine_state, Register::RSI);
+            let destination = operation.read_element(machine_state, Register::RDI);
+            // flags of (%rsi) - (%rdi)
+            self.sub_impl2(machine_state, destination as i64, source as i64, operation.size);
+            operation.advance(machine_state, Register::RSI, 1);
+            operation.advance(machine_state, Register::RDI, 1);
+            compare_continues(machine_state, arg)
+        })
+    }
+
+    pub fn stos(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let (first_argument, _) = arg.get_two_arguments();
+        let value = machine_state.get_register_value(&accumulator(first_argument)) as u64;
+        if let Some(count) = operation.bulk_count(machine_state, arg, None) {
+            let destination = operation.address(machine_state, Register::RDI);
+            let element = operation.to_bytes(value);
+            let data: Vec<u8> = element.iter().cloned().cycle().take((count * operation.element_size) as usize).collect();
+            machine_state.mem_write(destination, &data);
+            return operation.finish_bulk(machine_state, &[Register::RDI], count);
+        }
+        repeat(machine_state, arg, &operation, |machine_state| {
+            operation.write_element(machine_state, Register::RDI, value);
+            operation.advance(machine_state, Register::RDI, 1);
+            true
+        })
+    }
+
+    pub fn lods(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let (_, second_argument) = arg.get_two_arguments();
+        let register = accumulator(second_argument);
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let value = operation.read_element(machine_state, Register::RSI);
+            machine_state.set_register_value(&register, value as i64);
+            operation.advance(machine_state, Register::RSI, 1);
+            true
+        })
+    }
+
+    pub fn scas(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let (_, second_argument) = arg.get_two_arguments();
+        let value = machine_state.get_register_value(&accumulator(second_argument));
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let element = operation.read_element(machine_state, Register::RDI);
+            // flags of the accumulator - (%rdi)
+            self.sub_impl2(machine_state, element as i64, value, operation.size);
+            operation.advance(machine_state, Register::RDI, 1);
+            compare_continues(machine_state, arg)
+        })
+    }
+
+    pub fn ins(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let port = machine_state.get_register_value(&Register::DX) as u16;
+        // the exception ends the instruction, the decoder resets rip
+        if !machine_state.check_io_privilege(port, operation.element_size) {
+            return true;
+        }
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let value = machine_state.io_ports.read(port, operation.element_size);
+            operation.write_element(machine_state, Register::RDI, value);
+            operation.advance(machine_state, Register::RDI, 1);
+            true
+        })
+    }
+
+    pub fn outs(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> bool {
+        let operation = StringOperation::new(arg);
+        let port = machine_state.get_register_value(&Register::DX) as u16;
+        if !machine_state.check_io_privilege(port, operation.element_size) {
+            return true;
+        }
+        repeat(machine_state, arg, &operation, |machine_state| {
+            let value = operation.read_element(machine_state, Register::RSI);
+            machine_state.io_ports.write(port, operation.element_size, value);
+            machine_state.process_pci_functions();
+            operation.advance(machine_state, Register::RSI, 1);
+            true
+        })
+    }
+}
@@ -0,0 +1,717 @@
+use instruction_set::{InstructionArgument, InstructionArguments, Register, Flags, ArgumentSize, get_register_size};
+use machine_state::MachineState;
+use cpu::emu_instructions::EmulationCPU;
+use utils::{convert_i32_to_u8vec, convert_i64_to_u8vec};
+
+/* AVX, AVX2 and the XSAVE feature set.
+ *
+ * Like all other instructions the arguments are stored source first: for the three
+ * operand forms first_argument is ModRM r/m, second_argument VEX.vvvv and
+ * third_argument the destination. All instructions here are VEX encoded, so writing
+ * a XMM register clears the upper half of the YMM register (see set_vector_value).
+ */
+
+/// state components of XCR0 the emulator supports: x87, SSE and AVX
+pub const XCR0_SUPPORTED: u64 = 0b111;
+
+const XSAVE_LEGACY_SIZE: u64 = 512;
+const XSAVE_HEADER_SIZE: u64 = 64;
+const XSAVE_AVX_OFFSET: u64 = XSAVE_LEGACY_SIZE + XSAVE_HEADER_SIZE;
+const XSAVE_AVX_SIZE: u64 = 256;
+
+fn lane(value: &[u8; 32], index: usize, lane_size: usize) -> u64 {
+    let mut result = 0;
+    for i in 0..lane_size {
+        result |= (value[index * lane_size + i] as u64) << (i * 8);
+    }
+    result
+}
+
+fn set_lane(value: &mut [u8; 32], index: usize, lane_size: usize, lane: u64) {
+    for i in 0..lane_size {
+        value[index * lane_size + i] = (lane >> (i * 8)) as u8;
+    }
+}
+
+fn sign_extend(value: u64, lane_size: usize) -> i64 {
+    let shift = 64 - lane_size * 8;
+    ((value << shift) as i64) >> shift
+}
+
+fn to_float(value: u64, double: bool) -> f64 {
+    if double {
+        f64::from_bits(value)
+    } else {
+        f32::from_bits(value as u32) as f64
+    }
+}
+
+fn from_float(value: f64, double: bool) -> u64 {
+    if double {
+        value.to_bits()
+    } else {
+        (value as f32).to_bits() as u64
+    }
+}
+
+fn is_vector_register(argument: &InstructionArgument) -> bool {
+    match *argument {
+        InstructionArgument::Register { ref register } => {
+            match get_register_size(register) {
+                ArgumentSize::Bit128 | ArgumentSize::Bit256 => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Size of the operation, given by the destination register or the memory operand
+fn vector_size(arg: &InstructionArguments, destination: &InstructionArgument) -> ArgumentSize {
+    match *destination {
+        InstructionArgument::Register { ref register } => get_register_size(register),
+        _ => arg.explicit_size.expect("Vector memory operands need an explicit size"),
+    }
+}
+
+/// Reads a vector register or the memory operand of arg
+fn read_vector(machine_state: &mut MachineState, arg: &InstructionArguments, argument: &InstructionArgument) -> [u8; 32] {
+    machine_state.get_vector_value(argument, arg.explicit_size.unwrap_or(ArgumentSize::Bit256))
+}
+
+impl EmulationCPU {
+    // implementations used by multiple instructions
+    fn vector_move(&self, machine_state: &mut MachineState, arg: &InstructionArguments, aligned: bool) {
+        let (source, destination) = arg.get_two_arguments();
+        let size = vector_size(arg, destination);
+        if aligned {
+            for argument in &[source, destination] {
+                if let InstructionArgument::EffectiveAddress { .. } = **argument {
+                    let address = machine_state.linear_address(argument);
+                    if address % size.bytes() != 0 {
+                        panic!("General protection fault: unaligned vector access at {:x}", address);
+                    }
+                }
+            }
+        }
+        let value = machine_state.get_vector_value(source, size);
+        machine_state.set_vector_value(&value, destination, size);
+    }
+
+    /// vmovd and vmovq, one side is a general purpose register or memory
+    fn vector_move_scalar(&self, machine_state: &mut MachineState, arg: &InstructionArguments, size: ArgumentSize) {
+        let (source, destination) = arg.get_two_arguments();
+        let value = if is_vector_register(source) {
+            lane(&machine_state.get_vector_value(source, size), 0, 8)
+        } else {
+            machine_state.get_value(source, size) as u64
+        };
+        let value = match size {
+            ArgumentSize::Bit32 => value as u32 as u64,
+            _ => value,
+        };
+        if is_vector_register(destination) {
+            let mut result = [0; 32];
+            set_lane(&mut result, 0, 8, value);
+            machine_state.set_vector_value(&result, destination, size);
+        } else {
+            machine_state.set_value(value as i64, destination, size);
+        }
+    }
+
+    fn vector_broadcast(&self, machine_state: &mut MachineState, arg: &InstructionArguments, lane_size: ArgumentSize) {
+        let (source, destination) = arg.get_two_arguments();
+        let lane_size = lane_size.bytes() as usize;
+        let value = lane(&read_vector(machine_state, arg, source), 0, lane_size);
+        let size = vector_size(arg, destination);
+        let mut result = [0; 32];
+        for index in 0..size.bytes() as usize / lane_size {
+            set_lane(&mut result, index, lane_size, value);
+        }
+        machine_state.set_vector_value(&result, destination, size);
+    }
+
+    /// destination = operation(source1, source2) for every lane_size bytes
+    fn vector_integer_operation<F>(&self,
+                                   machine_state: &mut MachineState,
+                                   arg: &InstructionArguments,
+                                   lane_size: usize,
+                                   operation: F)
+        where F: Fn(u64, u64) -> u64
+    {
+        let (source2, source1, destination) = arg.get_three_arguments();
+        let size = vector_size(arg, destination);
+        let value1 = read_vector(machine_state, arg, source1);
+        let value2 = read_vector(machine_state, arg, source2);
+        let mut result = [0; 32];
+        for index in 0..size.bytes() as usize / lane_size {
+            let value = operation(lane(&value1, index, lane_size), lane(&value2, index, lane_size));
+            set_lane(&mut result, index, lane_size, value);
+        }
+        machine_state.set_vector_value(&result, destination, size);
+    }
+
+    /// Packed operations compute every lane, scalar operations only the lowest
+    /// one and copy the rest of the first source. Single precision values are
+    /// computed as double, which rounds the same for these operations.
+    fn vector_float_operation<F>(&self,
+                                 machine_state: &mut MachineState,
+                                 arg: &InstructionArguments,
+                                 double: bool,
+                                 packed: bool,
+                                 operation: F)
+        where F: Fn(f64, f64) -> f64
+    {
+        let (source1, source2, destination) = match arg.third_argument {
+            Some(ref destination) => {
+                let (source2, source1) = arg.get_two_arguments();
+                (source1, source2, destination)
+            }
+            // vsqrtps and vsqrtpd only have one source
+            None => {
+                let (source, destination) = arg.get_two_arguments();
+                (source, source, destination)
+            }
+        };
+        let size = vector_size(arg, destination);
+        let lane_size = if double { 8 } else { 4 };
+        let value1 = read_vector(machine_state, arg, source1);
+        let value2 = read_vector(machine_state, arg, source2);
+        let (mut result, lanes) = if packed {
+            ([0; 32], size.bytes() as usize / lane_size)
+        } else {
+            (value1, 1)
+        };
+        for index in 0..lanes {
+            let value = operation(to_float(lane(&value1, index, lane_size), double),
+                                  to_float(lane(&value2, index, lane_size), double));
+            set_lane(&mut result, index, lane_size, from_float(value, double));
+        }
+        machine_state.set_vector_value(&result, destination, size);
+    }
+
+    fn vector_compare_equal(&self, machine_state: &mut MachineState, arg: &InstructionArguments, lane_size: usize) {
+        self.vector_integer_operation(machine_state, arg, lane_size, |a, b| if a == b { u64::max_value() } else { 0 });
+    }
+
+    fn vector_compare_greater(&self, machine_state: &mut MachineState, arg: &InstructionArguments, lane_size: usize) {
+        self.vector_integer_operation(machine_state, arg, lane_size, |a, b| {
+            if sign_extend(a, lane_size) > sign_extend(b, lane_size) {
+                u64::max_value()
+            } else {
+                0
+            }
+        });
+    }
+
+    pub fn vmovdqa(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, true);
+    }
+
+    pub fn vmovdqu(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, false);
+    }
+
+    pub fn vmovaps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, true);
+    }
+
+    pub fn vmovapd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, true);
+    }
+
+    pub fn vmovups(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, false);
+    }
+
+    pub fn vmovupd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move(machine_state, arg, false);
+    }
+
+    pub fn vmovd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move_scalar(machine_state, arg, ArgumentSize::Bit32);
+    }
+
+    pub fn vmovq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_move_scalar(machine_state, arg, ArgumentSize::Bit64);
+    }
+
+    pub fn vpbroadcastb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit8);
+    }
+
+    pub fn vpbroadcastw(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit16);
+    }
+
+    pub fn vpbroadcastd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit32);
+    }
+
+    pub fn vpbroadcastq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit64);
+    }
+
+    pub fn vbroadcastss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit32);
+    }
+
+    pub fn vbroadcastsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_broadcast(machine_state, arg, ArgumentSize::Bit64);
+    }
+
+    pub fn vinserti128(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (immediate, source2, source1, destination) = arg.get_four_arguments();
+        let half = (machine_state.get_value(immediate, ArgumentSize::Bit8) & 1) as usize * 16;
+        let mut result = read_vector(machine_state, arg, source1);
+        let value = read_vector(machine_state, arg, source2);
+        result[half..half + 16].copy_from_slice(&value[..16]);
+        machine_state.set_vector_value(&result, destination, ArgumentSize::Bit256);
+    }
+
+    pub fn vextracti128(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (immediate, source, destination) = arg.get_three_arguments();
+        let half = (machine_state.get_value(immediate, ArgumentSize::Bit8) & 1) as usize * 16;
+        let value = read_vector(machine_state, arg, source);
+        let mut result = [0; 32];
+        result[..16].copy_from_slice(&value[half..half + 16]);
+        machine_state.set_vector_value(&result, destination, ArgumentSize::Bit128);
+    }
+
+    pub fn vperm2i128(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (immediate, source2, source1, destination) = arg.get_four_arguments();
+        let control = machine_state.get_value(immediate, ArgumentSize::Bit8);
+        let value1 = read_vector(machine_state, arg, source1);
+        let value2 = read_vector(machine_state, arg, source2);
+        let mut result = [0; 32];
+        for half in 0..2 {
+            let control = control >> (half * 4);
+            if control & 0b1000 != 0 {
+                continue;
+            }
+            let source = if control & 0b10 == 0 { &value1 } else { &value2 };
+            let offset = (control & 1) as usize * 16;
+            result[half * 16..half * 16 + 16].copy_from_slice(&source[offset..offset + 16]);
+        }
+        machine_state.set_vector_value(&result, destination, ArgumentSize::Bit256);
+    }
+
+    pub fn vzeroupper(&self, machine_state: &mut MachineState) {
+        for register in machine_state.ymm.iter_mut() {
+            for byte in register[16..].iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+
+    pub fn vzeroall(&self, machine_state: &mut MachineState) {
+        machine_state.ymm = [[0; 32]; 16];
+    }
+
+    pub fn vpaddb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 1, |a, b| a.wrapping_add(b));
+    }
+
+    pub fn vpaddw(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 2, |a, b| a.wrapping_add(b));
+    }
+
+    pub fn vpaddd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 4, |a, b| a.wrapping_add(b));
+    }
+
+    pub fn vpaddq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| a.wrapping_add(b));
+    }
+
+    pub fn vpsubb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 1, |a, b| a.wrapping_sub(b));
+    }
+
+    pub fn vpsubw(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 2, |a, b| a.wrapping_sub(b));
+    }
+
+    pub fn vpsubd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 4, |a, b| a.wrapping_sub(b));
+    }
+
+    pub fn vpsubq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| a.wrapping_sub(b));
+    }
+
+    pub fn vpand(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| a & b);
+    }
+
+    pub fn vpandn(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| !a & b);
+    }
+
+    pub fn vpor(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| a | b);
+    }
+
+    pub fn vpxor(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 8, |a, b| a ^ b);
+    }
+
+    pub fn vpcmpeqb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_equal(machine_state, arg, 1);
+    }
+
+    pub fn vpcmpeqw(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_equal(machine_state, arg, 2);
+    }
+
+    pub fn vpcmpeqd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_equal(machine_state, arg, 4);
+    }
+
+    pub fn vpcmpeqq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_equal(machine_state, arg, 8);
+    }
+
+    pub fn vpcmpgtb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_greater(machine_state, arg, 1);
+    }
+
+    pub fn vpcmpgtw(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_greater(machine_state, arg, 2);
+    }
+
+    pub fn vpcmpgtd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_greater(machine_state, arg, 4);
+    }
+
+    pub fn vpcmpgtq(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_compare_greater(machine_state, arg, 8);
+    }
+
+    pub fn vpminub(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 1, |a, b| a.min(b));
+    }
+
+    pub fn vpmaxub(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_integer_operation(machine_state, arg, 1, |a, b| a.max(b));
+    }
+
+    pub fn vpmovmskb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (source, destination) = arg.get_two_arguments();
+        let size = vector_size(arg, source);
+        let value = machine_state.get_vector_value(source, size);
+        let mut mask = 0;
+        for (index, byte) in value[..size.bytes() as usize].iter().enumerate() {
+            mask |= ((byte >> 7) as i64) << index;
+        }
+        machine_state.set_value(mask, destination, ArgumentSize::Bit32);
+    }
+
+    pub fn vptest(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (source2, source1) = arg.get_two_arguments();
+        let size = vector_size(arg, source1);
+        let value1 = read_vector(machine_state, arg, source1);
+        let value2 = read_vector(machine_state, arg, source2);
+        let (mut and, mut and_not) = (0, 0);
+        for index in 0..size.bytes() as usize {
+            and |= value1[index] & value2[index];
+            and_not |= !value1[index] & value2[index];
+        }
+        machine_state.set_flag(Flags::Zero, and == 0);
+        machine_state.set_flag(Flags::Carry, and_not == 0);
+        machine_state.set_flag(Flags::Overflow, false);
+        machine_state.set_flag(Flags::Sign, false);
+        machine_state.set_flag(Flags::Parity, false);
+    }
+
+    /// shuffles the bytes of source1 within each 128 bit lane
+    pub fn vpshufb(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (source2, source1, destination) = arg.get_three_arguments();
+        let size = vector_size(arg, destination);
+        let value = read_vector(machine_state, arg, source1);
+        let control = read_vector(machine_state, arg, source2);
+        let mut result = [0; 32];
+        for index in 0..size.bytes() as usize {
+            if control[index] & 0x80 == 0 {
+                result[index] = value[index & !0xF | (control[index] & 0xF) as usize];
+            }
+        }
+        machine_state.set_vector_value(&result, destination, size);
+    }
+
+    /// shuffles the dwords of the source within each 128 bit lane
+    pub fn vpshufd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let (immediate, source, destination) = arg.get_three_arguments();
+        let size = vector_size(arg, destination);
+        let control = machine_state.get_value(immediate, ArgumentSize::Bit8) as usize;
+        let value = read_vector(machine_state, arg, source);
+        let mut result = [0; 32];
+        for index in 0..size.bytes() as usize / 4 {
+            let selected = index & !0b11 | (control >> ((index & 0b11) * 2)) & 0b11;
+            set_lane(&mut result, index, 4, lane(&value, selected, 4));
+        }
+        machine_state.set_vector_value(&result, destination, size);
+    }
+
+    pub fn vaddps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| a + b);
+    }
+
+    pub fn vaddpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| a + b);
+    }
+
+    pub fn vaddss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| a + b);
+    }
+
+    pub fn vaddsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| a + b);
+    }
+
+    pub fn vsubps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| a - b);
+    }
+
+    pub fn vsubpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| a - b);
+    }
+
+    pub fn vsubss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| a - b);
+    }
+
+    pub fn vsubsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| a - b);
+    }
+
+    pub fn vmulps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| a * b);
+    }
+
+    pub fn vmulpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| a * b);
+    }
+
+    pub fn vmulss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| a * b);
+    }
+
+    pub fn vmulsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| a * b);
+    }
+
+    pub fn vdivps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| a / b);
+    }
+
+    pub fn vdivpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| a / b);
+    }
+
+    pub fn vdivss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| a / b);
+    }
+
+    pub fn vdivsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| a / b);
+    }
+
+    // min and max return the second source if the values are unordered or both zero
+    pub fn vminps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| if a < b { a } else { b });
+    }
+
+    pub fn vminpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| if a < b { a } else { b });
+    }
+
+    pub fn vminss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| if a < b { a } else { b });
+    }
+
+    pub fn vminsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| if a < b { a } else { b });
+    }
+
+    pub fn vmaxps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |a, b| if a > b { a } else { b });
+    }
+
+    pub fn vmaxpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |a, b| if a > b { a } else { b });
+    }
+
+    pub fn vmaxss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |a, b| if a > b { a } else { b });
+    }
+
+    pub fn vmaxsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |a, b| if a > b { a } else { b });
+    }
+
+    pub fn vsqrtps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, true, |_, b| b.sqrt());
+    }
+
+    pub fn vsqrtpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, true, |_, b| b.sqrt());
+    }
+
+    pub fn vsqrtss(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, false, false, |_, b| b.sqrt());
+    }
+
+    pub fn vsqrtsd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vector_float_operation(machine_state, arg, true, false, |_, b| b.sqrt());
+    }
+
+    // the bitwise floating point operations do not care about the lane size
+    pub fn vandps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpand(machine_state, arg);
+    }
+
+    pub fn vandpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpand(machine_state, arg);
+    }
+
+    pub fn vandnps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpandn(machine_state, arg);
+    }
+
+    pub fn vandnpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpandn(machine_state, arg);
+    }
+
+    pub fn vorps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpor(machine_state, arg);
+    }
+
+    pub fn vorpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpor(machine_state, arg);
+    }
+
+    pub fn vxorps(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpxor(machine_state, arg);
+    }
+
+    pub fn vxorpd(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        self.vpxor(machine_state, arg);
+    }
+
+    pub fn xgetbv(&self, machine_state: &mut MachineState) {
+        let ecx = machine_state.get_register_value(&Register::ECX);
+        if ecx != 0 {
+            panic!("XGETBV: unsupported extended control register: {:x}", ecx);
+        }
+        let xcr0 = machine_state.xcr0;
+        machine_state.set_register_value(&Register::EAX, xcr0 as i64);
+        machine_state.set_register_value(&Register::EDX, (xcr0 >> 32) as i64);
+    }
+
+    pub fn xsetbv(&self, machine_state: &mut MachineState) {
+        if !machine_state.check_privileged("xsetbv") {
+            return;
+        }
+        let ecx = machine_state.get_register_value(&Register::ECX);
+        if ecx != 0 {
+            panic!("XSETBV: unsupported extended control register: {:x}", ecx);
+        }
+        let value = self.edx_eax(machine_state);
+        // x87 state cannot be disabled and AVX needs SSE
+        if value & !machine_state.cpu_model.xcr0_supported() != 0 || value & 1 == 0 || value & 0b110 == 0b100 {
+            panic!("XSETBV: invalid XCR0 value: {:x}", value);
+        }
+        machine_state.xcr0 = value;
+    }
+
+    /// Saves the state components in XCR0 & EDX:EAX in the standard format.
+    /// The x87 state is not emulated, only its control word is written.
+    pub fn xsave(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let address = self.xsave_area(machine_state, arg);
+        let components = machine_state.xcr0 & self.edx_eax(machine_state);
+
+        if components & 1 != 0 {
+            machine_state.mem_write(address, &[0x7F, 0x03]);
+        }
+        if components & 0b110 != 0 {
+            let mxcsr = machine_state.mxcsr as i32;
+            machine_state.mem_write(address + 24, &convert_i32_to_u8vec(mxcsr));
+            machine_state.mem_write(address + 28, &convert_i32_to_u8vec(0xFFFF));
+        }
+        for register in 0..16 {
+            let value = machine_state.ymm[register];
+            let register = register as u64;
+            if components & 0b10 != 0 {
+                machine_state.mem_write(address + 160 + register * 16, &value[..16]);
+            }
+            if components & 0b100 != 0 {
+                machine_state.mem_write(address + XSAVE_AVX_OFFSET + register * 16, &value[16..]);
+            }
+        }
+
+        let header = address + XSAVE_LEGACY_SIZE;
+        let state = self.read_u64(machine_state, header);
+        let state = state & !components | components;
+        machine_state.mem_write(header, &convert_i64_to_u8vec(state as i64));
+    }
+
+    /// Restores the state components in XCR0 & EDX:EAX, components missing
+    /// in the XSTATE_BV field of the header are set to their initial state.
+    pub fn xrstor(&self, machine_state: &mut MachineState, arg: &InstructionArguments) {
+        let address = self.xsave_area(machine_state, arg);
+        let components = machine_state.xcr0 & self.edx_eax(machine_state);
+        let state = self.read_u64(machine_state, address + XSAVE_LEGACY_SIZE);
+
+        if components & 0b110 != 0 {
+            machine_state.mxcsr = self.read_u64(machine_state, address + 24) as u32;
+        }
+        for register in 0..16 {
+            let offset = register as u64 * 16;
+            if components & 0b10 != 0 {
+                let value = if state & 0b10 != 0 {
+                    machine_state.mem_read(address + 160 + offset, 16)
+                } else {
+                    vec![0; 16]
+                };
+                machine_state.ymm[register][..16].copy_from_slice(&value);
+            }
+            if components & 0b100 != 0 {
+                let value = if state & 0b100 != 0 {
+                    machine_state.mem_read(address + XSAVE_AVX_OFFSET + offset, 16)
+                } else {
+                    vec![0; 16]
+                };
+                machine_state.ymm[register][16..].copy_from_slice(&value);
+            }
+        }
+    }
+
+    fn xsave_area(&self, machine_state: &mut MachineState, arg: &InstructionArguments) -> u64 {
+        let address = machine_state.linear_address(arg.get_one_argument());
+        if address % 64 != 0 {
+            panic!("General protection fault: xsave area at {:x} is not 64 byte aligned", address);
+        }
+        address
+    }
+
+    fn edx_eax(&self, machine_state: &mut MachineState) -> u64 {
+        let eax = machine_state.get_register_value(&Register::EAX) as u32 as u64;
+        let edx = machine_state.get_register_value(&Register::EDX) as u32 as u64;
+        edx << 32 | eax
+    }
+
+    fn read_u64(&self, machine_state: &mut MachineState, address: u64) -> u64 {
+        let value = machine_state.mem_read(address, 8);
+        (0..8).fold(0, |result, index| result | (value[index] as u64) << (index * 8))
+    }
+
+    /// Size of the xsave area for the state components enabled in XCR0 (CPUID leaf 0xD)
+    pub fn xsave_size(&self, xcr0: u64) -> u64 {
+        if xcr0 & 0b100 != 0 {
+            XSAVE_AVX_OFFSET + XSAVE_AVX_SIZE
+        } else {
+            XSAVE_AVX_OFFSET
+        }
+    }
+}
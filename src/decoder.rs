@@ -1,19 +1,37 @@
 use std::io::Write;
+use std::ptr;
+use std::rc::Rc;
 use fnv::FnvHashMap;
-use std::collections::hash_map::{Entry};
 use time::PreciseTime;
 
 use instruction_set::{Register, RegisterSize, InstructionArguments, InstructionArgumentsBuilder,
-                      InstructionArgument, ArgumentSize, Instruction, InstructionCache};
+                      InstructionArgument, ArgumentSize, Instruction, InstructionCache, Prefixes};
 use machine_state::MachineState;
 use cpu::emu_instructions::EmulationCPU;
+use cpu::mode::CodeSize;
+use cpu::model::Feature;
+use cpu::protection::Exception;
+use trace::{TraceSink, TraceRecord, RegisterSnapshot};
+use disassembler::format_instruction;
+use ir::{Mnemonic, mnemonic, lock_allowed, required_feature};
+use formatter::{InstructionFormatter, AttFormatter};
+use opcode_table::{OpcodeEntry, MandatoryPrefix, VexEncoding, VectorLength, Operands, OperandSpec, OperandKind,
+                   OperandSize, Immediate, ONE_BYTE_MAP, TWO_BYTE_MAP, THREE_BYTE_38_MAP, THREE_BYTE_3A_MAP};
 
 use zero;
 
+/// instructions between two polls of the devices
+const DEVICE_POLL_INTERVAL: u64 = 1000;
+
 pub struct Decoder<'a> {
     machine_state: &'a mut MachineState,
     cpu: &'a EmulationCPU,
     counter: u64,
+    /// decoded instructions by linear address and code size
+    instruction_cache: FnvHashMap<(u64, CodeSize), Rc<InstructionCache>>,
+    trace_sinks: Vec<Box<dyn TraceSink>>,
+    /// syntax of the instructions in trace records
+    formatter: Box<dyn InstructionFormatter>,
 }
 
 impl<'a> Decoder<'a> {
@@ -22,89 +40,557 @@ impl<'a> Decoder<'a> {
             cpu: cpu,
             machine_state: machine_state,
             counter: 0,
+            instruction_cache: FnvHashMap::default(),
+            trace_sinks: Vec::new(),
+            formatter: Box::new(AttFormatter),
         }
     }
 
-    pub fn execute(&mut self, benchmark: bool) {
-        let mut instruction_cache = FnvHashMap::default();
+    pub fn add_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.machine_state.print_instructions = true;
+        self.trace_sinks.push(sink);
+    }
+
+    pub fn set_formatter(&mut self, formatter: Box<dyn InstructionFormatter>) {
+        self.formatter = formatter;
+    }
+
+    pub fn machine_state(&mut self) -> &mut MachineState {
+        self.machine_state
+    }
 
+    pub fn execute(&mut self, benchmark: bool) {
         let start = PreciseTime::now();
-        loop {
-            self.counter += 1;
-            let instruction_start = self.machine_state.rip as u64;
-
-            let cache_entry = match instruction_cache.entry(instruction_start) {
-                Entry::Occupied(entry) => {
-                    let ref entry: InstructionCache = *entry.into_mut();
-                    self.machine_state.rip += entry.size as i64;
-                    entry
-                },
-                Entry::Vacant(entry) => {
-                    let cache_entry = self.decode();
-
-                    let instruction_end = self.machine_state.rip as u64;
-
-                    let cache_entry = InstructionCache {
-                        instruction: cache_entry.0,
-                        arguments: cache_entry.1,
-                        size: instruction_end - instruction_start,
-                    };
-                    entry.insert(cache_entry)
+        while self.step() {}
+        self.finish();
+        if benchmark {
+            let r = writeln!(&mut ::std::io::stderr(), "duration: {}", start.to(PreciseTime::now()));
+            r.expect("failed printing to stderr");
+        }
+    }
+
+    /// Executes a single instruction, returns false when the emulation has ended.
+    pub fn step(&mut self) -> bool {
+        self.counter += 1;
+        self.machine_state.clock.tick();
+        // devices waiting for the host (received frames, console input) interrupt
+        // without the driver touching them
+        if self.machine_state.halted || self.counter % DEVICE_POLL_INTERVAL == 0 {
+            self.machine_state.process_pci_functions();
+        }
+        if self.machine_state.interrupt_shadow {
+            self.machine_state.interrupt_shadow = false;
+        } else {
+            self.machine_state.deliver_interrupts();
+        }
+        // a halted cpu idles until an interrupt resumes it, without interrupts
+        // it would never run again
+        if self.machine_state.halted {
+            return self.machine_state.takes_interrupts();
+        }
+        let instruction_start = self.machine_state.instruction_address();
+        if instruction_start == 0 {
+            panic!("Instruction pointer is set to 0, aborting...");
+        }
+
+        if self.machine_state.code_modified {
+            self.machine_state.code_modified = false;
+            self.instruction_cache.clear();
+        }
+        let code_size = self.machine_state.code_size();
+        let cached = self.instruction_cache.get(&(instruction_start, code_size)).cloned();
+        let cache_entry = match cached {
+            Some(entry) => entry,
+            None => {
+                let cache_entry = Rc::new(self.fetch(instruction_start, code_size));
+                if let Err(exception) = self.machine_state.add_code_pages(instruction_start, cache_entry.size) {
+                    self.machine_state.deliver_exception(exception);
+                    return true;
                 }
-            };
+                self.instruction_cache.insert((instruction_start, code_size), cache_entry.clone());
+                cache_entry
+            }
+        };
+        let rip = self.machine_state.rip;
+        let rsp = self.machine_state.rsp;
+        self.machine_state.rip += cache_entry.size as i64;
 
-            self.execute_instruction(cache_entry);
-            match cache_entry.instruction {
-                Instruction::Int => {
-                    break;
-                },
-                _ => (),
+        let mnemonic = match cache_entry.mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => {
+                let bytes = self.machine_state.mem_read(instruction_start, cache_entry.size);
+                let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                panic!("Unknown instruction: {}, executed instructions: {}", bytes.join(" "), self.counter);
             }
+        };
+        if cache_entry.prefixes.lock && !lock_allowed(mnemonic, cache_entry.arguments.as_ref()) {
+            let bytes = self.machine_state.mem_read(instruction_start, cache_entry.size);
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let reason = format!("lock prefix on {}, executed instructions: {}", bytes.join(" "), self.counter);
+            self.machine_state.rip = rip;
+            self.machine_state.deliver_exception(Exception::invalid_opcode(reason));
+            return true;
+        }
+        // cpus without BMI1 or ABM ignore the rep prefix of tzcnt and lzcnt
+        let mnemonic = match mnemonic {
+            Mnemonic::Tzcnt if !self.machine_state.cpu_model.has_feature(Feature::Bmi1) => Mnemonic::Bsf,
+            Mnemonic::Lzcnt if !self.machine_state.cpu_model.has_feature(Feature::Lzcnt) => Mnemonic::Bsr,
+            _ => mnemonic,
+        };
+        if let Some(feature) = required_feature(mnemonic, cache_entry.arguments.as_ref()) {
+            if !self.machine_state.cpu_model.has_feature(feature) {
+                let bytes = self.machine_state.mem_read(instruction_start, cache_entry.size);
+                let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                let reason = format!("{} needs {:?}, which the cpu model does not advertise, executed instructions: {}",
+                                     bytes.join(" "), feature, self.counter);
+                self.machine_state.rip = rip;
+                self.machine_state.deliver_exception(Exception::invalid_opcode(reason));
+                return true;
+            }
+        }
+
+        // memory operands use this segment instead of ds or ss
+        self.machine_state.segment_override = cache_entry.prefixes.segment;
 
-            if self.machine_state.print_registers {
-                println!("{}", self.machine_state);
+        if self.trace_sinks.is_empty() {
+            self.dispatch(mnemonic, &cache_entry);
+        } else {
+            let bytes = self.machine_state.mem_read(instruction_start, cache_entry.size);
+            self.machine_state.trace.clear();
+            self.machine_state.trace.record_memory = true;
+            let registers_before = RegisterSnapshot::new(self.machine_state);
+
+            self.dispatch(mnemonic, &cache_entry);
+
+            self.machine_state.trace.record_memory = false;
+            let registers_after = RegisterSnapshot::new(self.machine_state);
+            let (mnemonic, operands) = format_instruction(&*self.formatter, &cache_entry, code_size,
+                                                          instruction_start);
+            let record = TraceRecord {
+                counter: self.counter,
+                rip: instruction_start,
+                bytes: bytes,
+                mnemonic: mnemonic,
+                operands: operands,
+                registers: registers_before.delta(&registers_after),
+                memory: self.machine_state.trace.memory.drain(..).collect(),
+            };
+            for sink in self.trace_sinks.iter_mut() {
+                sink.record(&record);
             }
         }
-        if benchmark {
-            let r = writeln!(&mut ::std::io::stderr(), "duration: {}", start.to(PreciseTime::now()));
-            r.expect("failed printing to stderr");
+        // faults restart the instruction once the handler returns, with the
+        // stack of the instruction (iret and far returns pop before they fault)
+        if let Some(exception) = self.machine_state.exception.take() {
+            self.machine_state.rip = rip;
+            self.machine_state.rsp = rsp;
+            self.machine_state.deliver_exception(exception);
         }
+        // outside of 64 bit mode the instruction pointer wraps around
+        match self.machine_state.code_size() {
+            CodeSize::Bit16 => self.machine_state.rip &= 0xFFFF,
+            CodeSize::Bit32 => self.machine_state.rip &= 0xFFFFFFFF,
+            CodeSize::Bit64 => (),
+        }
+        if self.machine_state.stopped {
+            return false;
+        }
+
+        if self.machine_state.print_registers {
+            println!("{}", self.machine_state);
+        }
+        true
     }
 
-    pub fn decode(&mut self) -> (Instruction, Option<InstructionArguments>) {
-        let mut first_byte;
+    fn fetch(&mut self, address: u64, code_size: CodeSize) -> InstructionCache {
+        // stay on the current page if possible, the next one might not be mapped
+        let page_end = (address | 0xfff) + 1;
+        let length = MAX_INSTRUCTION_LENGTH.min(page_end - address);
+        let bytes = self.machine_state.mem_read(address, length);
+        match decode_instruction_for(&bytes, code_size) {
+            Some(cache_entry) => cache_entry,
+            None => {
+                let bytes = self.machine_state.mem_read(address, MAX_INSTRUCTION_LENGTH);
+                decode_instruction_for(&bytes, code_size).expect("Instruction is longer than 15 bytes")
+            }
+        }
+    }
 
-        let mut decoder_flags = DecoderFlags { bits: 0 };
+    /// Flushes all trace sinks, call after the last step.
+    pub fn finish(&mut self) {
+        for sink in self.trace_sinks.iter_mut() {
+            sink.finish();
+        }
+    }
 
-        loop {
-            let rip = self.machine_state.rip as u64;
+    fn fetch_argument(cache_entry: &InstructionCache) -> &InstructionArguments {
+        match cache_entry.arguments {
+            Some(ref arg) => arg,
+            None => panic!("expected arg")
+        }
+    }
+
+    /// Executes an instruction, locked read-modify-write instructions (xchg with
+    /// memory is always locked) go through execute_locked
+    fn dispatch(&mut self, mnemonic: Mnemonic, cache_entry: &InstructionCache) {
+        let locked = cache_entry.prefixes.lock ||
+            mnemonic == Mnemonic::Xchg && lock_allowed(mnemonic, cache_entry.arguments.as_ref());
+        if locked {
+            self.execute_locked(mnemonic, cache_entry);
+        } else {
+            self.execute_instruction(mnemonic, cache_entry);
+        }
+    }
 
-            if rip == 0 {
-                panic!("Instruction pointer is set to 0, aborting...");
+    /// The one place locked read-modify-write instructions run. With a single vCPU
+    /// every instruction completes before the next one starts, so nothing sees
+    /// a partial update. They are not atomic with respect to other vCPUs, which
+    /// do not exist yet: those would need a lock on the memory operand here.
+    fn execute_locked(&mut self, mnemonic: Mnemonic, cache_entry: &InstructionCache) {
+        self.execute_instruction(mnemonic, cache_entry);
+    }
+
+    fn execute_instruction(&mut self, mnemonic: Mnemonic, cache_entry: &InstructionCache) {
+        match mnemonic {
+            Mnemonic::Adc => self.cpu.adc(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Add => self.cpu.add(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::And => self.cpu.and(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bt => self.cpu.bt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bts => self.cpu.bts(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Btr => self.cpu.btr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Btc => self.cpu.btc(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Call => self.cpu.call(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cld => self.cpu.cld(self.machine_state),
+            Mnemonic::Cli => self.cpu.cli(self.machine_state),
+            Mnemonic::Cmova => self.cpu.cmova(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovae => self.cpu.cmovae(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovb => self.cpu.cmovb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovbe => self.cpu.cmovbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmove => self.cpu.cmove(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovg => self.cpu.cmovg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovge => self.cpu.cmovge(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovl => self.cpu.cmovl(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovle => self.cpu.cmovle(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovne => self.cpu.cmovne(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovno => self.cpu.cmovno(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovnp => self.cpu.cmovnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovns => self.cpu.cmovns(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovo => self.cpu.cmovo(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovp => self.cpu.cmovp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmovs => self.cpu.cmovs(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmp => self.cpu.cmp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cpuid => self.cpu.cpuid(self.machine_state),
+            Mnemonic::Dec => self.cpu.dec(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Enter => self.cpu.enter(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Hlt => self.cpu.hlt(self.machine_state),
+            Mnemonic::Imul => self.cpu.imul(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::In => self.cpu.in_port(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Inc => self.cpu.inc(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            // abuse int X instruction to signal passed test program, see step()
+            Mnemonic::Int => self.cpu.int(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            // abuse int 3 instruction to signal failed test program
+            Mnemonic::Int3 => panic!("int3 instruction"),
+            Mnemonic::Iret => self.cpu.iret(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Ja => self.cpu.ja(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jae => self.cpu.jae(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jb => self.cpu.jb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jbe => self.cpu.jbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Je => self.cpu.je(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jg => self.cpu.jg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jge => self.cpu.jge(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jl => self.cpu.jl(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jle => self.cpu.jle(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jmp => self.cpu.jmp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jne => self.cpu.jne(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jno => self.cpu.jno(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jnp => self.cpu.jnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jns => self.cpu.jns(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jo => self.cpu.jo(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jp => self.cpu.jp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Jrcxz => self.cpu.jrcxz(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Js => self.cpu.js(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Lcall => self.cpu.lcall(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Lea => self.cpu.lea(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Leave => self.cpu.leave(self.machine_state),
+            Mnemonic::Lidt => self.cpu.lidt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Lgdt => self.cpu.lgdt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Ljmp => self.cpu.ljmp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Lldt => self.cpu.lldt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Loop => self.cpu.loop_rcx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Loope => self.cpu.loope(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Loopne => self.cpu.loopne(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Ltr => self.cpu.ltr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Mov => self.cpu.mov(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Movsx => self.cpu.movsx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Movzx => self.cpu.movzx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Nop => (),
+            Mnemonic::Or => self.cpu.or(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Out => self.cpu.out(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Pop => self.cpu.pop(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Popf => self.cpu.popf(self.machine_state),
+            Mnemonic::Push => self.cpu.push(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Pushf => self.cpu.pushf(self.machine_state),
+            Mnemonic::Ret => self.cpu.ret(self.machine_state, cache_entry.arguments.as_ref()),
+            Mnemonic::Lret => self.cpu.lret(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rdmsr => self.cpu.rdmsr(self.machine_state),
+            Mnemonic::Rdrand => self.cpu.rdrand(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rdseed => self.cpu.rdseed(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rdtsc => self.cpu.rdtsc(self.machine_state),
+            Mnemonic::Rdtscp => self.cpu.rdtscp(self.machine_state),
+            Mnemonic::Sbb => self.cpu.sbb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sldt => self.cpu.sldt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Std => self.cpu.std(self.machine_state),
+            Mnemonic::Sti => self.cpu.sti(self.machine_state),
+            Mnemonic::Str => self.cpu.str(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sub => self.cpu.sub(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Swapgs => self.cpu.swapgs(self.machine_state),
+            Mnemonic::Test => self.cpu.test(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Wrmsr => self.cpu.wrmsr(self.machine_state),
+            Mnemonic::Xor => self.cpu.xor(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Movs | Mnemonic::Cmps | Mnemonic::Stos | Mnemonic::Lods | Mnemonic::Scas |
+            Mnemonic::Ins | Mnemonic::Outs => {
+                let arg = Decoder::fetch_argument(cache_entry);
+                let finished = match mnemonic {
+                    Mnemonic::Movs => self.cpu.movs(self.machine_state, arg),
+                    Mnemonic::Cmps => self.cpu.cmps(self.machine_state, arg),
+                    Mnemonic::Stos => self.cpu.stos(self.machine_state, arg),
+                    Mnemonic::Lods => self.cpu.lods(self.machine_state, arg),
+                    Mnemonic::Scas => self.cpu.scas(self.machine_state, arg),
+                    Mnemonic::Ins => self.cpu.ins(self.machine_state, arg),
+                    _ => self.cpu.outs(self.machine_state, arg),
+                };
+                // rep string instructions run in chunks, rip stays on the instruction until rcx is 0
+                if !finished {
+                    self.machine_state.rip -= cache_entry.size as i64;
+                }
             }
+            Mnemonic::Cmpxchg => self.cpu.cmpxchg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Xchg => self.cpu.xchg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Syscall => self.cpu.syscall(self.machine_state),
+            Mnemonic::Sysret => self.cpu.sysret(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Seto => self.cpu.seto(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setno => self.cpu.setno(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setb => self.cpu.setb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setae => self.cpu.setae(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sete => self.cpu.sete(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setne => self.cpu.setne(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setbe => self.cpu.setbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Seta => self.cpu.seta(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sets => self.cpu.sets(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setns => self.cpu.setns(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setp => self.cpu.setp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setnp => self.cpu.setnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setl => self.cpu.setl(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setge => self.cpu.setge(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setle => self.cpu.setle(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Setg => self.cpu.setg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovdqa => self.cpu.vmovdqa(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovdqu => self.cpu.vmovdqu(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovaps => self.cpu.vmovaps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovapd => self.cpu.vmovapd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovups => self.cpu.vmovups(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovupd => self.cpu.vmovupd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovd => self.cpu.vmovd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmovq => self.cpu.vmovq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpbroadcastb => self.cpu.vpbroadcastb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpbroadcastw => self.cpu.vpbroadcastw(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpbroadcastd => self.cpu.vpbroadcastd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpbroadcastq => self.cpu.vpbroadcastq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vbroadcastss => self.cpu.vbroadcastss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vbroadcastsd => self.cpu.vbroadcastsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vinserti128 => self.cpu.vinserti128(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vextracti128 => self.cpu.vextracti128(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vperm2i128 => self.cpu.vperm2i128(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vzeroupper => self.cpu.vzeroupper(self.machine_state),
+            Mnemonic::Vzeroall => self.cpu.vzeroall(self.machine_state),
+            Mnemonic::Vpaddb => self.cpu.vpaddb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpaddw => self.cpu.vpaddw(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpaddd => self.cpu.vpaddd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpaddq => self.cpu.vpaddq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpsubb => self.cpu.vpsubb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpsubw => self.cpu.vpsubw(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpsubd => self.cpu.vpsubd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpsubq => self.cpu.vpsubq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpand => self.cpu.vpand(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpandn => self.cpu.vpandn(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpor => self.cpu.vpor(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpxor => self.cpu.vpxor(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpeqb => self.cpu.vpcmpeqb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpeqw => self.cpu.vpcmpeqw(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpeqd => self.cpu.vpcmpeqd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpeqq => self.cpu.vpcmpeqq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpgtb => self.cpu.vpcmpgtb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpgtw => self.cpu.vpcmpgtw(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpgtd => self.cpu.vpcmpgtd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpcmpgtq => self.cpu.vpcmpgtq(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpminub => self.cpu.vpminub(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpmaxub => self.cpu.vpmaxub(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpmovmskb => self.cpu.vpmovmskb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vptest => self.cpu.vptest(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpshufb => self.cpu.vpshufb(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vpshufd => self.cpu.vpshufd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vaddps => self.cpu.vaddps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vaddpd => self.cpu.vaddpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vaddss => self.cpu.vaddss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vaddsd => self.cpu.vaddsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsubps => self.cpu.vsubps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsubpd => self.cpu.vsubpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsubss => self.cpu.vsubss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsubsd => self.cpu.vsubsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmulps => self.cpu.vmulps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmulpd => self.cpu.vmulpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmulss => self.cpu.vmulss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmulsd => self.cpu.vmulsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vdivps => self.cpu.vdivps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vdivpd => self.cpu.vdivpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vdivss => self.cpu.vdivss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vdivsd => self.cpu.vdivsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vminps => self.cpu.vminps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vminpd => self.cpu.vminpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vminss => self.cpu.vminss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vminsd => self.cpu.vminsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmaxps => self.cpu.vmaxps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmaxpd => self.cpu.vmaxpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmaxss => self.cpu.vmaxss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vmaxsd => self.cpu.vmaxsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsqrtps => self.cpu.vsqrtps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsqrtpd => self.cpu.vsqrtpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsqrtss => self.cpu.vsqrtss(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vsqrtsd => self.cpu.vsqrtsd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vandps => self.cpu.vandps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vandpd => self.cpu.vandpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vandnps => self.cpu.vandnps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vandnpd => self.cpu.vandnpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vorps => self.cpu.vorps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vorpd => self.cpu.vorpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vxorps => self.cpu.vxorps(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Vxorpd => self.cpu.vxorpd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Xgetbv => self.cpu.xgetbv(self.machine_state),
+            Mnemonic::Xsetbv => self.cpu.xsetbv(self.machine_state),
+            Mnemonic::Xsave => self.cpu.xsave(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Xrstor => self.cpu.xrstor(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bsf => self.cpu.bsf(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bsr => self.cpu.bsr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bswap => self.cpu.bswap(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Popcnt => self.cpu.popcnt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Lzcnt => self.cpu.lzcnt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Tzcnt => self.cpu.tzcnt(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Andn => self.cpu.andn(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bextr => self.cpu.bextr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Blsi => self.cpu.blsi(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Blsmsk => self.cpu.blsmsk(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Blsr => self.cpu.blsr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Bzhi => self.cpu.bzhi(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Pdep => self.cpu.pdep(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Pext => self.cpu.pext(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Mulx => self.cpu.mulx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sarx => self.cpu.sarx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Shlx => self.cpu.shlx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Shrx => self.cpu.shrx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rorx => self.cpu.rorx(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Xadd => self.cpu.xadd(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmpxchg8b => self.cpu.cmpxchg8b(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Cmpxchg16b => self.cpu.cmpxchg8b(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Div => self.cpu.div(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Idiv => self.cpu.idiv(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Mul => self.cpu.mul(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Neg => self.cpu.neg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Not => self.cpu.not(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rcl => self.cpu.rcl(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rcr => self.cpu.rcr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Rol => self.cpu.rol(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Ror => self.cpu.ror(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Sar => self.cpu.sar(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Shl => self.cpu.shl(self.machine_state, Decoder::fetch_argument(cache_entry)),
+            Mnemonic::Shr => self.cpu.shr(self.machine_state, Decoder::fetch_argument(cache_entry)),
+        }
+    }
+}
+
+/// Longest possible x86 instruction
+const MAX_INSTRUCTION_LENGTH: u64 = 15;
+
+/// Decodes the instruction at the start of bytes in 16, 32 or 64 bit code without executing
+/// it. Returns None if bytes ends before the instruction does.
+pub fn decode_instruction_for(bytes: &[u8], code_size: CodeSize) -> Option<InstructionCache> {
+    let mut reader = InstructionReader {
+        bytes: bytes,
+        rip: 0,
+        prefixes: Prefixes::default(),
+        code_size: code_size,
+    };
+    let (instruction, arguments) = reader.decode();
+    if reader.rip as usize > bytes.len() {
+        return None;
+    }
+    let mnemonic = mnemonic(&instruction, arguments.as_ref());
+    Some(InstructionCache {
+        instruction: instruction,
+        mnemonic: mnemonic,
+        arguments: arguments,
+        size: reader.rip as u64,
+        prefixes: reader.prefixes,
+    })
+}
+
+/// Decodes one instruction from a byte slice. rip is the offset into the slice,
+/// reads past the end of the slice return zero.
+struct InstructionReader<'b> {
+    bytes: &'b [u8],
+    rip: i64,
+    prefixes: Prefixes,
+    code_size: CodeSize,
+}
+
+impl<'b> InstructionReader<'b> {
+    fn read_byte(&self, offset: u64) -> u8 {
+        self.bytes.get(offset as usize).cloned().unwrap_or(0)
+    }
+
+    fn read(&self, offset: u64, length: u64) -> Vec<u8> {
+        (offset..offset + length).map(|offset| self.read_byte(offset)).collect()
+    }
+
+    fn decode(&mut self) -> (Instruction, Option<InstructionArguments>) {
+        let mut first_byte;
+
+        let mut decoder_flags = DecoderFlags { bits: 0 };
 
-            first_byte = self.machine_state.mem_read_byte(rip);
+        loop {
+            let rip = self.rip as u64;
+            first_byte = self.read_byte(rip);
             match first_byte {
                 0xF0 => {
-                    // todo: do not ignore lock/bound prefix
+                    // checked in Decoder::step()
+                    self.prefixes.lock = true;
                 }
                 0xF2 => {
                     decoder_flags |= REPEAT_NOT_EQUAL;
+                    self.prefixes.repeat_not_equal = true;
                 }
                 0xF3 => {
                     decoder_flags |= REPEAT_EQUAL;
+                    self.prefixes.repeat_equal = true;
                 }
                 0x2E | 0x3E | 0x36 | 0x26 | 0x64 | 0x65 => {
-                    //TODO: do not ignore segment prefix (or probably we should?)
+                    self.prefixes.segment = Some(match first_byte {
+                        0x2E => Register::CS,
+                        0x3E => Register::DS,
+                        0x36 => Register::SS,
+                        0x26 => Register::ES,
+                        0x64 => Register::FS,
+                        _ => Register::GS,
+                    });
                 }
                 0x66 => {
                     decoder_flags |= OPERAND_16_BIT;
                 }
                 0x67 => {
                     decoder_flags |= ADDRESS_SIZE_OVERRIDE;
+                    self.prefixes.address_size_override = true;
                 }
-                0x40...0x4F => {
-                    // 64bit REX prefix
+                0x40...0x4F if self.code_size == CodeSize::Bit64 => {
+                    // 64bit REX prefix, inc and dec in other modes
                     let temp_rex = REX { bits: first_byte };
                     if temp_rex.contains(B) {
                         decoder_flags |= NEW_64BIT_REGISTER;
@@ -122,1587 +608,654 @@ impl<'a> Decoder<'a> {
                 }
                 _ => break,
             }
-            self.machine_state.rip += 1;
+            self.rip += 1;
         }
 
-        let register_size = if decoder_flags.contains(OPERAND_64_BIT) {
-            RegisterSize::Bit64
-        } else {
-            if decoder_flags.contains(OPERAND_16_BIT) {
-                RegisterSize::Bit16
-            } else {
-                RegisterSize::Bit32
+        // in 64 bit mode C4 and C5 are always a VEX prefix, in other modes
+        // only if the next byte could not be the ModRM byte of les or lds
+        let rip = self.rip as u64;
+        let vex_allowed = self.code_size == CodeSize::Bit64 || self.read_byte(rip + 1) >> 6 == 0b11;
+        let vex = match first_byte {
+            0xC4 if vex_allowed => {
+                let byte1 = self.read_byte(rip + 1);
+                let byte2 = self.read_byte(rip + 2);
+                // R, X and B are stored inverted
+                if byte1 & 0x80 == 0 {
+                    decoder_flags |= MOD_R_M_EXTENSION;
+                }
+                if byte1 & 0x40 == 0 {
+                    decoder_flags |= SIB_EXTENSION;
+                }
+                if byte1 & 0x20 == 0 && self.code_size == CodeSize::Bit64 {
+                    decoder_flags |= NEW_64BIT_REGISTER;
+                }
+                if byte2 & 0x80 != 0 {
+                    decoder_flags |= OPERAND_64_BIT;
+                }
+                self.rip += 3;
+                Some((Vex::new(byte2, byte2 & 0x80 != 0), byte1 & 0b11111))
             }
+            0xC5 if vex_allowed => {
+                let byte1 = self.read_byte(rip + 1);
+                if byte1 & 0x80 == 0 {
+                    decoder_flags |= MOD_R_M_EXTENSION;
+                }
+                self.rip += 2;
+                Some((Vex::new(byte1, false), 1))
+            }
+            _ => None,
         };
 
-        let rip = self.machine_state.rip as u64;
-        first_byte = self.machine_state.mem_read_byte(rip);
-        match first_byte {
-            0x00 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Add, Some(argument))
-            }
-            0x01 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Add, Some(argument))
-            }
-            0x02 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Add, Some(argument))
-            }
-            0x03 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Add, Some(argument))
-            }
-            0x04 => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Add, Some(argument))
-            }
-            0x05 => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Add, Some(argument))
-            }
-            0x08 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Or, Some(argument))
-            }
-            0x09 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Or, Some(argument))
-            }
-            0x0A => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Or, Some(argument))
-            }
-            0x0B => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Or, Some(argument))
-            }
-            0x0C => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Or, Some(argument))
-            }
-            0x0D => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Or, Some(argument))
-            }
-            0x10 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Adc, Some(argument))
-            }
-            0x11 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Adc, Some(argument))
-            }
-            0x12 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Adc, Some(argument))
-            }
-            0x13 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Adc, Some(argument))
-            }
-            0x14 => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Adc, Some(argument))
-            }
-            0x15 => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Adc, Some(argument))
-            }
-            0x18 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Sbb, Some(argument))
-            }
-            0x19 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Sbb, Some(argument))
-            }
-            0x1A => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Sbb, Some(argument))
-            }
-            0x1B => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Sbb, Some(argument))
-            }
-            0x1C => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Sbb, Some(argument))
-            }
-            0x1D => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Sbb, Some(argument))
-            }
-            0x20 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::And, Some(argument))
-            }
-            0x21 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::And, Some(argument))
-            }
-            0x22 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::And, Some(argument))
-            }
-            0x23 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::And, Some(argument))
-            }
-            0x24 => {
-                let argument = self.decode_al_immediate();
-                (Instruction::And, Some(argument))
-            }
-            0x25 => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::And, Some(argument))
-            }
-            0x28 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Sub, Some(argument))
-            }
-            0x29 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Sub, Some(argument))
-            }
-            0x2A => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Sub, Some(argument))
-            }
-            0x2B => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Sub, Some(argument))
-            }
-            0x2C => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Sub, Some(argument))
-            }
-            0x2D => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Sub, Some(argument))
-            }
-            0x30 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Xor, Some(argument))
-            }
-            0x31 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Xor, Some(argument))
-            }
-            0x32 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Xor, Some(argument))
-            }
-            0x33 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Xor, Some(argument))
-            }
-            0x34 => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Xor, Some(argument))
-            }
-            0x35 => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Xor, Some(argument))
-            }
-            0x38 => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags);
-                (Instruction::Cmp, Some(argument))
-            }
-            0x39 => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags);
-                (Instruction::Cmp, Some(argument))
-            }
-            0x3A => {
-                let argument = self.decode_8bit_reg_8bit_immediate(decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Cmp, Some(argument))
-            }
-            0x3B => {
-                let argument = self.decode_reg_reg(register_size, decoder_flags | REVERSED_REGISTER_DIRECTION);
-                (Instruction::Cmp, Some(argument))
-            }
-            0x3C => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Cmp, Some(argument))
-            }
-            0x3D => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Cmp, Some(argument))
-            }
-            opcode @ 0x50...0x57 => {
-                self.inc_rip(1);
-                (Instruction::Push, Some(
-                            InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Register {
-                                register: get_register(opcode - 0x50, RegisterSize::Bit64,
-                                        decoder_flags.contains(NEW_64BIT_REGISTER),
-                                        decoder_flags.contains(NEW_8BIT_REGISTER)),
-                            }).finalize()))
-            }
-            opcode @ 0x58...0x5F => {
-                let argument =
-                    InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Register {
-                            register:
-                                get_register(opcode - 0x58,
-                                                RegisterSize::Bit64,
-                                                decoder_flags.contains(NEW_64BIT_REGISTER),
-                                                decoder_flags.contains(NEW_8BIT_REGISTER)),
-                        })
-                        .finalize();
-                self.inc_rip(1);
-                (Instruction::Pop, Some(argument))
-            }
-            0x63 => {
-                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.override_argument_size(&mut argument, ArgumentSize::Bit32, rip, &decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Movsx, Some(argument))
-            }
-            0x68 => {
-                let immediate = if decoder_flags.contains(OPERAND_16_BIT) {
-                    let immediate = self.get_i16_value(1) as i64;
-                    self.inc_rip(3);
-                    immediate
-                } else {
-                    let immediate = self.get_i32_value(1) as i64;
-                    self.inc_rip(5);
-                    immediate
-                };
-                let argument = InstructionArgumentsBuilder::new().first_argument(
-                    InstructionArgument::Immediate { immediate: immediate }
-                ).finalize();
-                (Instruction::Push, Some(argument))
-            }
-            0x69 => {
-                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                  RegOrOpcode::Register,
-                                                                  ImmediateSize::None,
-                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.inc_rip(ip_offset);
-                let immediate = if decoder_flags.contains(OPERAND_16_BIT) {
-                    let immediate = self.get_i16_value(0) as i64;
-                    self.inc_rip(2);
-                    immediate
-                } else {
-                    let immediate = self.get_i32_value(0) as i64;
-                    self.inc_rip(4);
-                    immediate
-                };
-                argument.third_argument = argument.second_argument;
-                argument.second_argument = argument.first_argument;
-                argument.first_argument = Some(InstructionArgument::Immediate { immediate: immediate });
-                (Instruction::Imul, Some(argument))
-            }
-            0x6A => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Push, Some(arg))
-            }
-            0x6B => {
-                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                  RegOrOpcode::Register,
-                                                                  ImmediateSize::None,
-                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.inc_rip(ip_offset);
-                let rip = self.machine_state.rip as u64;
-                let immediate = self.machine_state.mem_read_byte(rip) as i8 as i64;
-                argument.third_argument = argument.second_argument;
-                argument.second_argument = argument.first_argument;
-                argument.first_argument = Some(InstructionArgument::Immediate { immediate: immediate });
-                self.inc_rip(1);
-                (Instruction::Imul, Some(argument))
-            }
-            0x70 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jo, Some(arg))
-            }
-            0x71 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jno, Some(arg))
-            }
-            0x72 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jb, Some(arg))
-            }
-            0x73 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jae, Some(arg))
-            }
-            0x74 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Je, Some(arg))
-            }
-            0x75 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jne, Some(arg))
-            }
-            0x76 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jbe, Some(arg))
-            }
-            0x77 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Ja, Some(arg))
-            }
-            0x78 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Js, Some(arg))
-            }
-            0x79 => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jns, Some(arg))
-            }
-            0x7A => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jp, Some(arg))
-            }
-            0x7B => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jnp, Some(arg))
-            }
-            0x7C => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jl, Some(arg))
-            }
-            0x7D => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jge, Some(arg))
-            }
-            0x7E => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jle, Some(arg))
-            }
-            0x7F => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
-                self.inc_rip(ip_offset);
-                (Instruction::Jg, Some(arg))
-            }
-            0x80 => {
-                // arithmetic operation (8bit register target, 8bit immediate)
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit8,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Arithmetic, Some(argument))
-            }
-            0x81 => {
-                // arithmetic operation (32/64bit register target, 32bit immediate)
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit32,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Arithmetic, Some(argument))
-            }
-            0x83 => {
-                // arithmetic operation (32/64bit register target, 8bit immediate)
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit8,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Arithmetic, Some(argument))
-            }
-            0x84 => {
-                // test
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Test, Some(argument))
-            }
-            0x85 => {
-                // test
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Test, Some(argument))
-            }
-            0x86 => {
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Xchg, Some(argument))
-            }
-            0x87 => {
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Xchg, Some(argument))
-            }
-            0x88 => {
-                // mov
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0x89 => {
-                // mov
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0x8A => {
-                // mov
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0x8B => {
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Register,
-                                                                ImmediateSize::None,
-                                                                decoder_flags |
-                                                                REVERSED_REGISTER_DIRECTION);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0x8D => {
-                let (argument, ip_offset) =
-                    self.get_argument(register_size,
-                                        RegOrOpcode::Register,
-                                        ImmediateSize::None,
-                                        // TODO: REVERSED_REGISTER_DIRECTION correct?
-                                        decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.machine_state.rip += ip_offset;
-                self.inc_rip(0);
-                (Instruction::Lea, Some(argument))
-            }
-            0x8E => {
-                // mov 16bit segment registers
-                let (argument, ip_offset) =
-                    self.get_argument(RegisterSize::Segment,
-                                        RegOrOpcode::Register,
-                                        ImmediateSize::None,
-                                        // TODO: REVERSED_REGISTER_DIRECTION correct?
-                                        decoder_flags | REVERSED_REGISTER_DIRECTION);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0x8F => {
-                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                  RegOrOpcode::Register,
-                                                                  ImmediateSize::None,
-                                                                  decoder_flags |
-                                                                  REVERSED_REGISTER_DIRECTION);
-                argument.second_argument = None;
-                self.inc_rip(ip_offset);
-                (Instruction::Pop, Some(argument))
-            }
-            0x90 => {
-                self.inc_rip(1);
-                (Instruction::Nop, None)
+        let rip = self.rip as u64;
+        let (map, opcode) = match vex {
+            Some((_, map)) => {
+                let opcode = self.read_byte(rip);
+                match map {
+                    1 => (&TWO_BYTE_MAP, opcode),
+                    2 => (&THREE_BYTE_38_MAP, opcode),
+                    3 => (&THREE_BYTE_3A_MAP, opcode),
+                    _ => {
+                        self.inc_rip(1);
+                        return (Instruction::Unknown, None);
+                    }
+                }
             }
-            opcode @ 0x91...0x97 => {
-                let argument = InstructionArgumentsBuilder::new()
-                    .first_argument(InstructionArgument::Register {
-                        register: get_register(0, register_size,
-                                               decoder_flags.contains(NEW_64BIT_REGISTER),
-                                               decoder_flags.contains(NEW_8BIT_REGISTER)),
-                        })
-                        .second_argument(InstructionArgument::Register {
-                            register: get_register(opcode - 0x90,
-                                                   register_size,
-                                                   decoder_flags.contains(NEW_64BIT_REGISTER),
-                                                   decoder_flags.contains(NEW_8BIT_REGISTER)),
-                        })
-                        .finalize();
-                self.inc_rip(1);
-                (Instruction::Xchg, Some(argument))
+            None => {
+                match self.read_byte(rip) {
+                    0x0F => {
+                        self.rip += 1;
+                        match self.read_byte(rip + 1) {
+                            0x38 => {
+                                self.rip += 1;
+                                (&THREE_BYTE_38_MAP, self.read_byte(rip + 2))
+                            }
+                            0x3A => {
+                                self.rip += 1;
+                                (&THREE_BYTE_3A_MAP, self.read_byte(rip + 2))
+                            }
+                            opcode => (&TWO_BYTE_MAP, opcode),
+                        }
+                    }
+                    opcode => (&ONE_BYTE_MAP, opcode),
+                }
             }
-            0x98 => {
-                let (register1, register2) = if decoder_flags.contains(OPERAND_16_BIT) {
-                    (Register::AL, Register::AX)
-                } else if decoder_flags.contains(OPERAND_64_BIT) {
-                    (Register::EAX, Register::RAX)
-                } else {
-                    (Register::AX, Register::EAX)
-                };
+        };
+        let vex = vex.map(|(vex, _)| vex);
 
-                let argument = InstructionArgumentsBuilder::new().first_argument(
-                    InstructionArgument::Register{register: register1}
-                ).second_argument(InstructionArgument::Register{register: register2})
-                .finalize();
-                self.inc_rip(1);
-                (Instruction::Mov, Some(argument))
-            }
-            0x99 => {
-                let (register1, register2) = if decoder_flags.contains(OPERAND_16_BIT) {
-                    (Register::AX, Register::DX)
-                } else if decoder_flags.contains(OPERAND_64_BIT) {
-                    (Register::RAX, Register::RDX)
-                } else {
-                    (Register::EAX, Register::EDX)
-                };
+        if self.code_size == CodeSize::Bit64 && ptr::eq(map, &ONE_BYTE_MAP) &&
+           INVALID_IN_64BIT_MODE.contains(&opcode) {
+            self.inc_rip(1);
+            return (Instruction::Unknown, None);
+        }
 
-                let argument = InstructionArgumentsBuilder::new().first_argument(
-                    InstructionArgument::Register{register: register1}
-                ).second_argument(InstructionArgument::Register{register: register2})
-                .finalize();
-                self.inc_rip(1);
-                (Instruction::Mov, Some(argument))
-            }
-            0x9C => {
+        // self.rip now points to the last opcode byte
+        let rip = self.rip as u64;
+        let modrm = self.read_byte(rip + 1);
+        let entry = match find_entry(map[opcode as usize], modrm, decoder_flags, vex.as_ref()) {
+            Some(entry) => entry,
+            None => {
                 self.inc_rip(1);
-                (Instruction::Pushf, None)
+                return (Instruction::Unknown, None);
             }
-            0x9D => {
-                self.inc_rip(1);
-                (Instruction::Popf, None)
+        };
+
+        // a mandatory prefix is part of the opcode, not a prefix for the instruction
+        match entry.prefix {
+            MandatoryPrefix::None => (),
+            MandatoryPrefix::OperandSize => decoder_flags.remove(OPERAND_16_BIT),
+            MandatoryPrefix::RepeatNotEqual => {
+                decoder_flags.remove(REPEAT_NOT_EQUAL);
+                self.prefixes.repeat_not_equal = false;
             }
-            0xA4 => {
-                self.inc_rip(1);
-                (Instruction::Movs, Some(InstructionArgumentsBuilder::new()
-                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
-                    .explicit_size(ArgumentSize::Bit8)
-                    .finalize()))
+            MandatoryPrefix::RepeatEqual => {
+                decoder_flags.remove(REPEAT_EQUAL);
+                self.prefixes.repeat_equal = false;
             }
-            0xA5 => {
-                let argument_size = if decoder_flags.contains(OPERAND_16_BIT) {
-                    ArgumentSize::Bit16
-                } else if decoder_flags.contains(OPERAND_64_BIT) {
-                    ArgumentSize::Bit64
+        }
+        // 16 bit code defaults to 16 bit operands, 66 selects 32 bit ones
+        if self.code_size == CodeSize::Bit16 {
+            decoder_flags.toggle(OPERAND_16_BIT);
+        }
+
+        let register_size = operand_size(decoder_flags);
+        let register_size = match entry.size {
+            OperandSize::Byte => RegisterSize::Bit8,
+            OperandSize::Word => RegisterSize::Bit16,
+            OperandSize::Dword => RegisterSize::Bit32,
+            OperandSize::Qword => RegisterSize::Bit64,
+            OperandSize::Segment => RegisterSize::Segment,
+            // stack operations and control register moves default to 64 bit in 64 bit mode
+            OperandSize::Full64 if self.code_size == CodeSize::Bit64 => {
+                if decoder_flags.contains(OPERAND_16_BIT) {
+                    RegisterSize::Bit16
                 } else {
-                    ArgumentSize::Bit32
-                };
-                self.inc_rip(1);
-                (Instruction::Movs, Some(InstructionArgumentsBuilder::new()
-                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
-                    .explicit_size(argument_size)
-                    .finalize()))
-            }
-            0xA8 => {
-                let argument = self.decode_al_immediate();
-                (Instruction::Test, Some(argument))
-            }
-            0xA9 => {
-                let argument = self.decode_ax_immediate(register_size, decoder_flags);
-                (Instruction::Test, Some(argument))
-            }
-            0xAA => {
-                self.inc_rip(1);
-                (Instruction::Stos, Some(InstructionArgumentsBuilder::new()
-                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
-                    .explicit_size(ArgumentSize::Bit8)
-                    .finalize()))
+                    RegisterSize::Bit64
+                }
             }
-            0xAB => {
-                self.inc_rip(1);
-                let argument_size = match register_size {
-                    RegisterSize::Bit8 => ArgumentSize::Bit8,
-                    RegisterSize::Bit16 => ArgumentSize::Bit16,
-                    RegisterSize::Bit32 => ArgumentSize::Bit32,
-                    RegisterSize::Bit64 => ArgumentSize::Bit64,
-                    RegisterSize::Segment => panic!("Unsupported register size"),
-                };
-                (Instruction::Stos, Some(InstructionArgumentsBuilder::new()
-                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
-                    .explicit_size(argument_size)
-                    .finalize()))
+            OperandSize::Full64 | OperandSize::Full | OperandSize::None | OperandSize::DoubleQuadword | OperandSize::QuadQuadword |
+            OperandSize::Vector | OperandSize::DwordOrQword => register_size,
+        };
+
+        let arguments = match entry.operands {
+            Operands::Explicit(specs) => {
+                Some(self.decode_explicit_operands(specs, register_size, vex.as_ref(), decoder_flags))
             }
-            0xAE => {
+            _ => self.decode_operands(entry, opcode, register_size, decoder_flags),
+        };
+        (entry.instruction, arguments)
+    }
+
+    fn decode_operands(&mut self,
+                       entry: &OpcodeEntry,
+                       opcode: u8,
+                       register_size: RegisterSize,
+                       decoder_flags: DecoderFlags)
+                       -> Option<InstructionArguments> {
+        let rip = self.rip as u64;
+        let immediate_size = match entry.immediate {
+            // 16 bit immediates are only used without a ModRM byte (ret, lret)
+            Immediate::None | Immediate::UnsignedBit16 => ImmediateSize::None,
+            Immediate::Bit8 | Immediate::UnsignedBit8 => ImmediateSize::Bit8,
+            Immediate::Bit16Or32 | Immediate::Bit32 | Immediate::Full | Immediate::Relative => ImmediateSize::Bit32,
+        };
+        // lret, iret and sysret pop or load operands of the operand size
+        let explicit_size = match entry.size {
+            OperandSize::None | OperandSize::Segment => None,
+            _ => Some(argument_size(register_size)),
+        };
+
+        match entry.operands {
+            Operands::None => {
                 self.inc_rip(1);
-                (Instruction::Scas, Some(InstructionArgumentsBuilder::new()
-                    .first_argument(InstructionArgument::EffectiveAddress{
-                        base: Some(Register::RDI),
-                        index: None,
-                        scale: None,
-                        displacement: 0,
-                     })
-                    .second_argument(InstructionArgument::Register{ register: Register::AL })
-                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
-                    .finalize()))
+                explicit_size.map(|size| InstructionArgumentsBuilder::new().explicit_size(size).finalize())
             }
-            opcode @ 0xB0...0xB7 => {
-                let immediate = self.machine_state.mem_read_byte(rip + 1) as i64;
-                let argument =
-                    InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                            immediate: immediate as i64,
-                        })
-                        .second_argument(InstructionArgument::Register {
-                            register:
-                                get_register(opcode - 0xB0,
-                                                RegisterSize::Bit8,
-                                                decoder_flags.contains(NEW_64BIT_REGISTER),
-                                                decoder_flags.contains(NEW_8BIT_REGISTER)),
-                        })
-                        .finalize();
-                self.inc_rip(2);
-                (Instruction::Mov, Some(argument))
-            }
-            opcode @ 0xB8...0xBF => {
-                let (immediate, ip_offset) = if decoder_flags.contains(OPERAND_64_BIT) {
-                    (self.get_i64_value(1) as i64, 9)
-                } else {
-                    if decoder_flags.contains(OPERAND_16_BIT) {
-                        (self.get_i16_value(1) as i64, 3)
-                    } else {
-                        (self.get_i32_value(1) as i64, 5)
-                    }
-                };
-                let argument =
-                    InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                            immediate: immediate,
-                        })
-                        .second_argument(InstructionArgument::Register {
-                            register:
-                                get_register(opcode - 0xB8,
-                                                register_size,
-                                                decoder_flags.contains(NEW_64BIT_REGISTER),
-                                                decoder_flags.contains(NEW_8BIT_REGISTER)),
-                        })
-                        .finalize();
+            Operands::ModRm => {
+                let (_, ip_offset) = self.get_argument(register_size,
+                                                       RegOrOpcode::Register,
+                                                       ImmediateSize::None,
+                                                       decoder_flags | REVERSED_REGISTER_DIRECTION);
                 self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
+                None
             }
-            0xC6 => {
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit8,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0xC7 => {
+            Operands::Rm => {
                 let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit32,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::Mov, Some(argument))
-            }
-            0xC0 => {
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
                                                               RegOrOpcode::Opcode,
-                                                              ImmediateSize::Bit8,
+                                                              immediate_size,
                                                               decoder_flags);
                 self.inc_rip(ip_offset);
-                (Instruction::ShiftRotate, Some(argument))
-            }
-            0xC1 => {
-                let (argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::Bit8,
-                                                                decoder_flags);
-                self.inc_rip(ip_offset);
-                (Instruction::ShiftRotate, Some(argument))
-            }
-            0xC3 => {
-                self.inc_rip(0);
-                (Instruction::Ret, None)
-            }
-            0xC9 => {
-                self.inc_rip(1);
-                (Instruction::Leave, None)
+                Some(argument)
             }
-            0xCB => {
-                self.inc_rip(0);
-                (Instruction::Lret, None)
-            }
-            0xD1 => {
+            Operands::RmNoSize => {
                 let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                argument.second_argument = Some(argument.first_argument.unwrap());
-                argument.first_argument = Some(InstructionArgument::Immediate{
-                    immediate: 1,
-                });
-                self.inc_rip(ip_offset);
-                (Instruction::ShiftRotate, Some(argument))
-            }
-            0xD2 => {
-                let (mut argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                  RegOrOpcode::Opcode,
+                                                                  RegOrOpcode::Register,
                                                                   ImmediateSize::None,
-                                                                  decoder_flags);
-                argument.second_argument = Some(argument.first_argument.unwrap());
-                argument.first_argument = Some(InstructionArgument::Register{
-                    register: Register::CL
-                });
-                self.inc_rip(ip_offset);
-                (Instruction::ShiftRotate, Some(argument))
-            }
-            0xD3 => {
-                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                RegOrOpcode::Opcode,
-                                                                ImmediateSize::None,
-                                                                decoder_flags);
-                let size = argument.size();
-                argument.second_argument = Some(argument.first_argument.unwrap());
-                argument.first_argument = Some(InstructionArgument::Register{
-                    register: Register::CL
-                });
-                argument.explicit_size = Some(size);
-                self.inc_rip(ip_offset);
-                (Instruction::ShiftRotate, Some(argument))
-            }
-            0xEB => {
-                let (arg, ip_offset) = self.read_immediate_8bit();
+                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
+                argument.second_argument = None;
+                argument.opcode = entry.extension;
                 self.inc_rip(ip_offset);
-                (Instruction::Jmp, Some(arg))
-            }
-            0xE8 => {
-                let immediate = self.get_i32_value(1);
-                self.inc_rip(5);
-                (Instruction::Call, Some(
-                            InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                                immediate: immediate as i64,
-                            }).finalize()))
-            }
-            0xE9 => {
-                let immediate = self.get_i32_value(1);
-                self.inc_rip(5);
-                (Instruction::Jmp, Some(
-                            InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                                immediate: immediate as i64,
-                            }).finalize()))
+                Some(argument)
             }
-            0xEE => {
-                self.inc_rip(1);
-                (Instruction::Out, None)
-            }
-            0xF6 => {
-                let rip = self.machine_state.rip as u64;
-                let modrm = self.machine_state.mem_read_byte(rip + 1);
-                let opcode = (modrm & 0b00111000) >> 3;
-
-                let (argument, ip_offset) = match opcode {
-                    0 | 1 => {
-                        self.get_argument(RegisterSize::Bit8,
-                                          RegOrOpcode::Opcode,
-                                          ImmediateSize::Bit8,
-                                          decoder_flags)
-                    },
-                    2 | 3 => {
-                        self.get_argument(RegisterSize::Bit8,
-                                          RegOrOpcode::Opcode,
-                                          ImmediateSize::None,
-                                          decoder_flags)
-                    }
-                    _ => panic!("no supported"),
+            Operands::RmReg | Operands::RegRm => {
+                let decoder_flags = match entry.operands {
+                    Operands::RegRm => decoder_flags | REVERSED_REGISTER_DIRECTION,
+                    _ => decoder_flags,
                 };
+                let (argument, ip_offset) = self.get_argument(register_size,
+                                                              RegOrOpcode::Register,
+                                                              ImmediateSize::None,
+                                                              decoder_flags);
                 self.inc_rip(ip_offset);
-                (Instruction::CompareMulOperation, Some(argument))
+                Some(argument)
             }
-            0xF7 => {
-                let rip = self.machine_state.rip as u64;
-                let modrm = self.machine_state.mem_read_byte(rip + 1);
-                let opcode = (modrm & 0b00111000) >> 3;
-
-                let (argument, ip_offset) = match opcode {
-                    0 | 1 => {
-                        // TODO: could also be 16 bit immediate
-                        self.get_argument(register_size,
-                                            RegOrOpcode::Opcode,
-                                            ImmediateSize::Bit32,
-                                            decoder_flags)
-                    },
-                    2 | 3 => {
-                        self.get_argument(register_size,
-                                            RegOrOpcode::Opcode,
-                                            ImmediateSize::None,
-                                            decoder_flags)
-                    },
-                    4 | 5 | 6 | 7 => {
-                        /*let register = get_register(
-                            0, register_size,decoder_flags.contains(NEW_64BIT_REGISTER), false);
-
-                        (InstructionArgumentsBuilder::new().first_argument(
-                            InstructionArgument::Register{register: register})
-                            .opcode(opcode)
-                            .finalize(),
-                        2)*/
-                        let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                          RegOrOpcode::Opcode,
-                                                                          ImmediateSize::None,
-                                                                          decoder_flags);
-                        argument.second_argument = None;
-                        argument.opcode = Some(opcode);
-                        (argument, ip_offset)
-                    },
-                    _ => unreachable!()
+            Operands::RegRm8 | Operands::RegRm16 | Operands::RegRm32 => {
+                let source_size = match entry.operands {
+                    Operands::RegRm8 => ArgumentSize::Bit8,
+                    Operands::RegRm16 => ArgumentSize::Bit16,
+                    _ => ArgumentSize::Bit32,
                 };
-                self.inc_rip(ip_offset);
-                (Instruction::CompareMulOperation, Some(argument))
-            }
-            0xFA => {
-                // todo: implement cli instruction
-                self.inc_rip(1);
-                (Instruction::Nop, None)
-            }
-            0xFB => {
-                // todo: implement sti instruction
-                self.inc_rip(1);
-                (Instruction::Nop, None)
-            }
-            0xFC => {
-                self.inc_rip(1);
-                (Instruction::Cld, None)
-            }
-            0xFD => {
-                self.inc_rip(1);
-                (Instruction::Std, None)
-            }
-            0xFE => {
-                let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                  RegOrOpcode::Opcode,
+                let (mut argument, ip_offset) = self.get_argument(register_size,
+                                                                  RegOrOpcode::Register,
                                                                   ImmediateSize::None,
-                                                                  decoder_flags);
+                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
+                self.override_argument_size(&mut argument, source_size, rip, &decoder_flags);
                 self.inc_rip(ip_offset);
-                if argument.opcode.unwrap() > 1 {
-                    panic!("Invalid opcode");
-                }
-                (Instruction::RegisterOperation, Some(argument))
+                Some(argument)
             }
-            0xFF => {
-                // todo: cleanup code
-                let modrm = self.machine_state.mem_read_byte(rip + 1);
-                let opcode = (modrm & 0b00111000) >> 3;
+            Operands::RegRmImm => {
                 let (mut argument, ip_offset) = self.get_argument(register_size,
                                                                   RegOrOpcode::Register,
                                                                   ImmediateSize::None,
-                                                                  decoder_flags |
-                                                                  REVERSED_REGISTER_DIRECTION);
-                argument.second_argument = None;
-                argument.opcode = Some(opcode);
+                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
                 self.inc_rip(ip_offset);
-                (Instruction::RegisterOperation, Some(argument))
-            }
-            0x0F => {
-                // two byte instructions
-                self.machine_state.rip += 1;
-                let rip = self.machine_state.rip as u64;
-                let second_byte = self.machine_state.mem_read_byte(rip);
-                match second_byte {
-                    0x01 => {
-                        let modrm = self.machine_state.mem_read_byte(rip + 1);
-                        let opcode = (modrm & 0b00111000) >> 3;
-                        match opcode {
-                            2  | 3 => {
-                                let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                                  RegOrOpcode::Opcode,
-                                                                                  ImmediateSize::Bit32,
-                                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
-                                argument.first_argument = Some(argument.second_argument.unwrap());
-                                argument.second_argument = None;
-                                self.inc_rip(ip_offset - 4);
-                                if opcode == 2 {
-                                    (Instruction::Lgdt, Some(argument))
-                                } else {
-                                    (Instruction::Lidt, Some(argument))
-                                }
-                            },
-                            _ => panic!("0F 01 unsupported opcode: {:x}", opcode)
-                        }
-                    }
-                    0x05 => {
-                        self.inc_rip(1);
-                        (Instruction::Syscall, None)
-                    }
-                    0x1F => {
-                        // NOP with hint
-                        let (_, ip_offset) = self.get_argument(register_size,
-                                                                        RegOrOpcode::Register,
-                                                                        ImmediateSize::None,
-                                                                        decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Nop, None)
-                    }
-                    0x20 => {
-                        let (mut argument, ip_offset) = self.get_argument(RegisterSize::Bit64,
-                                                                      RegOrOpcode::Register,
-                                                                      ImmediateSize::None,
-                                                                      decoder_flags);
-                        let register = match argument.first_argument.unwrap() {
-                            InstructionArgument::Register { register } => {
-                                match register {
-                                    Register::R8 => Register::CR8,
-                                    Register::RAX => Register::CR0,
-                                    Register::RDX => Register::CR2,
-                                    Register::RBX => Register::CR3,
-                                    Register::RSP => Register::CR4,
-                                    _ => panic!("Invalid argument for mov r64, CRn instruciton"),
-                                }
-                            },
-                            _ => panic!("Invalid argument for mov r64, CRn instruciton"),
-                        };
-                        argument.first_argument = Some(InstructionArgument::Register {register: register});
-                        self.inc_rip(ip_offset);
-                        (Instruction::Mov, Some(argument))
-                    },
-                    0x22 => {
-                        let (mut argument, ip_offset) = self.get_argument(RegisterSize::Bit64,
-                                                                      RegOrOpcode::Register,
-                                                                      ImmediateSize::None,
-                                                                      decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        let register = match argument.second_argument.unwrap() {
-                            InstructionArgument::Register { register } => {
-                                match register {
-                                    Register::R8 => Register::CR8,
-                                    Register::RAX => Register::CR0,
-                                    Register::RDX => Register::CR2,
-                                    Register::RBX => Register::CR3,
-                                    Register::RSP => Register::CR4,
-                                    _ => panic!("Invalid argument for mov r64, CRn instruciton"),
-                                }
-                            },
-                            _ => panic!("Invalid argument for mov r64, CRn instruciton"),
-                        };
-                        argument.second_argument = Some(InstructionArgument::Register {register: register});
-                        self.inc_rip(ip_offset);
-                        (Instruction::Mov, Some(argument))
-                    },
-                    0x30 => {
-                        self.inc_rip(1);
-                        (Instruction::Wrmsr, None)
-                    }
-                    0x32 => {
-                        self.inc_rip(1);
-                        (Instruction::Rdmsr, None)
-                    }
-                    0x40 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovo, Some(argument))
-                    },
-                    0x41 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovno, Some(argument))
-                    },
-                    0x42 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovb, Some(argument))
-                    },
-                    0x43 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovae, Some(argument))
-                    },
-                    0x44 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmove, Some(argument))
-                    },
-                    0x45 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovne, Some(argument))
-                    },
-                    0x46 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovbe, Some(argument))
-                    },
-                    0x47 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmova, Some(argument))
-                    },
-                    0x48 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovs, Some(argument))
-                    },
-                    0x49 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovns, Some(argument))
-                    },
-                    0x4a => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovp, Some(argument))
-                    },
-                    0x4b => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovnp, Some(argument))
-                    },
-                    0x4c => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovl, Some(argument))
-                    },
-                    0x4d => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovge, Some(argument))
-                    },
-                    0x4e => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovle, Some(argument))
-                    },
-                    0x4f => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmovg, Some(argument))
-                    },
-                    0x80 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jo, Some(argument))
-                    },
-                    0x81 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jno, Some(argument))
-                    },
-                    0x82 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jb, Some(argument))
-                    },
-                    0x83 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jae, Some(argument))
-                    },
-                    0x84 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Je, Some(argument))
-                    },
-                    0x85 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jne, Some(argument))
-                    },
-                    0x86 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jbe, Some(argument))
-                    },
-                    0x87 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Ja, Some(argument))
-                    },
-                    0x88 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Js, Some(argument))
-                    },
-                    0x89 => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jns, Some(argument))
-                    },
-                    0x8A => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jp, Some(argument))
-                    },
-                    0x8B => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jnp, Some(argument))
-                    },
-                    0x8C => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jl, Some(argument))
-                    },
-                    0x8D => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jge, Some(argument))
-                    },
-                    0x8E => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jle, Some(argument))
-                    },
-                    0x8F => {
-                        // TODO: could also be 16bit value
-                        let argument = self.read_immediate_32bit();
-                        self.inc_rip(5);
-                        (Instruction::Jg, Some(argument))
-                    },
-                    opcode @ 0x90...0x9F => {
-                        let (mut argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                        RegOrOpcode::Register,
-                                                                        ImmediateSize::None,
-                                                                        decoder_flags);
-                        // TODO: change this hack to Something sane
-                        argument.first_argument = Some(argument.second_argument.unwrap());
-                        argument.second_argument = None;
-                        self.inc_rip(ip_offset);
-                        match opcode {
-                            0x90 => (Instruction::Seto, Some(argument)),
-                            0x91 => (Instruction::Setno, Some(argument)),
-                            0x92 => (Instruction::Setb, Some(argument)),
-                            0x93 => (Instruction::Setae, Some(argument)),
-                            0x94 => (Instruction::Sete, Some(argument)),
-                            0x95 => (Instruction::Setne, Some(argument)),
-                            0x96 => (Instruction::Setbe, Some(argument)),
-                            0x97 => (Instruction::Seta, Some(argument)),
-                            0x98 => (Instruction::Sets, Some(argument)),
-                            0x99 => (Instruction::Setns, Some(argument)),
-                            0x9A => (Instruction::Setp, Some(argument)),
-                            0x9B => (Instruction::Setnp, Some(argument)),
-                            0x9C => (Instruction::Setl, Some(argument)),
-                            0x9D => (Instruction::Setge, Some(argument)),
-                            0x9E => (Instruction::Setle, Some(argument)),
-                            0x9F => (Instruction::Setg, Some(argument)),
-                            _ => unreachable!(),
-                        }
-                    },
-                    0xA2 => {
+                let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 0, decoder_flags);
+                self.inc_rip(ip_offset);
+                argument.third_argument = argument.second_argument;
+                argument.second_argument = argument.first_argument;
+                argument.first_argument = Some(InstructionArgument::Immediate { immediate: immediate });
+                Some(argument)
+            }
+            Operands::RmOne => {
+                let (mut argument, ip_offset) = self.get_argument(register_size,
+                                                                  RegOrOpcode::Opcode,
+                                                                  ImmediateSize::None,
+                                                                  decoder_flags);
+                argument.second_argument = Some(argument.first_argument.unwrap());
+                argument.first_argument = Some(InstructionArgument::Immediate {
+                    immediate: 1,
+                });
+                self.inc_rip(ip_offset);
+                Some(argument)
+            }
+            Operands::RmCl => {
+                let (mut argument, ip_offset) = self.get_argument(register_size,
+                                                                  RegOrOpcode::Opcode,
+                                                                  ImmediateSize::None,
+                                                                  decoder_flags);
+                // size() would pick up cl once it is the first argument
+                if entry.size == OperandSize::Full {
+                    argument.explicit_size = Some(argument.size());
+                }
+                argument.second_argument = Some(argument.first_argument.unwrap());
+                argument.first_argument = Some(InstructionArgument::Register {
+                    register: Register::CL
+                });
+                self.inc_rip(ip_offset);
+                Some(argument)
+            }
+            Operands::RmCr => {
+                let (mut argument, ip_offset) = self.get_argument(self.control_register_size(),
+                                                                  RegOrOpcode::Register,
+                                                                  ImmediateSize::None,
+                                                                  decoder_flags);
+                let register = control_register(argument.first_argument.unwrap());
+                argument.first_argument = Some(InstructionArgument::Register { register: register });
+                self.inc_rip(ip_offset);
+                Some(argument)
+            }
+            Operands::CrRm => {
+                let (mut argument, ip_offset) = self.get_argument(self.control_register_size(),
+                                                                  RegOrOpcode::Register,
+                                                                  ImmediateSize::None,
+                                                                  decoder_flags | REVERSED_REGISTER_DIRECTION);
+                let register = control_register(argument.second_argument.unwrap());
+                argument.second_argument = Some(InstructionArgument::Register { register: register });
+                self.inc_rip(ip_offset);
+                Some(argument)
+            }
+            Operands::Memory => {
+                let (mut argument, ip_offset) = self.get_argument(register_size,
+                                                                  RegOrOpcode::Opcode,
+                                                                  ImmediateSize::None,
+                                                                  decoder_flags);
+                argument.explicit_size = Some(argument_size(register_size));
+                self.inc_rip(ip_offset);
+                Some(argument)
+            }
+            Operands::SegmentRegister => {
+                let register = get_register((opcode >> 3) & 0b111, RegisterSize::Segment, false, false);
+                self.inc_rip(1);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Register { register: register })
+                    .explicit_size(argument_size(register_size))
+                    .finalize())
+            }
+            Operands::FarPointer => {
+                // offset first, then the selector
+                let (offset, ip_offset) = self.read_immediate(&Immediate::Bit16Or32, 1, decoder_flags);
+                let selector = self.get_i16_value(ip_offset + 1) as u16 as i64;
+                self.inc_rip(ip_offset + 3);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: offset })
+                    .second_argument(InstructionArgument::Immediate { immediate: selector })
+                    .explicit_size(argument_size(register_size))
+                    .finalize())
+            }
+            Operands::Port => {
+                // in and out transfer at most 32 bits, rex.w is ignored
+                let (accumulator, argument_size) = match register_size {
+                    RegisterSize::Bit8 => (Register::AL, ArgumentSize::Bit8),
+                    RegisterSize::Bit16 => (Register::AX, ArgumentSize::Bit16),
+                    _ => (Register::EAX, ArgumentSize::Bit32),
+                };
+                let accumulator = InstructionArgument::Register { register: accumulator };
+                let port = match entry.immediate {
+                    Immediate::None => {
                         self.inc_rip(1);
-                        (Instruction::Cpuid, None)
-                    }
-                    0xA3 => {
-                        let argument = self.decode_reg_reg(register_size, decoder_flags);
-                        (Instruction::Bt, Some(argument))
-                    }
-                    0xAB => {
-                        let argument = self.decode_reg_reg(register_size, decoder_flags);
-                        (Instruction::Bts, Some(argument))
-                    }
-                    0xAF => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                    RegOrOpcode::Register,
-                                                                    ImmediateSize::None,
-                                                                    decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Imul, Some(argument))
-                    }
-                    0xB0 => {
-                        let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                                      RegOrOpcode::Register,
-                                                                      ImmediateSize::None,
-                                                                      decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmpxchg, Some(argument))
-                    }
-                    0xB1 => {
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                      RegOrOpcode::Register,
-                                                                      ImmediateSize::None,
-                                                                      decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Cmpxchg, Some(argument))
-                    }
-                    0xB3 => {
-                        let argument = self.decode_reg_reg(register_size, decoder_flags);
-                        (Instruction::Btr, Some(argument))
-                    }
-                    0xB6 => {
-                        let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                            RegOrOpcode::Register,
-                                                                            ImmediateSize::None,
-                                                                            decoder_flags | REVERSED_REGISTER_DIRECTION);
-
-                        self.override_argument_size(&mut argument, ArgumentSize::Bit8, rip, &decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Movzx, Some(argument))
-                    }
-                    0xB7 => {
-                        let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                            RegOrOpcode::Register,
-                                                                            ImmediateSize::None,
-                                                                            decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.override_argument_size(&mut argument, ArgumentSize::Bit16, rip, &decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Movzx, Some(argument))
+                        InstructionArgument::Register { register: Register::DX }
                     }
-                    0xBA => {
-                        // bit manipulation
-                        let (argument, ip_offset) = self.get_argument(register_size,
-                                                                      RegOrOpcode::Opcode,
-                                                                      ImmediateSize::Bit8,
-                                                                      decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::BitManipulation, Some(argument))
+                    _ => {
+                        let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 1, decoder_flags);
+                        self.inc_rip(ip_offset + 1);
+                        InstructionArgument::Immediate { immediate: immediate }
                     }
-                    0xBB => {
-                        let argument = self.decode_reg_reg(register_size, decoder_flags);
-                        (Instruction::Btc, Some(argument))
-                    }
-                    0xBE => {
-                        let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                            RegOrOpcode::Register,
-                                                                            ImmediateSize::None,
-                                                                            decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.override_argument_size(&mut argument, ArgumentSize::Bit8, rip, &decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Movsx, Some(argument))
-                    }
-                    0xBF => {
-                        let (mut argument, ip_offset) = self.get_argument(register_size,
-                                                                            RegOrOpcode::Register,
-                                                                            ImmediateSize::None,
-                                                                            decoder_flags | REVERSED_REGISTER_DIRECTION);
-                        self.override_argument_size(&mut argument, ArgumentSize::Bit16, rip, &decoder_flags);
-                        self.inc_rip(ip_offset);
-                        (Instruction::Movsx, Some(argument))
-                    }
-                    _ => panic!("Unknown instruction: 0F {:X}, executed instructions: {}", second_byte, self.counter),
-                }
+                };
+                let (first_argument, second_argument) = match entry.instruction {
+                    Instruction::In => (port, accumulator),
+                    _ => (accumulator, port),
+                };
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(first_argument)
+                    .second_argument(second_argument)
+                    .explicit_size(argument_size)
+                    .finalize())
+            }
+            Operands::AlImm | Operands::RaxImm => {
+                let register = match entry.operands {
+                    Operands::AlImm => Register::AL,
+                    _ => get_register(0, register_size, decoder_flags.contains(NEW_64BIT_REGISTER), false),
+                };
+                let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 1, decoder_flags);
+                self.inc_rip(ip_offset + 1);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: immediate })
+                    .second_argument(InstructionArgument::Register { register: register })
+                    .finalize())
+            }
+            Operands::RaxReg => {
+                let argument = InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Register {
+                        register: get_register(0, register_size,
+                                               decoder_flags.contains(NEW_64BIT_REGISTER),
+                                               decoder_flags.contains(NEW_8BIT_REGISTER)),
+                    })
+                    .second_argument(InstructionArgument::Register {
+                        register: get_register(opcode & 0b111,
+                                               register_size,
+                                               decoder_flags.contains(NEW_64BIT_REGISTER),
+                                               decoder_flags.contains(NEW_8BIT_REGISTER)),
+                    })
+                    .finalize();
+                self.inc_rip(1);
+                Some(argument)
             }
-            0xCC => {
-                // abuse int 3 instruction to signal failed test program
-                panic!("int3 instruction");
+            Operands::Reg => {
+                let argument = InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Register {
+                        register: get_register(opcode & 0b111,
+                                               register_size,
+                                               decoder_flags.contains(NEW_64BIT_REGISTER),
+                                               decoder_flags.contains(NEW_8BIT_REGISTER)),
+                    })
+                    .finalize();
+                self.inc_rip(1);
+                Some(argument)
             }
-            0xCD => {
-                // abuse int X instruction to signal passed test program
-                (Instruction::Int, None)
+            Operands::RegImm => {
+                let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 1, decoder_flags);
+                let argument = InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: immediate })
+                    .second_argument(InstructionArgument::Register {
+                        register: get_register(opcode & 0b111,
+                                               register_size,
+                                               decoder_flags.contains(NEW_64BIT_REGISTER),
+                                               decoder_flags.contains(NEW_8BIT_REGISTER)),
+                    })
+                    .finalize();
+                self.inc_rip(ip_offset + 1);
+                Some(argument)
+            }
+            Operands::Imm => {
+                let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 1, decoder_flags);
+                self.inc_rip(ip_offset + 1);
+                let mut argument = InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: immediate })
+                    .finalize();
+                argument.explicit_size = explicit_size;
+                Some(argument)
+            }
+            Operands::RcxImm => {
+                let (immediate, ip_offset) = self.read_immediate(&entry.immediate, 1, decoder_flags);
+                self.inc_rip(ip_offset + 1);
+                // the address size selects the counter
+                let counter = get_register(1, address_size(decoder_flags, self.code_size), false, false);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: immediate })
+                    .second_argument(InstructionArgument::Register { register: counter })
+                    .finalize())
+            }
+            Operands::Enter => {
+                let size = self.get_i16_value(1) as u16 as i64;
+                let level = self.get_i8_value(3) as u8 as i64;
+                self.inc_rip(4);
+                let mut argument = InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Immediate { immediate: size })
+                    .second_argument(InstructionArgument::Immediate { immediate: level })
+                    .finalize();
+                argument.explicit_size = explicit_size;
+                Some(argument)
+            }
+            Operands::Cbw | Operands::Cwd => {
+                let (register1, register2) = match (&entry.operands, register_size) {
+                    (&Operands::Cbw, RegisterSize::Bit16) => (Register::AL, Register::AX),
+                    (&Operands::Cbw, RegisterSize::Bit64) => (Register::EAX, Register::RAX),
+                    (&Operands::Cbw, _) => (Register::AX, Register::EAX),
+                    (_, RegisterSize::Bit16) => (Register::AX, Register::DX),
+                    (_, RegisterSize::Bit64) => (Register::RAX, Register::RDX),
+                    (_, _) => (Register::EAX, Register::EDX),
+                };
+                self.inc_rip(1);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(InstructionArgument::Register { register: register1 })
+                    .second_argument(InstructionArgument::Register { register: register2 })
+                    .finalize())
+            }
+            Operands::String => {
+                let argument_size = match (&entry.instruction, register_size) {
+                    (_, RegisterSize::Bit8) => ArgumentSize::Bit8,
+                    (_, RegisterSize::Bit16) => ArgumentSize::Bit16,
+                    (&Instruction::Ins, RegisterSize::Bit64) |
+                    (&Instruction::Outs, RegisterSize::Bit64) |
+                    (_, RegisterSize::Bit32) => ArgumentSize::Bit32,
+                    (_, RegisterSize::Bit64) => ArgumentSize::Bit64,
+                    (_, RegisterSize::Segment) => panic!("Unsupported register size"),
+                };
+                // the address size selects rsi/rdi/rcx, esi/edi/ecx or si/di/cx
+                let address_size = address_size(decoder_flags, self.code_size);
+                let source = get_register(6, address_size, false, false);
+                let destination = get_register(7, address_size, false, false);
+                let memory = |register: Register| {
+                    InstructionArgument::EffectiveAddress {
+                        base: Some(register),
+                        index: None,
+                        scale: None,
+                        displacement: 0,
+                    }
+                };
+                let accumulator = InstructionArgument::Register {
+                    register: get_register(0, register_size, false, false),
+                };
+                let port = InstructionArgument::Register { register: Register::DX };
+                let (first_argument, second_argument) = match entry.instruction {
+                    Instruction::Movs => (memory(source), memory(destination)),
+                    Instruction::Cmps => (memory(destination), memory(source)),
+                    Instruction::Stos => (accumulator, memory(destination)),
+                    Instruction::Lods => (memory(source), accumulator),
+                    Instruction::Scas => (memory(destination), accumulator),
+                    Instruction::Ins => (port, memory(destination)),
+                    Instruction::Outs => (memory(source), port),
+                    _ => panic!("Operand encoding string used by a non-string instruction"),
+                };
+                self.inc_rip(1);
+                Some(InstructionArgumentsBuilder::new()
+                    .first_argument(first_argument)
+                    .second_argument(second_argument)
+                    .repeat(decoder_flags.contains(REPEAT_EQUAL), decoder_flags.contains(REPEAT_NOT_EQUAL))
+                    .explicit_size(argument_size)
+                    .finalize())
             }
-            _ => panic!("Unknown instruction: {:x}, executed instructions: {}", first_byte, self.counter),
+            Operands::Explicit(_) => unreachable!("operand lists are decoded by decode_explicit_operands"),
         }
     }
 
-    fn fetch_argument(cache_entry: &InstructionCache) -> &InstructionArguments {
-        match cache_entry.arguments {
-            Some(ref arg) => arg,
-            None => panic!("expected arg")
+    /// Decodes an operand list like Vx,Hx,Wx. The list has the destination first,
+    /// the arguments are stored source first like for all other instructions.
+    fn decode_explicit_operands(&mut self,
+                                specs: &[OperandSpec],
+                                register_size: RegisterSize,
+                                vex: Option<&Vex>,
+                                decoder_flags: DecoderFlags)
+                                -> InstructionArguments {
+        let rip = self.rip as u64;
+        let modrm = self.read_byte(rip + 1);
+        let has_modrm = specs.iter().any(|spec| match spec.kind {
+            OperandKind::H | OperandKind::B | OperandKind::I => false,
+            _ => true,
+        });
+        let (mut memory, mut ip_offset) = if has_modrm {
+            let (argument, ip_offset) = self.get_argument(RegisterSize::Bit64,
+                                                          RegOrOpcode::Register,
+                                                          ImmediateSize::None,
+                                                          decoder_flags | REVERSED_REGISTER_DIRECTION);
+            (argument.first_argument, ip_offset)
+        } else {
+            (None, 1)
+        };
+
+        let reg = (modrm & 0b00111000) >> 3 | if decoder_flags.contains(MOD_R_M_EXTENSION) { 8 } else { 0 };
+        let rm = modrm & 0b00000111 | if decoder_flags.contains(NEW_64BIT_REGISTER) { 8 } else { 0 };
+        let vvvv = vex.map_or(0, |vex| vex.register);
+        let general_register = |number: u8, size: Option<ArgumentSize>| {
+            let size = match size {
+                Some(ArgumentSize::Bit8) => RegisterSize::Bit8,
+                Some(ArgumentSize::Bit16) => RegisterSize::Bit16,
+                Some(ArgumentSize::Bit32) => RegisterSize::Bit32,
+                Some(ArgumentSize::Bit64) => RegisterSize::Bit64,
+                _ => panic!("Invalid size for a general purpose register operand"),
+            };
+            let register = get_register(number & 0b111, size, number > 7, decoder_flags.contains(NEW_8BIT_REGISTER));
+            InstructionArgument::Register { register: register }
+        };
+        let vector_register = |number: u8, size: Option<ArgumentSize>| {
+            InstructionArgument::Register { register: vector_register(number, size) }
+        };
+
+        let mut explicit_size = None;
+        let mut arguments = Vec::new();
+        for spec in specs {
+            let size = explicit_operand_size(&spec.size, register_size, vex, decoder_flags);
+            let argument = match spec.kind {
+                OperandKind::G => general_register(reg, size),
+                OperandKind::V => vector_register(reg, size),
+                OperandKind::H => vector_register(vvvv, size),
+                OperandKind::B => general_register(vvvv, size),
+                OperandKind::E | OperandKind::R | OperandKind::W | OperandKind::U | OperandKind::M => {
+                    match (modrm >> 6, &spec.kind) {
+                        (0b11, &OperandKind::E) | (0b11, &OperandKind::R) => general_register(rm, size),
+                        (0b11, _) => vector_register(rm, size),
+                        _ => {
+                            explicit_size = size;
+                            memory.take().expect("Only one memory operand per instruction")
+                        }
+                    }
+                }
+                OperandKind::I => {
+                    let (immediate, length) = self.read_immediate(&Immediate::UnsignedBit8, ip_offset, decoder_flags);
+                    ip_offset += length;
+                    InstructionArgument::Immediate { immediate: immediate }
+                }
+            };
+            arguments.push(argument);
+        }
+        self.inc_rip(ip_offset);
+
+        let mut builder = InstructionArgumentsBuilder::new();
+        for (index, argument) in arguments.into_iter().rev().enumerate() {
+            builder = match index {
+                0 => builder.first_argument(argument),
+                1 => builder.second_argument(argument),
+                2 => builder.third_argument(argument),
+                _ => builder.fourth_argument(argument),
+            };
+        }
+        if let Some(size) = explicit_size {
+            builder = builder.explicit_size(size);
         }
+        builder.finalize()
     }
 
-    fn execute_instruction(&mut self, cache_entry: &InstructionCache) {
-        match cache_entry.instruction {
-            Instruction::Adc => self.cpu.adc(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Add => self.cpu.add(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::And => self.cpu.and(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Arithmetic => self.cpu.arithmetic(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::BitManipulation => self.cpu.bit_manipulation(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Bt => self.cpu.bt(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Bts => self.cpu.bts(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Btr => self.cpu.btr(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Btc => self.cpu.btc(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Call => self.cpu.call(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cld => self.cpu.cld(self.machine_state),
-            Instruction::Cmova => self.cpu.cmova(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovae => self.cpu.cmovae(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovb => self.cpu.cmovb(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovbe => self.cpu.cmovbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmove => self.cpu.cmove(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovg => self.cpu.cmovg(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovge => self.cpu.cmovge(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovl => self.cpu.cmovl(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovle => self.cpu.cmovle(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovne => self.cpu.cmovne(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovno => self.cpu.cmovno(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovnp => self.cpu.cmovnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovns => self.cpu.cmovns(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovo => self.cpu.cmovo(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovp => self.cpu.cmovp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmovs => self.cpu.cmovs(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmp => self.cpu.cmp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cpuid => self.cpu.cpuid(self.machine_state),
-            Instruction::CompareMulOperation => self.cpu.compare_mul_operation(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Imul => self.cpu.imul(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Int => {
-                if self.machine_state.print_instructions {
-                    self.machine_state.print_instr("int    $0x80");
-                }
-            },
-            Instruction::Ja => self.cpu.ja(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jae => self.cpu.jae(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jb => self.cpu.jb(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jbe => self.cpu.jbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Je => self.cpu.je(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jg => self.cpu.jg(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jge => self.cpu.jge(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jl => self.cpu.jl(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jle => self.cpu.jle(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jmp => self.cpu.jmp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jne => self.cpu.jne(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jno => self.cpu.jno(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jnp => self.cpu.jnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jns => self.cpu.jns(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jo => self.cpu.jo(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Jp => self.cpu.jp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Js => self.cpu.js(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Lea => self.cpu.lea(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Leave => self.cpu.leave(self.machine_state),
-            Instruction::Lidt => self.cpu.lidt(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Lgdt => self.cpu.lgdt(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Mov => self.cpu.mov(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Movs => self.cpu.movs(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Movsx => self.cpu.movsx(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Movzx => self.cpu.movzx(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Nop => (),
-            Instruction::Or => self.cpu.or(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Out => self.cpu.out(self.machine_state),
-            Instruction::Pop => self.cpu.pop(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Popf => self.cpu.popf(self.machine_state),
-            Instruction::Push => self.cpu.push(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Pushf => self.cpu.pushf(self.machine_state),
-            Instruction::RegisterOperation => self.cpu.register_operation(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Ret => self.cpu.ret(self.machine_state),
-            Instruction::Lret => self.cpu.lret(self.machine_state),
-            Instruction::Rdmsr => self.cpu.rdmsr(self.machine_state),
-            Instruction::Sbb => self.cpu.sbb(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::ShiftRotate => self.cpu.shift_rotate(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Std => self.cpu.std(self.machine_state),
-            Instruction::Stos => self.cpu.stos(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Sub => self.cpu.sub(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Test => self.cpu.test(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Wrmsr => self.cpu.wrmsr(self.machine_state),
-            Instruction::Xor => self.cpu.xor(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Scas => self.cpu.scas(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Cmpxchg => self.cpu.cmpxchg(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Xchg => self.cpu.xchg(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Syscall => self.cpu.syscall(self.machine_state),
-            Instruction::Seto => self.cpu.seto(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setno => self.cpu.setno(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setb => self.cpu.setb(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setae => self.cpu.setae(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Sete => self.cpu.sete(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setne => self.cpu.setne(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setbe => self.cpu.setbe(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Seta => self.cpu.seta(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Sets => self.cpu.sets(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setns => self.cpu.setns(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setp => self.cpu.setp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setnp => self.cpu.setnp(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setl => self.cpu.setl(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setge => self.cpu.setge(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setle => self.cpu.setle(self.machine_state, Decoder::fetch_argument(cache_entry)),
-            Instruction::Setg => self.cpu.setg(self.machine_state, Decoder::fetch_argument(cache_entry)),
+    /// Control register moves ignore the operand size prefix
+    fn control_register_size(&self) -> RegisterSize {
+        match self.code_size {
+            CodeSize::Bit64 => RegisterSize::Bit64,
+            _ => RegisterSize::Bit32,
         }
     }
 
     fn inc_rip(&mut self, ip_offset: i64) {
-        self.machine_state.rip += ip_offset;
+        self.rip += ip_offset;
     }
 
     fn get_i64_value(&mut self, ip_offset: i64) -> i64 {
-        let rip = (self.machine_state.rip + ip_offset) as u64;
-        let value = self.machine_state.mem_read(rip, 8);
+        let rip = (self.rip + ip_offset) as u64;
+        let value = self.read(rip, 8);
         *zero::read::<i64>(&value)
     }
 
     fn get_i32_value(&mut self, ip_offset: i64) -> i32 {
-        let rip = (self.machine_state.rip + ip_offset) as u64;
-        let value = self.machine_state.mem_read(rip, 4);
+        let rip = (self.rip + ip_offset) as u64;
+        let value = self.read(rip, 4);
         *zero::read::<i32>(&value)
     }
 
     fn get_i16_value(&mut self, ip_offset: i64) -> i16 {
-        let rip = (self.machine_state.rip + ip_offset) as u64;
-        let value = self.machine_state.mem_read(rip, 2);
+        let rip = (self.rip + ip_offset) as u64;
+        let value = self.read(rip, 2);
         *zero::read::<i16>(&value)
     }
 
     fn get_i8_value(&mut self, ip_offset: i64) -> i8 {
-        let rip = (self.machine_state.rip + ip_offset) as u64;
-        self.machine_state.mem_read_byte(rip) as i8
-    }
-
-    fn read_immediate_8bit(&mut self) -> (InstructionArguments, i64) {
-        let rip = self.machine_state.rip as u64;
-        let immediate = self.machine_state.mem_read_byte(rip + 1) as i8 as i64;
-
-        (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate { immediate: immediate })
-             .finalize(),
-         2)
+        let rip = (self.rip + ip_offset) as u64;
+        self.read_byte(rip) as i8
     }
 
-    fn read_immediate_32bit(&mut self) -> InstructionArguments {
-        let immediate = self.get_i32_value(1) as i64;
-        InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate { immediate: immediate })
-             .finalize()
+    /// Reads an immediate at rip + ip_offset, returns the value and its length
+    fn read_immediate(&mut self, immediate: &Immediate, ip_offset: i64, decoder_flags: DecoderFlags) -> (i64, i64) {
+        match *immediate {
+            Immediate::Bit8 => (self.get_i8_value(ip_offset) as i64, 1),
+            Immediate::UnsignedBit8 => (self.get_i8_value(ip_offset) as u8 as i64, 1),
+            Immediate::UnsignedBit16 => (self.get_i16_value(ip_offset) as u16 as i64, 2),
+            Immediate::Bit32 => (self.get_i32_value(ip_offset) as i64, 4),
+            // 66 only shortens branch displacements outside of 64 bit mode
+            Immediate::Relative if self.code_size == CodeSize::Bit64 => (self.get_i32_value(ip_offset) as i64, 4),
+            Immediate::Bit16Or32 | Immediate::Full | Immediate::Relative => {
+                if *immediate == Immediate::Full && decoder_flags.contains(OPERAND_64_BIT) {
+                    (self.get_i64_value(ip_offset), 8)
+                } else if decoder_flags.contains(OPERAND_16_BIT) {
+                    (self.get_i16_value(ip_offset) as i64, 2)
+                } else {
+                    (self.get_i32_value(ip_offset) as i64, 4)
+                }
+            }
+            Immediate::None => panic!("Instruction has no immediate"),
+        }
     }
 
     fn get_argument(&mut self,
                     register_size: RegisterSize,
                     reg_or_opcode: RegOrOpcode,
                     immediate_size: ImmediateSize,
-                    mut decoder_flags: DecoderFlags)
+                    decoder_flags: DecoderFlags)
                     -> (InstructionArguments, i64) {
-        let rip = (self.machine_state.rip + 1) as u64;
-        let modrm = self.machine_state.mem_read_byte(rip);
-
-        let mut address_mod = modrm >> 6;
+        let rip = (self.rip + 1) as u64;
+        let modrm = self.read_byte(rip);
 
-        match address_mod {
+        match modrm >> 6 {
             0b00 | 0b01 | 0b10 => {
-                // effective address / effecive address + 8 bit deplacement /
-                // effecive address + 32 bit deplacement
-                let rm = modrm & 0b00000111;
-
-                // special case: RIP relative adressing. We fake a 32bit displacement instruction.
-                if address_mod == 0b00 && rm == 0x5 {
-                    address_mod = 0b100;
-                }
-
-                // sib byte
-                let (sib, offset) = if rm == 0b100 {
-                    (Some(self.machine_state.mem_read_byte(rip + 1)), 3)
-                } else {
-                    (None, 2)
-                };
-
-                let (displacement, mut ip_offset) = match address_mod {
-                    0b00 => {
-                        match sib {
-                            Some(sib) => {
-                                let base = sib & 0b00000111;
-                                if base == 0x5 {
-                                    let displacement = self.get_i32_value(offset);
-                                    decoder_flags |= SIB_DISPLACEMENT_ONLY;
-                                    (displacement, 4)
-                                } else {
-                                    (0, 0)
-                                }
-                            },
-                            None => (0, 0)
-                        }
-                    }
-                    0b01 => {
-                        let rip = (self.machine_state.rip + offset) as u64;
-                        (self.machine_state.mem_read_byte(rip) as i8 as i32, 1)
-                    }
-                    0b10 | 0b100 => {
-                        let displacement = self.get_i32_value(offset);
-                        // change RIP relative addressing mode back to 0b00
-                        if address_mod == 0b100 {
-                            address_mod = 0b00;
-                        }
-
-                        (displacement, 4)
-                    }
-                    _ => unreachable!(),
-                };
-                ip_offset += offset; // skip instruction + modrm byte
-
+                let (memory, mut ip_offset) = self.memory_operand(modrm, decoder_flags);
                 let register_or_opcode = (modrm & 0b00111000) >> 3;
                 // TODO: based on REX, this could be a 64bit value
                 match immediate_size {
                     ImmediateSize::Bit8 => {
                         assert!(reg_or_opcode == RegOrOpcode::Opcode);
-                        let rip = (self.machine_state.rip + ip_offset) as u64;
-                        let immediate = self.machine_state.mem_read_byte(rip);
-
-                        let argument_size = match register_size {
-                            RegisterSize::Bit8 => ArgumentSize::Bit8,
-                            RegisterSize::Bit16 => ArgumentSize::Bit16,
-                            RegisterSize::Bit32 => ArgumentSize::Bit32,
-                            RegisterSize::Bit64 => ArgumentSize::Bit64,
-                            RegisterSize::Segment => panic!("Unsupported register size"),
-                        };
-                        let register = if address_mod == 0b00 && rm == 0x5 {
-                            Register::RIP
-                        } else {
-                            let register_size = if decoder_flags.contains(ADDRESS_SIZE_OVERRIDE) {
-                                RegisterSize::Bit32
-                            } else {
-                                RegisterSize::Bit64
-                            };
-                            get_register(rm, register_size, decoder_flags.contains(NEW_64BIT_REGISTER),
-                                         decoder_flags.contains(NEW_8BIT_REGISTER))
-                        };
+                        let rip = (self.rip + ip_offset) as u64;
+                        let immediate = self.read_byte(rip);
 
                         (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
                                  immediate: immediate as i64,
                              })
-                             .second_argument(self.effective_address(sib, register, displacement, decoder_flags))
+                             .second_argument(memory)
                              .opcode(register_or_opcode)
-                             .explicit_size(argument_size)
+                             .explicit_size(argument_size(register_size))
                              .finalize(),
                          ip_offset + 1)
                     }
@@ -1716,52 +1269,16 @@ impl<'a> Decoder<'a> {
                             self.get_i32_value(ip_offset - 4) as i64
                         };
 
-                        let argument_size = match register_size {
-                            RegisterSize::Bit8 => ArgumentSize::Bit8,
-                            RegisterSize::Bit16 => ArgumentSize::Bit16,
-                            RegisterSize::Bit32 => ArgumentSize::Bit32,
-                            RegisterSize::Bit64 => ArgumentSize::Bit64,
-                            RegisterSize::Segment => panic!("Unsupported register size"),
-                        };
-
-                        let register = if address_mod == 0b00 && rm == 0x5 {
-                            Register::RIP
-                        } else {
-                            let register_size = if decoder_flags.contains(ADDRESS_SIZE_OVERRIDE) {
-                                RegisterSize::Bit32
-                            } else {
-                                RegisterSize::Bit64
-                            };
-                            get_register(rm, register_size, decoder_flags.contains(NEW_64BIT_REGISTER),
-                                         decoder_flags.contains(NEW_8BIT_REGISTER))
-                        };
-
                         (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
                                  immediate: immediate,
                              })
-                             .second_argument(self.effective_address(sib, register, displacement, decoder_flags))
+                             .second_argument(memory)
                              .opcode(register_or_opcode)
-                             .explicit_size(argument_size)
+                             .explicit_size(argument_size(register_size))
                              .finalize(),
                          ip_offset)
                     }
                     ImmediateSize::None => {
-                        let first_reg_size = if decoder_flags.contains(ADDRESS_SIZE_OVERRIDE) {
-                            RegisterSize::Bit32
-                        } else {
-                            RegisterSize::Bit64
-                        };
-
-                        // special case: RIP relative adressing.
-                        let register1 = if address_mod == 0b00 && rm == 0x5 {
-                            Register::RIP
-                        } else {
-                            get_register(rm,
-                                         first_reg_size,
-                                         decoder_flags.contains(NEW_64BIT_REGISTER),
-                                         decoder_flags.contains(NEW_8BIT_REGISTER))
-                        };
-
                         (match reg_or_opcode {
                             RegOrOpcode::Register => {
                                 let register2 = get_register(register_or_opcode,
@@ -1770,7 +1287,7 @@ impl<'a> Decoder<'a> {
                                                             decoder_flags.contains(NEW_8BIT_REGISTER));
 
                                 if decoder_flags.contains(REVERSED_REGISTER_DIRECTION) {
-                                    InstructionArgumentsBuilder::new().first_argument(self.effective_address(sib, register1, displacement, decoder_flags))
+                                    InstructionArgumentsBuilder::new().first_argument(memory)
                                     .second_argument(
                                         InstructionArgument::Register {
                                             register: register2,
@@ -1779,13 +1296,13 @@ impl<'a> Decoder<'a> {
                                     InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Register {
                                             register: register2,
                                         })
-                                        .second_argument(self.effective_address(sib, register1, displacement, decoder_flags))
+                                        .second_argument(memory)
                                         .finalize()
                                 }
                             },
                             RegOrOpcode::Opcode => {
                                 InstructionArgumentsBuilder::new()
-                                    .first_argument(self.effective_address(sib, register1, displacement, decoder_flags))
+                                    .first_argument(memory)
                                     .opcode(register_or_opcode)
                                     .explicit_size(ArgumentSize::Bit64)
                                     .finalize()
@@ -1795,9 +1312,13 @@ impl<'a> Decoder<'a> {
                 }
             }
             0b11 => {
-                // register
+                // register, mov to or from a segment register uses a general purpose one of the operand size
+                let rm_size = match register_size {
+                    RegisterSize::Segment => operand_size(decoder_flags),
+                    size => size,
+                };
                 let register1 = get_register(modrm & 0b00000111,
-                                             register_size,
+                                             rm_size,
                                              decoder_flags.contains(NEW_64BIT_REGISTER),
                                              decoder_flags.contains(NEW_8BIT_REGISTER));
                 let value2 = (modrm & 0b00111000) >> 3;
@@ -1831,8 +1352,8 @@ impl<'a> Decoder<'a> {
                     RegOrOpcode::Opcode => {
                         match immediate_size {
                             ImmediateSize::Bit8 => {
-                                let rip = (self.machine_state.rip + 2) as u64;
-                                let immediate = self.machine_state.mem_read_byte(rip);
+                                let rip = (self.rip + 2) as u64;
+                                let immediate = self.read_byte(rip);
                                 (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
                                          immediate: immediate as i8 as i64,
                                      })
@@ -1844,16 +1365,20 @@ impl<'a> Decoder<'a> {
                                  3)
                             }
                             ImmediateSize::Bit32 => {
-                                let immediate = self.get_i32_value(2);
+                                let (immediate, length) = if decoder_flags.contains(OPERAND_16_BIT) {
+                                    (self.get_i16_value(2) as i64, 4)
+                                } else {
+                                    (self.get_i32_value(2) as i64, 6)
+                                };
                                 (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                                         immediate: immediate as i64,
+                                         immediate: immediate,
                                      })
                                      .second_argument(InstructionArgument::Register {
                                          register: register1,
                                      })
                                      .opcode(value2)
                                      .finalize(),
-                                 6)
+                                 length)
                             }
                             ImmediateSize::None => {
                                 (InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Register {
@@ -1872,125 +1397,84 @@ impl<'a> Decoder<'a> {
     }
 
 
-    fn effective_address(&self, sib: Option<u8>, register: Register, displacement: i32, decoder_flags: DecoderFlags) -> InstructionArgument {
-        match sib {
-            None => {
-                InstructionArgument::EffectiveAddress {
-                    base: Some(register),
-                    index: None,
-                    scale: None,
-                    displacement: displacement,
-                }
-            }
-            Some(sib) => {
-                let base_num = sib & 0b00000111;
-                let index = (sib & 0b00111000) >> 3;
-                let scale = (sib & 0b11000000) >> 6;
-                let scale = 2u8.pow(scale as u32) as u8;
-
-                let register_size = if decoder_flags.contains(ADDRESS_SIZE_OVERRIDE) {
-                    RegisterSize::Bit32
-                } else {
-                    RegisterSize::Bit64
-                };
-
-                let base = get_register(base_num, register_size,
-                                       decoder_flags.contains(NEW_64BIT_REGISTER), false);
-
-                if index == 0x4 {
-                    if base_num == 0x5 && decoder_flags.contains(SIB_DISPLACEMENT_ONLY) {
-                        InstructionArgument::EffectiveAddress {
-                            base: None,
-                            displacement: displacement,
-                            scale: None,
-                            index: None,
-                        }
-                    } else {
-                        InstructionArgument::EffectiveAddress {
-                            base: Some(base),
-                            displacement: displacement,
-                            scale: None,
-                            index: None,
-                        }
-                    }
-                } else {
-                    if base_num == 0x5 && decoder_flags.contains(SIB_DISPLACEMENT_ONLY) {
-                        InstructionArgument::EffectiveAddress {
-                            base: None,
-                            displacement: displacement,
-                            scale: Some(scale),
-                            index: Some(get_register(index, register_size,
-                                                    decoder_flags.contains(SIB_EXTENSION), false))
-                        }
-                    } else {
-                        InstructionArgument::EffectiveAddress {
-                            base: Some(base),
-                            displacement: displacement,
-                            scale: Some(scale),
-                            index: Some(get_register(index, register_size,
-                                                    decoder_flags.contains(SIB_EXTENSION), false))
-                        }
-                    }
-                }
-            }
+    /// Decodes the memory operand of the ModRM byte at rip + 1 and the SIB byte
+    /// and displacement following it. Returns the operand and the offset of
+    /// the first byte after it.
+    fn memory_operand(&mut self, modrm: u8, decoder_flags: DecoderFlags) -> (InstructionArgument, i64) {
+        let address_size = address_size(decoder_flags, self.code_size);
+        if let RegisterSize::Bit16 = address_size {
+            return self.memory_operand_16bit(modrm);
         }
 
-    }
-
-
-    fn decode_8bit_reg_8bit_immediate(&mut self, decoder_flags: DecoderFlags) -> InstructionArguments {
-        let (argument, ip_offset) = self.get_argument(RegisterSize::Bit8,
-                                                      RegOrOpcode::Register,
-                                                      ImmediateSize::None,
-                                                      decoder_flags);
-        self.inc_rip(ip_offset);
-        argument
-    }
-
-    fn decode_reg_reg(&mut self, register_size: RegisterSize, decoder_flags: DecoderFlags) -> InstructionArguments {
-        let (argument, ip_offset) = self.get_argument(register_size,
-                                                      RegOrOpcode::Register,
-                                                      ImmediateSize::None,
-                                                      decoder_flags);
-        self.inc_rip(ip_offset);
-        argument
-    }
+        let address_mod = modrm >> 6;
+        let rm = modrm & 0b00000111;
+        let (base, index, scale, offset) = if rm == 0b100 {
+            let sib = self.read_byte((self.rip + 2) as u64);
+            let base = sib & 0b00000111;
+            let index = (sib & 0b00111000) >> 3;
+            let scale = 1 << (sib >> 6);
+            // no base with mod 00 means a 32 bit displacement only
+            let base = if base == 0b101 && address_mod == 0b00 {
+                None
+            } else {
+                Some(get_register(base, address_size, decoder_flags.contains(NEW_64BIT_REGISTER), false))
+            };
+            if index == 0b100 && !decoder_flags.contains(SIB_EXTENSION) {
+                (base, None, None, 3)
+            } else {
+                let index = get_register(index, address_size, decoder_flags.contains(SIB_EXTENSION), false);
+                (base, Some(index), Some(scale), 3)
+            }
+        } else if address_mod == 0b00 && rm == 0b101 {
+            // rip relative in 64 bit mode, an absolute address otherwise
+            match self.code_size {
+                CodeSize::Bit64 => (Some(Register::RIP), None, None, 2),
+                _ => (None, None, None, 2),
+            }
+        } else {
+            let base = get_register(rm, address_size, decoder_flags.contains(NEW_64BIT_REGISTER), false);
+            (Some(base), None, None, 2)
+        };
 
-    fn decode_al_immediate(&mut self) -> InstructionArguments {
-        let immediate = self.get_i8_value(1);
-        let argument =
-            InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                    immediate: immediate as i64,
-                })
-                .second_argument(InstructionArgument::Register {
-                    register: Register::AL,
-                })
-                .finalize();
-        self.inc_rip(2);
-        argument
+        let (displacement, length) = match (address_mod, base) {
+            (0b01, _) => (self.get_i8_value(offset) as i32, 1),
+            (0b10, _) | (_, None) | (_, Some(Register::RIP)) => (self.get_i32_value(offset), 4),
+            _ => (0, 0),
+        };
+        (InstructionArgument::EffectiveAddress {
+            base: base,
+            index: index,
+            scale: scale,
+            displacement: displacement,
+        }, offset + length)
     }
 
-    fn decode_ax_immediate(&mut self, register_size: RegisterSize, decoder_flags: DecoderFlags) -> InstructionArguments {
-        let (immediate, ip_offset) = if decoder_flags.contains(OPERAND_16_BIT) {
-            (self.get_i16_value(1) as i64, 3)
-        } else {
-            (self.get_i32_value(1) as i64, 5)
+    /// 16 bit addressing has no SIB byte, r/m selects one of eight base and index combinations
+    fn memory_operand_16bit(&mut self, modrm: u8) -> (InstructionArgument, i64) {
+        let address_mod = modrm >> 6;
+        let rm = modrm & 0b00000111;
+        let (base, index) = match rm {
+            0b000 => (Register::BX, Some(Register::SI)),
+            0b001 => (Register::BX, Some(Register::DI)),
+            0b010 => (Register::BP, Some(Register::SI)),
+            0b011 => (Register::BP, Some(Register::DI)),
+            0b100 => (Register::SI, None),
+            0b101 => (Register::DI, None),
+            0b110 => (Register::BP, None),
+            _ => (Register::BX, None),
         };
-
-        let register = get_register(0,
-            register_size, decoder_flags.contains(NEW_64BIT_REGISTER),
-            false);
-
-        let argument =
-            InstructionArgumentsBuilder::new().first_argument(InstructionArgument::Immediate {
-                    immediate: immediate,
-                })
-                .second_argument(InstructionArgument::Register {
-                    register: register,
-                })
-                .finalize();
-        self.inc_rip(ip_offset);
-        argument
+        let (base, displacement, length) = match address_mod {
+            0b00 if rm == 0b110 => (None, self.get_i16_value(2) as u16 as i32, 2),
+            0b00 => (Some(base), 0, 0),
+            0b01 => (Some(base), self.get_i8_value(2) as i32, 1),
+            _ => (Some(base), self.get_i16_value(2) as i32, 2),
+        };
+        (InstructionArgument::EffectiveAddress {
+            base: base,
+            index: index,
+            scale: None,
+            displacement: displacement,
+        }, 2 + length)
     }
 
     fn override_argument_size(&mut self,
@@ -2008,8 +1492,9 @@ impl<'a> Decoder<'a> {
                             ArgumentSize::Bit16 => RegisterSize::Bit16,
                             ArgumentSize::Bit32 => RegisterSize::Bit32,
                             ArgumentSize::Bit64 => RegisterSize::Bit64,
+                            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Unsupported register size"),
                         };
-                        let modrm = self.machine_state.mem_read_byte(rip + 1);
+                        let modrm = self.read_byte(rip + 1);
                         let register = modrm & 0b00000111;
                         let register = get_register(register, register_size,
                                                     decoder_flags.contains(NEW_64BIT_REGISTER),
@@ -2068,7 +1553,186 @@ bitflags! {
         const SIB_EXTENSION = 1 << 8,
         const OPERAND_16_BIT = 1 << 9,
         const OPERAND_64_BIT = 1 << 10,
-        const SIB_DISPLACEMENT_ONLY = 1 << 11,
+    }
+}
+
+/// VEX.vvvv, VEX.L and VEX.pp, the other VEX bits are stored in the decoder flags
+struct Vex {
+    register: u8,
+    length_256: bool,
+    w: bool,
+    prefix: MandatoryPrefix,
+}
+
+impl Vex {
+    /// byte is the last byte of the VEX prefix
+    fn new(byte: u8, w: bool) -> Vex {
+        Vex {
+            // vvvv is stored inverted
+            register: !byte >> 3 & 0b1111,
+            length_256: byte & 0b100 != 0,
+            w: w,
+            prefix: match byte & 0b11 {
+                0 => MandatoryPrefix::None,
+                1 => MandatoryPrefix::OperandSize,
+                2 => MandatoryPrefix::RepeatEqual,
+                _ => MandatoryPrefix::RepeatNotEqual,
+            },
+        }
+    }
+
+    fn matches(&self, encoding: &VexEncoding) -> bool {
+        let length = match encoding.length {
+            VectorLength::Ignored => true,
+            VectorLength::Bit128 => !self.length_256,
+            VectorLength::Bit256 => self.length_256,
+            VectorLength::Bit512 => false,
+        };
+        !encoding.evex && length && encoding.w.map_or(true, |w| w == self.w)
+    }
+}
+
+/// Picks the opcode table row matching the ModRM byte. Rows with a mandatory
+/// prefix win if that prefix is present, VEX encoded rows need an exact match.
+fn find_entry(entries: &'static [OpcodeEntry],
+              modrm: u8,
+              decoder_flags: DecoderFlags,
+              vex: Option<&Vex>)
+              -> Option<&'static OpcodeEntry> {
+    let extension = (modrm & 0b00111000) >> 3;
+    let candidates = || entries.iter().filter(move |entry| {
+        entry.vex.is_some() == vex.is_some() &&
+        entry.extension.map_or(true, |entry_extension| entry_extension == extension) &&
+        entry.modrm.map_or(true, |entry_modrm| entry_modrm == modrm) &&
+        !(entry.memory_only() && modrm >> 6 == 0b11) &&
+        !(entry.register_only() && modrm >> 6 != 0b11)
+    });
+    if let Some(vex) = vex {
+        return candidates().find(|entry| {
+            entry.prefix == vex.prefix && vex.matches(entry.vex.as_ref().unwrap()) &&
+            (vex.register == 0 || entry.vex_register())
+        });
+    }
+    candidates().find(|entry| match entry.prefix {
+        MandatoryPrefix::None => false,
+        MandatoryPrefix::OperandSize => decoder_flags.contains(OPERAND_16_BIT),
+        MandatoryPrefix::RepeatNotEqual => decoder_flags.contains(REPEAT_NOT_EQUAL),
+        MandatoryPrefix::RepeatEqual => decoder_flags.contains(REPEAT_EQUAL),
+    }).or_else(|| candidates().find(|entry| entry.prefix == MandatoryPrefix::None))
+}
+
+fn explicit_operand_size(size: &OperandSize,
+                         register_size: RegisterSize,
+                         vex: Option<&Vex>,
+                         decoder_flags: DecoderFlags)
+                         -> Option<ArgumentSize> {
+    match *size {
+        OperandSize::None => None,
+        OperandSize::Byte => Some(ArgumentSize::Bit8),
+        OperandSize::Word => Some(ArgumentSize::Bit16),
+        OperandSize::Dword => Some(ArgumentSize::Bit32),
+        OperandSize::Qword => Some(ArgumentSize::Bit64),
+        OperandSize::DoubleQuadword => Some(ArgumentSize::Bit128),
+        OperandSize::QuadQuadword => Some(ArgumentSize::Bit256),
+        OperandSize::Vector => {
+            if vex.map_or(false, |vex| vex.length_256) {
+                Some(ArgumentSize::Bit256)
+            } else {
+                Some(ArgumentSize::Bit128)
+            }
+        }
+        OperandSize::DwordOrQword => {
+            if decoder_flags.contains(OPERAND_64_BIT) {
+                Some(ArgumentSize::Bit64)
+            } else {
+                Some(ArgumentSize::Bit32)
+            }
+        }
+        OperandSize::Full | OperandSize::Full64 => {
+            match register_size {
+                RegisterSize::Bit16 => Some(ArgumentSize::Bit16),
+                RegisterSize::Bit64 => Some(ArgumentSize::Bit64),
+                _ => Some(ArgumentSize::Bit32),
+            }
+        }
+        OperandSize::Segment => panic!("Segment registers are not supported in operand lists"),
+    }
+}
+
+/// XMM register for sizes up to 128 bit, YMM register for 256 bit
+fn vector_register(number: u8, size: Option<ArgumentSize>) -> Register {
+    let registers = match size {
+        Some(ArgumentSize::Bit256) => VECTOR_REGISTERS_256,
+        _ => VECTOR_REGISTERS_128,
+    };
+    registers[number as usize]
+}
+
+const VECTOR_REGISTERS_128: [Register; 16] = [
+    Register::XMM0, Register::XMM1, Register::XMM2, Register::XMM3,
+    Register::XMM4, Register::XMM5, Register::XMM6, Register::XMM7,
+    Register::XMM8, Register::XMM9, Register::XMM10, Register::XMM11,
+    Register::XMM12, Register::XMM13, Register::XMM14, Register::XMM15,
+];
+
+const VECTOR_REGISTERS_256: [Register; 16] = [
+    Register::YMM0, Register::YMM1, Register::YMM2, Register::YMM3,
+    Register::YMM4, Register::YMM5, Register::YMM6, Register::YMM7,
+    Register::YMM8, Register::YMM9, Register::YMM10, Register::YMM11,
+    Register::YMM12, Register::YMM13, Register::YMM14, Register::YMM15,
+];
+
+/// One byte opcodes that are undefined in 64 bit mode, C4 and C5 are VEX prefixes there
+const INVALID_IN_64BIT_MODE: [u8; 18] = [
+    0x06, 0x07, 0x0E, 0x16, 0x17, 0x1E, 0x1F, 0x27, 0x2F, 0x37, 0x3F, 0x60, 0x61, 0x62, 0x82, 0x9A, 0xCE, 0xEA,
+];
+
+/// Operand size of instructions that default to 32 bit, the 16 bit code
+/// default is already folded into OPERAND_16_BIT
+fn operand_size(decoder_flags: DecoderFlags) -> RegisterSize {
+    if decoder_flags.contains(OPERAND_64_BIT) {
+        RegisterSize::Bit64
+    } else if decoder_flags.contains(OPERAND_16_BIT) {
+        RegisterSize::Bit16
+    } else {
+        RegisterSize::Bit32
+    }
+}
+
+/// Size of the registers in a memory operand, 67 switches to the other size
+/// of the mode: 64/32 bit in 64 bit mode, 32/16 bit otherwise
+fn address_size(decoder_flags: DecoderFlags, code_size: CodeSize) -> RegisterSize {
+    let address_size_override = decoder_flags.contains(ADDRESS_SIZE_OVERRIDE);
+    match (code_size, address_size_override) {
+        (CodeSize::Bit64, false) => RegisterSize::Bit64,
+        (CodeSize::Bit16, false) | (CodeSize::Bit32, true) => RegisterSize::Bit16,
+        _ => RegisterSize::Bit32,
+    }
+}
+
+fn argument_size(register_size: RegisterSize) -> ArgumentSize {
+    match register_size {
+        RegisterSize::Bit8 => ArgumentSize::Bit8,
+        RegisterSize::Bit16 => ArgumentSize::Bit16,
+        RegisterSize::Bit32 => ArgumentSize::Bit32,
+        RegisterSize::Bit64 => ArgumentSize::Bit64,
+        RegisterSize::Segment => panic!("Unsupported register size"),
+    }
+}
+
+fn control_register(argument: InstructionArgument) -> Register {
+    match argument {
+        InstructionArgument::Register { register } => {
+            match register {
+                Register::R8 | Register::R8D => Register::CR8,
+                Register::RAX | Register::EAX => Register::CR0,
+                Register::RDX | Register::EDX => Register::CR2,
+                Register::RBX | Register::EBX => Register::CR3,
+                Register::RSP | Register::ESP => Register::CR4,
+                _ => panic!("Invalid argument for mov r64, CRn instruciton"),
+            }
+        },
+        _ => panic!("Invalid argument for mov r64, CRn instruciton"),
     }
 }
 
@@ -2209,3 +1873,136 @@ fn get_register(num: u8, size: RegisterSize, new_64bit_register: bool, new_8bit_
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::model::CpuModel;
+    use instruction_set::Flags;
+
+    fn decode_instruction(bytes: &[u8]) -> Option<InstructionCache> {
+        decode_instruction_for(bytes, CodeSize::Bit64)
+    }
+
+    fn length(bytes: &[u8], code_size: CodeSize) -> u64 {
+        decode_instruction_for(bytes, code_size).expect("Instruction is cut off").size
+    }
+
+    #[test]
+    fn instruction_lengths() {
+        assert_eq!(length(&[0x48, 0x89, 0xc3], CodeSize::Bit64), 3);
+        assert_eq!(length(&[0x48, 0x8d, 0x54, 0x8c, 0xf8], CodeSize::Bit64), 5);
+        assert_eq!(length(&[0x8a, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00], CodeSize::Bit64), 7);
+        assert_eq!(length(&[0x48, 0xc7, 0x00, 0xff, 0xff, 0xff, 0xff], CodeSize::Bit64), 7);
+        assert_eq!(length(&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], CodeSize::Bit64), 10);
+        assert_eq!(length(&[0x66, 0xb8, 0x34, 0x12], CodeSize::Bit64), 4);
+        assert_eq!(length(&[0xc3], CodeSize::Bit64), 1);
+    }
+
+    #[test]
+    fn code_size_changes_lengths() {
+        // 0x40 is a REX prefix in 64 bit code and inc %eax otherwise
+        assert_eq!(length(&[0x40, 0x90], CodeSize::Bit64), 2);
+        assert_eq!(length(&[0x40, 0x90], CodeSize::Bit32), 1);
+        // the immediate follows the operand size
+        assert_eq!(length(&[0xb8, 0x34, 0x12, 0x00, 0x00], CodeSize::Bit32), 5);
+        assert_eq!(length(&[0xb8, 0x34, 0x12, 0x00, 0x00], CodeSize::Bit16), 3);
+        assert_eq!(length(&[0x66, 0xb8, 0x34, 0x12, 0x00, 0x00], CodeSize::Bit16), 6);
+        // 16 bit addressing has no sib byte
+        assert_eq!(length(&[0x8b, 0x46, 0x08], CodeSize::Bit16), 3);
+        assert_eq!(length(&[0x8b, 0x44, 0x24, 0x08], CodeSize::Bit32), 4);
+    }
+
+    #[test]
+    fn cut_off_instructions() {
+        assert!(decode_instruction(&[]).is_none());
+        assert!(decode_instruction(&[0x00, 0x90, 0x66]).is_none());
+        assert!(decode_instruction(&[0x48, 0xb8, 1, 2, 3]).is_none());
+        assert!(decode_instruction(&[0xe8, 0x00]).is_none());
+    }
+
+    #[test]
+    fn prefixes() {
+        let decoded = decode_instruction(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(decoded.size, 9);
+        assert!(match decoded.prefixes.segment { Some(Register::FS) => true, _ => false });
+        assert!(!decoded.prefixes.lock);
+
+        let decoded = decode_instruction(&[0xf0, 0x48, 0x01, 0x03]).unwrap();
+        assert!(decoded.prefixes.lock);
+        assert!(decoded.prefixes.segment.is_none());
+
+        let decoded = decode_instruction(&[0xf3, 0xa4]).unwrap();
+        assert!(decoded.prefixes.repeat_equal);
+        assert!(!decoded.prefixes.repeat_not_equal);
+
+        let decoded = decode_instruction(&[0x67, 0x8b, 0x03]).unwrap();
+        assert!(decoded.prefixes.address_size_override);
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        let decoded = decode_instruction(&[0x0f, 0xff, 0x00]).unwrap();
+        assert!(match decoded.instruction { Instruction::Unknown => true, _ => false });
+        assert!(decoded.mnemonic.is_none());
+        // ff /7 is not an instruction
+        assert!(decode_instruction(&[0xff, 0xf8]).unwrap().mnemonic.is_none());
+    }
+
+    #[test]
+    fn group_mnemonics() {
+        let mnemonic = |bytes: &[u8]| decode_instruction(bytes).unwrap().mnemonic;
+        assert_eq!(mnemonic(&[0x48, 0x83, 0xe8, 0x01]), Some(Mnemonic::Sub));
+        assert_eq!(mnemonic(&[0x48, 0xc1, 0xf8, 0x02]), Some(Mnemonic::Sar));
+        assert_eq!(mnemonic(&[0x48, 0xf7, 0xd8]), Some(Mnemonic::Neg));
+        assert_eq!(mnemonic(&[0x48, 0x0f, 0xba, 0xe8, 0x03]), Some(Mnemonic::Bts));
+        assert_eq!(mnemonic(&[0xff, 0xc0]), Some(Mnemonic::Inc));
+        assert_eq!(mnemonic(&[0x48, 0x0f, 0xc7, 0x0f]), Some(Mnemonic::Cmpxchg16b));
+    }
+
+    fn run(bytes: &[u8], model: &str, rax: i64) -> MachineState {
+        let cpu = EmulationCPU {};
+        let mut machine_state = MachineState::new();
+        machine_state.cpu_model = CpuModel::preset(model).unwrap();
+        machine_state.mem_write(0x1000, bytes);
+        machine_state.rip = 0x1000;
+        machine_state.rax = rax;
+        machine_state.rbx = 0x55;
+        assert!(Decoder::new(&cpu, &mut machine_state).step());
+        machine_state
+    }
+
+    #[test]
+    fn rep_bsf_and_bsr_without_bmi1_and_abm() {
+        // tzcnt %eax,%ebx and lzcnt %eax,%ebx
+        let tzcnt = [0xf3, 0x0f, 0xbc, 0xd8];
+        let lzcnt = [0xf3, 0x0f, 0xbd, 0xd8];
+        assert_eq!(run(&tzcnt, "max", 0).rbx, 32);
+        assert_eq!(run(&lzcnt, "max", 1).rbx, 31);
+        // bsf leaves the destination alone for a zero source
+        let machine_state = run(&tzcnt, "minimal-x86-64", 0);
+        assert_eq!(machine_state.rbx, 0x55);
+        assert!(machine_state.get_flag(Flags::Zero));
+        assert_eq!(run(&lzcnt, "minimal-x86-64", 1).rbx, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid opcode (#UD)")]
+    fn missing_feature_raises_invalid_opcode() {
+        // popcnt %eax,%ebx, delivering the exception panics without an IDT
+        run(&[0xf3, 0x0f, 0xb8, 0xd8], "minimal-x86-64", 1);
+    }
+
+    #[test]
+    fn ir_address_size() {
+        let address_size = |bytes: &[u8], code_size| {
+            ::ir::decode(bytes, 0x1000, code_size).unwrap().address_size.bytes()
+        };
+        assert_eq!(address_size(&[0x8b, 0x03], CodeSize::Bit64), 8);
+        assert_eq!(address_size(&[0x67, 0x8b, 0x03], CodeSize::Bit64), 4);
+        assert_eq!(address_size(&[0x8b, 0x03], CodeSize::Bit32), 4);
+        assert_eq!(address_size(&[0x67, 0x8b, 0x07], CodeSize::Bit32), 2);
+        assert_eq!(address_size(&[0x8b, 0x07], CodeSize::Bit16), 2);
+        assert_eq!(address_size(&[0x67, 0x8b, 0x03], CodeSize::Bit16), 4);
+    }
+}
@@ -0,0 +1,359 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::ptr;
+use std::rc::Rc;
+
+use libc;
+
+use machine_state::MachineState;
+use decoder::Decoder;
+use cpu::emu_instructions::EmulationCPU;
+use trace::{TraceSink, TraceRecord};
+
+/* Differential testing against the host cpu: the binary runs natively under
+ * ptrace single step and inside the emulator at the same time, after every
+ * instruction the register files are compared.
+ * Only works for static binaries on a x86_64 linux host.
+ */
+
+// default flags compared after every instruction: carry, parity, zero, sign, direction, overflow
+pub const DEFAULT_FLAGS_MASK: u64 = 0x1 | 0x4 | 0x40 | 0x80 | 0x400 | 0x800;
+
+const PTRACE_TRACEME: libc::c_uint = 0;
+const PTRACE_SINGLESTEP: libc::c_uint = 9;
+const PTRACE_GETREGS: libc::c_uint = 12;
+const PTRACE_KILL: libc::c_uint = 8;
+
+// struct user_regs_struct from <sys/user.h>
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct UserRegs {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    orig_rax: u64,
+    rip: u64,
+    cs: u64,
+    eflags: u64,
+    rsp: u64,
+    ss: u64,
+    fs_base: u64,
+    gs_base: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+}
+
+impl UserRegs {
+    fn registers(&self) -> [(&'static str, u64); 18] {
+        [("rip", self.rip), ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx),
+         ("rdx", self.rdx), ("rsp", self.rsp), ("rbp", self.rbp), ("rsi", self.rsi),
+         ("rdi", self.rdi), ("r8", self.r8), ("r9", self.r9), ("r10", self.r10),
+         ("r11", self.r11), ("r12", self.r12), ("r13", self.r13), ("r14", self.r14),
+         ("r15", self.r15), ("rflags", self.eflags)]
+    }
+
+    fn from_machine_state(machine_state: &MachineState) -> UserRegs {
+        UserRegs {
+            rip: machine_state.rip as u64,
+            rax: machine_state.rax as u64,
+            rbx: machine_state.rbx as u64,
+            rcx: machine_state.rcx as u64,
+            rdx: machine_state.rdx as u64,
+            rsp: machine_state.rsp as u64,
+            rbp: machine_state.rbp as u64,
+            rsi: machine_state.rsi as u64,
+            rdi: machine_state.rdi as u64,
+            r8: machine_state.r8 as u64,
+            r9: machine_state.r9 as u64,
+            r10: machine_state.r10 as u64,
+            r11: machine_state.r11 as u64,
+            r12: machine_state.r12 as u64,
+            r13: machine_state.r13 as u64,
+            r14: machine_state.r14 as u64,
+            r15: machine_state.r15 as u64,
+            eflags: machine_state.rflags as u64,
+            ..UserRegs::default()
+        }
+    }
+
+    fn copy_to_machine_state(&self, machine_state: &mut MachineState) {
+        machine_state.rip = self.rip as i64;
+        machine_state.rax = self.rax as i64;
+        machine_state.rbx = self.rbx as i64;
+        machine_state.rcx = self.rcx as i64;
+        machine_state.rdx = self.rdx as i64;
+        machine_state.rsp = self.rsp as i64;
+        machine_state.rbp = self.rbp as i64;
+        machine_state.rsi = self.rsi as i64;
+        machine_state.rdi = self.rdi as i64;
+        machine_state.r8 = self.r8 as i64;
+        machine_state.r9 = self.r9 as i64;
+        machine_state.r10 = self.r10 as i64;
+        machine_state.r11 = self.r11 as i64;
+        machine_state.r12 = self.r12 as i64;
+        machine_state.r13 = self.r13 as i64;
+        machine_state.r14 = self.r14 as i64;
+        machine_state.r15 = self.r15 as i64;
+        machine_state.rflags = self.eflags as i64;
+    }
+}
+
+struct NativeProcess {
+    pid: libc::pid_t,
+}
+
+enum NativeStatus {
+    Stopped,
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl NativeProcess {
+    fn spawn(filename: &str) -> NativeProcess {
+        let path = CString::new(filename).unwrap();
+        let argv = [path.as_ptr(), ptr::null()];
+        unsafe {
+            let pid = libc::fork();
+            if pid < 0 {
+                panic!("fork failed");
+            }
+            if pid == 0 {
+                libc::ptrace(PTRACE_TRACEME as _, 0, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>());
+                libc::execv(path.as_ptr(), argv.as_ptr());
+                libc::_exit(127);
+            }
+            let process = NativeProcess { pid: pid };
+            // the child stops with SIGTRAP at the first instruction after execv
+            match process.wait() {
+                NativeStatus::Stopped => process,
+                _ => panic!("Cannot start {} under ptrace", filename),
+            }
+        }
+    }
+
+    fn wait(&self) -> NativeStatus {
+        let mut status: libc::c_int = 0;
+        unsafe {
+            libc::waitpid(self.pid, &mut status, 0);
+        }
+        if status & 0x7f == 0 {
+            NativeStatus::Exited((status >> 8) & 0xff)
+        } else if status & 0xff == 0x7f {
+            let signal = (status >> 8) & 0xff;
+            if signal == libc::SIGTRAP {
+                NativeStatus::Stopped
+            } else {
+                NativeStatus::Signaled(signal)
+            }
+        } else {
+            NativeStatus::Signaled(status & 0x7f)
+        }
+    }
+
+    fn step(&self) -> NativeStatus {
+        unsafe {
+            libc::ptrace(PTRACE_SINGLESTEP as _, self.pid, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>());
+        }
+        self.wait()
+    }
+
+    fn registers(&self) -> UserRegs {
+        let mut registers = UserRegs::default();
+        unsafe {
+            libc::ptrace(PTRACE_GETREGS as _, self.pid, ptr::null_mut::<libc::c_void>(),
+                         &mut registers as *mut UserRegs as *mut libc::c_void);
+        }
+        registers
+    }
+
+    /// Copies all readable mappings (program image, stack with argv/envp/auxv) into the emulator.
+    fn copy_memory(&self, machine_state: &mut MachineState) {
+        let maps = File::open(format!("/proc/{}/maps", self.pid)).expect("Cannot read process maps");
+        let mut memory = File::open(format!("/proc/{}/mem", self.pid)).expect("Cannot read process memory");
+        for line in BufReader::new(maps).lines() {
+            let line = line.unwrap();
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap();
+            let permissions = fields.next().unwrap();
+            if !permissions.starts_with('r') {
+                continue;
+            }
+            let mut range = range.split('-');
+            let start = u64::from_str_radix(range.next().unwrap(), 16).unwrap();
+            let end = u64::from_str_radix(range.next().unwrap(), 16).unwrap();
+            let mut data = vec![0; (end - start) as usize];
+            if memory.seek(SeekFrom::Start(start)).is_err() || memory.read_exact(&mut data).is_err() {
+                // e.g. [vvar] and [vsyscall] cannot be read
+                continue;
+            }
+            machine_state.mem_write(start, &data);
+        }
+    }
+
+    fn kill(&self) {
+        unsafe {
+            libc::ptrace(PTRACE_KILL as _, self.pid, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>());
+            libc::kill(self.pid, libc::SIGKILL);
+            let mut status = 0;
+            libc::waitpid(self.pid, &mut status, 0);
+        }
+    }
+}
+
+// keeps the last executed instruction for the divergence report
+struct LastInstruction {
+    record: Rc<RefCell<Option<TraceRecord>>>,
+}
+
+impl TraceSink for LastInstruction {
+    fn record(&mut self, record: &TraceRecord) {
+        *self.record.borrow_mut() = Some(record.clone());
+    }
+}
+
+/// The result of the host cpu cannot be emulated for these instructions (syscall, cpuid, rdtsc),
+/// they are executed natively and the native registers are copied into the emulator.
+fn is_host_dependent(bytes: &[u8]) -> bool {
+    let opcode: Vec<u8> = bytes.iter().cloned().skip_while(|b| (*b & 0xF0) == 0x40).take(2).collect();
+    opcode == [0x0F, 0x05] || opcode == [0x0F, 0xA2] || opcode == [0x0F, 0x31]
+}
+
+/// Repeated string instructions stop after every iteration when single stepped natively.
+fn is_repeated_string_instruction(bytes: &[u8]) -> bool {
+    let mut repeat = false;
+    for byte in bytes {
+        match *byte {
+            0xF2 | 0xF3 => repeat = true,
+            0x66 | 0x67 | 0x2E | 0x3E | 0x26 | 0x36 | 0x64 | 0x65 => (),
+            0x40...0x4F => (),
+            0x6C...0x6F | 0xA4...0xA7 | 0xAA...0xAF => return repeat,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Runs filename natively and in the emulator side by side.
+/// Returns true if the register files never diverged.
+pub fn difftest(filename: &str, flags_mask: u64, verbose: bool) -> bool {
+    let native = NativeProcess::spawn(filename);
+
+    let mut machine_state = MachineState::new();
+    native.copy_memory(&mut machine_state);
+    native.registers().copy_to_machine_state(&mut machine_state);
+    machine_state.set_up_linux_user_segments();
+
+    let last_instruction = Rc::new(RefCell::new(None));
+    let cpu = EmulationCPU {};
+    let mut decoder = Decoder::new(&cpu, &mut machine_state);
+    decoder.add_trace_sink(Box::new(LastInstruction { record: last_instruction.clone() }));
+
+    let mut counter: u64 = 0;
+    loop {
+        counter += 1;
+        let rip = decoder.machine_state().rip as u64;
+        let bytes = decoder.machine_state().mem_read(rip, 15);
+
+        let mut native_status = native.step();
+        if is_repeated_string_instruction(&bytes) {
+            loop {
+                match native_status {
+                    NativeStatus::Stopped if native.registers().rip == rip => native_status = native.step(),
+                    _ => break,
+                }
+            }
+        }
+        match native_status {
+            NativeStatus::Stopped => (),
+            NativeStatus::Exited(code) => {
+                println!("native process exited with status {} after {} instructions, no divergence",
+                         code, counter);
+                return true;
+            }
+            NativeStatus::Signaled(signal) => {
+                println!("native process received signal {} at instruction {} (rip {:#x})",
+                         signal, counter, rip);
+                native.kill();
+                return false;
+            }
+        }
+        let native_registers = native.registers();
+
+        if is_host_dependent(&bytes) {
+            native_registers.copy_to_machine_state(decoder.machine_state());
+            if verbose {
+                println!("{:>8} {:#x}: executed natively", counter, rip);
+            }
+            continue;
+        }
+
+        let mut running = decoder.step();
+        // the emulator executes long repeated string instructions in chunks and
+        // stays on the instruction until the last one
+        if is_repeated_string_instruction(&bytes) {
+            while running && decoder.machine_state().rip as u64 == rip {
+                running = decoder.step();
+            }
+        }
+
+        let emulator_registers = UserRegs::from_machine_state(decoder.machine_state());
+        let mut divergences = Vec::new();
+        for (&(name, native_value), &(_, emulator_value)) in native_registers.registers()
+            .iter()
+            .zip(emulator_registers.registers().iter()) {
+            let (native_value, emulator_value) = if name == "rflags" {
+                (native_value & flags_mask, emulator_value & flags_mask)
+            } else {
+                (native_value, emulator_value)
+            };
+            if native_value != emulator_value {
+                divergences.push((name, native_value, emulator_value));
+            }
+        }
+
+        let instruction = match *last_instruction.borrow() {
+            Some(ref record) => {
+                let bytes: Vec<String> = record.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("{:<30} {}", bytes.join(" "), record.text())
+            }
+            None => String::new(),
+        };
+        if verbose {
+            println!("{:>8} {:#x}: {}", counter, rip, instruction);
+        }
+
+        if !divergences.is_empty() {
+            println!("divergence after instruction {} at {:#x}:", counter, rip);
+            println!("    {}", instruction);
+            println!("    {:<8} {:>18} {:>18}", "register", "native", "emulator");
+            for &(name, native_value, emulator_value) in divergences.iter() {
+                println!("    {:<8} {:>#18x} {:>#18x}", name, native_value, emulator_value);
+            }
+            native.kill();
+            return false;
+        }
+
+        if !running {
+            // int $0x80 ends the emulation, let the native process finish
+            native.kill();
+            println!("emulation ended after {} instructions, no divergence", counter);
+            return true;
+        }
+    }
+}
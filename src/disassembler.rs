@@ -0,0 +1,859 @@
+use instruction_set::{Instruction, InstructionArguments, InstructionArgument, ArgumentSize, Register, InstructionCache,
+                      Prefixes};
+use decoder::decode_instruction_for;
+use cpu::mode::CodeSize;
+use formatter::{InstructionFormatter, AttFormatter};
+
+/// One instruction decoded by disassemble().
+#[derive(Debug, Clone)]
+pub struct DecodedInstruction {
+    pub address: u64,
+    pub bytes: Vec<u8>,
+    /// legacy and REX prefixes, in the order they appear in bytes
+    pub prefixes: Vec<u8>,
+    pub mnemonic: String,
+    pub operands: String,
+}
+
+impl DecodedInstruction {
+    pub fn length(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// One line of assembler, the same format as the execution trace.
+    pub fn text(&self) -> String {
+        if self.operands.is_empty() {
+            format!("{:<6}", self.mnemonic)
+        } else {
+            format!("{:<6} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+/// Decodes bytes as if they were loaded at address, without executing anything.
+/// Unknown opcodes are returned as "(bad)", like objdump an instruction cut off at
+/// the end of bytes is a single "(bad)" byte and decoding resumes after it.
+pub fn disassemble<'a>(bytes: &'a [u8], address: u64) -> Disassembler<'a> {
+    Disassembler {
+        bytes: bytes,
+        address: address,
+        offset: 0,
+        formatter: Box::new(AttFormatter),
+        code_size: CodeSize::Bit64,
+    }
+}
+
+pub struct Disassembler<'a> {
+    bytes: &'a [u8],
+    address: u64,
+    offset: usize,
+    formatter: Box<dyn InstructionFormatter>,
+    code_size: CodeSize,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Use another syntax than AT&T.
+    pub fn formatter(mut self, formatter: Box<dyn InstructionFormatter>) -> Disassembler<'a> {
+        self.formatter = formatter;
+        self
+    }
+
+    /// Decode 16 or 32 bit code instead of 64 bit code.
+    pub fn code_size(mut self, code_size: CodeSize) -> Disassembler<'a> {
+        self.code_size = code_size;
+        self
+    }
+}
+
+impl<'a> Iterator for Disassembler<'a> {
+    type Item = DecodedInstruction;
+
+    fn next(&mut self) -> Option<DecodedInstruction> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        let instruction_start = self.address + self.offset as u64;
+        let (bytes, mnemonic, operands) = match decode_instruction_for(&self.bytes[self.offset..], self.code_size) {
+            Some(decoded) => {
+                let (mnemonic, operands) = format_instruction(&*self.formatter, &decoded, self.code_size,
+                                                              instruction_start);
+                let length = decoded.size as usize;
+                (&self.bytes[self.offset..self.offset + length], mnemonic, operands)
+            }
+            None => (&self.bytes[self.offset..self.offset + 1], "(bad)".to_string(), String::new()),
+        };
+        self.offset += bytes.len();
+
+        let code_size = self.code_size;
+        let prefixes = bytes.iter()
+            .take_while(|&&byte| is_prefix(byte, code_size))
+            .cloned()
+            .collect();
+        Some(DecodedInstruction {
+            address: instruction_start,
+            bytes: bytes.to_vec(),
+            prefixes: prefixes,
+            mnemonic: mnemonic,
+            operands: operands,
+        })
+    }
+}
+
+fn is_prefix(byte: u8, code_size: CodeSize) -> bool {
+    match byte {
+        0xF0 | 0xF2 | 0xF3 | 0x2E | 0x3E | 0x36 | 0x26 | 0x64 | 0x65 | 0x66 | 0x67 => true,
+        // inc and dec outside of 64 bit mode
+        0x40...0x4F => code_size == CodeSize::Bit64,
+        _ => false,
+    }
+}
+
+/// The segment override and the address size of an instruction, memory
+/// operands depend on them
+#[derive(Clone, Copy)]
+struct Addressing {
+    segment: Option<Register>,
+    address_size: ArgumentSize,
+}
+
+impl Addressing {
+    fn new(prefixes: &Prefixes, code_size: CodeSize) -> Addressing {
+        Addressing {
+            segment: prefixes.segment,
+            address_size: prefixes.address_size(code_size),
+        }
+    }
+
+    /// The displacement of an operand without registers is sign extended to
+    /// the address size
+    fn absolute(&self, displacement: i32) -> u64 {
+        match self.address_size {
+            ArgumentSize::Bit16 => displacement as u16 as u64,
+            ArgumentSize::Bit32 => displacement as u32 as u64,
+            _ => displacement as i64 as u64,
+        }
+    }
+}
+
+/// true if a register operand tells the size of the memory operand
+fn has_register_operand(arg: &InstructionArguments) -> bool {
+    [&arg.first_argument, &arg.second_argument, &arg.third_argument, &arg.fourth_argument]
+        .iter()
+        .any(|argument| match **argument {
+            Some(InstructionArgument::Register { .. }) => true,
+            _ => false,
+        })
+}
+
+fn format_operand(formatter: &dyn InstructionFormatter,
+                  addressing: Addressing,
+                  arg: &InstructionArguments,
+                  argument: &InstructionArgument,
+                  annotate: bool)
+                  -> String {
+    match *argument {
+        InstructionArgument::Register { ref register } => formatter.register(register),
+        InstructionArgument::Immediate { immediate } => formatter.immediate(immediate, arg.size()),
+        InstructionArgument::EffectiveAddress { ref base, ref index, displacement, .. } => {
+            let explicit = arg.explicit_size.is_some() || !has_register_operand(arg);
+            let size = if annotate && formatter.annotate_memory_size(explicit) {
+                Some(arg.size())
+            } else {
+                None
+            };
+            match (base, index) {
+                (&None, &None) => formatter.absolute(addressing.segment, addressing.absolute(displacement), size),
+                _ => formatter.memory(addressing.segment, argument, size),
+            }
+        }
+    }
+}
+
+/// annotate is false for instructions which only use the address (lea, lgdt)
+fn format_operands(formatter: &dyn InstructionFormatter,
+                   addressing: Addressing,
+                   arg: &InstructionArguments,
+                   annotate: bool)
+                   -> String {
+    let mut operands = Vec::new();
+    if let Some(ref first_argument) = arg.first_argument {
+        operands.push(format_operand(formatter, addressing, arg, first_argument, annotate));
+    }
+    if let Some(ref second_argument) = arg.second_argument {
+        operands.push(format_operand(formatter, addressing, arg, second_argument, annotate));
+    }
+    if let Some(ref third_argument) = arg.third_argument {
+        operands.push(format_operand(formatter, addressing, arg, third_argument, annotate));
+    }
+    if let Some(ref fourth_argument) = arg.fourth_argument {
+        operands.push(format_operand(formatter, addressing, arg, fourth_argument, annotate));
+    }
+    formatter.operands(operands)
+}
+
+/// VEX encoded mnemonics (AVX, BMI) carry no size suffix and their immediates are always 8 bit
+fn format_vector_instruction(formatter: &dyn InstructionFormatter,
+                             addressing: Addressing,
+                             mnemonic: &str,
+                             arg: &InstructionArguments)
+                             -> (String, String) {
+    let operands = [&arg.first_argument, &arg.second_argument, &arg.third_argument, &arg.fourth_argument]
+        .iter()
+        .filter_map(|argument| argument.as_ref())
+        .map(|argument| {
+            match *argument {
+                InstructionArgument::Immediate { immediate } => formatter.immediate(immediate, ArgumentSize::Bit8),
+                _ => format_operand(formatter, addressing, arg, argument, true),
+            }
+        })
+        .collect();
+    (formatter.mnemonic(mnemonic, None), formatter.operands(operands))
+}
+
+/// jumps and calls with an immediate are relative to the next instruction, show the target instead
+fn format_branch(formatter: &dyn InstructionFormatter,
+                 addressing: Addressing,
+                 mnemonic: &str,
+                 arg: &InstructionArguments,
+                 address: u64,
+                 length: u64)
+                 -> (String, String) {
+    let mnemonic = formatter.mnemonic(mnemonic, arg.explicit_size);
+    match arg.first_argument {
+        Some(InstructionArgument::Immediate { immediate }) => {
+            let target = address.wrapping_add(length).wrapping_add(immediate as u64);
+            (mnemonic, formatter.branch_target(target))
+        }
+        Some(ref argument) => (mnemonic, formatter.indirect(format_operand(formatter, addressing, arg, argument, true))),
+        None => (mnemonic, String::new()),
+    }
+}
+
+/// true if loop or jrcxz count with ecx because of the address size prefix
+fn counter_32bit(arg: &InstructionArguments) -> bool {
+    match arg.second_argument {
+        Some(InstructionArgument::Register { register: Register::ECX }) => true,
+        _ => false,
+    }
+}
+
+fn format_loop(formatter: &dyn InstructionFormatter,
+               addressing: Addressing,
+               mnemonic: &str,
+               arg: &InstructionArguments,
+               address: u64,
+               length: u64)
+               -> (String, String) {
+    let (_, target) = format_branch(formatter, addressing, mnemonic, arg, address, length);
+    let size = if counter_32bit(arg) { Some(ArgumentSize::Bit32) } else { None };
+    (formatter.mnemonic(mnemonic, size), target)
+}
+
+/// lret and iret only have a suffix if the operand size is not 32 bit
+fn far_size(arg: &InstructionArguments) -> Option<ArgumentSize> {
+    match arg.explicit_size {
+        Some(ArgumentSize::Bit32) => None,
+        size => size,
+    }
+}
+
+/// Indirect far call or jmp through a far pointer in memory, only the 16 bit
+/// pointer has a suffix
+fn format_far_branch(formatter: &dyn InstructionFormatter,
+                     addressing: Addressing,
+                     mnemonic: &str,
+                     arg: &InstructionArguments)
+                     -> (String, String) {
+    if let (&Some(InstructionArgument::Immediate { immediate: offset }),
+            &Some(InstructionArgument::Immediate { immediate: selector })) = (&arg.first_argument, &arg.second_argument) {
+        let target = formatter.far_pointer(formatter.immediate(selector, ArgumentSize::Bit16),
+                                           formatter.immediate(offset, arg.size()));
+        return (formatter.mnemonic(mnemonic, None), target);
+    }
+    let size = match arg.explicit_size {
+        Some(ArgumentSize::Bit16) => Some(ArgumentSize::Bit16),
+        _ => None,
+    };
+    let target = formatter.indirect(format_operand(formatter, addressing, arg, arg.get_one_argument(), true));
+    (formatter.mnemonic(mnemonic, size), target)
+}
+
+/// The port is an 8 bit immediate or dx, the other operand the accumulator
+fn format_port_instruction(formatter: &dyn InstructionFormatter,
+                           mnemonic: &str,
+                           arg: &InstructionArguments)
+                           -> (String, String) {
+    let operands = [&arg.first_argument, &arg.second_argument].iter()
+        .map(|argument| {
+            match **argument {
+                Some(InstructionArgument::Register { register: Register::DX }) => formatter.port(&Register::DX),
+                Some(InstructionArgument::Register { ref register }) => formatter.register(register),
+                Some(InstructionArgument::Immediate { immediate }) => formatter.immediate(immediate, ArgumentSize::Bit8),
+                _ => unreachable!(),
+            }
+        })
+        .collect();
+    (mnemonic.to_string(), formatter.operands(operands))
+}
+
+/// The operands are (%rsi), (%rdi), the accumulator or the port in dx, source first
+fn format_string_instruction(formatter: &dyn InstructionFormatter,
+                             addressing: Addressing,
+                             mnemonic: &str,
+                             arg: &InstructionArguments,
+                             sized_mnemonic: bool)
+                             -> (String, String) {
+    let size = arg.explicit_size.unwrap_or(ArgumentSize::Bit8);
+    let repeat = if arg.repeat_not_equal {
+        "repnz "
+    } else if arg.repeat_equal {
+        match mnemonic {
+            // scas and cmps compare, rep is repz for them
+            "scas" | "cmps" => "repz ",
+            _ => "rep ",
+        }
+    } else {
+        ""
+    };
+    if let Some(mnemonic) = formatter.string_instruction(mnemonic, size) {
+        return (repeat.to_owned() + &mnemonic, String::new());
+    }
+
+    let memory_size = if formatter.annotate_memory_size(true) { Some(size) } else { None };
+    let operands = [&arg.first_argument, &arg.second_argument].iter()
+        .map(|argument| {
+            match **argument {
+                Some(InstructionArgument::Register { register: Register::DX }) => formatter.port(&Register::DX),
+                Some(InstructionArgument::Register { ref register }) => formatter.register(register),
+                Some(ref address) => {
+                    // rdi is always relative to es, the segment of rsi can be overridden
+                    let segment = match *address {
+                        InstructionArgument::EffectiveAddress { base: Some(Register::RDI), .. } |
+                        InstructionArgument::EffectiveAddress { base: Some(Register::EDI), .. } |
+                        InstructionArgument::EffectiveAddress { base: Some(Register::DI), .. } => Register::ES,
+                        _ => addressing.segment.unwrap_or(Register::DS),
+                    };
+                    formatter.memory(Some(segment), address, memory_size)
+                }
+                None => panic!("String instructions have two operands"),
+            }
+        })
+        .collect();
+    let mnemonic = formatter.mnemonic(mnemonic, if sized_mnemonic { Some(size) } else { None });
+    (repeat.to_owned() + &mnemonic, formatter.operands(operands))
+}
+
+/// Mnemonic and operands of a decoded instruction, the address is needed to resolve
+/// relative jump targets, the code size for the address size of memory operands.
+pub fn format_instruction(formatter: &dyn InstructionFormatter,
+                          decoded: &InstructionCache,
+                          code_size: CodeSize,
+                          address: u64)
+                          -> (String, String) {
+    let (mnemonic, operands) = format_without_lock(formatter, decoded, code_size, address);
+    if decoded.prefixes.lock {
+        ("lock ".to_owned() + &mnemonic, operands)
+    } else {
+        (mnemonic, operands)
+    }
+}
+
+fn format_without_lock(formatter: &dyn InstructionFormatter,
+                       decoded: &InstructionCache,
+                       code_size: CodeSize,
+                       address: u64)
+                       -> (String, String) {
+    let length = decoded.size;
+    let addressing = Addressing::new(&decoded.prefixes, code_size);
+    let arg = match decoded.arguments.as_ref() {
+        Some(arg) => arg,
+        None => {
+            let mnemonic = match decoded.instruction {
+                Instruction::Cld => "cld",
+                Instruction::Cli => "cli",
+                Instruction::Cpuid => "cpuid",
+                Instruction::Hlt => "hlt",
+                Instruction::Int3 => "int3",
+                Instruction::Leave => "leave",
+                Instruction::Nop => "nop",
+                Instruction::Popf => "popf",
+                Instruction::Pushf => "pushf",
+                Instruction::Rdmsr => "rdmsr",
+                Instruction::Rdtsc => "rdtsc",
+                Instruction::Rdtscp => "rdtscp",
+                Instruction::Ret => "ret",
+                Instruction::Std => "std",
+                Instruction::Sti => "sti",
+                Instruction::Swapgs => "swapgs",
+                Instruction::Syscall => "syscall",
+                Instruction::Wrmsr => "wrmsr",
+                Instruction::Vzeroall => "vzeroall",
+                Instruction::Vzeroupper => "vzeroupper",
+                Instruction::Xgetbv => "xgetbv",
+                Instruction::Xsetbv => "xsetbv",
+                _ => "(bad)",
+            };
+            return (mnemonic.to_string(), String::new());
+        }
+    };
+
+    let mnemonic = match decoded.instruction {
+        Instruction::Adc => "adc",
+        Instruction::Add => "add",
+        Instruction::And => "and",
+        Instruction::Andn => return format_vector_instruction(formatter, addressing, "andn", arg),
+        Instruction::Arithmetic => {
+            match arg.opcode {
+                Some(0) => "add",
+                Some(1) => "or",
+                Some(2) => "adc",
+                Some(3) => "sbb",
+                Some(4) => "and",
+                Some(5) => "sub",
+                Some(6) => "xor",
+                Some(7) => "cmp",
+                _ => "(bad)",
+            }
+        }
+        Instruction::Bextr => return format_vector_instruction(formatter, addressing, "bextr", arg),
+        Instruction::BitManipulation => {
+            match arg.opcode {
+                Some(4) => "bt",
+                Some(5) => "bts",
+                Some(6) => "btr",
+                Some(7) => "btc",
+                _ => "(bad)",
+            }
+        }
+        Instruction::Blsi => return format_vector_instruction(formatter, addressing, "blsi", arg),
+        Instruction::Blsmsk => return format_vector_instruction(formatter, addressing, "blsmsk", arg),
+        Instruction::Blsr => return format_vector_instruction(formatter, addressing, "blsr", arg),
+        Instruction::Bsf => "bsf",
+        Instruction::Bsr => "bsr",
+        Instruction::Bswap => "bswap",
+        Instruction::Bt => "bt",
+        Instruction::Bts => "bts",
+        Instruction::Btr => "btr",
+        Instruction::Btc => "btc",
+        Instruction::Bzhi => return format_vector_instruction(formatter, addressing, "bzhi", arg),
+        Instruction::Call => return format_branch(formatter, addressing, "call", arg, address, length),
+        Instruction::Cmova => "cmova",
+        Instruction::Cmovae => "cmovae",
+        Instruction::Cmovb => "cmovb",
+        Instruction::Cmovbe => "cmovbe",
+        Instruction::Cmove => "cmove",
+        Instruction::Cmovg => "cmovg",
+        Instruction::Cmovge => "cmovge",
+        Instruction::Cmovl => "cmovl",
+        Instruction::Cmovle => "cmovle",
+        Instruction::Cmovne => "cmovne",
+        Instruction::Cmovno => "cmovno",
+        Instruction::Cmovnp => "cmovnp",
+        Instruction::Cmovns => "cmovns",
+        Instruction::Cmovo => "cmovo",
+        Instruction::Cmovp => "cmovp",
+        Instruction::Cmovs => "cmovs",
+        Instruction::Cmp => "cmp",
+        Instruction::Cmps => return format_string_instruction(formatter, addressing, "cmps", arg, true),
+        Instruction::Cmpxchg => "cmpxchg",
+        Instruction::Cmpxchg8b => {
+            let mnemonic = match arg.explicit_size {
+                Some(ArgumentSize::Bit64) => "cmpxchg16b",
+                _ => "cmpxchg8b",
+            };
+            return (mnemonic.to_string(), format_operands(formatter, addressing, arg, false));
+        }
+        Instruction::CompareMulOperation => {
+            match arg.opcode {
+                Some(0) | Some(1) => "test",
+                Some(2) => "not",
+                Some(3) => "neg",
+                Some(4) => "mul",
+                Some(5) => "imul",
+                Some(6) => "div",
+                Some(7) => "idiv",
+                _ => "(bad)",
+            }
+        }
+        Instruction::Dec => "dec",
+        Instruction::Enter => {
+            let (size, level) = match (&arg.first_argument, &arg.second_argument) {
+                (&Some(InstructionArgument::Immediate { immediate: size }),
+                 &Some(InstructionArgument::Immediate { immediate: level })) => (size, level),
+                _ => unreachable!(),
+            };
+            // the frame size comes first in both syntaxes
+            let operands = format!("{},{}", formatter.immediate(size, ArgumentSize::Bit16),
+                                   formatter.immediate(level, ArgumentSize::Bit8));
+            return ("enter".to_string(), operands);
+        }
+        Instruction::Imul => "imul",
+        Instruction::In => return format_port_instruction(formatter, "in", arg),
+        Instruction::Inc => "inc",
+        Instruction::Ins => return format_string_instruction(formatter, addressing, "ins", arg, true),
+        Instruction::Int => {
+            let vector = match *arg.get_one_argument() {
+                InstructionArgument::Immediate { immediate } => formatter.immediate(immediate, ArgumentSize::Bit8),
+                _ => unreachable!(),
+            };
+            return ("int".to_string(), vector);
+        }
+        Instruction::Iret => return (formatter.mnemonic("iret", far_size(arg)), String::new()),
+        Instruction::Ja => return format_branch(formatter, addressing, "ja", arg, address, length),
+        Instruction::Jae => return format_branch(formatter, addressing, "jae", arg, address, length),
+        Instruction::Jb => return format_branch(formatter, addressing, "jb", arg, address, length),
+        Instruction::Jbe => return format_branch(formatter, addressing, "jbe", arg, address, length),
+        Instruction::Je => return format_branch(formatter, addressing, "je", arg, address, length),
+        Instruction::Jg => return format_branch(formatter, addressing, "jg", arg, address, length),
+        Instruction::Jge => return format_branch(formatter, addressing, "jge", arg, address, length),
+        Instruction::Jl => return format_branch(formatter, addressing, "jl", arg, address, length),
+        Instruction::Jle => return format_branch(formatter, addressing, "jle", arg, address, length),
+        Instruction::Jmp => return format_branch(formatter, addressing, "jmp", arg, address, length),
+        Instruction::Jne => return format_branch(formatter, addressing, "jne", arg, address, length),
+        Instruction::Jno => return format_branch(formatter, addressing, "jno", arg, address, length),
+        Instruction::Jnp => return format_branch(formatter, addressing, "jnp", arg, address, length),
+        Instruction::Jns => return format_branch(formatter, addressing, "jns", arg, address, length),
+        Instruction::Jo => return format_branch(formatter, addressing, "jo", arg, address, length),
+        Instruction::Jp => return format_branch(formatter, addressing, "jp", arg, address, length),
+        Instruction::Jrcxz => {
+            let mnemonic = if counter_32bit(arg) { "jecxz" } else { "jrcxz" };
+            return format_branch(formatter, addressing, mnemonic, arg, address, length);
+        }
+        Instruction::Js => return format_branch(formatter, addressing, "js", arg, address, length),
+        Instruction::Lcall => return format_far_branch(formatter, addressing, "lcall", arg),
+        Instruction::Lea => {
+            return (formatter.mnemonic("lea", arg.explicit_size), format_operands(formatter, addressing, arg, false));
+        }
+        Instruction::Lgdt => return ("lgdt".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Lidt => return ("lidt".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Ljmp => return format_far_branch(formatter, addressing, "ljmp", arg),
+        Instruction::Lldt => return ("lldt".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Lods => return format_string_instruction(formatter, addressing, "lods", arg, false),
+        Instruction::Loop => return format_loop(formatter, addressing, "loop", arg, address, length),
+        Instruction::Loope => return format_loop(formatter, addressing, "loope", arg, address, length),
+        Instruction::Loopne => return format_loop(formatter, addressing, "loopne", arg, address, length),
+        Instruction::Ltr => return ("ltr".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Lret => {
+            let mnemonic = formatter.mnemonic("lret", far_size(arg));
+            return match arg.first_argument {
+                Some(InstructionArgument::Immediate { immediate }) => {
+                    (mnemonic, formatter.immediate(immediate, ArgumentSize::Bit16))
+                }
+                _ => (mnemonic, String::new()),
+            };
+        }
+        Instruction::Lzcnt => "lzcnt",
+        Instruction::Mov => "mov",
+        Instruction::Movs => return format_string_instruction(formatter, addressing, "movs", arg, true),
+        Instruction::Movsx => return ("movsx".to_string(), format_operands(formatter, addressing, arg, true)),
+        Instruction::Movzx => return ("movzx".to_string(), format_operands(formatter, addressing, arg, true)),
+        Instruction::Mulx => return format_vector_instruction(formatter, addressing, "mulx", arg),
+        Instruction::Or => "or",
+        Instruction::Out => return format_port_instruction(formatter, "out", arg),
+        Instruction::Outs => return format_string_instruction(formatter, addressing, "outs", arg, true),
+        Instruction::Pdep => return format_vector_instruction(formatter, addressing, "pdep", arg),
+        Instruction::Pext => return format_vector_instruction(formatter, addressing, "pext", arg),
+        // objdump only shows the size of memory operands
+        Instruction::Pop => return (formatter.mnemonic("pop", None), format_operands(formatter, addressing, arg, true)),
+        Instruction::Popcnt => "popcnt",
+        Instruction::Push => return (formatter.mnemonic("push", None), format_operands(formatter, addressing, arg, true)),
+        Instruction::Rdrand => "rdrand",
+        Instruction::Rdseed => "rdseed",
+        Instruction::RegisterOperation => {
+            match arg.opcode {
+                Some(0) => "inc",
+                Some(1) => "dec",
+                Some(2) => return format_branch(formatter, addressing, "call", arg, address, length),
+                Some(4) => return format_branch(formatter, addressing, "jmp", arg, address, length),
+                Some(6) => "push",
+                _ => "(bad)",
+            }
+        }
+        Instruction::Ret => {
+            let immediate = match *arg.get_one_argument() {
+                InstructionArgument::Immediate { immediate } => formatter.immediate(immediate, ArgumentSize::Bit16),
+                _ => unreachable!(),
+            };
+            return ("ret".to_string(), immediate);
+        }
+        Instruction::Rorx => return format_vector_instruction(formatter, addressing, "rorx", arg),
+        Instruction::Sarx => return format_vector_instruction(formatter, addressing, "sarx", arg),
+        Instruction::Sbb => "sbb",
+        Instruction::Scas => return format_string_instruction(formatter, addressing, "scas", arg, false),
+        Instruction::Seta => "seta",
+        Instruction::Setae => "setae",
+        Instruction::Setb => "setb",
+        Instruction::Setbe => "setbe",
+        Instruction::Sete => "sete",
+        Instruction::Setg => "setg",
+        Instruction::Setge => "setge",
+        Instruction::Setl => "setl",
+        Instruction::Setle => "setle",
+        Instruction::Setne => "setne",
+        Instruction::Setno => "setno",
+        Instruction::Setnp => "setnp",
+        Instruction::Setns => "setns",
+        Instruction::Seto => "seto",
+        Instruction::Setp => "setp",
+        Instruction::Sets => "sets",
+        Instruction::ShiftRotate => {
+            match arg.opcode {
+                Some(0) => "rol",
+                Some(1) => "ror",
+                Some(2) => "rcl",
+                Some(3) => "rcr",
+                Some(4) | Some(6) => "shl", // sal and shl are the same
+                Some(5) => "shr",
+                Some(7) => "sar",
+                _ => "(bad)",
+            }
+        }
+        Instruction::Shlx => return format_vector_instruction(formatter, addressing, "shlx", arg),
+        Instruction::Shrx => return format_vector_instruction(formatter, addressing, "shrx", arg),
+        Instruction::Sldt => return ("sldt".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Stos => return format_string_instruction(formatter, addressing, "stos", arg, false),
+        Instruction::Str => return ("str".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Sub => "sub",
+        Instruction::Sysret => {
+            // sysret returns to 64 bit code with REX.W and to 32 bit code otherwise
+            let size = match arg.explicit_size {
+                Some(ArgumentSize::Bit64) => ArgumentSize::Bit64,
+                _ => ArgumentSize::Bit32,
+            };
+            return (formatter.mnemonic("sysret", Some(size)), String::new());
+        }
+        Instruction::Test => "test",
+        Instruction::Tzcnt => "tzcnt",
+        Instruction::Vaddpd => return format_vector_instruction(formatter, addressing, "vaddpd", arg),
+        Instruction::Vaddps => return format_vector_instruction(formatter, addressing, "vaddps", arg),
+        Instruction::Vaddsd => return format_vector_instruction(formatter, addressing, "vaddsd", arg),
+        Instruction::Vaddss => return format_vector_instruction(formatter, addressing, "vaddss", arg),
+        Instruction::Vandnpd => return format_vector_instruction(formatter, addressing, "vandnpd", arg),
+        Instruction::Vandnps => return format_vector_instruction(formatter, addressing, "vandnps", arg),
+        Instruction::Vandpd => return format_vector_instruction(formatter, addressing, "vandpd", arg),
+        Instruction::Vandps => return format_vector_instruction(formatter, addressing, "vandps", arg),
+        Instruction::Vbroadcastsd => return format_vector_instruction(formatter, addressing, "vbroadcastsd", arg),
+        Instruction::Vbroadcastss => return format_vector_instruction(formatter, addressing, "vbroadcastss", arg),
+        Instruction::Vdivpd => return format_vector_instruction(formatter, addressing, "vdivpd", arg),
+        Instruction::Vdivps => return format_vector_instruction(formatter, addressing, "vdivps", arg),
+        Instruction::Vdivsd => return format_vector_instruction(formatter, addressing, "vdivsd", arg),
+        Instruction::Vdivss => return format_vector_instruction(formatter, addressing, "vdivss", arg),
+        Instruction::Vextracti128 => return format_vector_instruction(formatter, addressing, "vextracti128", arg),
+        Instruction::Vinserti128 => return format_vector_instruction(formatter, addressing, "vinserti128", arg),
+        Instruction::Vmaxpd => return format_vector_instruction(formatter, addressing, "vmaxpd", arg),
+        Instruction::Vmaxps => return format_vector_instruction(formatter, addressing, "vmaxps", arg),
+        Instruction::Vmaxsd => return format_vector_instruction(formatter, addressing, "vmaxsd", arg),
+        Instruction::Vmaxss => return format_vector_instruction(formatter, addressing, "vmaxss", arg),
+        Instruction::Vminpd => return format_vector_instruction(formatter, addressing, "vminpd", arg),
+        Instruction::Vminps => return format_vector_instruction(formatter, addressing, "vminps", arg),
+        Instruction::Vminsd => return format_vector_instruction(formatter, addressing, "vminsd", arg),
+        Instruction::Vminss => return format_vector_instruction(formatter, addressing, "vminss", arg),
+        Instruction::Vmovapd => return format_vector_instruction(formatter, addressing, "vmovapd", arg),
+        Instruction::Vmovaps => return format_vector_instruction(formatter, addressing, "vmovaps", arg),
+        Instruction::Vmovd => return format_vector_instruction(formatter, addressing, "vmovd", arg),
+        Instruction::Vmovdqa => return format_vector_instruction(formatter, addressing, "vmovdqa", arg),
+        Instruction::Vmovdqu => return format_vector_instruction(formatter, addressing, "vmovdqu", arg),
+        Instruction::Vmovq => return format_vector_instruction(formatter, addressing, "vmovq", arg),
+        Instruction::Vmovupd => return format_vector_instruction(formatter, addressing, "vmovupd", arg),
+        Instruction::Vmovups => return format_vector_instruction(formatter, addressing, "vmovups", arg),
+        Instruction::Vmulpd => return format_vector_instruction(formatter, addressing, "vmulpd", arg),
+        Instruction::Vmulps => return format_vector_instruction(formatter, addressing, "vmulps", arg),
+        Instruction::Vmulsd => return format_vector_instruction(formatter, addressing, "vmulsd", arg),
+        Instruction::Vmulss => return format_vector_instruction(formatter, addressing, "vmulss", arg),
+        Instruction::Vorpd => return format_vector_instruction(formatter, addressing, "vorpd", arg),
+        Instruction::Vorps => return format_vector_instruction(formatter, addressing, "vorps", arg),
+        Instruction::Vpaddb => return format_vector_instruction(formatter, addressing, "vpaddb", arg),
+        Instruction::Vpaddd => return format_vector_instruction(formatter, addressing, "vpaddd", arg),
+        Instruction::Vpaddq => return format_vector_instruction(formatter, addressing, "vpaddq", arg),
+        Instruction::Vpaddw => return format_vector_instruction(formatter, addressing, "vpaddw", arg),
+        Instruction::Vpand => return format_vector_instruction(formatter, addressing, "vpand", arg),
+        Instruction::Vpandn => return format_vector_instruction(formatter, addressing, "vpandn", arg),
+        Instruction::Vpbroadcastb => return format_vector_instruction(formatter, addressing, "vpbroadcastb", arg),
+        Instruction::Vpbroadcastd => return format_vector_instruction(formatter, addressing, "vpbroadcastd", arg),
+        Instruction::Vpbroadcastq => return format_vector_instruction(formatter, addressing, "vpbroadcastq", arg),
+        Instruction::Vpbroadcastw => return format_vector_instruction(formatter, addressing, "vpbroadcastw", arg),
+        Instruction::Vpcmpeqb => return format_vector_instruction(formatter, addressing, "vpcmpeqb", arg),
+        Instruction::Vpcmpeqd => return format_vector_instruction(formatter, addressing, "vpcmpeqd", arg),
+        Instruction::Vpcmpeqq => return format_vector_instruction(formatter, addressing, "vpcmpeqq", arg),
+        Instruction::Vpcmpeqw => return format_vector_instruction(formatter, addressing, "vpcmpeqw", arg),
+        Instruction::Vpcmpgtb => return format_vector_instruction(formatter, addressing, "vpcmpgtb", arg),
+        Instruction::Vpcmpgtd => return format_vector_instruction(formatter, addressing, "vpcmpgtd", arg),
+        Instruction::Vpcmpgtq => return format_vector_instruction(formatter, addressing, "vpcmpgtq", arg),
+        Instruction::Vpcmpgtw => return format_vector_instruction(formatter, addressing, "vpcmpgtw", arg),
+        Instruction::Vperm2i128 => return format_vector_instruction(formatter, addressing, "vperm2i128", arg),
+        Instruction::Vpmaxub => return format_vector_instruction(formatter, addressing, "vpmaxub", arg),
+        Instruction::Vpminub => return format_vector_instruction(formatter, addressing, "vpminub", arg),
+        Instruction::Vpmovmskb => return format_vector_instruction(formatter, addressing, "vpmovmskb", arg),
+        Instruction::Vpor => return format_vector_instruction(formatter, addressing, "vpor", arg),
+        Instruction::Vpshufb => return format_vector_instruction(formatter, addressing, "vpshufb", arg),
+        Instruction::Vpshufd => return format_vector_instruction(formatter, addressing, "vpshufd", arg),
+        Instruction::Vpsubb => return format_vector_instruction(formatter, addressing, "vpsubb", arg),
+        Instruction::Vpsubd => return format_vector_instruction(formatter, addressing, "vpsubd", arg),
+        Instruction::Vpsubq => return format_vector_instruction(formatter, addressing, "vpsubq", arg),
+        Instruction::Vpsubw => return format_vector_instruction(formatter, addressing, "vpsubw", arg),
+        Instruction::Vptest => return format_vector_instruction(formatter, addressing, "vptest", arg),
+        Instruction::Vpxor => return format_vector_instruction(formatter, addressing, "vpxor", arg),
+        Instruction::Vsqrtpd => return format_vector_instruction(formatter, addressing, "vsqrtpd", arg),
+        Instruction::Vsqrtps => return format_vector_instruction(formatter, addressing, "vsqrtps", arg),
+        Instruction::Vsqrtsd => return format_vector_instruction(formatter, addressing, "vsqrtsd", arg),
+        Instruction::Vsqrtss => return format_vector_instruction(formatter, addressing, "vsqrtss", arg),
+        Instruction::Vsubpd => return format_vector_instruction(formatter, addressing, "vsubpd", arg),
+        Instruction::Vsubps => return format_vector_instruction(formatter, addressing, "vsubps", arg),
+        Instruction::Vsubsd => return format_vector_instruction(formatter, addressing, "vsubsd", arg),
+        Instruction::Vsubss => return format_vector_instruction(formatter, addressing, "vsubss", arg),
+        Instruction::Vxorpd => return format_vector_instruction(formatter, addressing, "vxorpd", arg),
+        Instruction::Vxorps => return format_vector_instruction(formatter, addressing, "vxorps", arg),
+        Instruction::Xadd => "xadd",
+        Instruction::Xchg => "xchg",
+        Instruction::Xor => "xor",
+        Instruction::Xrstor => return ("xrstor".to_string(), format_operands(formatter, addressing, arg, false)),
+        Instruction::Xsave => return ("xsave".to_string(), format_operands(formatter, addressing, arg, false)),
+        _ => "(bad)",
+    };
+    (formatter.mnemonic(mnemonic, arg.explicit_size), format_operands(formatter, addressing, arg, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formatter::{NasmFormatter, MasmFormatter};
+
+    fn lines(instructions: Vec<DecodedInstruction>) -> Vec<String> {
+        instructions.iter().map(|instruction| instruction.text().trim_end().to_string()).collect()
+    }
+
+    fn att(bytes: &[u8]) -> Vec<String> {
+        lines(disassemble(bytes, 0x1000).collect())
+    }
+
+    fn nasm(bytes: &[u8]) -> Vec<String> {
+        lines(disassemble(bytes, 0x1000).formatter(Box::new(NasmFormatter)).collect())
+    }
+
+    fn masm(bytes: &[u8]) -> Vec<String> {
+        lines(disassemble(bytes, 0x1000).formatter(Box::new(MasmFormatter)).collect())
+    }
+
+    #[test]
+    fn att_syntax() {
+        assert_eq!(att(&[0x48, 0x89, 0xc3]), ["mov    %rax,%rbx"]);
+        assert_eq!(att(&[0xb9, 0x34, 0x12, 0x00, 0x00]), ["mov    $0x1234,%ecx"]);
+        assert_eq!(att(&[0x03, 0x45, 0x10]), ["add    0x10(%rbp),%eax"]);
+        assert_eq!(att(&[0x48, 0x8d, 0x54, 0x8c, 0xf8]), ["lea    -0x8(%rsp,%rcx,4),%rdx"]);
+        assert_eq!(att(&[0x41, 0x5c]), ["pop    %r12"]);
+        assert_eq!(att(&[0x48, 0x6b, 0xc2, 0x10]), ["imul   $0x10,%rdx,%rax"]);
+        assert_eq!(att(&[0x48, 0x83, 0x7b, 0x08, 0x05]), ["cmpq   $0x5,0x8(%rbx)"]);
+        assert_eq!(att(&[0x48, 0xc7, 0x00, 0xff, 0xff, 0xff, 0xff]), ["movq   $0xffffffffffffffff,0x0(%rax)"]);
+        assert_eq!(att(&[0x8a, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]), ["mov    0x28,%al"]);
+        assert_eq!(att(&[0xf3, 0x48, 0x0f, 0xb8, 0xd1]), ["popcnt %rcx,%rdx"]);
+        assert_eq!(att(&[0xec, 0xe6, 0x80]), ["in     (%dx),%al", "out    %al,$0x80"]);
+        assert_eq!(att(&[0xcd, 0x80, 0xf4, 0xc9]), ["int    $0x80", "hlt", "leave"]);
+    }
+
+    #[test]
+    fn branch_targets() {
+        // relative to the next instruction
+        assert_eq!(att(&[0xeb, 0xfe]), ["jmp    0x1000"]);
+        assert_eq!(att(&[0xe8, 0xfb, 0xff, 0xff, 0xff]), ["call   0x1000"]);
+        assert_eq!(att(&[0x90, 0x74, 0x02]), ["nop", "je     0x1005"]);
+        assert_eq!(att(&[0xff, 0x50, 0x08]), ["call   *0x8(%rax)"]);
+    }
+
+    #[test]
+    fn string_instructions() {
+        assert_eq!(att(&[0xf3, 0xa4]), ["rep movsb %ds:(%rsi),%es:(%rdi)"]);
+        assert_eq!(att(&[0xf3, 0x48, 0xab]), ["rep stos %rax,%es:(%rdi)"]);
+        assert_eq!(att(&[0xf2, 0xae]), ["repnz scas %es:(%rdi),%al"]);
+        assert_eq!(nasm(&[0xf3, 0xa4, 0xf3, 0x48, 0xab]), ["rep movsb", "rep stosq"]);
+        assert_eq!(masm(&[0xf3, 0xa4]), ["rep movs BYTE PTR es:[rdi],BYTE PTR ds:[rsi]"]);
+    }
+
+    #[test]
+    fn intel_syntax() {
+        assert_eq!(nasm(&[0x03, 0x45, 0x10]), ["add    eax,[rbp+0x10]"]);
+        assert_eq!(nasm(&[0x48, 0x8d, 0x54, 0x8c, 0xf8]), ["lea    rdx,[rsp+rcx*4-0x8]"]);
+        assert_eq!(nasm(&[0x48, 0x83, 0x7b, 0x08, 0x05]), ["cmp    qword [rbx+0x8],0x5"]);
+        assert_eq!(masm(&[0x03, 0x45, 0x10]), ["add    eax,DWORD PTR [rbp+0x10]"]);
+        assert_eq!(masm(&[0x48, 0x8d, 0x54, 0x8c, 0xf8]), ["lea    rdx,[rsp+rcx*4-0x8]"]);
+        assert_eq!(masm(&[0x8a, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]), ["mov    al,BYTE PTR ds:0x28"]);
+    }
+
+    #[test]
+    fn segment_overrides() {
+        let bytes = [0x64, 0x8a, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00];
+        assert_eq!(att(&bytes), ["mov    %fs:0x28,%al"]);
+        assert_eq!(nasm(&bytes), ["mov    al,[fs:0x28]"]);
+        assert_eq!(masm(&bytes), ["mov    al,BYTE PTR fs:0x28"]);
+        let bytes = [0x64, 0x48, 0x8b, 0x43, 0x08];
+        assert_eq!(att(&bytes), ["mov    %fs:0x8(%rbx),%rax"]);
+        assert_eq!(nasm(&bytes), ["mov    rax,[fs:rbx+0x8]"]);
+        assert_eq!(masm(&bytes), ["mov    rax,QWORD PTR fs:[rbx+0x8]"]);
+        // only the source of string instructions can be overridden
+        assert_eq!(att(&[0x65, 0xa4]), ["movsb  %gs:(%rsi),%es:(%rdi)"]);
+        assert_eq!(masm(&[0x65, 0xa4]), ["movs   BYTE PTR es:[rdi],BYTE PTR gs:[rsi]"]);
+    }
+
+    #[test]
+    fn absolute_addresses() {
+        // the displacement is sign extended to the address size
+        let bytes = [0x8a, 0x04, 0x25, 0x80, 0xff, 0xff, 0xff];
+        assert_eq!(att(&bytes), ["mov    0xffffffffffffff80,%al"]);
+        assert_eq!(nasm(&bytes), ["mov    al,[0xffffffffffffff80]"]);
+        assert_eq!(masm(&bytes), ["mov    al,BYTE PTR ds:0xffffffffffffff80"]);
+        assert_eq!(att(&[0x67, 0x8a, 0x04, 0x25, 0x80, 0xff, 0xff, 0xff]), ["mov    0xffffff80,%al"]);
+        let instructions: Vec<DecodedInstruction> = disassemble(&[0x8a, 0x05, 0x80, 0xff, 0xff, 0xff], 0)
+            .code_size(CodeSize::Bit32)
+            .formatter(Box::new(MasmFormatter))
+            .collect();
+        assert_eq!(lines(instructions), ["mov    al,BYTE PTR ds:0xffffff80"]);
+    }
+
+    #[test]
+    fn memory_operand_sizes() {
+        // nasm needs a size if no register operand tells it
+        assert_eq!(nasm(&[0xff, 0x33]), ["push   qword [rbx]"]);
+        assert_eq!(nasm(&[0xff, 0x50, 0x08]), ["call   qword [rax+0x8]"]);
+        assert_eq!(nasm(&[0x48, 0x8b, 0x03]), ["mov    rax,[rbx]"]);
+        assert_eq!(masm(&[0xff, 0x33]), ["push   QWORD PTR [rbx]"]);
+        assert_eq!(att(&[0xff, 0x33]), ["push   0x0(%rbx)"]);
+    }
+
+    #[test]
+    fn lock_prefix() {
+        assert_eq!(att(&[0xf0, 0x48, 0x01, 0x03]), ["lock add %rax,0x0(%rbx)"]);
+        assert_eq!(nasm(&[0xf0, 0x0f, 0xc1, 0x03]), ["lock xadd [rbx],eax"]);
+        assert_eq!(masm(&[0xf0, 0x48, 0x0f, 0xc7, 0x0f]), ["lock cmpxchg16b [rdi]"]);
+    }
+
+    #[test]
+    fn code_sizes() {
+        let bytes = [0x40, 0x66, 0xb8, 0x34, 0x12, 0x8b, 0x45, 0x08, 0xc3];
+        let instructions: Vec<DecodedInstruction> = disassemble(&bytes, 0).code_size(CodeSize::Bit32).collect();
+        assert_eq!(lines(instructions), ["inc    %eax", "mov    $0x1234,%ax", "mov    0x8(%ebp),%eax", "ret"]);
+        let bytes = [0x40, 0xb8, 0x34, 0x12, 0x8b, 0x46, 0x08, 0x66, 0x50];
+        let instructions: Vec<DecodedInstruction> = disassemble(&bytes, 0).code_size(CodeSize::Bit16).collect();
+        assert_eq!(lines(instructions), ["inc    %ax", "mov    $0x1234,%ax", "mov    0x8(%bp),%ax", "push   %eax"]);
+    }
+
+    #[test]
+    fn addresses_and_prefixes() {
+        let instructions: Vec<DecodedInstruction> = disassemble(&[0x90, 0xf3, 0x48, 0xab, 0xc3], 0x400000).collect();
+        let addresses: Vec<u64> = instructions.iter().map(|instruction| instruction.address).collect();
+        assert_eq!(addresses, [0x400000, 0x400001, 0x400004]);
+        assert_eq!(instructions[1].bytes, [0xf3, 0x48, 0xab]);
+        assert_eq!(instructions[1].prefixes, [0xf3, 0x48]);
+        assert_eq!(instructions[1].length(), 3);
+        assert!(instructions[2].prefixes.is_empty());
+    }
+
+    #[test]
+    fn bad_bytes() {
+        // the add needs a displacement, objdump goes on with the next byte
+        let instructions: Vec<DecodedInstruction> = disassemble(&[0x00, 0x90, 0x66], 0).collect();
+        let addresses: Vec<u64> = instructions.iter().map(|instruction| instruction.address).collect();
+        assert_eq!(addresses, [0, 1, 2]);
+        assert_eq!(lines(instructions), ["(bad)", "nop", "(bad)"]);
+        assert_eq!(att(&[0x0f, 0xff, 0x00])[0], "(bad)");
+        assert!(att(&[]).is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+use fnv::FnvHashMap;
+
+/* Minimal reader for the DWARF line number program (.debug_line), versions 2 to 5.
+ * Only the address -> file/line mapping is decoded, everything else is skipped.
+ */
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LineRow {
+    pub address: u64,
+    /// index into LineTable::files
+    pub file: usize,
+    pub line: u64,
+    /// first address after a sequence of instructions, does not belong to a line
+    pub end_sequence: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    pub rows: Vec<LineRow>,
+}
+
+/// String sections a DWARF 5 line program header can reference.
+#[derive(Default)]
+pub struct StringSections<'a> {
+    pub debug_str: &'a [u8],
+    pub debug_line_str: &'a [u8],
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn u8(&mut self) -> u8 {
+        let value = self.data[self.position];
+        self.position += 1;
+        value
+    }
+
+    fn unsigned(&mut self, size: usize) -> u64 {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.data[self.position + i] as u64) << (i * 8);
+        }
+        self.position += size;
+        value
+    }
+
+    fn uleb128(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8();
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> i64 {
+        let mut value: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8();
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return value;
+            }
+        }
+    }
+
+    fn string(&mut self) -> String {
+        let value = null_terminated(&self.data[self.position..]);
+        self.position += value.len() + 1;
+        value
+    }
+
+    fn skip(&mut self, size: usize) {
+        self.position += size;
+    }
+}
+
+fn null_terminated(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn join_path(directory: &str, file: &str) -> String {
+    if directory.is_empty() || file.starts_with('/') {
+        file.to_string()
+    } else {
+        format!("{}/{}", directory, file)
+    }
+}
+
+pub fn parse_debug_line(debug_line: &[u8], strings: &StringSections) -> LineTable {
+    let mut table = LineTable::default();
+    let mut file_indices = FnvHashMap::default();
+    let mut reader = Reader::new(debug_line);
+    while !reader.at_end() {
+        let mut unit_length = reader.unsigned(4);
+        let mut offset_size = 4;
+        if unit_length == 0xffffffff {
+            unit_length = reader.unsigned(8);
+            offset_size = 8;
+        }
+        let unit_end = reader.position + unit_length as usize;
+        let unit = &debug_line[reader.position..unit_end];
+        parse_unit(unit, offset_size, strings, &mut table, &mut file_indices);
+        reader.position = unit_end;
+    }
+    table
+}
+
+fn parse_unit(unit: &[u8],
+              offset_size: usize,
+              strings: &StringSections,
+              table: &mut LineTable,
+              file_indices: &mut FnvHashMap<String, usize>) {
+    let mut reader = Reader::new(unit);
+    let version = reader.unsigned(2);
+    if version < 2 || version > 5 {
+        // unknown format, we cannot even find the start of the program
+        return;
+    }
+    if version >= 5 {
+        reader.skip(2); // address_size, segment_selector_size
+    }
+    let header_length = reader.unsigned(offset_size) as usize;
+    let program_start = reader.position + header_length;
+    let minimum_instruction_length = reader.u8() as u64;
+    if version >= 4 {
+        reader.skip(1); // maximum_operations_per_instruction, only used by VLIW architectures
+    }
+    reader.skip(1); // default_is_stmt
+    let line_base = reader.u8() as i8 as i64;
+    let line_range = reader.u8() as u64;
+    let opcode_base = reader.u8();
+    let mut standard_opcode_lengths = Vec::new();
+    for _ in 1..opcode_base {
+        standard_opcode_lengths.push(reader.u8());
+    }
+
+    // paths of the files of this unit, indexed by the file register of the state machine
+    let mut files = Vec::new();
+    if version >= 5 {
+        let directories = parse_entries(&mut reader, offset_size, strings, &[]);
+        files = parse_entries(&mut reader, offset_size, strings, &directories);
+    } else {
+        let mut directories = vec![String::new()];
+        loop {
+            let directory = reader.string();
+            if directory.is_empty() {
+                break;
+            }
+            directories.push(directory);
+        }
+        // file numbers start with 1 before DWARF 5
+        files.push(String::new());
+        loop {
+            let file = reader.string();
+            if file.is_empty() {
+                break;
+            }
+            let directory = reader.uleb128() as usize;
+            reader.uleb128(); // modification time
+            reader.uleb128(); // file size
+            files.push(join_path(directories.get(directory).map_or("", |d| d), &file));
+        }
+    }
+
+    // rows refer to the files of this unit until the end
+    let mut rows = Vec::new();
+    reader.position = program_start;
+    let mut address = 0;
+    let mut file = 1;
+    let mut line: i64 = 1;
+    while !reader.at_end() {
+        let opcode = reader.u8();
+        if opcode >= opcode_base {
+            let adjusted = (opcode - opcode_base) as u64;
+            address += (adjusted / line_range) * minimum_instruction_length;
+            line += line_base + (adjusted % line_range) as i64;
+            rows.push(LineRow {
+                address: address,
+                file: file as usize,
+                line: line as u64,
+                end_sequence: false,
+            });
+            continue;
+        }
+        match opcode {
+            0 => {
+                let length = reader.uleb128() as usize;
+                let end = reader.position + length;
+                match reader.u8() {
+                    DW_LNE_END_SEQUENCE => {
+                        rows.push(LineRow {
+                            address: address,
+                            file: file as usize,
+                            line: line as u64,
+                            end_sequence: true,
+                        });
+                        address = 0;
+                        file = 1;
+                        line = 1;
+                    }
+                    DW_LNE_SET_ADDRESS => address = reader.unsigned(length - 1),
+                    DW_LNE_DEFINE_FILE => {
+                        let name = reader.string();
+                        reader.uleb128(); // directory index, the directories are gone by now
+                        files.push(name);
+                    }
+                    _ => (),
+                }
+                reader.position = end;
+            }
+            DW_LNS_COPY => {
+                rows.push(LineRow {
+                    address: address,
+                    file: file as usize,
+                    line: line as u64,
+                    end_sequence: false,
+                });
+            }
+            DW_LNS_ADVANCE_PC => address += reader.uleb128() * minimum_instruction_length,
+            DW_LNS_ADVANCE_LINE => line += reader.sleb128(),
+            DW_LNS_SET_FILE => file = reader.uleb128(),
+            DW_LNS_CONST_ADD_PC => {
+                address += ((255 - opcode_base) as u64 / line_range) * minimum_instruction_length
+            }
+            DW_LNS_FIXED_ADVANCE_PC => address += reader.unsigned(2),
+            _ => {
+                // set_column, negate_stmt, set_basic_block, prologue/epilogue, isa and opcodes we do not know
+                for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                    reader.uleb128();
+                }
+            }
+        }
+    }
+
+    let mut unit_files = Vec::new();
+    for path in files {
+        let next_index = table.files.len();
+        let index = *file_indices.entry(path.clone()).or_insert(next_index);
+        if index == next_index {
+            table.files.push(path);
+        }
+        unit_files.push(index);
+    }
+    for mut row in rows {
+        row.file = match unit_files.get(row.file) {
+            Some(&index) => index,
+            None => continue,
+        };
+        table.rows.push(row);
+    }
+}
+
+/// Parses a DWARF 5 directory or file name table.
+fn parse_entries(reader: &mut Reader,
+                 offset_size: usize,
+                 strings: &StringSections,
+                 directories: &[String])
+                 -> Vec<String> {
+    let format_count = reader.u8();
+    let mut format = Vec::new();
+    for _ in 0..format_count {
+        let content_type = reader.uleb128();
+        let form = reader.uleb128();
+        format.push((content_type, form));
+    }
+    let count = reader.uleb128();
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut directory = 0;
+        for &(content_type, form) in &format {
+            let mut value = 0;
+            let mut string = None;
+            match form {
+                DW_FORM_STRING => string = Some(reader.string()),
+                DW_FORM_LINE_STRP | DW_FORM_STRP => {
+                    let offset = reader.unsigned(offset_size) as usize;
+                    let section = if form == DW_FORM_LINE_STRP {
+                        strings.debug_line_str
+                    } else {
+                        strings.debug_str
+                    };
+                    string = Some(section.get(offset..).map_or(String::new(), null_terminated));
+                }
+                DW_FORM_UDATA => value = reader.uleb128(),
+                DW_FORM_DATA1 => value = reader.unsigned(1),
+                DW_FORM_DATA2 => value = reader.unsigned(2),
+                DW_FORM_DATA4 => value = reader.unsigned(4),
+                DW_FORM_DATA8 => value = reader.unsigned(8),
+                DW_FORM_DATA16 => reader.skip(16),
+                DW_FORM_BLOCK => {
+                    let length = reader.uleb128() as usize;
+                    reader.skip(length);
+                }
+                _ => panic!("Unsupported form {:#x} in line program header", form),
+            }
+            match content_type {
+                DW_LNCT_PATH => path = string.unwrap_or_default(),
+                DW_LNCT_DIRECTORY_INDEX => directory = value as usize,
+                _ => (),
+            }
+        }
+        if directories.is_empty() {
+            entries.push(path);
+        } else {
+            entries.push(join_path(directories.get(directory).map_or("", |d| d), &path));
+        }
+    }
+    entries
+}
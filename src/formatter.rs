@@ -0,0 +1,398 @@
+use instruction_set::{InstructionArgument, Register, ArgumentSize};
+
+/// Renders the parts of a decoded instruction in one assembler syntax,
+/// see disassembler::format_instruction for how the parts are put together.
+pub trait InstructionFormatter {
+    /// size is the operand size if it is not implied by a register operand
+    fn mnemonic(&self, mnemonic: &str, size: Option<ArgumentSize>) -> String;
+
+    fn register(&self, register: &Register) -> String;
+
+    fn immediate(&self, immediate: i64, size: ArgumentSize) -> String;
+
+    /// argument is an EffectiveAddress, size is Some when the size of the access should be shown
+    fn memory(&self, segment: Option<Register>, argument: &InstructionArgument, size: Option<ArgumentSize>) -> String;
+
+    /// memory operand without registers, address is the displacement extended to the address size
+    fn absolute(&self, segment: Option<Register>, address: u64, size: Option<ArgumentSize>) -> String;
+
+    /// Whether memory operands need a size, explicit is true if the instruction has no
+    /// register operand the size could be derived from.
+    fn annotate_memory_size(&self, explicit: bool) -> bool;
+
+    /// operands are passed source first, like the decoder stores them
+    fn operands(&self, operands: Vec<String>) -> String;
+
+    fn branch_target(&self, target: u64) -> String {
+        format!("{:#x}", target)
+    }
+
+    /// target of an indirect jump or call
+    fn indirect(&self, operand: String) -> String;
+
+    /// target of a far jump or call with the selector and offset in the instruction
+    fn far_pointer(&self, selector: String, offset: String) -> String {
+        format!("{}:{}", selector, offset)
+    }
+
+    /// i/o port in dx
+    fn port(&self, register: &Register) -> String;
+
+    /// Some syntaxes write string instructions without operands (stosb, movsq).
+    fn string_instruction(&self, _mnemonic: &str, _size: ArgumentSize) -> Option<String> {
+        None
+    }
+}
+
+/// Formatter for the syntax given on the command line: att, nasm or masm.
+pub fn formatter_for_syntax(syntax: &str) -> Box<dyn InstructionFormatter> {
+    match syntax {
+        "att" => Box::new(AttFormatter),
+        "nasm" => Box::new(NasmFormatter),
+        "masm" | "intel" => Box::new(MasmFormatter),
+        _ => panic!("Unknown assembler syntax: {}", syntax),
+    }
+}
+
+fn register_name(register: &Register) -> String {
+    format!("{:?}", register).to_lowercase()
+}
+
+fn immediate_value(immediate: i64, size: ArgumentSize) -> u64 {
+    match size {
+        ArgumentSize::Bit8 => immediate as u8 as u64,
+        ArgumentSize::Bit16 => immediate as u16 as u64,
+        ArgumentSize::Bit32 => immediate as u32 as u64,
+        ArgumentSize::Bit64 | ArgumentSize::Bit128 | ArgumentSize::Bit256 => immediate as u64,
+    }
+}
+
+/// The address of an operand without registers in 64 bit code
+fn absolute_address(argument: &InstructionArgument) -> Option<u64> {
+    match *argument {
+        InstructionArgument::EffectiveAddress { base: None, index: None, displacement, .. } => {
+            Some(displacement as i64 as u64)
+        }
+        _ => None,
+    }
+}
+
+/// AT&T syntax as printed by objdump, also the format of the execution traces.
+pub struct AttFormatter;
+
+impl InstructionFormatter for AttFormatter {
+    fn mnemonic(&self, mnemonic: &str, size: Option<ArgumentSize>) -> String {
+        match size {
+            Some(ArgumentSize::Bit8) => mnemonic.to_owned() + "b",
+            Some(ArgumentSize::Bit16) => mnemonic.to_owned() + "w",
+            Some(ArgumentSize::Bit32) => mnemonic.to_owned() + "l",
+            Some(ArgumentSize::Bit64) => mnemonic.to_owned() + "q",
+            Some(ArgumentSize::Bit128) | Some(ArgumentSize::Bit256) | None => mnemonic.to_owned(),
+        }
+    }
+
+    fn register(&self, register: &Register) -> String {
+        format!("%{}", register_name(register))
+    }
+
+    fn immediate(&self, immediate: i64, size: ArgumentSize) -> String {
+        format!("$0x{:x}", immediate_value(immediate, size))
+    }
+
+    fn memory(&self, segment: Option<Register>, argument: &InstructionArgument, size: Option<ArgumentSize>) -> String {
+        if let Some(address) = absolute_address(argument) {
+            return self.absolute(segment, address, size);
+        }
+        match (segment, argument) {
+            // string instructions, objdump omits the zero displacement
+            (Some(segment), &InstructionArgument::EffectiveAddress { base: Some(ref base), index: None, displacement: 0, .. }) => {
+                format!("{}:({})", self.register(&segment), self.register(base))
+            }
+            (Some(segment), _) => format!("{}:{}", self.register(&segment), argument),
+            // the effective address format lives in instruction_set.rs
+            (None, _) => format!("{}", argument),
+        }
+    }
+
+    fn absolute(&self, segment: Option<Register>, address: u64, _size: Option<ArgumentSize>) -> String {
+        match segment {
+            Some(segment) => format!("{}:{:#x}", self.register(&segment), address),
+            None => format!("{:#x}", address),
+        }
+    }
+
+    fn annotate_memory_size(&self, _explicit: bool) -> bool {
+        false
+    }
+
+    fn operands(&self, operands: Vec<String>) -> String {
+        operands.join(",")
+    }
+
+    fn indirect(&self, operand: String) -> String {
+        format!("*{}", operand)
+    }
+
+    fn far_pointer(&self, selector: String, offset: String) -> String {
+        format!("{},{}", selector, offset)
+    }
+
+    fn port(&self, register: &Register) -> String {
+        format!("({})", self.register(register))
+    }
+}
+
+/// [base+index*scale+displacement] without size and segment, at least one register is used
+fn intel_address(argument: &InstructionArgument) -> String {
+    match *argument {
+        InstructionArgument::EffectiveAddress { ref base, ref index, scale, displacement } => {
+            let mut address = String::new();
+            if let Some(ref base) = *base {
+                address.push_str(&register_name(base));
+            }
+            if let Some(ref index) = *index {
+                if !address.is_empty() {
+                    address.push('+');
+                }
+                match scale {
+                    Some(scale) => address.push_str(&format!("{}*{}", register_name(index), scale)),
+                    None => address.push_str(&register_name(index)),
+                }
+            }
+            if displacement < 0 {
+                address.push_str(&format!("-{:#x}", (displacement as i64).abs()));
+            } else if displacement > 0 {
+                address.push_str(&format!("+{:#x}", displacement));
+            }
+            address
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn intel_size(size: ArgumentSize) -> &'static str {
+    match size {
+        ArgumentSize::Bit8 => "byte",
+        ArgumentSize::Bit16 => "word",
+        ArgumentSize::Bit32 => "dword",
+        ArgumentSize::Bit64 => "qword",
+        ArgumentSize::Bit128 => "oword",
+        ArgumentSize::Bit256 => "yword",
+    }
+}
+
+fn nasm_memory(segment: Option<Register>, address: &str, size: Option<ArgumentSize>) -> String {
+    let size = match size {
+        Some(size) => format!("{} ", intel_size(size)),
+        None => String::new(),
+    };
+    let segment = match segment {
+        Some(segment) => format!("{}:", register_name(&segment)),
+        None => String::new(),
+    };
+    format!("{}[{}{}]", size, segment, address)
+}
+
+/// Intel syntax as accepted by NASM: sizes only where needed, segment inside the brackets.
+pub struct NasmFormatter;
+
+impl InstructionFormatter for NasmFormatter {
+    fn mnemonic(&self, mnemonic: &str, _size: Option<ArgumentSize>) -> String {
+        mnemonic.to_owned()
+    }
+
+    fn register(&self, register: &Register) -> String {
+        register_name(register)
+    }
+
+    fn immediate(&self, immediate: i64, size: ArgumentSize) -> String {
+        format!("0x{:x}", immediate_value(immediate, size))
+    }
+
+    fn memory(&self, segment: Option<Register>, argument: &InstructionArgument, size: Option<ArgumentSize>) -> String {
+        match absolute_address(argument) {
+            Some(address) => self.absolute(segment, address, size),
+            None => nasm_memory(segment, &intel_address(argument), size),
+        }
+    }
+
+    fn absolute(&self, segment: Option<Register>, address: u64, size: Option<ArgumentSize>) -> String {
+        nasm_memory(segment, &format!("{:#x}", address), size)
+    }
+
+    fn annotate_memory_size(&self, explicit: bool) -> bool {
+        explicit
+    }
+
+    fn operands(&self, mut operands: Vec<String>) -> String {
+        operands.reverse();
+        operands.join(",")
+    }
+
+    fn indirect(&self, operand: String) -> String {
+        operand
+    }
+
+    fn port(&self, register: &Register) -> String {
+        register_name(register)
+    }
+
+    fn string_instruction(&self, mnemonic: &str, size: ArgumentSize) -> Option<String> {
+        let suffix = match size {
+            ArgumentSize::Bit8 => "b",
+            ArgumentSize::Bit16 => "w",
+            ArgumentSize::Bit32 => "d",
+            ArgumentSize::Bit64 => "q",
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("No string instructions for vector sizes"),
+        };
+        Some(mnemonic.to_owned() + suffix)
+    }
+}
+
+fn masm_size(size: Option<ArgumentSize>) -> String {
+    match size {
+        // NASM calls them oword and yword
+        Some(ArgumentSize::Bit128) => "XMMWORD PTR ".to_string(),
+        Some(ArgumentSize::Bit256) => "YMMWORD PTR ".to_string(),
+        Some(size) => format!("{} PTR ", intel_size(size).to_uppercase()),
+        None => String::new(),
+    }
+}
+
+/// Intel syntax like MASM, IDA and objdump -M intel: every memory operand has a size.
+pub struct MasmFormatter;
+
+impl InstructionFormatter for MasmFormatter {
+    fn mnemonic(&self, mnemonic: &str, _size: Option<ArgumentSize>) -> String {
+        mnemonic.to_owned()
+    }
+
+    fn register(&self, register: &Register) -> String {
+        register_name(register)
+    }
+
+    fn immediate(&self, immediate: i64, size: ArgumentSize) -> String {
+        format!("0x{:x}", immediate_value(immediate, size))
+    }
+
+    fn memory(&self, segment: Option<Register>, argument: &InstructionArgument, size: Option<ArgumentSize>) -> String {
+        if let Some(address) = absolute_address(argument) {
+            return self.absolute(segment, address, size);
+        }
+        match segment {
+            Some(segment) => format!("{}{}:[{}]", masm_size(size), register_name(&segment), intel_address(argument)),
+            None => format!("{}[{}]", masm_size(size), intel_address(argument)),
+        }
+    }
+
+    fn absolute(&self, segment: Option<Register>, address: u64, size: Option<ArgumentSize>) -> String {
+        // absolute addresses are written without brackets, but need a segment
+        let segment = register_name(&segment.unwrap_or(Register::DS));
+        format!("{}{}:{:#x}", masm_size(size), segment, address)
+    }
+
+    fn annotate_memory_size(&self, _explicit: bool) -> bool {
+        true
+    }
+
+    fn operands(&self, mut operands: Vec<String>) -> String {
+        operands.reverse();
+        operands.join(",")
+    }
+
+    fn indirect(&self, operand: String) -> String {
+        operand
+    }
+
+    fn port(&self, register: &Register) -> String {
+        register_name(register)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(base: Option<Register>, index: Option<Register>, scale: Option<u8>, displacement: i32)
+               -> InstructionArgument {
+        InstructionArgument::EffectiveAddress {
+            base: base,
+            index: index,
+            scale: scale,
+            displacement: displacement,
+        }
+    }
+
+    #[test]
+    fn registers_and_immediates() {
+        assert_eq!(AttFormatter.register(&Register::R12), "%r12");
+        assert_eq!(NasmFormatter.register(&Register::AL), "al");
+        assert_eq!(MasmFormatter.register(&Register::EDX), "edx");
+        assert_eq!(AttFormatter.immediate(-1, ArgumentSize::Bit8), "$0xff");
+        assert_eq!(AttFormatter.immediate(-1, ArgumentSize::Bit64), "$0xffffffffffffffff");
+        assert_eq!(NasmFormatter.immediate(-2, ArgumentSize::Bit16), "0xfffe");
+        assert_eq!(MasmFormatter.immediate(0x80, ArgumentSize::Bit32), "0x80");
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(AttFormatter.mnemonic("mov", Some(ArgumentSize::Bit8)), "movb");
+        assert_eq!(AttFormatter.mnemonic("add", Some(ArgumentSize::Bit64)), "addq");
+        assert_eq!(AttFormatter.mnemonic("vpor", Some(ArgumentSize::Bit256)), "vpor");
+        assert_eq!(NasmFormatter.mnemonic("mov", Some(ArgumentSize::Bit32)), "mov");
+        assert_eq!(MasmFormatter.mnemonic("mov", None), "mov");
+        assert_eq!(NasmFormatter.string_instruction("stos", ArgumentSize::Bit32), Some("stosd".to_string()));
+        assert_eq!(AttFormatter.string_instruction("stos", ArgumentSize::Bit32), None);
+    }
+
+    #[test]
+    fn memory_operands() {
+        let argument = address(Some(Register::RSP), Some(Register::RCX), Some(4), -8);
+        assert_eq!(AttFormatter.memory(None, &argument, Some(ArgumentSize::Bit64)), "-0x8(%rsp,%rcx,4)");
+        assert_eq!(NasmFormatter.memory(None, &argument, None), "[rsp+rcx*4-0x8]");
+        assert_eq!(NasmFormatter.memory(None, &argument, Some(ArgumentSize::Bit64)), "qword [rsp+rcx*4-0x8]");
+        assert_eq!(MasmFormatter.memory(None, &argument, Some(ArgumentSize::Bit16)), "WORD PTR [rsp+rcx*4-0x8]");
+        assert_eq!(MasmFormatter.memory(None, &argument, Some(ArgumentSize::Bit128)), "XMMWORD PTR [rsp+rcx*4-0x8]");
+
+        let argument = address(Some(Register::RSI), None, None, 0);
+        assert_eq!(AttFormatter.memory(Some(Register::DS), &argument, None), "%ds:(%rsi)");
+        assert_eq!(NasmFormatter.memory(Some(Register::DS), &argument, Some(ArgumentSize::Bit8)), "byte [ds:rsi]");
+        assert_eq!(MasmFormatter.memory(Some(Register::ES), &argument, Some(ArgumentSize::Bit8)), "BYTE PTR es:[rsi]");
+
+        let argument = address(None, None, None, 0x28);
+        assert_eq!(AttFormatter.memory(None, &argument, None), "0x28");
+        assert_eq!(NasmFormatter.memory(None, &argument, None), "[0x28]");
+        assert_eq!(MasmFormatter.memory(None, &argument, Some(ArgumentSize::Bit32)), "DWORD PTR ds:0x28");
+    }
+
+    #[test]
+    fn absolute_operands() {
+        assert_eq!(AttFormatter.absolute(None, 0xffffff80, None), "0xffffff80");
+        assert_eq!(AttFormatter.absolute(Some(Register::FS), 0x28, None), "%fs:0x28");
+        assert_eq!(NasmFormatter.absolute(Some(Register::GS), 0x28, Some(ArgumentSize::Bit64)), "qword [gs:0x28]");
+        assert_eq!(MasmFormatter.absolute(None, 0x28, Some(ArgumentSize::Bit8)), "BYTE PTR ds:0x28");
+        assert_eq!(MasmFormatter.absolute(Some(Register::FS), 0x28, None), "fs:0x28");
+    }
+
+    #[test]
+    fn operand_order() {
+        let operands = vec!["%rax".to_string(), "%rbx".to_string()];
+        assert_eq!(AttFormatter.operands(operands), "%rax,%rbx");
+        let operands = vec!["rax".to_string(), "rbx".to_string()];
+        assert_eq!(NasmFormatter.operands(operands.clone()), "rbx,rax");
+        assert_eq!(MasmFormatter.operands(operands), "rbx,rax");
+        assert_eq!(AttFormatter.indirect("%rax".to_string()), "*%rax");
+        assert_eq!(NasmFormatter.indirect("rax".to_string()), "rax");
+        assert_eq!(AttFormatter.port(&Register::DX), "(%dx)");
+        assert_eq!(MasmFormatter.port(&Register::DX), "dx");
+        assert_eq!(AttFormatter.far_pointer("$0x8".to_string(), "$0x1000".to_string()), "$0x8,$0x1000");
+        assert_eq!(NasmFormatter.far_pointer("0x8".to_string(), "0x1000".to_string()), "0x8:0x1000");
+    }
+
+    #[test]
+    fn syntax_names() {
+        assert_eq!(formatter_for_syntax("att").register(&Register::RAX), "%rax");
+        assert_eq!(formatter_for_syntax("nasm").register(&Register::RAX), "rax");
+        assert_eq!(formatter_for_syntax("intel").memory(None, &address(None, None, None, 8), None), "ds:0x8");
+    }
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 
+use cpu::mode::CodeSize;
+use ir::Mnemonic;
+
 #[derive(Clone, Copy, Debug)]
 pub enum RegisterSize {
     Bit8,
@@ -105,13 +108,51 @@ pub enum Register {
     DS,
     FS,
     GS,
+
+    // 128 Bit, the lower half of the YMM registers
+    XMM0,
+    XMM1,
+    XMM2,
+    XMM3,
+    XMM4,
+    XMM5,
+    XMM6,
+    XMM7,
+    XMM8,
+    XMM9,
+    XMM10,
+    XMM11,
+    XMM12,
+    XMM13,
+    XMM14,
+    XMM15,
+
+    // 256 Bit
+    YMM0,
+    YMM1,
+    YMM2,
+    YMM3,
+    YMM4,
+    YMM5,
+    YMM6,
+    YMM7,
+    YMM8,
+    YMM9,
+    YMM10,
+    YMM11,
+    YMM12,
+    YMM13,
+    YMM14,
+    YMM15,
 }
 
 pub enum Flags {
     Carry = 1 << 0,
     Parity = 1 << 2,
+    Adjust = 1 << 4,
     Zero = 1 << 6,
     Sign = 1 << 7,
+    Interrupt = 1 << 9,
     Direction = 1 << 10,
     Overflow = 1 << 11,
 }
@@ -122,6 +163,21 @@ pub enum ArgumentSize {
     Bit32,
     Bit16,
     Bit8,
+    Bit128,
+    Bit256,
+}
+
+impl ArgumentSize {
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            ArgumentSize::Bit8 => 1,
+            ArgumentSize::Bit16 => 2,
+            ArgumentSize::Bit32 => 4,
+            ArgumentSize::Bit64 => 8,
+            ArgumentSize::Bit128 => 16,
+            ArgumentSize::Bit256 => 32,
+        }
+    }
 }
 
 pub fn get_register_size(reg: &Register) -> ArgumentSize {
@@ -148,6 +204,16 @@ pub fn get_register_size(reg: &Register) -> ArgumentSize {
         Register::SIL | Register::DIL | Register::R8B | Register::R9B |
         Register::R10B | Register::R11B | Register::R12B | Register::R13B | Register::R14B |
         Register::R15B => ArgumentSize::Bit8,
+
+        Register::XMM0 | Register::XMM1 | Register::XMM2 | Register::XMM3 | Register::XMM4 |
+        Register::XMM5 | Register::XMM6 | Register::XMM7 | Register::XMM8 | Register::XMM9 |
+        Register::XMM10 | Register::XMM11 | Register::XMM12 | Register::XMM13 | Register::XMM14 |
+        Register::XMM15 => ArgumentSize::Bit128,
+
+        Register::YMM0 | Register::YMM1 | Register::YMM2 | Register::YMM3 | Register::YMM4 |
+        Register::YMM5 | Register::YMM6 | Register::YMM7 | Register::YMM8 | Register::YMM9 |
+        Register::YMM10 | Register::YMM11 | Register::YMM12 | Register::YMM13 | Register::YMM14 |
+        Register::YMM15 => ArgumentSize::Bit256,
     }
 }
 
@@ -179,7 +245,9 @@ impl InstructionArgument {
                     ArgumentSize::Bit8 => immediate as u8 as u64,
                     ArgumentSize::Bit16 => immediate as u16 as u64,
                     ArgumentSize::Bit32 => immediate as u32 as u64,
-                    ArgumentSize::Bit64 => immediate as u64,
+                    ArgumentSize::Bit64 |
+                    ArgumentSize::Bit128 |
+                    ArgumentSize::Bit256 => immediate as u64,
                 })
             }
         }
@@ -191,6 +259,8 @@ pub struct InstructionArguments {
     pub first_argument: Option<InstructionArgument>,
     pub second_argument: Option<InstructionArgument>,
     pub third_argument: Option<InstructionArgument>,
+    /// only used by instructions with an explicit operand list in tables/opcodes.txt
+    pub fourth_argument: Option<InstructionArgument>,
     pub opcode: Option<u8>,
     pub explicit_size: Option<ArgumentSize>,
     pub repeat_equal: bool,
@@ -222,6 +292,25 @@ impl InstructionArguments {
         (first_argument, second_argument)
     }
 
+    pub fn get_three_arguments(&self) -> (&InstructionArgument, &InstructionArgument, &InstructionArgument) {
+        let (first_argument, second_argument) = self.get_two_arguments();
+        let third_argument = match self.third_argument {
+            Some(ref third_argument) => third_argument,
+            None => panic!("Instruction needs third_argument"),
+        };
+        (first_argument, second_argument, third_argument)
+    }
+
+    pub fn get_four_arguments(&self)
+                              -> (&InstructionArgument, &InstructionArgument, &InstructionArgument, &InstructionArgument) {
+        let (first_argument, second_argument, third_argument) = self.get_three_arguments();
+        let fourth_argument = match self.fourth_argument {
+            Some(ref fourth_argument) => fourth_argument,
+            None => panic!("Instruction needs fourth_argument"),
+        };
+        (first_argument, second_argument, third_argument, fourth_argument)
+    }
+
     pub fn size(&self) -> ArgumentSize {
         match self.explicit_size {
             Some(explicit_size) => explicit_size,
@@ -271,6 +360,8 @@ impl InstructionArguments {
 pub struct InstructionArgumentsBuilder {
     first_argument: Option<InstructionArgument>,
     second_argument: Option<InstructionArgument>,
+    third_argument: Option<InstructionArgument>,
+    fourth_argument: Option<InstructionArgument>,
     opcode: Option<u8>,
     explicit_size: Option<ArgumentSize>,
     repeat_equal: bool,
@@ -282,6 +373,8 @@ impl InstructionArgumentsBuilder {
         InstructionArgumentsBuilder {
             first_argument: None,
             second_argument: None,
+            third_argument: None,
+            fourth_argument: None,
             opcode: None,
             explicit_size: None,
             repeat_equal: false,
@@ -303,6 +396,20 @@ impl InstructionArgumentsBuilder {
         self
     }
 
+    pub fn third_argument(mut self,
+                          third_argument: InstructionArgument)
+                          -> InstructionArgumentsBuilder {
+        self.third_argument = Some(third_argument);
+        self
+    }
+
+    pub fn fourth_argument(mut self,
+                           fourth_argument: InstructionArgument)
+                           -> InstructionArgumentsBuilder {
+        self.fourth_argument = Some(fourth_argument);
+        self
+    }
+
     pub fn opcode(mut self, opcode: u8) -> InstructionArgumentsBuilder {
         self.opcode = Some(opcode);
         self
@@ -317,7 +424,8 @@ impl InstructionArgumentsBuilder {
         InstructionArguments {
             first_argument: self.first_argument,
             second_argument: self.second_argument,
-            third_argument: None,
+            third_argument: self.third_argument,
+            fourth_argument: self.fourth_argument,
             opcode: self.opcode,
             explicit_size: self.explicit_size,
             repeat_equal: self.repeat_equal,
@@ -380,9 +488,11 @@ fn format_effective_address(arg: &InstructionArgument) -> String {
                     }
                 }
                 Some(ref index) => {
-                    match *base {
-                        Some(ref base) => format!("({},{},{})", base, index, scale.unwrap()),
-                        None => format!("(,{},{})", index, scale.unwrap()),
+                    // 16 bit addressing like (%bx,%si) has no scale
+                    match (base, scale) {
+                        (&Some(ref base), Some(scale)) => format!("({},{},{})", base, index, scale),
+                        (&Some(ref base), None) => format!("({},{})", base, index),
+                        (&None, _) => format!("(,{},{})", index, scale.unwrap_or(1)),
                     }
                 }
             }
@@ -391,12 +501,38 @@ fn format_effective_address(arg: &InstructionArgument) -> String {
     }
 }
 
+/// Prefixes which are not part of the instruction arguments.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Prefixes {
+    pub lock: bool,
+    pub repeat_equal: bool,
+    pub repeat_not_equal: bool,
+    pub segment: Option<Register>,
+    pub address_size_override: bool,
+}
+
+impl Prefixes {
+    /// Size of the addresses of memory operands, 67 switches to the other size
+    /// of the mode: 64/32 bit in 64 bit mode, 32/16 bit otherwise
+    pub fn address_size(&self, code_size: CodeSize) -> ArgumentSize {
+        match (code_size, self.address_size_override) {
+            (CodeSize::Bit64, false) => ArgumentSize::Bit64,
+            (CodeSize::Bit64, true) | (CodeSize::Bit32, false) | (CodeSize::Bit16, true) => ArgumentSize::Bit32,
+            (CodeSize::Bit32, true) | (CodeSize::Bit16, false) => ArgumentSize::Bit16,
+        }
+    }
+}
+
 pub struct InstructionCache {
     pub instruction: Instruction,
+    /// what the cpu executes, group opcodes resolved, None for unknown instructions
+    pub mnemonic: Option<Mnemonic>,
     pub arguments: Option<InstructionArguments>,
-    pub size: u64
+    pub size: u64,
+    pub prefixes: Prefixes,
 }
 
+#[derive(Clone, Copy)]
 pub enum Instruction {
     Adc,
     Add,
@@ -409,6 +545,7 @@ pub enum Instruction {
     Btc,
     Call,
     Cld,
+    Cli,
     Cmova,
     Cmovae,
     Cmovb,
@@ -428,8 +565,15 @@ pub enum Instruction {
     Cmp,
     CompareMulOperation,
     Cpuid,
+    Dec,
+    Enter,
+    Hlt,
     Imul,
+    In,
+    Inc,
     Int,
+    Int3,
+    Iret,
     Ja,
     Jae,
     Jb,
@@ -446,11 +590,19 @@ pub enum Instruction {
     Jns,
     Jo,
     Jp,
+    Jrcxz,
     Js,
+    Lcall,
     Lea,
     Leave,
     Lidt,
     Lgdt,
+    Ljmp,
+    Lldt,
+    Loop,
+    Loope,
+    Loopne,
+    Ltr,
     Mov,
     Movs,
     Movsx,
@@ -463,21 +615,34 @@ pub enum Instruction {
     Push,
     Pushf,
     Rdmsr,
+    Rdrand,
+    Rdseed,
+    Rdtsc,
+    Rdtscp,
     RegisterOperation,
     Ret,
     Lret,
     Sbb,
     ShiftRotate,
+    Sldt,
     Std,
+    Sti,
     Stos,
+    Str,
     Sub,
+    Swapgs,
     Test,
     Wrmsr,
     Xor,
     Scas,
+    Cmps,
+    Lods,
+    Ins,
+    Outs,
     Cmpxchg,
     Xchg,
     Syscall,
+    Sysret,
     Seto,
     Setno,
     Setb,
@@ -494,4 +659,118 @@ pub enum Instruction {
     Setge,
     Setle,
     Setg,
+    // AVX and AVX2
+    Vmovdqa,
+    Vmovdqu,
+    Vmovaps,
+    Vmovapd,
+    Vmovups,
+    Vmovupd,
+    Vmovd,
+    Vmovq,
+    Vpbroadcastb,
+    Vpbroadcastw,
+    Vpbroadcastd,
+    Vpbroadcastq,
+    Vbroadcastss,
+    Vbroadcastsd,
+    Vinserti128,
+    Vextracti128,
+    Vperm2i128,
+    Vzeroupper,
+    Vzeroall,
+    Vpaddb,
+    Vpaddw,
+    Vpaddd,
+    Vpaddq,
+    Vpsubb,
+    Vpsubw,
+    Vpsubd,
+    Vpsubq,
+    Vpand,
+    Vpandn,
+    Vpor,
+    Vpxor,
+    Vpcmpeqb,
+    Vpcmpeqw,
+    Vpcmpeqd,
+    Vpcmpeqq,
+    Vpcmpgtb,
+    Vpcmpgtw,
+    Vpcmpgtd,
+    Vpcmpgtq,
+    Vpminub,
+    Vpmaxub,
+    Vpmovmskb,
+    Vptest,
+    Vpshufb,
+    Vpshufd,
+    Vaddps,
+    Vaddpd,
+    Vaddss,
+    Vaddsd,
+    Vsubps,
+    Vsubpd,
+    Vsubss,
+    Vsubsd,
+    Vmulps,
+    Vmulpd,
+    Vmulss,
+    Vmulsd,
+    Vdivps,
+    Vdivpd,
+    Vdivss,
+    Vdivsd,
+    Vminps,
+    Vminpd,
+    Vminss,
+    Vminsd,
+    Vmaxps,
+    Vmaxpd,
+    Vmaxss,
+    Vmaxsd,
+    Vsqrtps,
+    Vsqrtpd,
+    Vsqrtss,
+    Vsqrtsd,
+    Vandps,
+    Vandpd,
+    Vandnps,
+    Vandnpd,
+    Vorps,
+    Vorpd,
+    Vxorps,
+    Vxorpd,
+    // XSAVE feature set
+    Xgetbv,
+    Xsetbv,
+    Xsave,
+    Xrstor,
+    // bit scans and counts
+    Bsf,
+    Bsr,
+    Bswap,
+    Popcnt,
+    Lzcnt,
+    Tzcnt,
+    // BMI1 and BMI2
+    Andn,
+    Bextr,
+    Blsi,
+    Blsmsk,
+    Blsr,
+    Bzhi,
+    Pdep,
+    Pext,
+    Mulx,
+    Sarx,
+    Shlx,
+    Shrx,
+    Rorx,
+    // atomic read-modify-write
+    Xadd,
+    /// cmpxchg16b if the operand is 64 bit (REX.W)
+    Cmpxchg8b,
+    // opcodes the decoder does not know, executing them stops the emulation
+    Unknown,
 }
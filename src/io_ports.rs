@@ -0,0 +1,76 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/* The i/o port address space of in, out, ins and outs. Devices claim ranges
+ * of ports (PortHandler), accesses are 1, 2 or 4 bytes and go to the device
+ * owning the first port. Like on a PC bus reads of ports without a device
+ * return all ones and writes to them are dropped.
+ */
+
+/// A device behind a range of i/o ports, it gets the port of the access
+pub trait PortHandler {
+    fn read(&mut self, port: u16, size: u64) -> u64;
+    fn write(&mut self, port: u16, size: u64, value: u64);
+}
+
+/// Devices on the i/o ports with their first port and the number of ports,
+/// see CpuOptions::port_handlers
+pub type PortHandlers = Vec<(u16, u32, Rc<RefCell<dyn PortHandler>>)>;
+
+struct PortRange {
+    first: u16,
+    count: u32,
+    handler: Rc<RefCell<dyn PortHandler>>,
+}
+
+#[derive(Default)]
+pub struct IoPorts {
+    ranges: Vec<PortRange>,
+}
+
+impl IoPorts {
+    /// Gives count ports starting at first to the device
+    pub fn add(&mut self, first: u16, count: u32, handler: Rc<RefCell<dyn PortHandler>>) {
+        if count == 0 || first as u32 + count > 0x10000 {
+            panic!("Invalid port range {:#x} with {} ports", first, count);
+        }
+        for range in &self.ranges {
+            if (first as u32) < range.first as u32 + range.count && (range.first as u32) < first as u32 + count {
+                panic!("Ports {:#x} to {:#x} are already taken", first, first as u32 + count - 1);
+            }
+        }
+        self.ranges.push(PortRange {
+            first: first,
+            count: count,
+            handler: handler,
+        });
+    }
+
+    /// Removes the device with the ports starting at first
+    pub fn remove(&mut self, first: u16) {
+        self.ranges.retain(|range| range.first != first);
+    }
+
+    fn handler(&self, port: u16) -> Option<Rc<RefCell<dyn PortHandler>>> {
+        self.ranges.iter()
+            .find(|range| port >= range.first && ((port - range.first) as u32) < range.count)
+            .map(|range| range.handler.clone())
+    }
+
+    pub fn read(&self, port: u16, size: u64) -> u64 {
+        match self.handler(port) {
+            Some(handler) => handler.borrow_mut().read(port, size) & size_mask(size),
+            None => size_mask(size),
+        }
+    }
+
+    pub fn write(&self, port: u16, size: u64, value: u64) {
+        if let Some(handler) = self.handler(port) {
+            handler.borrow_mut().write(port, size, value & size_mask(size));
+        }
+    }
+}
+
+fn size_mask(size: u64) -> u64 {
+    u64::MAX >> (64 - size * 8)
+}
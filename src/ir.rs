@@ -0,0 +1,838 @@
+use std::fmt;
+
+use decoder::decode_instruction_for;
+use cpu::model::Feature;
+use cpu::mode::CodeSize;
+use instruction_set::{Instruction as Opcode, InstructionArguments, InstructionArgument, ArgumentSize,
+                      Register, Prefixes, get_register_size};
+
+/* Decoded instruction with everything the encoding leaves implicit made explicit:
+ * group opcodes (0x80, 0xC1, 0xF7, 0xFF...) are resolved to their mnemonic, relative
+ * jump targets to addresses and string instructions get their memory operands.
+ * decode() only looks at the given bytes, so its result can be cached by address and code size.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    Adc,
+    Add,
+    And,
+    Andn,
+    Bextr,
+    Blsi,
+    Blsmsk,
+    Blsr,
+    Bsf,
+    Bsr,
+    Bswap,
+    Bt,
+    Btc,
+    Btr,
+    Bts,
+    Bzhi,
+    Call,
+    Cld,
+    Cli,
+    Cmova,
+    Cmovae,
+    Cmovb,
+    Cmovbe,
+    Cmove,
+    Cmovg,
+    Cmovge,
+    Cmovl,
+    Cmovle,
+    Cmovne,
+    Cmovno,
+    Cmovnp,
+    Cmovns,
+    Cmovo,
+    Cmovp,
+    Cmovs,
+    Cmp,
+    Cmps,
+    Cmpxchg,
+    Cmpxchg16b,
+    Cmpxchg8b,
+    Cpuid,
+    Dec,
+    Div,
+    Enter,
+    Hlt,
+    Idiv,
+    Imul,
+    In,
+    Inc,
+    Ins,
+    Int,
+    Int3,
+    Iret,
+    Ja,
+    Jae,
+    Jb,
+    Jbe,
+    Je,
+    Jg,
+    Jge,
+    Jl,
+    Jle,
+    Jmp,
+    Jne,
+    Jno,
+    Jnp,
+    Jns,
+    Jo,
+    Jp,
+    Jrcxz,
+    Js,
+    Lcall,
+    Lea,
+    Leave,
+    Lgdt,
+    Lidt,
+    Ljmp,
+    Lldt,
+    Lods,
+    Loop,
+    Loope,
+    Loopne,
+    Lret,
+    Ltr,
+    Lzcnt,
+    Mov,
+    Movs,
+    Movsx,
+    Movzx,
+    Mul,
+    Mulx,
+    Neg,
+    Nop,
+    Not,
+    Or,
+    Out,
+    Outs,
+    Pdep,
+    Pext,
+    Pop,
+    Popcnt,
+    Popf,
+    Push,
+    Pushf,
+    Rcl,
+    Rcr,
+    Rdmsr,
+    Rdrand,
+    Rdseed,
+    Rdtsc,
+    Rdtscp,
+    Ret,
+    Rol,
+    Ror,
+    Rorx,
+    Sar,
+    Sarx,
+    Sbb,
+    Scas,
+    Seta,
+    Setae,
+    Setb,
+    Setbe,
+    Sete,
+    Setg,
+    Setge,
+    Setl,
+    Setle,
+    Setne,
+    Setno,
+    Setnp,
+    Setns,
+    Seto,
+    Setp,
+    Sets,
+    Shl,
+    Shlx,
+    Shr,
+    Shrx,
+    Sldt,
+    Std,
+    Sti,
+    Stos,
+    Str,
+    Sub,
+    Swapgs,
+    Syscall,
+    Sysret,
+    Test,
+    Tzcnt,
+    Vaddpd,
+    Vaddps,
+    Vaddsd,
+    Vaddss,
+    Vandnpd,
+    Vandnps,
+    Vandpd,
+    Vandps,
+    Vbroadcastsd,
+    Vbroadcastss,
+    Vdivpd,
+    Vdivps,
+    Vdivsd,
+    Vdivss,
+    Vextracti128,
+    Vinserti128,
+    Vmaxpd,
+    Vmaxps,
+    Vmaxsd,
+    Vmaxss,
+    Vminpd,
+    Vminps,
+    Vminsd,
+    Vminss,
+    Vmovapd,
+    Vmovaps,
+    Vmovd,
+    Vmovdqa,
+    Vmovdqu,
+    Vmovq,
+    Vmovupd,
+    Vmovups,
+    Vmulpd,
+    Vmulps,
+    Vmulsd,
+    Vmulss,
+    Vorpd,
+    Vorps,
+    Vpaddb,
+    Vpaddd,
+    Vpaddq,
+    Vpaddw,
+    Vpand,
+    Vpandn,
+    Vpbroadcastb,
+    Vpbroadcastd,
+    Vpbroadcastq,
+    Vpbroadcastw,
+    Vpcmpeqb,
+    Vpcmpeqd,
+    Vpcmpeqq,
+    Vpcmpeqw,
+    Vpcmpgtb,
+    Vpcmpgtd,
+    Vpcmpgtq,
+    Vpcmpgtw,
+    Vperm2i128,
+    Vpmaxub,
+    Vpminub,
+    Vpmovmskb,
+    Vpor,
+    Vpshufb,
+    Vpshufd,
+    Vpsubb,
+    Vpsubd,
+    Vpsubq,
+    Vpsubw,
+    Vptest,
+    Vpxor,
+    Vsqrtpd,
+    Vsqrtps,
+    Vsqrtsd,
+    Vsqrtss,
+    Vsubpd,
+    Vsubps,
+    Vsubsd,
+    Vsubss,
+    Vxorpd,
+    Vxorps,
+    Vzeroall,
+    Vzeroupper,
+    Wrmsr,
+    Xadd,
+    Xchg,
+    Xgetbv,
+    Xor,
+    Xrstor,
+    Xsave,
+    Xsetbv,
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rep = format!("{:?}", self).to_lowercase();
+        write!(f, "{}", rep)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    Register { register: Register },
+    Immediate { immediate: i64 },
+    /// target address of a relative jump or call
+    Target { address: u64 },
+    Memory {
+        /// only set if there is a segment override prefix
+        segment: Option<Register>,
+        base: Option<Register>,
+        index: Option<Register>,
+        scale: u8,
+        displacement: i64,
+        size: ArgumentSize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub address: u64,
+    pub length: u64,
+    pub mnemonic: Mnemonic,
+    /// source first, destination last (AT&T order)
+    pub operands: Vec<Operand>,
+    pub operand_size: ArgumentSize,
+    pub address_size: ArgumentSize,
+    pub segment: Option<Register>,
+    pub lock: bool,
+    pub repeat_equal: bool,
+    pub repeat_not_equal: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// the bytes end in the middle of the instruction
+    Truncated,
+    /// the decoder does not know the opcode, length is how many bytes it skipped
+    Unknown { length: u64 },
+}
+
+/// Decodes the instruction at the start of bytes, which are located at address in code of
+/// the given size.
+pub fn decode(bytes: &[u8], address: u64, code_size: CodeSize) -> Result<Instruction, DecodeError> {
+    let decoded = match decode_instruction_for(bytes, code_size) {
+        Some(decoded) => decoded,
+        None => return Err(DecodeError::Truncated),
+    };
+    let length = decoded.size;
+    let arguments = decoded.arguments.as_ref();
+    let mnemonic = match decoded.mnemonic {
+        Some(mnemonic) => mnemonic,
+        None => return Err(DecodeError::Unknown { length: length }),
+    };
+
+    let prefixes = decoded.prefixes;
+    let operand_size = operand_size(mnemonic, arguments);
+    let operands = match arguments {
+        Some(arguments) => operands(mnemonic, arguments, &prefixes, operand_size, address + length),
+        None => Vec::new(),
+    };
+    Ok(Instruction {
+        address: address,
+        length: length,
+        mnemonic: mnemonic,
+        operands: operands,
+        operand_size: operand_size,
+        address_size: prefixes.address_size(code_size),
+        segment: prefixes.segment,
+        lock: prefixes.lock,
+        repeat_equal: prefixes.repeat_equal,
+        repeat_not_equal: prefixes.repeat_not_equal,
+    })
+}
+
+/// Whether a lock prefix is allowed on the instruction: only read-modify-write
+/// instructions with a memory destination can be locked, anything else raises #UD.
+pub fn lock_allowed(mnemonic: Mnemonic, arguments: Option<&InstructionArguments>) -> bool {
+    let arguments = match arguments {
+        Some(arguments) => arguments,
+        None => return false,
+    };
+    let is_memory = |argument: &Option<InstructionArgument>| match *argument {
+        Some(InstructionArgument::EffectiveAddress { .. }) => true,
+        _ => false,
+    };
+    match mnemonic {
+        Mnemonic::Adc | Mnemonic::Add | Mnemonic::And | Mnemonic::Btc | Mnemonic::Btr | Mnemonic::Bts |
+        Mnemonic::Cmpxchg | Mnemonic::Cmpxchg8b | Mnemonic::Cmpxchg16b | Mnemonic::Dec | Mnemonic::Inc |
+        Mnemonic::Neg | Mnemonic::Not | Mnemonic::Or | Mnemonic::Sbb | Mnemonic::Sub | Mnemonic::Xadd |
+        Mnemonic::Xor => {
+            if arguments.second_argument.is_some() {
+                is_memory(&arguments.second_argument)
+            } else {
+                is_memory(&arguments.first_argument)
+            }
+        }
+        // xchg is locked even without the prefix, either operand can be in memory
+        Mnemonic::Xchg => is_memory(&arguments.first_argument) || is_memory(&arguments.second_argument),
+        _ => false,
+    }
+}
+
+/// The cpuid feature flag the cpu model needs to advertise for the instruction, None for
+/// instructions every x86-64 cpu has.
+pub fn required_feature(mnemonic: Mnemonic, arguments: Option<&InstructionArguments>) -> Option<Feature> {
+    let feature = match mnemonic {
+        Mnemonic::Cmova | Mnemonic::Cmovae | Mnemonic::Cmovb | Mnemonic::Cmovbe | Mnemonic::Cmove |
+        Mnemonic::Cmovg | Mnemonic::Cmovge | Mnemonic::Cmovl | Mnemonic::Cmovle | Mnemonic::Cmovne |
+        Mnemonic::Cmovno | Mnemonic::Cmovnp | Mnemonic::Cmovns | Mnemonic::Cmovo | Mnemonic::Cmovp |
+        Mnemonic::Cmovs => Feature::Cmov,
+        Mnemonic::Cmpxchg8b => Feature::Cx8,
+        Mnemonic::Cmpxchg16b => Feature::Cx16,
+        Mnemonic::Rdtsc => Feature::Tsc,
+        Mnemonic::Rdtscp => Feature::Rdtscp,
+        Mnemonic::Rdmsr | Mnemonic::Wrmsr => Feature::Msr,
+        Mnemonic::Rdrand => Feature::Rdrand,
+        Mnemonic::Rdseed => Feature::Rdseed,
+        Mnemonic::Popcnt => Feature::Popcnt,
+        Mnemonic::Lzcnt => Feature::Lzcnt,
+        Mnemonic::Tzcnt | Mnemonic::Andn | Mnemonic::Bextr | Mnemonic::Blsi | Mnemonic::Blsmsk |
+        Mnemonic::Blsr => Feature::Bmi1,
+        Mnemonic::Bzhi | Mnemonic::Pdep | Mnemonic::Pext | Mnemonic::Mulx | Mnemonic::Sarx |
+        Mnemonic::Shlx | Mnemonic::Shrx | Mnemonic::Rorx => Feature::Bmi2,
+        Mnemonic::Xgetbv | Mnemonic::Xsetbv | Mnemonic::Xsave | Mnemonic::Xrstor => Feature::Xsave,
+        Mnemonic::Vpbroadcastb | Mnemonic::Vpbroadcastw | Mnemonic::Vpbroadcastd | Mnemonic::Vpbroadcastq |
+        Mnemonic::Vinserti128 | Mnemonic::Vextracti128 | Mnemonic::Vperm2i128 => Feature::Avx2,
+        // broadcasts from a register are AVX2, from memory AVX
+        Mnemonic::Vbroadcastss | Mnemonic::Vbroadcastsd => {
+            match register_size(&arguments.unwrap().first_argument) {
+                Some(_) => Feature::Avx2,
+                None => Feature::Avx,
+            }
+        }
+        // integer operations on ymm registers are AVX2, on xmm registers AVX
+        Mnemonic::Vpaddb | Mnemonic::Vpaddw | Mnemonic::Vpaddd | Mnemonic::Vpaddq | Mnemonic::Vpsubb |
+        Mnemonic::Vpsubw | Mnemonic::Vpsubd | Mnemonic::Vpsubq | Mnemonic::Vpand | Mnemonic::Vpandn |
+        Mnemonic::Vpor | Mnemonic::Vpxor | Mnemonic::Vpcmpeqb | Mnemonic::Vpcmpeqw | Mnemonic::Vpcmpeqd |
+        Mnemonic::Vpcmpeqq | Mnemonic::Vpcmpgtb | Mnemonic::Vpcmpgtw | Mnemonic::Vpcmpgtd | Mnemonic::Vpcmpgtq |
+        Mnemonic::Vpminub | Mnemonic::Vpmaxub | Mnemonic::Vpmovmskb | Mnemonic::Vpshufb | Mnemonic::Vpshufd => {
+            let arguments = arguments.unwrap();
+            let ymm = [&arguments.first_argument, &arguments.second_argument, &arguments.third_argument]
+                .iter()
+                .any(|argument| match register_size(argument) {
+                    Some(ArgumentSize::Bit256) => true,
+                    _ => false,
+                });
+            if ymm {
+                Feature::Avx2
+            } else {
+                Feature::Avx
+            }
+        }
+        Mnemonic::Vmovdqa | Mnemonic::Vmovdqu | Mnemonic::Vmovaps | Mnemonic::Vmovapd | Mnemonic::Vmovups |
+        Mnemonic::Vmovupd | Mnemonic::Vmovd | Mnemonic::Vmovq | Mnemonic::Vzeroupper | Mnemonic::Vzeroall |
+        Mnemonic::Vptest | Mnemonic::Vaddps | Mnemonic::Vaddpd | Mnemonic::Vaddss | Mnemonic::Vaddsd |
+        Mnemonic::Vsubps | Mnemonic::Vsubpd | Mnemonic::Vsubss | Mnemonic::Vsubsd | Mnemonic::Vmulps |
+        Mnemonic::Vmulpd | Mnemonic::Vmulss | Mnemonic::Vmulsd | Mnemonic::Vdivps | Mnemonic::Vdivpd |
+        Mnemonic::Vdivss | Mnemonic::Vdivsd | Mnemonic::Vminps | Mnemonic::Vminpd | Mnemonic::Vminss |
+        Mnemonic::Vminsd | Mnemonic::Vmaxps | Mnemonic::Vmaxpd | Mnemonic::Vmaxss | Mnemonic::Vmaxsd |
+        Mnemonic::Vsqrtps | Mnemonic::Vsqrtpd | Mnemonic::Vsqrtss | Mnemonic::Vsqrtsd | Mnemonic::Vandps |
+        Mnemonic::Vandpd | Mnemonic::Vandnps | Mnemonic::Vandnpd | Mnemonic::Vorps | Mnemonic::Vorpd |
+        Mnemonic::Vxorps | Mnemonic::Vxorpd => Feature::Avx,
+        _ => return None,
+    };
+    Some(feature)
+}
+
+/// Resolves group opcodes by the reg field of their ModRM byte, None for unknown instructions.
+pub fn mnemonic(opcode: &Opcode, arguments: Option<&InstructionArguments>) -> Option<Mnemonic> {
+    let group = arguments.and_then(|arguments| arguments.opcode);
+    let mnemonic = match *opcode {
+        Opcode::Adc => Mnemonic::Adc,
+        Opcode::Add => Mnemonic::Add,
+        Opcode::And => Mnemonic::And,
+        Opcode::Arithmetic => {
+            match group {
+                Some(0) => Mnemonic::Add,
+                Some(1) => Mnemonic::Or,
+                Some(2) => Mnemonic::Adc,
+                Some(3) => Mnemonic::Sbb,
+                Some(4) => Mnemonic::And,
+                Some(5) => Mnemonic::Sub,
+                Some(6) => Mnemonic::Xor,
+                Some(7) => Mnemonic::Cmp,
+                _ => return None,
+            }
+        }
+        Opcode::BitManipulation => {
+            match group {
+                Some(4) => Mnemonic::Bt,
+                Some(5) => Mnemonic::Bts,
+                Some(6) => Mnemonic::Btr,
+                Some(7) => Mnemonic::Btc,
+                _ => return None,
+            }
+        }
+        Opcode::Bt => Mnemonic::Bt,
+        Opcode::Bts => Mnemonic::Bts,
+        Opcode::Btr => Mnemonic::Btr,
+        Opcode::Btc => Mnemonic::Btc,
+        Opcode::Call => Mnemonic::Call,
+        Opcode::Cld => Mnemonic::Cld,
+        Opcode::Cli => Mnemonic::Cli,
+        Opcode::Cmova => Mnemonic::Cmova,
+        Opcode::Cmovae => Mnemonic::Cmovae,
+        Opcode::Cmovb => Mnemonic::Cmovb,
+        Opcode::Cmovbe => Mnemonic::Cmovbe,
+        Opcode::Cmove => Mnemonic::Cmove,
+        Opcode::Cmovg => Mnemonic::Cmovg,
+        Opcode::Cmovge => Mnemonic::Cmovge,
+        Opcode::Cmovl => Mnemonic::Cmovl,
+        Opcode::Cmovle => Mnemonic::Cmovle,
+        Opcode::Cmovne => Mnemonic::Cmovne,
+        Opcode::Cmovno => Mnemonic::Cmovno,
+        Opcode::Cmovnp => Mnemonic::Cmovnp,
+        Opcode::Cmovns => Mnemonic::Cmovns,
+        Opcode::Cmovo => Mnemonic::Cmovo,
+        Opcode::Cmovp => Mnemonic::Cmovp,
+        Opcode::Cmovs => Mnemonic::Cmovs,
+        Opcode::Cmp => Mnemonic::Cmp,
+        Opcode::Cmpxchg => Mnemonic::Cmpxchg,
+        Opcode::CompareMulOperation => {
+            match group {
+                Some(0) | Some(1) => Mnemonic::Test,
+                Some(2) => Mnemonic::Not,
+                Some(3) => Mnemonic::Neg,
+                Some(4) => Mnemonic::Mul,
+                Some(5) => Mnemonic::Imul,
+                Some(6) => Mnemonic::Div,
+                Some(7) => Mnemonic::Idiv,
+                _ => return None,
+            }
+        }
+        Opcode::Cpuid => Mnemonic::Cpuid,
+        Opcode::Dec => Mnemonic::Dec,
+        Opcode::Enter => Mnemonic::Enter,
+        Opcode::Hlt => Mnemonic::Hlt,
+        Opcode::Imul => Mnemonic::Imul,
+        Opcode::In => Mnemonic::In,
+        Opcode::Inc => Mnemonic::Inc,
+        Opcode::Int => Mnemonic::Int,
+        Opcode::Int3 => Mnemonic::Int3,
+        Opcode::Iret => Mnemonic::Iret,
+        Opcode::Ja => Mnemonic::Ja,
+        Opcode::Jae => Mnemonic::Jae,
+        Opcode::Jb => Mnemonic::Jb,
+        Opcode::Jbe => Mnemonic::Jbe,
+        Opcode::Je => Mnemonic::Je,
+        Opcode::Jg => Mnemonic::Jg,
+        Opcode::Jge => Mnemonic::Jge,
+        Opcode::Jl => Mnemonic::Jl,
+        Opcode::Jle => Mnemonic::Jle,
+        Opcode::Jmp => Mnemonic::Jmp,
+        Opcode::Jne => Mnemonic::Jne,
+        Opcode::Jno => Mnemonic::Jno,
+        Opcode::Jnp => Mnemonic::Jnp,
+        Opcode::Jns => Mnemonic::Jns,
+        Opcode::Jo => Mnemonic::Jo,
+        Opcode::Jp => Mnemonic::Jp,
+        Opcode::Jrcxz => Mnemonic::Jrcxz,
+        Opcode::Js => Mnemonic::Js,
+        Opcode::Lcall => Mnemonic::Lcall,
+        Opcode::Lea => Mnemonic::Lea,
+        Opcode::Leave => Mnemonic::Leave,
+        Opcode::Lidt => Mnemonic::Lidt,
+        Opcode::Lgdt => Mnemonic::Lgdt,
+        Opcode::Ljmp => Mnemonic::Ljmp,
+        Opcode::Lldt => Mnemonic::Lldt,
+        Opcode::Ltr => Mnemonic::Ltr,
+        Opcode::Loop => Mnemonic::Loop,
+        Opcode::Loope => Mnemonic::Loope,
+        Opcode::Loopne => Mnemonic::Loopne,
+        Opcode::Mov => Mnemonic::Mov,
+        Opcode::Movs => Mnemonic::Movs,
+        Opcode::Movsx => Mnemonic::Movsx,
+        Opcode::Movzx => Mnemonic::Movzx,
+        Opcode::Nop => Mnemonic::Nop,
+        Opcode::Or => Mnemonic::Or,
+        Opcode::Out => Mnemonic::Out,
+        Opcode::Pop => Mnemonic::Pop,
+        Opcode::Popf => Mnemonic::Popf,
+        Opcode::Push => Mnemonic::Push,
+        Opcode::Pushf => Mnemonic::Pushf,
+        Opcode::Rdmsr => Mnemonic::Rdmsr,
+        Opcode::Rdrand => Mnemonic::Rdrand,
+        Opcode::Rdseed => Mnemonic::Rdseed,
+        Opcode::Rdtsc => Mnemonic::Rdtsc,
+        Opcode::Rdtscp => Mnemonic::Rdtscp,
+        Opcode::RegisterOperation => {
+            match group {
+                Some(0) => Mnemonic::Inc,
+                Some(1) => Mnemonic::Dec,
+                Some(2) => Mnemonic::Call,
+                Some(4) => Mnemonic::Jmp,
+                Some(6) => Mnemonic::Push,
+                _ => return None,
+            }
+        }
+        Opcode::Ret => Mnemonic::Ret,
+        Opcode::Lret => Mnemonic::Lret,
+        Opcode::Sbb => Mnemonic::Sbb,
+        Opcode::ShiftRotate => {
+            match group {
+                Some(0) => Mnemonic::Rol,
+                Some(1) => Mnemonic::Ror,
+                Some(2) => Mnemonic::Rcl,
+                Some(3) => Mnemonic::Rcr,
+                Some(4) | Some(6) => Mnemonic::Shl,
+                Some(5) => Mnemonic::Shr,
+                Some(7) => Mnemonic::Sar,
+                _ => return None,
+            }
+        }
+        Opcode::Sldt => Mnemonic::Sldt,
+        Opcode::Std => Mnemonic::Std,
+        Opcode::Sti => Mnemonic::Sti,
+        Opcode::Stos => Mnemonic::Stos,
+        Opcode::Str => Mnemonic::Str,
+        Opcode::Sub => Mnemonic::Sub,
+        Opcode::Swapgs => Mnemonic::Swapgs,
+        Opcode::Test => Mnemonic::Test,
+        Opcode::Wrmsr => Mnemonic::Wrmsr,
+        Opcode::Xor => Mnemonic::Xor,
+        Opcode::Scas => Mnemonic::Scas,
+        Opcode::Xchg => Mnemonic::Xchg,
+        Opcode::Syscall => Mnemonic::Syscall,
+        Opcode::Sysret => Mnemonic::Sysret,
+        Opcode::Seto => Mnemonic::Seto,
+        Opcode::Setno => Mnemonic::Setno,
+        Opcode::Setb => Mnemonic::Setb,
+        Opcode::Setae => Mnemonic::Setae,
+        Opcode::Sete => Mnemonic::Sete,
+        Opcode::Setne => Mnemonic::Setne,
+        Opcode::Setbe => Mnemonic::Setbe,
+        Opcode::Seta => Mnemonic::Seta,
+        Opcode::Sets => Mnemonic::Sets,
+        Opcode::Setns => Mnemonic::Setns,
+        Opcode::Setp => Mnemonic::Setp,
+        Opcode::Setnp => Mnemonic::Setnp,
+        Opcode::Setl => Mnemonic::Setl,
+        Opcode::Setge => Mnemonic::Setge,
+        Opcode::Setle => Mnemonic::Setle,
+        Opcode::Setg => Mnemonic::Setg,
+        Opcode::Vmovdqa => Mnemonic::Vmovdqa,
+        Opcode::Vmovdqu => Mnemonic::Vmovdqu,
+        Opcode::Vmovaps => Mnemonic::Vmovaps,
+        Opcode::Vmovapd => Mnemonic::Vmovapd,
+        Opcode::Vmovups => Mnemonic::Vmovups,
+        Opcode::Vmovupd => Mnemonic::Vmovupd,
+        Opcode::Vmovd => Mnemonic::Vmovd,
+        Opcode::Vmovq => Mnemonic::Vmovq,
+        Opcode::Vpbroadcastb => Mnemonic::Vpbroadcastb,
+        Opcode::Vpbroadcastw => Mnemonic::Vpbroadcastw,
+        Opcode::Vpbroadcastd => Mnemonic::Vpbroadcastd,
+        Opcode::Vpbroadcastq => Mnemonic::Vpbroadcastq,
+        Opcode::Vbroadcastss => Mnemonic::Vbroadcastss,
+        Opcode::Vbroadcastsd => Mnemonic::Vbroadcastsd,
+        Opcode::Vinserti128 => Mnemonic::Vinserti128,
+        Opcode::Vextracti128 => Mnemonic::Vextracti128,
+        Opcode::Vperm2i128 => Mnemonic::Vperm2i128,
+        Opcode::Vzeroupper => Mnemonic::Vzeroupper,
+        Opcode::Vzeroall => Mnemonic::Vzeroall,
+        Opcode::Vpaddb => Mnemonic::Vpaddb,
+        Opcode::Vpaddw => Mnemonic::Vpaddw,
+        Opcode::Vpaddd => Mnemonic::Vpaddd,
+        Opcode::Vpaddq => Mnemonic::Vpaddq,
+        Opcode::Vpsubb => Mnemonic::Vpsubb,
+        Opcode::Vpsubw => Mnemonic::Vpsubw,
+        Opcode::Vpsubd => Mnemonic::Vpsubd,
+        Opcode::Vpsubq => Mnemonic::Vpsubq,
+        Opcode::Vpand => Mnemonic::Vpand,
+        Opcode::Vpandn => Mnemonic::Vpandn,
+        Opcode::Vpor => Mnemonic::Vpor,
+        Opcode::Vpxor => Mnemonic::Vpxor,
+        Opcode::Vpcmpeqb => Mnemonic::Vpcmpeqb,
+        Opcode::Vpcmpeqw => Mnemonic::Vpcmpeqw,
+        Opcode::Vpcmpeqd => Mnemonic::Vpcmpeqd,
+        Opcode::Vpcmpeqq => Mnemonic::Vpcmpeqq,
+        Opcode::Vpcmpgtb => Mnemonic::Vpcmpgtb,
+        Opcode::Vpcmpgtw => Mnemonic::Vpcmpgtw,
+        Opcode::Vpcmpgtd => Mnemonic::Vpcmpgtd,
+        Opcode::Vpcmpgtq => Mnemonic::Vpcmpgtq,
+        Opcode::Vpminub => Mnemonic::Vpminub,
+        Opcode::Vpmaxub => Mnemonic::Vpmaxub,
+        Opcode::Vpmovmskb => Mnemonic::Vpmovmskb,
+        Opcode::Vptest => Mnemonic::Vptest,
+        Opcode::Vpshufb => Mnemonic::Vpshufb,
+        Opcode::Vpshufd => Mnemonic::Vpshufd,
+        Opcode::Vaddps => Mnemonic::Vaddps,
+        Opcode::Vaddpd => Mnemonic::Vaddpd,
+        Opcode::Vaddss => Mnemonic::Vaddss,
+        Opcode::Vaddsd => Mnemonic::Vaddsd,
+        Opcode::Vsubps => Mnemonic::Vsubps,
+        Opcode::Vsubpd => Mnemonic::Vsubpd,
+        Opcode::Vsubss => Mnemonic::Vsubss,
+        Opcode::Vsubsd => Mnemonic::Vsubsd,
+        Opcode::Vmulps => Mnemonic::Vmulps,
+        Opcode::Vmulpd => Mnemonic::Vmulpd,
+        Opcode::Vmulss => Mnemonic::Vmulss,
+        Opcode::Vmulsd => Mnemonic::Vmulsd,
+        Opcode::Vdivps => Mnemonic::Vdivps,
+        Opcode::Vdivpd => Mnemonic::Vdivpd,
+        Opcode::Vdivss => Mnemonic::Vdivss,
+        Opcode::Vdivsd => Mnemonic::Vdivsd,
+        Opcode::Vminps => Mnemonic::Vminps,
+        Opcode::Vminpd => Mnemonic::Vminpd,
+        Opcode::Vminss => Mnemonic::Vminss,
+        Opcode::Vminsd => Mnemonic::Vminsd,
+        Opcode::Vmaxps => Mnemonic::Vmaxps,
+        Opcode::Vmaxpd => Mnemonic::Vmaxpd,
+        Opcode::Vmaxss => Mnemonic::Vmaxss,
+        Opcode::Vmaxsd => Mnemonic::Vmaxsd,
+        Opcode::Vsqrtps => Mnemonic::Vsqrtps,
+        Opcode::Vsqrtpd => Mnemonic::Vsqrtpd,
+        Opcode::Vsqrtss => Mnemonic::Vsqrtss,
+        Opcode::Vsqrtsd => Mnemonic::Vsqrtsd,
+        Opcode::Vandps => Mnemonic::Vandps,
+        Opcode::Vandpd => Mnemonic::Vandpd,
+        Opcode::Vandnps => Mnemonic::Vandnps,
+        Opcode::Vandnpd => Mnemonic::Vandnpd,
+        Opcode::Vorps => Mnemonic::Vorps,
+        Opcode::Vorpd => Mnemonic::Vorpd,
+        Opcode::Vxorps => Mnemonic::Vxorps,
+        Opcode::Vxorpd => Mnemonic::Vxorpd,
+        Opcode::Xgetbv => Mnemonic::Xgetbv,
+        Opcode::Xsetbv => Mnemonic::Xsetbv,
+        Opcode::Xsave => Mnemonic::Xsave,
+        Opcode::Xrstor => Mnemonic::Xrstor,
+        Opcode::Bsf => Mnemonic::Bsf,
+        Opcode::Bsr => Mnemonic::Bsr,
+        Opcode::Bswap => Mnemonic::Bswap,
+        Opcode::Popcnt => Mnemonic::Popcnt,
+        Opcode::Lzcnt => Mnemonic::Lzcnt,
+        Opcode::Tzcnt => Mnemonic::Tzcnt,
+        Opcode::Andn => Mnemonic::Andn,
+        Opcode::Bextr => Mnemonic::Bextr,
+        Opcode::Blsi => Mnemonic::Blsi,
+        Opcode::Blsmsk => Mnemonic::Blsmsk,
+        Opcode::Blsr => Mnemonic::Blsr,
+        Opcode::Bzhi => Mnemonic::Bzhi,
+        Opcode::Pdep => Mnemonic::Pdep,
+        Opcode::Pext => Mnemonic::Pext,
+        Opcode::Mulx => Mnemonic::Mulx,
+        Opcode::Sarx => Mnemonic::Sarx,
+        Opcode::Shlx => Mnemonic::Shlx,
+        Opcode::Shrx => Mnemonic::Shrx,
+        Opcode::Rorx => Mnemonic::Rorx,
+        Opcode::Xadd => Mnemonic::Xadd,
+        Opcode::Cmps => Mnemonic::Cmps,
+        Opcode::Lods => Mnemonic::Lods,
+        Opcode::Ins => Mnemonic::Ins,
+        Opcode::Outs => Mnemonic::Outs,
+        Opcode::Cmpxchg8b => {
+            match arguments.and_then(|arguments| arguments.explicit_size) {
+                Some(ArgumentSize::Bit64) => Mnemonic::Cmpxchg16b,
+                _ => Mnemonic::Cmpxchg8b,
+            }
+        }
+        Opcode::Unknown => return None,
+    };
+    Some(mnemonic)
+}
+
+fn is_branch(mnemonic: Mnemonic) -> bool {
+    match mnemonic {
+        Mnemonic::Call | Mnemonic::Jmp | Mnemonic::Ja | Mnemonic::Jae | Mnemonic::Jb | Mnemonic::Jbe |
+        Mnemonic::Je | Mnemonic::Jg | Mnemonic::Jge | Mnemonic::Jl | Mnemonic::Jle | Mnemonic::Jne |
+        Mnemonic::Jno | Mnemonic::Jnp | Mnemonic::Jns | Mnemonic::Jo | Mnemonic::Jp | Mnemonic::Js |
+        Mnemonic::Jrcxz | Mnemonic::Loop | Mnemonic::Loope | Mnemonic::Loopne => true,
+        _ => false,
+    }
+}
+
+fn register_size(argument: &Option<InstructionArgument>) -> Option<ArgumentSize> {
+    match *argument {
+        Some(InstructionArgument::Register { ref register }) => Some(get_register_size(register)),
+        _ => None,
+    }
+}
+
+/// Size of the operation, for movsx and movzx the size of the destination.
+fn operand_size(mnemonic: Mnemonic, arguments: Option<&InstructionArguments>) -> ArgumentSize {
+    let arguments = match arguments {
+        Some(arguments) => arguments,
+        None => return ArgumentSize::Bit64,
+    };
+    match mnemonic {
+        Mnemonic::Seta | Mnemonic::Setae | Mnemonic::Setb | Mnemonic::Setbe | Mnemonic::Sete |
+        Mnemonic::Setg | Mnemonic::Setge | Mnemonic::Setl | Mnemonic::Setle | Mnemonic::Setne |
+        Mnemonic::Setno | Mnemonic::Setnp | Mnemonic::Setns | Mnemonic::Seto | Mnemonic::Setp |
+        Mnemonic::Sets => return ArgumentSize::Bit8,
+        Mnemonic::Cmpxchg8b => return ArgumentSize::Bit64,
+        Mnemonic::Cmpxchg16b => return ArgumentSize::Bit128,
+        Mnemonic::Movsx | Mnemonic::Movzx => {
+            return register_size(&arguments.second_argument).expect("movsx/movzx need a register destination");
+        }
+        _ => (),
+    }
+    arguments.explicit_size
+        .or_else(|| register_size(&arguments.second_argument))
+        .or_else(|| register_size(&arguments.first_argument))
+        .unwrap_or(ArgumentSize::Bit64)
+}
+
+fn string_operand(segment: Option<Register>, register: Register, size: ArgumentSize) -> Operand {
+    Operand::Memory {
+        segment: segment,
+        base: Some(register),
+        index: None,
+        scale: 1,
+        displacement: 0,
+        size: size,
+    }
+}
+
+fn operands(mnemonic: Mnemonic,
+            arguments: &InstructionArguments,
+            prefixes: &Prefixes,
+            operand_size: ArgumentSize,
+            next_instruction: u64)
+            -> Vec<Operand> {
+    match mnemonic {
+        // only ds:(rsi) can have a segment override, es:(rdi) is fixed
+        Mnemonic::Movs | Mnemonic::Cmps | Mnemonic::Stos | Mnemonic::Lods | Mnemonic::Scas |
+        Mnemonic::Ins | Mnemonic::Outs => {
+            return [&arguments.first_argument, &arguments.second_argument]
+                .iter()
+                .map(|argument| {
+                    match **argument {
+                        Some(InstructionArgument::EffectiveAddress { base: Some(base), .. }) => {
+                            let segment = match base {
+                                Register::RDI | Register::EDI => None,
+                                _ => prefixes.segment,
+                            };
+                            string_operand(segment, base, operand_size)
+                        }
+                        Some(InstructionArgument::Register { register }) => Operand::Register { register: register },
+                        _ => panic!("String instructions have two operands"),
+                    }
+                })
+                .collect();
+        }
+        _ => (),
+    }
+
+    // movsx and movzx read less than they write
+    let memory_size = match mnemonic {
+        Mnemonic::Movsx | Mnemonic::Movzx => arguments.explicit_size.unwrap_or(operand_size),
+        _ => operand_size,
+    };
+    let operand = |argument: &InstructionArgument| {
+        match *argument {
+            InstructionArgument::Register { register } => Operand::Register { register: register },
+            InstructionArgument::Immediate { immediate } if is_branch(mnemonic) => {
+                Operand::Target { address: next_instruction.wrapping_add(immediate as u64) }
+            }
+            InstructionArgument::Immediate { immediate } => Operand::Immediate { immediate: immediate },
+            InstructionArgument::EffectiveAddress { base, index, scale, displacement } => {
+                Operand::Memory {
+                    segment: prefixes.segment,
+                    base: base,
+                    index: index,
+                    scale: scale.unwrap_or(1),
+                    displacement: displacement as i64,
+                    size: memory_size,
+                }
+            }
+        }
+    };
+    [&arguments.first_argument, &arguments.second_argument, &arguments.third_argument, &arguments.fourth_argument]
+        .iter()
+        .filter_map(|argument| argument.as_ref().map(&operand))
+        .collect()
+}
@@ -1,10 +1,26 @@
 pub mod cpu;
 pub mod loader;
 pub mod machine_state;
+pub mod trace;
+pub mod difftest;
+pub mod coverage;
+pub mod profiler;
+pub mod symbols;
+pub mod disassembler;
+pub mod formatter;
+pub mod ir;
+pub mod memory_map;
+pub mod io_ports;
+pub mod pci;
+pub mod pic;
+pub mod virtio;
+pub mod acpi;
 mod decoder;
+mod opcode_table;
 mod instruction_set;
 mod utils;
 mod mmu;
+mod dwarf;
 
 #[macro_use]
 extern crate bitflags;
@@ -14,10 +30,14 @@ extern crate xmas_elf;
 extern crate time;
 extern crate fnv;
 extern crate extprim;
+extern crate libc;
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate bincode;
+extern crate serde_json;
+extern crate toml;
 
 #[macro_use]
 extern crate syscall;
@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::Read;
+
+use machine_state::MachineState;
+use decoder::Decoder;
+use trace::TraceSink;
+use formatter::InstructionFormatter;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::options::CpuOptions;
+
+const BOOT_SECTOR_ADDRESS: u64 = 0x7C00;
+/// the BIOS passes the boot drive in dl, 0x80 is the first hard disk
+const BOOT_DRIVE: i64 = 0x80;
+
+/* Starts a boot sector or any other flat binary like a BIOS would: the file is
+ * loaded at 0x7C00 and the cpu starts there in real mode with cs:ip 0:7C00.
+ * There are no BIOS services, the code has to switch modes on its own.
+ */
+pub fn boot(filename: &str,
+            trace_sinks: Vec<Box<dyn TraceSink>>,
+            formatter: Box<dyn InstructionFormatter>,
+            options: &CpuOptions,
+            print_registers: bool) {
+    let mut file = File::open(filename).expect("Cannot open file");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).expect("Failed to read file.");
+
+    let mut machine_state = MachineState::new();
+    machine_state.print_registers = print_registers;
+    options.apply(&mut machine_state);
+
+    machine_state.reset_to_real_mode();
+    machine_state.mem_write(BOOT_SECTOR_ADDRESS, &buffer);
+    machine_state.rip = BOOT_SECTOR_ADDRESS as i64;
+    machine_state.rsp = BOOT_SECTOR_ADDRESS as i64;
+    machine_state.rdx = BOOT_DRIVE;
+
+    let mut cpu = EmulationCPU {};
+    let mut decoder = Decoder::new(&mut cpu, &mut machine_state);
+    for sink in trace_sinks {
+        decoder.add_trace_sink(sink);
+    }
+    decoder.set_formatter(formatter);
+    decoder.execute(false);
+}
@@ -1,14 +1,25 @@
 use machine_state::load_machine_state;
 use cpu::emu_instructions::EmulationCPU;
 use decoder::Decoder;
+use trace::TraceSink;
+use formatter::InstructionFormatter;
+use cpu::options::CpuOptions;
 
-pub fn dump(filename: &str, print_instructions: bool, print_registers: bool) {
+pub fn dump(filename: &str,
+            trace_sinks: Vec<Box<dyn TraceSink>>,
+            formatter: Box<dyn InstructionFormatter>,
+            options: &CpuOptions,
+            print_registers: bool) {
     let mut cpu = EmulationCPU {};
 
     let mut machine_state = load_machine_state(filename);
-    machine_state.print_instructions = print_instructions;
     machine_state.print_registers = print_registers;
+    options.apply(&mut machine_state);
 
     let mut decoder = Decoder::new(&mut cpu, &mut machine_state);
+    for sink in trace_sinks {
+        decoder.add_trace_sink(sink);
+    }
+    decoder.set_formatter(formatter);
     decoder.execute(false);
 }
@@ -8,10 +8,20 @@ use xmas_elf::symbol_table::Entry;
 
 use machine_state::MachineState;
 use decoder::Decoder;
+use trace::TraceSink;
+use formatter::InstructionFormatter;
 use cpu::emu_instructions::EmulationCPU;
+use cpu::options::CpuOptions;
+use cpu::model::Feature;
 use utils::convert_i64_to_u8vec;
 
-pub fn elf(filename: &str, symbol: &str, print_instructions: bool, print_registers: bool, benchmark: bool) {
+pub fn elf(filename: &str,
+           symbol: &str,
+           trace_sinks: Vec<Box<dyn TraceSink>>,
+           formatter: Box<dyn InstructionFormatter>,
+           options: &CpuOptions,
+           print_registers: bool,
+           benchmark: bool) {
     let mut file = File::open(filename).expect("Cannot open file");
     let mut buffer = Vec::new();
 
@@ -23,17 +33,30 @@ pub fn elf(filename: &str, symbol: &str, print_instructions: bool, print_registe
     let main_symbol_address = get_main_symbol_address(&elf_file, &symbol);
 
     let mut machine_state = MachineState::new();
+    options.apply(&mut machine_state);
     load_program_image(&elf_file, &buffer, &mut machine_state);
     machine_state.rip = main_symbol_address as i64;
 
+    // user space runs with the state components of the cpu enabled by the kernel
+    if machine_state.cpu_model.has_feature(Feature::Xsave) {
+        machine_state.cr4 |= 1 << 18;
+        machine_state.xcr0 = machine_state.cpu_model.xcr0_supported();
+    }
+
+    // the user code and stack segments of Linux, running at privilege level 3
+    machine_state.set_up_linux_user_segments();
+
     machine_state.rsp = 0x7fffffffe018;
     machine_state.stack_push(&convert_i64_to_u8vec(1));
 
-    machine_state.print_instructions = print_instructions;
     machine_state.print_registers = print_registers;
 
     let mut cpu = EmulationCPU {};
     let mut decoder = Decoder::new(&mut cpu, &mut machine_state);
+    for sink in trace_sinks {
+        decoder.add_trace_sink(sink);
+    }
+    decoder.set_formatter(formatter);
     decoder.execute(benchmark);
 }
 
@@ -3,37 +3,240 @@ use std::io::Read;
 
 use machine_state::MachineState;
 use decoder::Decoder;
-use utils::convert_i32_to_u8vec;
+use trace::TraceSink;
+use formatter::InstructionFormatter;
+use utils::{convert_i32_to_u8vec, convert_i64_to_u8vec};
 use cpu::emu_instructions::EmulationCPU;
+use cpu::options::CpuOptions;
+use loader::options::BootOptions;
 
 const SETUP_HEADER_OFFSET: u64 = 0x1F1;
 const BIT64_OFFSET: u64 = 0x200;
 const ZERO_PAGE_ADDRESS: u64 = 0x140a0;
+const ZERO_PAGE_SIZE: usize = 0x1000;
 const COMMAND_LINE_ADDRESS: u64 = 0x20000;
 const LOAD_ADDRESS: u64 = 0x100000;
 
+/// fields of the zero page (struct boot_params)
+const ALT_MEM_K: u64 = 0x1E0;
+const EXT_RAMDISK_IMAGE: u64 = 0x0C0;
+const EXT_RAMDISK_SIZE: u64 = 0x0C4;
+const EXT_CMD_LINE_PTR: u64 = 0x0C8;
+const E820_ENTRIES: u64 = 0x1E8;
+const E820_TABLE: u64 = 0x2D0;
+const E820_MAX_ENTRIES: usize = 128;
+
+/// fields of the setup header, at the same offsets in the file and the zero page
+const BOOT_FLAG: usize = 0x1FE;
+const HEADER: usize = 0x202;
+const VERSION: usize = 0x206;
+const TYPE_OF_LOADER: u64 = 0x210;
+const LOADFLAGS: usize = 0x211;
+const CODE32_START: u64 = 0x214;
+const RAMDISK_IMAGE: u64 = 0x218;
+const RAMDISK_SIZE: u64 = 0x21C;
+const HEAP_END_PTR: u64 = 0x224;
+const CMD_LINE_PTR: u64 = 0x228;
+const INITRD_ADDR_MAX: usize = 0x22C;
+const KERNEL_ALIGNMENT: usize = 0x230;
+const RELOCATABLE_KERNEL: usize = 0x234;
+const CMDLINE_SIZE: usize = 0x238;
+const XLOADFLAGS: usize = 0x236;
+const PREF_ADDRESS: usize = 0x258;
+const INIT_SIZE: usize = 0x260;
+
+const HDRS_MAGIC: u32 = 0x53726448;
+const BOOT_FLAG_MAGIC: u16 = 0xAA55;
+/// 2.06 has the command line size, the oldest protocol this loader supports
+const MIN_PROTOCOL_VERSION: u16 = 0x206;
+/// 2.12 has xloadflags to tell if the kernel has a 64 bit entry point
+const XLOADFLAGS_VERSION: u16 = 0x20C;
+/// 2.10 has pref_address and init_size
+const PREF_ADDRESS_VERSION: u16 = 0x20A;
+
+const LOADED_HIGH: u8 = 1 << 0;
+const CAN_USE_HEAP: u8 = 1 << 7;
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// the boot loader id, 0xFF is an undefined boot loader
+const UNDEFINED_LOADER: u8 = 0xFF;
+/// the real mode heap ends below the command line, like the boot protocol suggests
+const HEAP_END: u16 = 0xFE00 - 0x200;
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    buffer[offset] as u16 | (buffer[offset + 1] as u16) << 8
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    buffer[offset..offset + 4].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    read_u32(buffer, offset) as u64 | (read_u32(buffer, offset + 4) as u64) << 32
+}
+
+/// The parts of the setup header the loader needs
+struct SetupHeader {
+    /// offset of the protected mode kernel in the file
+    kernel_offset: usize,
+    initrd_addr_max: u64,
+    cmdline_size: u64,
+    load_address: u64,
+    init_size: u64,
+}
+
+/// Checks the setup header of a bzImage, a kernel the loader cannot start is
+/// an error instead of a jump into garbage
+fn parse_setup_header(buffer: &[u8]) -> SetupHeader {
+    if buffer.len() < INIT_SIZE + 4 {
+        panic!("Invalid bzImage: the file has only {} bytes", buffer.len());
+    }
+    if read_u16(buffer, BOOT_FLAG) != BOOT_FLAG_MAGIC {
+        panic!("Invalid bzImage: boot flag {:#x} instead of {:#x}", read_u16(buffer, BOOT_FLAG), BOOT_FLAG_MAGIC);
+    }
+    if read_u32(buffer, HEADER) != HDRS_MAGIC {
+        panic!("Invalid bzImage: no \"HdrS\" signature in the setup header");
+    }
+    let version = read_u16(buffer, VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        panic!("Unsupported boot protocol version {}.{:02}, at least {}.{:02} is needed",
+               version >> 8, version & 0xFF, MIN_PROTOCOL_VERSION >> 8, MIN_PROTOCOL_VERSION & 0xFF);
+    }
+    if buffer[LOADFLAGS] & LOADED_HIGH == 0 {
+        panic!("Invalid bzImage: zImage kernels loaded at 0x10000 are not supported");
+    }
+    // older kernels cannot tell, they are assumed to have one
+    if version >= XLOADFLAGS_VERSION && read_u16(buffer, XLOADFLAGS) & XLF_KERNEL_64 == 0 {
+        panic!("Invalid bzImage: the kernel has no 64 bit entry point");
+    }
+
+    // count of setup sects is the first value in the setup header struct,
+    // 0 means 4 for compatibility
+    let setup_sects = match buffer[SETUP_HEADER_OFFSET as usize] {
+        0 => 4,
+        sects => sects as usize,
+    };
+    let kernel_offset = (setup_sects + 1) * 512;
+    if kernel_offset + BIT64_OFFSET as usize >= buffer.len() {
+        panic!("Invalid bzImage: {} setup sectors, but the file has only {} bytes", setup_sects, buffer.len());
+    }
+
+    // a relocatable kernel is moved to its alignment, the others must run
+    // where they want to be
+    let kernel_alignment = read_u32(buffer, KERNEL_ALIGNMENT) as u64;
+    let load_address = if buffer[RELOCATABLE_KERNEL] != 0 {
+        if !kernel_alignment.is_power_of_two() {
+            panic!("Invalid bzImage: kernel alignment {:#x} is not a power of two", kernel_alignment);
+        }
+        (LOAD_ADDRESS + kernel_alignment - 1) & !(kernel_alignment - 1)
+    } else if version >= PREF_ADDRESS_VERSION {
+        read_u64(buffer, PREF_ADDRESS)
+    } else {
+        LOAD_ADDRESS
+    };
+    let init_size = if version >= PREF_ADDRESS_VERSION {
+        read_u32(buffer, INIT_SIZE) as u64
+    } else {
+        (buffer.len() - kernel_offset) as u64
+    };
+
+    SetupHeader {
+        kernel_offset: kernel_offset,
+        initrd_addr_max: read_u32(buffer, INITRD_ADDR_MAX) as u64,
+        cmdline_size: read_u32(buffer, CMDLINE_SIZE) as u64,
+        load_address: load_address,
+        init_size: init_size,
+    }
+}
+
+/// Puts the initrd on the highest pages of RAM below initrd_addr_max, above the kernel
+fn load_initrd(filename: &str, header: &SetupHeader, machine_state: &mut MachineState) {
+    let mut file = File::open(filename).expect("Cannot open initrd");
+    let mut initrd = Vec::new();
+    file.read_to_end(&mut initrd).expect("Failed to read initrd.");
+
+    let size = initrd.len() as u64;
+    let kernel_end = header.load_address + header.init_size;
+    let address = match machine_state.memory_map.highest_ram(size, kernel_end, header.initrd_addr_max + 1) {
+        Some(address) => address,
+        None => panic!("The initrd of {:#x} bytes does not fit into the RAM between the kernel end {:#x} and {:#x}",
+                       size, kernel_end, header.initrd_addr_max + 1),
+    };
+    machine_state.mem_write(address, &initrd);
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + RAMDISK_IMAGE, &convert_i32_to_u8vec(address as i32));
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + RAMDISK_SIZE, &convert_i32_to_u8vec(size as i32));
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + EXT_RAMDISK_IMAGE, &convert_i32_to_u8vec((address >> 32) as i32));
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + EXT_RAMDISK_SIZE, &convert_i32_to_u8vec((size >> 32) as i32));
+}
+
+/// The e820 table with the memory map of the machine
+fn write_e820_table(machine_state: &mut MachineState) {
+    let memory_map = machine_state.memory_map.e820();
+    if memory_map.len() > E820_MAX_ENTRIES {
+        panic!("The memory map has {} entries, the zero page holds {}", memory_map.len(), E820_MAX_ENTRIES);
+    }
+    for (index, region) in memory_map.iter().enumerate() {
+        let entry = ZERO_PAGE_ADDRESS + E820_TABLE + index as u64 * 20;
+        machine_state.mem_write(entry, &convert_i64_to_u8vec(region.base as i64));
+        machine_state.mem_write(entry + 8, &convert_i64_to_u8vec(region.size as i64));
+        machine_state.mem_write(entry + 16, &convert_i32_to_u8vec(region.e820_type() as i32));
+    }
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + E820_ENTRIES, &[memory_map.len() as u8]);
+}
+
 /* see <linux kernel source>/Documentation/x86/boot.txt and zero-page.txt
  * for documentation of the 64 bit boot protocol
  */
-pub fn linux(filename: &str, print_instructions: bool, print_registers: bool) {
+pub fn linux(filename: &str,
+             boot_options: &BootOptions,
+             trace_sinks: Vec<Box<dyn TraceSink>>,
+             formatter: Box<dyn InstructionFormatter>,
+             options: &CpuOptions,
+             print_registers: bool) {
     // load kernel image from disk
     let mut file = File::open(filename).expect("Cannot open file");
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).expect("Failed to read file.");
+    let header = parse_setup_header(&buffer);
 
     let mut machine_state = MachineState::new();
-    machine_state.print_instructions = print_instructions;
     machine_state.print_registers = print_registers;
+    options.apply(&mut machine_state);
+
+    if !machine_state.memory_map.is_ram(header.load_address, header.init_size) {
+        panic!("The kernel needs RAM from {:#x} to {:#x}",
+               header.load_address, header.load_address + header.init_size);
+    }
 
     // create zero page and copy setup header into it
     let setup_header_end: usize = 0x202 + buffer[0x201] as usize;
     let setup_header = &buffer[SETUP_HEADER_OFFSET as usize..setup_header_end];
+    machine_state.mem_write(ZERO_PAGE_ADDRESS, &vec![0; ZERO_PAGE_SIZE]);
     machine_state.mem_write(ZERO_PAGE_ADDRESS + SETUP_HEADER_OFFSET, setup_header);
     machine_state.rsi = ZERO_PAGE_ADDRESS as i64;
 
+    // fields a boot loader has to fill in
+    let loadflags = buffer[LOADFLAGS] | CAN_USE_HEAP;
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + TYPE_OF_LOADER, &[UNDEFINED_LOADER, loadflags]);
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + HEAP_END_PTR, &[HEAP_END as u8, (HEAP_END >> 8) as u8]);
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + CODE32_START, &convert_i32_to_u8vec(header.load_address as i32));
+    let upper_memory_kb = machine_state.memory_map.upper_memory() / 1024;
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + ALT_MEM_K, &convert_i32_to_u8vec(upper_memory_kb as i32));
+    write_e820_table(&mut machine_state);
+
     // set kernel command line
-    machine_state.mem_write(COMMAND_LINE_ADDRESS, b"debug earlyprintk=vga");
-    machine_state.mem_write(ZERO_PAGE_ADDRESS + 0x228, &convert_i32_to_u8vec(COMMAND_LINE_ADDRESS as i32));
+    if boot_options.command_line.len() as u64 > header.cmdline_size {
+        panic!("The command line has {} bytes, the kernel accepts {}",
+               boot_options.command_line.len(), header.cmdline_size);
+    }
+    let mut command_line = boot_options.command_line.clone().into_bytes();
+    command_line.push(0);
+    machine_state.mem_write(COMMAND_LINE_ADDRESS, &command_line);
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + CMD_LINE_PTR, &convert_i32_to_u8vec(COMMAND_LINE_ADDRESS as i32));
+    machine_state.mem_write(ZERO_PAGE_ADDRESS + EXT_CMD_LINE_PTR, &convert_i32_to_u8vec(0));
+
+    if let Some(ref initrd) = boot_options.initrd {
+        load_initrd(initrd, &header, &mut machine_state);
+    }
 
     // set video mode
     machine_state.mem_write(ZERO_PAGE_ADDRESS + 0x01, &vec![9]); // screeninfo.y
@@ -41,16 +244,21 @@ pub fn linux(filename: &str, print_instructions: bool, print_registers: bool) {
     machine_state.mem_write(ZERO_PAGE_ADDRESS + 0x07, &vec![80]); // screeninfo.orig_video_cols
     machine_state.mem_write(ZERO_PAGE_ADDRESS + 0x0e, &vec![25]); // screeninfo.orig_video_lines
 
-    // load code at 0x100.000
-    // count of setup sects is the first value in the setup header struct
-    let setup_sect = buffer[SETUP_HEADER_OFFSET as usize];
-    let offset = (setup_sect + 1) as usize * 512;
-
-    machine_state.mem_write(LOAD_ADDRESS as u64, &buffer[offset..]);
-    machine_state.rip = (LOAD_ADDRESS + BIT64_OFFSET) as i64;
+    // load the protected mode kernel behind the setup sectors, usually at 0x100.000
+    machine_state.mem_write(header.load_address, &buffer[header.kernel_offset..]);
+    machine_state.rip = (header.load_address + BIT64_OFFSET) as i64;
+    // the boot protocol expects __BOOT_CS in cs and __BOOT_DS in ds, es and ss
+    machine_state.cs = 0x10;
+    machine_state.ds = 0x18;
+    machine_state.es = 0x18;
+    machine_state.ss = 0x18;
 
     // start execution
     let mut cpu = EmulationCPU {};
     let mut decoder = Decoder::new(&mut cpu, &mut machine_state);
+    for sink in trace_sinks {
+        decoder.add_trace_sink(sink);
+    }
+    decoder.set_formatter(formatter);
     decoder.execute(false);
 }
@@ -1,3 +1,6 @@
 pub mod linux;
 pub mod elf;
 pub mod dump;
+pub mod boot;
+pub mod options;
+pub mod multiboot;
@@ -0,0 +1,611 @@
+use std::fs::File;
+use std::io::Read;
+
+use xmas_elf::{ElfFile, program};
+use xmas_elf::header::HeaderPt2;
+
+use machine_state::MachineState;
+use decoder::Decoder;
+use trace::TraceSink;
+use formatter::InstructionFormatter;
+use cpu::emu_instructions::EmulationCPU;
+use cpu::options::CpuOptions;
+use loader::options::BootOptions;
+use memory_map::{MemoryMap, LOWER_MEMORY_END};
+
+/* Loads multiboot and multiboot2 kernels, see
+ * https://www.gnu.org/software/grub/manual/multiboot/multiboot.html and
+ * https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+ *
+ * The header in the first 8 KB (32 KB for multiboot2) says where the kernel
+ * goes: either the address fields of the header (the a.out kludge) or the
+ * program headers of the ELF file. The modules follow the kernel on page
+ * boundaries, the boot information at 0x10000 describes the command line,
+ * the modules and the memory map. The kernel starts in 32 bit protected mode
+ * without paging, eax holds the magic value of the boot loader and ebx the
+ * address of the boot information.
+ */
+
+const MULTIBOOT_MAGIC: u32 = 0x1BADB002;
+const MULTIBOOT_BOOTLOADER_MAGIC: i64 = 0x2BADB002;
+const MULTIBOOT_SEARCH_LENGTH: usize = 8192;
+
+const MULTIBOOT2_MAGIC: u32 = 0xE85250D6;
+const MULTIBOOT2_BOOTLOADER_MAGIC: i64 = 0x36D76289;
+const MULTIBOOT2_SEARCH_LENGTH: usize = 32768;
+const MULTIBOOT2_ARCHITECTURE_I386: u32 = 0;
+
+/// multiboot header flags
+const FLAG_PAGE_ALIGN: u32 = 1 << 0;
+const FLAG_MEMORY_INFO: u32 = 1 << 1;
+const FLAG_VIDEO_MODE: u32 = 1 << 2;
+const FLAG_ADDRESS_FIELDS: u32 = 1 << 16;
+
+/// flags of the multiboot boot information
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_COMMAND_LINE: u32 = 1 << 2;
+const INFO_MODULES: u32 = 1 << 3;
+const INFO_MEMORY_MAP: u32 = 1 << 6;
+const INFO_BOOT_LOADER_NAME: u32 = 1 << 9;
+const INFO_FRAMEBUFFER: u32 = 1 << 12;
+/// size of the multiboot boot information including the framebuffer fields
+const INFO_SIZE: usize = 116;
+
+/// multiboot2 header tags
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+const HEADER_TAG_RELOCATABLE: u16 = 10;
+const HEADER_TAG_OPTIONAL: u16 = 1;
+
+/// multiboot2 boot information tags
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMORY_INFO: u32 = 4;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+const INFO_ADDRESS: u64 = 0x10000;
+const BOOT_LOADER_NAME: &str = "x86emu";
+const PAGE_SIZE: u64 = 0x1000;
+
+/// the VGA text buffer, the only framebuffer there is
+const TEXT_BUFFER_ADDRESS: u64 = 0xB8000;
+const TEXT_COLUMNS: u32 = 80;
+const TEXT_LINES: u32 = 25;
+const FRAMEBUFFER_TYPE_EGA_TEXT: u8 = 2;
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    buffer[offset] as u16 | (buffer[offset + 1] as u16) << 8
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    buffer[offset..offset + 4].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// The address fields of a multiboot header or the address tag of a multiboot2
+/// header, where the file is loaded if it is not an ELF file
+struct AddressFields {
+    /// file offset of the header, loaded at header_address
+    header_offset: usize,
+    header_address: u32,
+    load_address: u32,
+    /// 0 loads the whole file
+    load_end_address: u32,
+    /// 0 if there is no bss
+    bss_end_address: u32,
+}
+
+enum Header {
+    Multiboot {
+        flags: u32,
+        address: Option<(AddressFields, u32)>,
+    },
+    Multiboot2 {
+        address: Option<AddressFields>,
+        entry: Option<u32>,
+        framebuffer: bool,
+    },
+}
+
+/// Where the kernel ended up
+struct Image {
+    entry: u64,
+    end: u64,
+}
+
+/// A module loaded after the kernel
+struct Module {
+    start: u64,
+    end: u64,
+    string: String,
+}
+
+/// Collects the boot information and the strings it refers to
+struct InfoArea {
+    data: Vec<u8>,
+}
+
+impl InfoArea {
+    fn new(size: usize) -> InfoArea {
+        InfoArea { data: vec![0; size] }
+    }
+
+    fn address(&self) -> u64 {
+        INFO_ADDRESS + self.data.len() as u64
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        for index in 0..4 {
+            self.data[offset + index] = (value >> (index * 8)) as u8;
+        }
+    }
+
+    fn push_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.data.extend((0..2).map(|index| (value >> (index * 8)) as u8));
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.data.extend((0..4).map(|index| (value >> (index * 8)) as u8));
+    }
+
+    fn push_u64(&mut self, value: u64) {
+        self.data.extend((0..8).map(|index| (value >> (index * 8)) as u8));
+    }
+
+    /// Appends a zero terminated string and returns its address
+    fn push_string(&mut self, string: &str) -> u32 {
+        let address = self.address() as u32;
+        self.data.extend(string.as_bytes());
+        self.data.push(0);
+        address
+    }
+
+    fn pad_to(&mut self, alignment: usize) {
+        while self.data.len() % alignment != 0 {
+            self.data.push(0);
+        }
+    }
+}
+
+fn find_header(buffer: &[u8]) -> Header {
+    // multiboot2 headers are 8 byte aligned, multiboot headers 4 byte aligned,
+    // both end within the search length
+    let multiboot2 = (0..(buffer.len().min(MULTIBOOT2_SEARCH_LENGTH) + 1).saturating_sub(16)).step_by(8)
+        .find(|&offset| {
+            let magic = read_u32(buffer, offset);
+            let architecture = read_u32(buffer, offset + 4);
+            let length = read_u32(buffer, offset + 8);
+            let checksum = read_u32(buffer, offset + 12);
+            magic == MULTIBOOT2_MAGIC &&
+                magic.wrapping_add(architecture).wrapping_add(length).wrapping_add(checksum) == 0
+        });
+    if let Some(offset) = multiboot2 {
+        return parse_multiboot2_header(buffer, offset);
+    }
+
+    let multiboot = (0..(buffer.len().min(MULTIBOOT_SEARCH_LENGTH) + 1).saturating_sub(12)).step_by(4)
+        .find(|&offset| {
+            let magic = read_u32(buffer, offset);
+            let flags = read_u32(buffer, offset + 4);
+            let checksum = read_u32(buffer, offset + 8);
+            magic == MULTIBOOT_MAGIC && magic.wrapping_add(flags).wrapping_add(checksum) == 0
+        });
+    match multiboot {
+        Some(offset) => parse_multiboot_header(buffer, offset),
+        None => panic!("No multiboot or multiboot2 header in the first 32 KB of the kernel"),
+    }
+}
+
+fn parse_multiboot_header(buffer: &[u8], offset: usize) -> Header {
+    let flags = read_u32(buffer, offset + 4);
+    // bits 0-15 are requirements, the kernel must not be booted if one is not understood
+    let unsupported = flags & 0xFFFF & !(FLAG_PAGE_ALIGN | FLAG_MEMORY_INFO | FLAG_VIDEO_MODE);
+    if unsupported != 0 {
+        panic!("Unsupported multiboot header flags: {:#x}", unsupported);
+    }
+    let address = if flags & FLAG_ADDRESS_FIELDS != 0 {
+        // header_addr, load_addr, load_end_addr, bss_end_addr and entry_addr
+        if offset + 32 > buffer.len() {
+            panic!("The multiboot header at {:#x} has address fields after the end of the kernel", offset);
+        }
+        let fields = AddressFields {
+            header_offset: offset,
+            header_address: read_u32(buffer, offset + 12),
+            load_address: read_u32(buffer, offset + 16),
+            load_end_address: read_u32(buffer, offset + 20),
+            bss_end_address: read_u32(buffer, offset + 24),
+        };
+        Some((fields, read_u32(buffer, offset + 28)))
+    } else {
+        None
+    };
+    Header::Multiboot {
+        flags: flags,
+        address: address,
+    }
+}
+
+fn parse_multiboot2_header(buffer: &[u8], offset: usize) -> Header {
+    let architecture = read_u32(buffer, offset + 4);
+    if architecture != MULTIBOOT2_ARCHITECTURE_I386 {
+        panic!("Unsupported multiboot2 architecture: {}", architecture);
+    }
+    let header_end = offset + read_u32(buffer, offset + 8) as usize;
+    if header_end > buffer.len() {
+        panic!("The multiboot2 header at {:#x} ends after the end of the kernel", offset);
+    }
+    let mut address = None;
+    let mut entry = None;
+    let mut framebuffer = false;
+
+    let mut tag = offset + 16;
+    while tag + 8 <= header_end {
+        let tag_type = read_u16(buffer, tag);
+        let optional = read_u16(buffer, tag + 2) & HEADER_TAG_OPTIONAL != 0;
+        let size = read_u32(buffer, tag + 4) as usize;
+        // tags hold at least the fields the loader reads
+        let minimum_size = match tag_type {
+            HEADER_TAG_ADDRESS => 24,
+            HEADER_TAG_ENTRY_ADDRESS => 12,
+            _ => 8,
+        };
+        if size < minimum_size || tag + size > header_end {
+            panic!("Invalid multiboot2 header tag {} with size {}", tag_type, size);
+        }
+        match tag_type {
+            HEADER_TAG_END => break,
+            HEADER_TAG_INFORMATION_REQUEST => {
+                for request in (tag + 8..tag + size).step_by(4) {
+                    match read_u32(buffer, request) {
+                        TAG_COMMAND_LINE | TAG_BOOT_LOADER_NAME | TAG_MODULE | TAG_BASIC_MEMORY_INFO |
+                        TAG_MEMORY_MAP => (),
+                        TAG_FRAMEBUFFER => framebuffer = true,
+                        request if !optional => panic!("Unsupported multiboot2 information request: {}", request),
+                        _ => (),
+                    }
+                }
+            }
+            HEADER_TAG_ADDRESS => {
+                address = Some(AddressFields {
+                    header_offset: offset,
+                    header_address: read_u32(buffer, tag + 8),
+                    load_address: read_u32(buffer, tag + 12),
+                    load_end_address: read_u32(buffer, tag + 16),
+                    bss_end_address: read_u32(buffer, tag + 20),
+                });
+            }
+            HEADER_TAG_ENTRY_ADDRESS => entry = Some(read_u32(buffer, tag + 8)),
+            HEADER_TAG_FRAMEBUFFER => framebuffer = true,
+            // there is only the text console, modules are always page aligned
+            // and the kernel is loaded where it wants to be
+            HEADER_TAG_CONSOLE_FLAGS | HEADER_TAG_MODULE_ALIGN | HEADER_TAG_RELOCATABLE => (),
+            _ if optional => (),
+            _ => panic!("Unsupported multiboot2 header tag: {}", tag_type),
+        }
+        tag += align(size as u64, 8) as usize;
+    }
+    Header::Multiboot2 {
+        address: address,
+        entry: entry,
+        framebuffer: framebuffer,
+    }
+}
+
+/// Loads the file like the address fields say (the a.out kludge)
+fn load_address_fields(buffer: &[u8], fields: &AddressFields, entry: u32, machine_state: &mut MachineState) -> Image {
+    let load_offset = fields.header_offset - (fields.header_address - fields.load_address) as usize;
+    let load_end = match fields.load_end_address {
+        0 => buffer.len(),
+        end => load_offset + (end - fields.load_address) as usize,
+    };
+    machine_state.mem_write(fields.load_address as u64, &buffer[load_offset..load_end]);
+    let end = fields.load_address as u64 + (load_end - load_offset) as u64;
+    let bss_end = (fields.bss_end_address as u64).max(end);
+    machine_state.mem_write(end, &vec![0; (bss_end - end) as usize]);
+    Image {
+        entry: entry as u64,
+        end: bss_end,
+    }
+}
+
+/// Loads the program headers of an ELF file at their physical addresses
+fn load_elf(buffer: &[u8], machine_state: &mut MachineState) -> Image {
+    let elf_file = ElfFile::new(buffer);
+    let entry = match elf_file.header.pt2 {
+        Ok(HeaderPt2::Header32(header)) => header.entry_point as u64,
+        Ok(HeaderPt2::Header64(header)) => header.entry_point,
+        Err(error) => panic!("Invalid ELF header: {}", error),
+    };
+    let mut end = 0;
+    for segment in elf_file.program_iter() {
+        if let Ok(program::Type::Load) = segment.get_type() {
+            let from = segment.offset() as usize;
+            let to = from + segment.file_size() as usize;
+            let address = segment.physical_addr();
+            machine_state.mem_write(address, &buffer[from..to]);
+            let bss = segment.mem_size() - segment.file_size();
+            machine_state.mem_write(address + segment.file_size(), &vec![0; bss as usize]);
+            end = end.max(address + segment.mem_size());
+        }
+    }
+    Image {
+        entry: entry,
+        end: end,
+    }
+}
+
+/// Loads the modules on the pages after the kernel
+fn load_modules(boot_options: &BootOptions, kernel_end: u64, machine_state: &mut MachineState) -> Vec<Module> {
+    let mut address = align(kernel_end, PAGE_SIZE);
+    boot_options.modules.iter().map(|module| {
+        let filename = module.split_whitespace().next().expect("Empty module");
+        let mut file = File::open(filename).expect("Cannot open module");
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).expect("Failed to read module.");
+        machine_state.mem_write(address, &buffer);
+        let start = address;
+        address = align(address + buffer.len() as u64, PAGE_SIZE);
+        Module {
+            start: start,
+            end: start + buffer.len() as u64,
+            string: module.clone(),
+        }
+    }).collect()
+}
+
+fn lower_memory_kb() -> u32 {
+    (LOWER_MEMORY_END / 1024) as u32
+}
+
+fn upper_memory_kb(memory_map: &MemoryMap) -> u32 {
+    (memory_map.upper_memory() / 1024) as u32
+}
+
+/// The multiboot boot information
+fn multiboot_info(memory_map: &MemoryMap, command_line: &str, modules: &[Module], framebuffer: bool) -> InfoArea {
+    let mut info = InfoArea::new(INFO_SIZE);
+    let mut flags = INFO_MEMORY | INFO_COMMAND_LINE | INFO_MODULES | INFO_MEMORY_MAP | INFO_BOOT_LOADER_NAME;
+    info.write_u32(4, lower_memory_kb());
+    info.write_u32(8, upper_memory_kb(memory_map));
+
+    let command_line = info.push_string(command_line);
+    info.write_u32(16, command_line);
+    let name = info.push_string(BOOT_LOADER_NAME);
+    info.write_u32(64, name);
+
+    let strings: Vec<u32> = modules.iter().map(|module| info.push_string(&module.string)).collect();
+    info.pad_to(4);
+    info.write_u32(20, modules.len() as u32);
+    let modules_address = info.address() as u32;
+    info.write_u32(24, modules_address);
+    for (module, string) in modules.iter().zip(strings) {
+        info.push_u32(module.start as u32);
+        info.push_u32(module.end as u32);
+        info.push_u32(string);
+        info.push_u32(0);
+    }
+
+    // the size field precedes each entry and does not count itself
+    let memory_map_address = info.address() as u32;
+    for region in memory_map.e820() {
+        info.push_u32(20);
+        info.push_u64(region.base);
+        info.push_u64(region.size);
+        info.push_u32(region.e820_type());
+    }
+    info.write_u32(44, info.address() as u32 - memory_map_address);
+    info.write_u32(48, memory_map_address);
+
+    if framebuffer {
+        flags |= INFO_FRAMEBUFFER;
+        let fields = text_framebuffer();
+        info.data[88..88 + fields.len()].copy_from_slice(&fields);
+    }
+    info.write_u32(0, flags);
+    info
+}
+
+/// framebuffer address, pitch, width, height, bits per pixel and type of the
+/// VGA text mode, shared by multiboot and multiboot2
+fn text_framebuffer() -> Vec<u8> {
+    let mut fields = InfoArea { data: Vec::new() };
+    fields.push_u64(TEXT_BUFFER_ADDRESS);
+    fields.push_u32(TEXT_COLUMNS * 2);
+    fields.push_u32(TEXT_COLUMNS);
+    fields.push_u32(TEXT_LINES);
+    fields.push_u8(16);
+    fields.push_u8(FRAMEBUFFER_TYPE_EGA_TEXT);
+    fields.data
+}
+
+/// Starts a multiboot2 tag, returns its offset for finish_tag
+fn start_tag(info: &mut InfoArea, tag_type: u32) -> usize {
+    info.pad_to(8);
+    let offset = info.data.len();
+    info.push_u32(tag_type);
+    info.push_u32(0);
+    offset
+}
+
+fn finish_tag(info: &mut InfoArea, offset: usize) {
+    let size = (info.data.len() - offset) as u32;
+    info.write_u32(offset + 4, size);
+}
+
+/// The multiboot2 boot information
+fn multiboot2_info(memory_map: &MemoryMap, command_line: &str, modules: &[Module], framebuffer: bool) -> InfoArea {
+    // total size and a reserved field
+    let mut info = InfoArea::new(8);
+
+    let tag = start_tag(&mut info, TAG_COMMAND_LINE);
+    info.push_string(command_line);
+    finish_tag(&mut info, tag);
+
+    let tag = start_tag(&mut info, TAG_BOOT_LOADER_NAME);
+    info.push_string(BOOT_LOADER_NAME);
+    finish_tag(&mut info, tag);
+
+    for module in modules {
+        let tag = start_tag(&mut info, TAG_MODULE);
+        info.push_u32(module.start as u32);
+        info.push_u32(module.end as u32);
+        info.push_string(&module.string);
+        finish_tag(&mut info, tag);
+    }
+
+    let tag = start_tag(&mut info, TAG_BASIC_MEMORY_INFO);
+    info.push_u32(lower_memory_kb());
+    info.push_u32(upper_memory_kb(memory_map));
+    finish_tag(&mut info, tag);
+
+    // entry size and version, then base, length, type and a reserved field
+    let tag = start_tag(&mut info, TAG_MEMORY_MAP);
+    info.push_u32(24);
+    info.push_u32(0);
+    for region in memory_map.e820() {
+        info.push_u64(region.base);
+        info.push_u64(region.size);
+        info.push_u32(region.e820_type());
+        info.push_u32(0);
+    }
+    finish_tag(&mut info, tag);
+
+    if framebuffer {
+        let tag = start_tag(&mut info, TAG_FRAMEBUFFER);
+        info.data.extend(text_framebuffer());
+        info.push_u16(0);
+        finish_tag(&mut info, tag);
+    }
+
+    let tag = start_tag(&mut info, TAG_END);
+    finish_tag(&mut info, tag);
+    let size = info.data.len() as u32;
+    info.write_u32(0, size);
+    info
+}
+
+pub fn multiboot(filename: &str,
+                 boot_options: &BootOptions,
+                 trace_sinks: Vec<Box<dyn TraceSink>>,
+                 formatter: Box<dyn InstructionFormatter>,
+                 options: &CpuOptions,
+                 print_registers: bool) {
+    let mut file = File::open(filename).expect("Cannot open file");
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).expect("Failed to read file.");
+
+    let mut machine_state = MachineState::new();
+    machine_state.print_registers = print_registers;
+    options.apply(&mut machine_state);
+    machine_state.set_up_flat_protected_mode();
+
+    // like grub the command line starts with the kernel
+    let command_line = if boot_options.command_line.is_empty() {
+        filename.to_string()
+    } else {
+        format!("{} {}", filename, boot_options.command_line)
+    };
+
+    let (info, magic, entry) = match find_header(&buffer) {
+        Header::Multiboot { flags, address } => {
+            let image = match address {
+                Some((ref fields, entry)) => load_address_fields(&buffer, fields, entry, &mut machine_state),
+                None => load_elf(&buffer, &mut machine_state),
+            };
+            let modules = load_modules(boot_options, image.end, &mut machine_state);
+            let framebuffer = flags & FLAG_VIDEO_MODE != 0;
+            (multiboot_info(&machine_state.memory_map, &command_line, &modules, framebuffer), MULTIBOOT_BOOTLOADER_MAGIC,
+             image.entry)
+        }
+        Header::Multiboot2 { address, entry, framebuffer } => {
+            let image = match address {
+                Some(ref fields) => {
+                    let entry = entry.expect("Multiboot2 header with an address tag, but without an entry address");
+                    load_address_fields(&buffer, fields, entry, &mut machine_state)
+                }
+                None => load_elf(&buffer, &mut machine_state),
+            };
+            let entry = entry.map_or(image.entry, |entry| entry as u64);
+            let modules = load_modules(boot_options, image.end, &mut machine_state);
+            (multiboot2_info(&machine_state.memory_map, &command_line, &modules, framebuffer), MULTIBOOT2_BOOTLOADER_MAGIC,
+             entry)
+        }
+    };
+    machine_state.mem_write(INFO_ADDRESS, &info.data);
+    machine_state.rax = magic;
+    machine_state.rbx = INFO_ADDRESS as i64;
+    machine_state.rip = entry as i64;
+
+    let mut cpu = EmulationCPU {};
+    let mut decoder = Decoder::new(&mut cpu, &mut machine_state);
+    for sink in trace_sinks {
+        decoder.add_trace_sink(sink);
+    }
+    decoder.set_formatter(formatter);
+    decoder.execute(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multiboot_header(flags: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        for value in [MULTIBOOT_MAGIC, flags, 0u32.wrapping_sub(MULTIBOOT_MAGIC).wrapping_sub(flags)].iter() {
+            header.extend(&[*value as u8, (*value >> 8) as u8, (*value >> 16) as u8, (*value >> 24) as u8]);
+        }
+        header
+    }
+
+    fn multiboot2_header(tags: &[u32]) -> Vec<u8> {
+        let length = 16 + 4 * tags.len() as u32;
+        let checksum = 0u32.wrapping_sub(MULTIBOOT2_MAGIC).wrapping_sub(length);
+        let mut header = Vec::new();
+        for value in [MULTIBOOT2_MAGIC, 0, length, checksum].iter().chain(tags) {
+            header.extend(&[*value as u8, (*value >> 8) as u8, (*value >> 16) as u8, (*value >> 24) as u8]);
+        }
+        header
+    }
+
+    #[test]
+    fn header_at_the_end_of_the_search_length() {
+        let mut buffer = vec![0; MULTIBOOT_SEARCH_LENGTH - 12];
+        buffer.extend(multiboot_header(FLAG_MEMORY_INFO));
+        assert!(match find_header(&buffer) { Header::Multiboot { flags: FLAG_MEMORY_INFO, .. } => true, _ => false });
+
+        // without tags the header is 16 bytes long
+        let mut buffer = vec![0; 16];
+        buffer.extend(multiboot2_header(&[]));
+        assert!(match find_header(&buffer) { Header::Multiboot2 { .. } => true, _ => false });
+    }
+
+    #[test]
+    #[should_panic(expected = "address fields after the end of the kernel")]
+    fn address_fields_after_the_end() {
+        let mut buffer = multiboot_header(FLAG_ADDRESS_FIELDS);
+        buffer.extend(&[0; 16]);
+        find_header(&buffer);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid multiboot2 header tag")]
+    fn empty_multiboot2_tag() {
+        // an entry address tag without the address
+        find_header(&multiboot2_header(&[3, 8, 0, 8]));
+    }
+}
@@ -0,0 +1,12 @@
+/// Settings of the boot loaders chosen on the command line
+#[derive(Default)]
+pub struct BootOptions {
+    /// kernel command line
+    pub command_line: String,
+    /// files loaded next to the kernel (multiboot modules), the path can be
+    /// followed by the command line of the module
+    pub modules: Vec<String>,
+    /// initial ramdisk of a linux kernel
+    pub initrd: Option<String>,
+}
+
@@ -1,12 +1,25 @@
 use std::fmt;
 use std::io::prelude::*;
 use std::fs::File;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use bincode::{serialize, deserialize, Infinite};
 use zero;
 
-use instruction_set::{InstructionArgument, Register, Flags, ArgumentSize};
+use instruction_set::{InstructionArgument, Register, Flags, ArgumentSize, get_register_size};
+use trace::InstructionTrace;
+use cpu::clock::Clock;
+use cpu::random::Random;
+use cpu::model::CpuModel;
+use cpu::msr::Msrs;
+use cpu::protection::Exception;
+use memory_map::MemoryMap;
+use io_ports::IoPorts;
+use pci::PciBus;
+use pic::Pic;
+use cpu::mode::{SegmentDescriptor, DescriptorTableRegister, CR0_PE, CR0_PG, CR4_PAE};
 use utils::{convert_i8_to_u8vec, convert_i16_to_u8vec, convert_i32_to_u8vec, convert_i64_to_u8vec};
 
 #[derive(Serialize, Deserialize)]
@@ -33,19 +46,91 @@ pub struct MachineState {
 
     pub rflags: i64,
 
+    /// segment selectors, the descriptors they selected are in segments
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+    pub es: u16,
+    pub fs: u16,
+    pub gs: u16,
+
     pub cr0: i64,
     pub cr2: i64,
     pub cr3: i64,
     pub cr4: i64,
     pub cr8: i64,
 
-    pub gdt: i64,
-    pub idt: i64,
+    /// descriptor caches of es, cs, ss, ds, fs and gs, see cpu/mode.rs
+    pub segments: [SegmentDescriptor; 6],
+
+    pub gdtr: DescriptorTableRegister,
+    pub idtr: DescriptorTableRegister,
+
+    /// selectors of the LDT and the TSS, their bases and limits are in ldt and tss
+    pub ldtr: u16,
+    pub tr: u16,
+    pub ldt: DescriptorTableRegister,
+    pub tss: DescriptorTableRegister,
+
+    /// model specific registers, see cpu/msr.rs
+    pub msrs: Msrs,
+
+    /// YMM0-YMM15, XMMn is the lower half of YMMn
+    pub ymm: [[u8; 32]; 16],
+    pub mxcsr: u32,
+    /// extended control register 0, the state components enabled for xsave and AVX
+    pub xcr0: u64,
 
     pub print_instructions: bool,
     pub print_registers: bool,
 
     pub memory: FnvHashMap<u64, Vec<u8>>,
+    // RAM, ROM, MMIO windows and holes of the physical address space, see memory_map.rs
+    #[serde(skip_serializing, skip_deserializing)]
+    pub memory_map: MemoryMap,
+    // devices on the i/o ports, see io_ports.rs
+    #[serde(skip_serializing, skip_deserializing)]
+    pub io_ports: IoPorts,
+    // the PCI bus if the machine has one, see pci/mod.rs
+    #[serde(skip_serializing, skip_deserializing)]
+    pub pci: Option<Rc<RefCell<PciBus>>>,
+    // the interrupt controllers of a PC, see pic.rs
+    #[serde(skip_serializing, skip_deserializing)]
+    pub pic: Option<Rc<RefCell<Pic>>>,
+
+    // set by instructions which end the emulation (e.g. the exit syscall)
+    #[serde(skip_serializing, skip_deserializing)]
+    pub stopped: bool,
+
+    // set by hlt, the cpu idles until the next interrupt
+    pub halted: bool,
+    // set by sti, no interrupts before the next instruction
+    #[serde(skip_serializing, skip_deserializing)]
+    pub interrupt_shadow: bool,
+    // physical pages holding instructions the decoder has cached
+    #[serde(skip_serializing, skip_deserializing)]
+    pub code_pages: FnvHashSet<u64>,
+    // set when cached instructions are stale, the decoder drops them before the next one
+    #[serde(skip_serializing, skip_deserializing)]
+    pub code_modified: bool,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    pub trace: InstructionTrace,
+
+    // time stamp counter and random numbers, set up by the loaders from the cpu options
+    #[serde(skip_serializing, skip_deserializing)]
+    pub clock: Clock,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub random: Random,
+    // what cpuid returns and which instructions and MSRs exist
+    #[serde(skip_serializing, skip_deserializing)]
+    pub cpu_model: CpuModel,
+    // segment override prefix of the current instruction, set by the decoder
+    #[serde(skip_serializing, skip_deserializing)]
+    pub segment_override: Option<Register>,
+    // fault of the current instruction, the decoder delivers it through the IDT
+    #[serde(skip_serializing, skip_deserializing)]
+    pub exception: Option<Exception>,
 }
 
 impl MachineState {
@@ -72,19 +157,61 @@ impl MachineState {
 
             rflags: 0,
 
-            cr0: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+            es: 0,
+            fs: 0,
+            gs: 0,
+
+            // 64 bit mode with flat segments, paging only translates once cr3 is set
+            cr0: CR0_PE | CR0_PG,
             cr2: 0,
             cr3: 0,
-            cr4: 0,
+            cr4: CR4_PAE,
             cr8: 0,
 
-            gdt: 0,
-            idt: 0,
+            segments: [SegmentDescriptor::data(0), SegmentDescriptor::code64(0), SegmentDescriptor::data(0),
+                       SegmentDescriptor::data(0), SegmentDescriptor::data(0), SegmentDescriptor::data(0)],
+
+            gdtr: DescriptorTableRegister::default(),
+            idtr: DescriptorTableRegister::default(),
+
+            ldtr: 0,
+            tr: 0,
+            ldt: DescriptorTableRegister::default(),
+            tss: DescriptorTableRegister::default(),
+
+            msrs: Msrs::new(),
+
+            ymm: [[0; 32]; 16],
+            mxcsr: 0x1F80,
+            // x87 state is always enabled
+            xcr0: 1,
 
             print_instructions: false,
             print_registers: false,
 
             memory: FnvHashMap::default(),
+            memory_map: MemoryMap::default(),
+            io_ports: IoPorts::default(),
+            pci: None,
+            pic: None,
+
+            stopped: false,
+
+            halted: false,
+            interrupt_shadow: false,
+            code_pages: FnvHashSet::default(),
+            code_modified: false,
+
+            trace: InstructionTrace::default(),
+
+            clock: Clock::default(),
+            random: Random::default(),
+            cpu_model: CpuModel::default(),
+            segment_override: None,
+            exception: None,
         }
     }
 
@@ -108,6 +235,7 @@ impl MachineState {
             ArgumentSize::Bit16 => (result as u64) & 0x8000 != 0,
             ArgumentSize::Bit32 => (result as u64) & 0x80000000 != 0,
             ArgumentSize::Bit64 => (result as u64) & 0x8000000000000000 != 0,
+            ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Flags of vector results are computed by the instruction"),
         };
         self.set_flag(Flags::Sign, sign);
 
@@ -125,7 +253,7 @@ impl MachineState {
             InstructionArgument::Register { ref register } => self.get_register_value(register),
             InstructionArgument::Immediate { immediate } => immediate,
             InstructionArgument::EffectiveAddress { .. } => {
-                let address = self.calculate_effective_address(arg);
+                let address = self.linear_address(arg);
                 match argument_size {
                     ArgumentSize::Bit8 => self.mem_read_byte(address) as i64,
                     ArgumentSize::Bit16 => {
@@ -155,6 +283,7 @@ impl MachineState {
                         }
                         value
                     }
+                    ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Use get_vector_value for vector operands"),
                 }
             }
         }
@@ -250,12 +379,25 @@ impl MachineState {
             Register::SIL => self.rsi as i8 as i64,
             Register::DIL => self.rdi as i8 as i64,
 
-            Register::ES => 0,
-            Register::CS => 0,
-            Register::SS => 0,
-            Register::DS => 0,
-            Register::FS => 0,
-            Register::GS => 0,
+            Register::ES => self.es as i64,
+            Register::CS => self.cs as i64,
+            Register::SS => self.ss as i64,
+            Register::DS => self.ds as i64,
+            Register::FS => self.fs as i64,
+            Register::GS => self.gs as i64,
+
+            // the lower 64 bit, like movq
+            Register::XMM0 | Register::XMM1 | Register::XMM2 | Register::XMM3 |
+            Register::XMM4 | Register::XMM5 | Register::XMM6 | Register::XMM7 |
+            Register::XMM8 | Register::XMM9 | Register::XMM10 | Register::XMM11 |
+            Register::XMM12 | Register::XMM13 | Register::XMM14 | Register::XMM15 |
+            Register::YMM0 | Register::YMM1 | Register::YMM2 | Register::YMM3 |
+            Register::YMM4 | Register::YMM5 | Register::YMM6 | Register::YMM7 |
+            Register::YMM8 | Register::YMM9 | Register::YMM10 | Register::YMM11 |
+            Register::YMM12 | Register::YMM13 | Register::YMM14 | Register::YMM15 => {
+                let value = &self.ymm[vector_register_number(register)];
+                *zero::read::<i64>(&value[..8])
+            }
         }
     }
 
@@ -282,7 +424,7 @@ impl MachineState {
 
             Register::CR0 => {
                 println!("CR0: {:x}", value);
-                self.cr0 = value
+                self.set_cr0(value)
             },
             Register::CR2 => {
                 println!("CR2: {:x}", value);
@@ -290,11 +432,13 @@ impl MachineState {
             },
             Register::CR3 => {
                 println!("CR3: {:x}", value);
-                self.cr3 = value
+                self.cr3 = value;
+                self.invalidate_instructions()
             },
             Register::CR4 => {
                 println!("CR4: {:x}", value);
-                self.cr4 = value
+                self.cr4 = value;
+                self.invalidate_instructions()
             },
             Register::CR8 => {
                 println!("CR5: {:x}", value);
@@ -369,27 +513,26 @@ impl MachineState {
             Register::SIL => self.rsi = ((self.rsi as u64 & 0xFFFFFFFFFFFFFF00) | (value as u8 as u64)) as i64,
             Register::DIL => self.rdi = ((self.rdi as u64 & 0xFFFFFFFFFFFFFF00) | (value as u8 as u64)) as i64,
 
-            Register::ES => (),
-            Register::CS => (),
-            Register::SS => (),
-            Register::DS => (),
-            Register::FS => (),
-            Register::GS => (),
-        }
-    }
-
-    // stack operations
-    pub fn stack_push(&mut self, data: &[u8]) {
-        let rsp = self.rsp - data.len() as i64;
-        self.mem_write(rsp as u64, data);
-        self.rsp = rsp;
-    }
+            Register::ES | Register::CS | Register::SS | Register::DS | Register::FS | Register::GS => {
+                if let Err(exception) = self.load_segment(*register, value as u16) {
+                    self.raise(exception);
+                }
+            }
 
-    pub fn stack_pop(&mut self) -> i64 {
-        let rsp = self.rsp as u64;
-        let data = self.mem_read(rsp, 8);
-        self.rsp += 8;
-        *zero::read::<i64>(&data)
+            // like vmovq, the rest of the register is cleared
+            Register::XMM0 | Register::XMM1 | Register::XMM2 | Register::XMM3 |
+            Register::XMM4 | Register::XMM5 | Register::XMM6 | Register::XMM7 |
+            Register::XMM8 | Register::XMM9 | Register::XMM10 | Register::XMM11 |
+            Register::XMM12 | Register::XMM13 | Register::XMM14 | Register::XMM15 |
+            Register::YMM0 | Register::YMM1 | Register::YMM2 | Register::YMM3 |
+            Register::YMM4 | Register::YMM5 | Register::YMM6 | Register::YMM7 |
+            Register::YMM8 | Register::YMM9 | Register::YMM10 | Register::YMM11 |
+            Register::YMM12 | Register::YMM13 | Register::YMM14 | Register::YMM15 => {
+                let mut value = convert_i64_to_u8vec(value);
+                value.resize(32, 0);
+                self.ymm[vector_register_number(register)].copy_from_slice(&value);
+            }
+        }
     }
 
     pub fn set_value(&mut self,
@@ -401,12 +544,13 @@ impl MachineState {
                 self.set_register_value(register, value)
             }
             InstructionArgument::EffectiveAddress { .. } => {
-                let address = self.calculate_effective_address(arg);
+                let address = self.linear_address(arg);
                 let vector = match argument_size {
                     ArgumentSize::Bit8 => convert_i8_to_u8vec(value as i8),
                     ArgumentSize::Bit16 => convert_i16_to_u8vec(value as i16),
                     ArgumentSize::Bit32 => convert_i32_to_u8vec(value as i32),
                     ArgumentSize::Bit64 => convert_i64_to_u8vec(value),
+                    ArgumentSize::Bit128 | ArgumentSize::Bit256 => panic!("Use set_vector_value for vector operands"),
                 };
 
                 self.mem_write(address, &vector);
@@ -415,6 +559,43 @@ impl MachineState {
         }
     }
 
+    /// Reads a vector register or size bytes of memory. Registers are returned
+    /// as a whole, the result is zero extended to 256 bit for memory operands.
+    pub fn get_vector_value(&mut self, arg: &InstructionArgument, size: ArgumentSize) -> [u8; 32] {
+        let mut value = [0; 32];
+        match *arg {
+            InstructionArgument::Register { ref register } => {
+                value = self.ymm[vector_register_number(register)];
+            }
+            InstructionArgument::EffectiveAddress { .. } => {
+                let address = self.linear_address(arg);
+                let length = size.bytes() as usize;
+                value[..length].copy_from_slice(&self.mem_read(address, length as u64));
+            }
+            InstructionArgument::Immediate { .. } => panic!("Vector operands cannot be immediates"),
+        }
+        value
+    }
+
+    /// Writes a vector register or size bytes of memory. Writing a XMM register
+    /// clears the upper half of the YMM register, like all VEX encoded instructions do.
+    pub fn set_vector_value(&mut self, value: &[u8; 32], arg: &InstructionArgument, size: ArgumentSize) {
+        match *arg {
+            InstructionArgument::Register { ref register } => {
+                let length = get_register_size(register).bytes() as usize;
+                let ymm = &mut self.ymm[vector_register_number(register)];
+                *ymm = [0; 32];
+                ymm[..length].copy_from_slice(&value[..length]);
+            }
+            InstructionArgument::EffectiveAddress { .. } => {
+                let address = self.linear_address(arg);
+                let length = size.bytes() as usize;
+                self.mem_write(address, &value[..length]);
+            }
+            InstructionArgument::Immediate { .. } => panic!("Cannot set value on immediate value"),
+        }
+    }
+
     pub fn calculate_effective_address(&self, arg: &InstructionArgument) -> u64 {
         match *arg {
             InstructionArgument::EffectiveAddress { ref base, ref index, scale, displacement} => {
@@ -424,16 +605,45 @@ impl MachineState {
                 };
                 address += match *index {
                     None => 0,
-                    Some(ref index) => self.get_register_value(index) * scale.unwrap() as i64,
+                    // 16 bit addressing has no scale
+                    Some(ref index) => self.get_register_value(index) * scale.unwrap_or(1) as i64,
                 };
                 address += displacement as i64;
-                address as u64
+                // 16 and 32 bit address registers wrap around
+                let register = base.as_ref().or(index.as_ref());
+                match register.map(get_register_size) {
+                    Some(ArgumentSize::Bit16) => address as u64 & 0xFFFF,
+                    Some(ArgumentSize::Bit32) => address as u64 & 0xFFFFFFFF,
+                    _ => address as u64,
+                }
             }
             _ => unreachable!(),
         }
     }
 }
 
+fn vector_register_number(register: &Register) -> usize {
+    match *register {
+        Register::XMM0 | Register::YMM0 => 0,
+        Register::XMM1 | Register::YMM1 => 1,
+        Register::XMM2 | Register::YMM2 => 2,
+        Register::XMM3 | Register::YMM3 => 3,
+        Register::XMM4 | Register::YMM4 => 4,
+        Register::XMM5 | Register::YMM5 => 5,
+        Register::XMM6 | Register::YMM6 => 6,
+        Register::XMM7 | Register::YMM7 => 7,
+        Register::XMM8 | Register::YMM8 => 8,
+        Register::XMM9 | Register::YMM9 => 9,
+        Register::XMM10 | Register::YMM10 => 10,
+        Register::XMM11 | Register::YMM11 => 11,
+        Register::XMM12 | Register::YMM12 => 12,
+        Register::XMM13 | Register::YMM13 => 13,
+        Register::XMM14 | Register::YMM14 => 14,
+        Register::XMM15 | Register::YMM15 => 15,
+        _ => panic!("{:?} is not a vector register", register),
+    }
+}
+
 impl fmt::Display for MachineState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
@@ -0,0 +1,375 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde_json;
+use toml;
+
+/* The physical address space of the guest. Without a memory map every address
+ * is RAM and pages are created on the first access, which is what programs
+ * started by the elf loader expect. With --memory the guest gets a PC memory
+ * map with a fixed amount of RAM, --memory-map loads one from a TOML or JSON
+ * file, addresses are numbers or hex strings:
+ *
+ *     unbacked = "all-ones"
+ *
+ *     [[regions]]
+ *     base = 0
+ *     size = "0x9fc00"
+ *     type = "ram"
+ *
+ *     [[regions]]
+ *     base = "0xf0000"
+ *     size = "0x10000"
+ *     type = "rom"
+ *     file = "bios.bin"
+ *
+ *     [[regions]]
+ *     base = "0xfec00000"
+ *     size = "0x1000"
+ *     type = "mmio"
+ *     name = "ioapic"
+ *
+ * Reserved regions are memory the guest should not use, ROMs ignore writes.
+ * MMIO windows go to the device registered under their name (MmioHandler),
+ * devices can also add windows while the guest runs. Addresses outside of the
+ * regions and MMIO windows without a device are unbacked: like on a PC bus
+ * reads return all ones and writes are dropped, or they raise a machine check
+ * with unbacked = "fault".
+ *
+ * The loaders build the e820 table and the multiboot memory map from the
+ * regions, MMIO windows are holes in them.
+ */
+
+/// RAM of the guest if there is no memory map
+pub const DEFAULT_MEMORY_SIZE: u64 = 128 << 20;
+/// end of the conventional memory below the extended BIOS data area
+pub const LOWER_MEMORY_END: u64 = 0x9FC00;
+pub const UPPER_MEMORY_START: u64 = 0x100000;
+const BIOS_ROM_START: u64 = 0xF0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionType {
+    Ram,
+    Rom,
+    Reserved,
+    Mmio,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Region {
+    #[serde(deserialize_with = "number")]
+    pub base: u64,
+    #[serde(deserialize_with = "number")]
+    pub size: u64,
+    #[serde(rename = "type")]
+    pub region_type: RegionType,
+    /// device of an MMIO window
+    #[serde(default)]
+    pub name: Option<String>,
+    /// contents of a ROM, the rest of the ROM is zero
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl Region {
+    fn new(base: u64, size: u64, region_type: RegionType) -> Region {
+        Region {
+            base: base,
+            size: size,
+            region_type: region_type,
+            name: None,
+            file: None,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    /// The type in an e820 table, multiboot uses the same values
+    pub fn e820_type(&self) -> u32 {
+        match self.region_type {
+            RegionType::Ram => 1,
+            RegionType::Rom | RegionType::Reserved | RegionType::Mmio => 2,
+        }
+    }
+}
+
+/// What accesses to addresses without memory or device do
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnbackedAccess {
+    /// reads return all ones, writes are dropped
+    AllOnes,
+    /// machine check
+    Fault,
+}
+
+impl Default for UnbackedAccess {
+    fn default() -> UnbackedAccess {
+        UnbackedAccess::AllOnes
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryConfig {
+    #[serde(default)]
+    pub unbacked: UnbackedAccess,
+    pub regions: Vec<Region>,
+}
+
+impl MemoryConfig {
+    /// A PC with memory_size bytes of RAM: conventional memory, the EBDA, the
+    /// BIOS ROM and the rest of the RAM above 1 MB. Fails for 1 MB or less.
+    pub fn pc(memory_size: u64) -> Result<MemoryConfig, String> {
+        if memory_size <= UPPER_MEMORY_START {
+            return Err(format!("{:#x} bytes of memory are too few, the guest needs more than 1 MB", memory_size));
+        }
+        Ok(MemoryConfig {
+            unbacked: UnbackedAccess::AllOnes,
+            regions: vec![
+                Region::new(0, LOWER_MEMORY_END, RegionType::Ram),
+                Region::new(LOWER_MEMORY_END, 0xA0000 - LOWER_MEMORY_END, RegionType::Reserved),
+                Region::new(BIOS_ROM_START, UPPER_MEMORY_START - BIOS_ROM_START, RegionType::Rom),
+                Region::new(UPPER_MEMORY_START, memory_size - UPPER_MEMORY_START, RegionType::Ram),
+            ],
+        })
+    }
+
+    /// Loads a memory map from a .toml or .json file
+    pub fn from_file(filename: &str) -> MemoryConfig {
+        let mut file = File::open(filename).expect("Cannot open memory map file");
+        let mut content = String::new();
+        file.read_to_string(&mut content).expect("Failed to read memory map file");
+        let mut config: MemoryConfig = match Path::new(filename).extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&content).unwrap_or_else(|error| panic!("Invalid memory map {}: {}", filename, error)),
+            Some("json") => serde_json::from_str(&content).unwrap_or_else(|error| panic!("Invalid memory map {}: {}", filename, error)),
+            _ => panic!("Unknown memory map file format: {}, expected .toml or .json", filename),
+        };
+        config.regions.sort_by_key(|region| region.base);
+        for (region, next) in config.regions.iter().zip(config.regions.iter().skip(1)) {
+            if region.end() > next.base {
+                panic!("Invalid memory map {}: region at {:#x} overlaps region at {:#x}", filename, region.base, next.base);
+            }
+        }
+        for region in &config.regions {
+            if region.size == 0 || region.base.checked_add(region.size).is_none() {
+                panic!("Invalid memory map {}: region at {:#x} has size {:#x}", filename, region.base, region.size);
+            }
+            if region.region_type == RegionType::Mmio && region.name.is_none() {
+                panic!("Invalid memory map {}: MMIO window at {:#x} has no name", filename, region.base);
+            }
+        }
+        config
+    }
+}
+
+/// A device behind an MMIO window. Accesses are 1, 2, 4 or 8 bytes at an
+/// offset into the window, wider ones are split.
+pub trait MmioHandler {
+    fn read(&mut self, offset: u64, size: u64) -> u64;
+    fn write(&mut self, offset: u64, size: u64, value: u64);
+}
+
+/// Devices of the MMIO windows in the memory map by name, see CpuOptions::mmio_handlers
+pub type MmioHandlers = Vec<(String, Rc<RefCell<dyn MmioHandler>>)>;
+
+/// Physical memory as devices doing DMA see it, through the memory map but
+/// without paging
+pub trait GuestMemory {
+    fn read(&mut self, address: u64, length: u64) -> Vec<u8>;
+    fn write(&mut self, address: u64, data: &[u8]);
+}
+
+struct MmioWindow {
+    base: u64,
+    size: u64,
+    handler: Rc<RefCell<dyn MmioHandler>>,
+}
+
+/// Where a physical address goes
+pub enum Backing {
+    Ram,
+    Rom,
+    /// the device and the offset into its window
+    Mmio(Rc<RefCell<dyn MmioHandler>>, u64),
+    Unbacked,
+}
+
+pub struct MemoryMap {
+    /// None if all of the address space is RAM
+    config: Option<MemoryConfig>,
+    windows: Vec<MmioWindow>,
+}
+
+impl MemoryMap {
+    pub fn new(config: Option<MemoryConfig>) -> MemoryMap {
+        MemoryMap {
+            config: config,
+            windows: Vec::new(),
+        }
+    }
+
+    /// Connects the device to the MMIO windows of the memory map with its name
+    pub fn connect(&mut self, name: &str, handler: Rc<RefCell<dyn MmioHandler>>) {
+        let windows: Vec<(u64, u64)> = self.regions().iter()
+            .filter(|region| region.region_type == RegionType::Mmio && region.name.as_ref().map(|n| &n[..]) == Some(name))
+            .map(|region| (region.base, region.size))
+            .collect();
+        if windows.is_empty() {
+            panic!("There is no MMIO window for the device {} in the memory map", name);
+        }
+        for (base, size) in windows {
+            self.add_window(base, size, handler.clone());
+        }
+    }
+
+    /// Maps the device at base, e.g. when the guest programs a PCI BAR. Windows
+    /// added later hide older windows at the same addresses.
+    pub fn add_window(&mut self, base: u64, size: u64, handler: Rc<RefCell<dyn MmioHandler>>) {
+        self.windows.insert(0, MmioWindow {
+            base: base,
+            size: size,
+            handler: handler,
+        });
+    }
+
+    /// Removes the windows of the device at base
+    pub fn remove_window(&mut self, base: u64) {
+        self.windows.retain(|window| window.base != base);
+    }
+
+    pub fn unbacked_access(&self) -> UnbackedAccess {
+        self.config.as_ref().map_or(UnbackedAccess::AllOnes, |config| config.unbacked)
+    }
+
+    fn regions(&self) -> &[Region] {
+        self.config.as_ref().map_or(&[], |config| &config.regions)
+    }
+
+    /// ROMs with contents from a file
+    pub fn rom_files(&self) -> Vec<(u64, u64, String)> {
+        self.regions().iter()
+            .filter(|region| region.region_type == RegionType::Rom)
+            .filter_map(|region| region.file.clone().map(|file| (region.base, region.size, file)))
+            .collect()
+    }
+
+    /// Where the address goes and how many bytes from there on go the same way,
+    /// at least one
+    pub fn lookup(&self, address: u64) -> (Backing, u64) {
+        // the last byte of the address space is a range of its own
+        let mut next = u64::MAX;
+        for window in &self.windows {
+            if address >= window.base && address - window.base < window.size {
+                let offset = address - window.base;
+                return (Backing::Mmio(window.handler.clone(), offset), window.size - offset);
+            }
+            if window.base > address {
+                next = next.min(window.base);
+            }
+        }
+        if self.config.is_none() {
+            return (Backing::Ram, (next - address).max(1));
+        }
+        for region in self.regions() {
+            if address < region.base {
+                next = next.min(region.base);
+                break;
+            }
+            if address < region.end() {
+                let backing = match region.region_type {
+                    RegionType::Ram | RegionType::Reserved => Backing::Ram,
+                    RegionType::Rom => Backing::Rom,
+                    RegionType::Mmio => Backing::Unbacked,
+                };
+                return (backing, next.min(region.end()) - address);
+            }
+        }
+        (Backing::Unbacked, (next - address).max(1))
+    }
+
+    /// The memory map reported to the guest (e820 table, multiboot memory map),
+    /// without the MMIO windows. Guests without memory map see a PC with the
+    /// default amount of RAM.
+    pub fn e820(&self) -> Vec<Region> {
+        match self.config {
+            Some(ref config) => config.regions.iter().filter(|region| region.region_type != RegionType::Mmio).cloned().collect(),
+            None => MemoryConfig::pc(DEFAULT_MEMORY_SIZE).expect("The default memory size is above 1 MB").regions,
+        }
+    }
+
+    /// Size of the RAM starting at 1 MB
+    pub fn upper_memory(&self) -> u64 {
+        self.e820().iter()
+            .find(|region| region.region_type == RegionType::Ram && region.base == UPPER_MEMORY_START)
+            .map_or(0, |region| region.size)
+    }
+
+    /// True if base..base + size is in one RAM region
+    pub fn is_ram(&self, base: u64, size: u64) -> bool {
+        self.e820().iter().any(|region| {
+            region.region_type == RegionType::Ram && base >= region.base && base + size <= region.end()
+        })
+    }
+
+    /// The highest page aligned address for size bytes of RAM between bottom
+    /// and top, None if they do not fit
+    pub fn highest_ram(&self, size: u64, bottom: u64, top: u64) -> Option<u64> {
+        self.e820().iter()
+            .filter(|region| region.region_type == RegionType::Ram)
+            .filter_map(|region| {
+                let end = region.end().min(top);
+                if end < size {
+                    return None;
+                }
+                let address = (end - size) & !(PAGE_SIZE - 1);
+                if address >= region.base.max(bottom) { Some(address) } else { None }
+            })
+            .max()
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> MemoryMap {
+        MemoryMap::new(None)
+    }
+}
+
+/// A 64 bit number in a memory map file, either a number or a hex string ("0x1000")
+struct NumberVisitor;
+
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a 64 bit number or a hex string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        if value < 0 {
+            return Err(E::custom(format!("{} is negative", value)));
+        }
+        Ok(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+        u64::from_str_radix(digits, 16).map_err(|_| E::custom(format!("invalid hex number: {}", value)))
+    }
+}
+
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    deserializer.deserialize_any(NumberVisitor)
+}
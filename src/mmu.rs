@@ -1,9 +1,23 @@
 use std::collections::hash_map::{Entry};
 use machine_state::MachineState;
-
-use zero;
+use cpu::msr::{EFER_NXE, EFER_LMA};
+use cpu::mode::{CR0_PG, CR4_PAE, CR4_PSE};
+use memory_map::{Backing, UnbackedAccess, GuestMemory};
+use cpu::protection::Exception;
 
 const PAGE_SIZE: u64 = 4096;
+/// devices see accesses of up to 8 bytes, wider ones are split
+const MMIO_ACCESS_SIZE: u64 = 8;
+/// bits 12 to 51 of a page table entry hold the physical address
+const ENTRY_ADDRESS_MASK: u64 = 0x000FFFFFFFFFF000;
+const EXECUTE_DISABLE: u64 = 1 << 63;
+const PRESENT: u64 = 1 << 0;
+/// PS bit of page directory and page directory pointer table entries
+const LARGE_PAGE: u64 = 1 << 7;
+/// page fault error code bits
+const PAGE_FAULT_PRESENT: u64 = 1 << 0;
+const PAGE_FAULT_USER: u64 = 1 << 2;
+const PAGE_FAULT_FETCH: u64 = 1 << 4;
 
 impl MachineState {
     fn get_page(&mut self, cell: u64) -> &mut Vec<u8> {
@@ -16,59 +30,120 @@ impl MachineState {
         }
     }
 
-    fn translate_virtual_to_physical_address(&mut self, address: u64) -> u64 {
-        let cr3 = self.cr3 as u64;
-        if cr3 == 0 {
-            address
-        } else {
-            // this code assumes that the operating system is using 2 megabyte pages
-            // todo: check some MSR register what page size is actually used
-            let page_address = address & 0b0000000000000000000000000000000000000000000111111111111111111111;
-            let level3 = (address & 0b0000000000000000000000000000000000111111111000000000000000000000) >> 21;
-            let level2 = (address & 0b0000000000000000000000000111111111000000000000000000000000000000) >> 30;
-            let level1 = (address & 0b0000000000000000111111111000000000000000000000000000000000000000) >> 39;
-
-            let entry = self.mem_read_phys(cr3 + level1 * 8, 8);
-            let entry = *zero::read::<u64>(&entry) >> 12 << 12;
+    /// Reads a 4 or 8 byte page table entry
+    fn read_page_table_entry(&mut self, address: u64, entry_size: u64) -> u64 {
+        let entry = self.mem_read_phys(address, entry_size);
+        let entry = entry.iter().rev().fold(0, |entry, &byte| entry << 8 | byte as u64);
+        // the execute disable bit is reserved unless EFER.NXE is set
+        if entry & EXECUTE_DISABLE != 0 && self.msrs.efer & EFER_NXE == 0 {
+            panic!("Page fault: reserved bit 63 set in page table entry {:x} at {:x}", entry, address);
+        }
+        entry
+    }
 
-            let entry = self.mem_read_phys(entry + level2 * 8, 8);
-            let entry = *zero::read::<u64>(&entry) >> 12 << 12;
+    /// Walks the page tables starting at table. Every level is the shift of
+    /// the address bits indexing it and whether its entries can map large pages.
+    /// Returns the physical address and whether no level disables execution.
+    fn walk_page_tables(&mut self, address: u64, table: u64, levels: &[(u64, bool)], entry_size: u64) -> (u64, bool) {
+        let index_mask = PAGE_SIZE / entry_size - 1;
+        let address_mask = match entry_size {
+            4 => 0xFFFFF000,
+            _ => ENTRY_ADDRESS_MASK,
+        };
+        let mut table = table;
+        let mut executable = true;
+        for &(shift, large_pages) in levels {
+            let entry_address = table + (address >> shift & index_mask) * entry_size;
+            let entry = self.read_page_table_entry(entry_address, entry_size);
+            if entry & PRESENT == 0 {
+                panic!("Page fault: {:x} is not mapped, entry {:x} at {:x}", address, entry, entry_address);
+            }
+            executable &= entry & EXECUTE_DISABLE == 0;
+            let page_size = 1 << shift;
+            if page_size == PAGE_SIZE || large_pages && entry & LARGE_PAGE != 0 {
+                return ((entry & address_mask & !(page_size - 1)) + (address & (page_size - 1)), executable);
+            }
+            table = entry & address_mask;
+        }
+        unreachable!("the last level of the page tables maps 4 KB pages")
+    }
 
-            let entry = self.mem_read_phys(entry + level3 * 8, 8);
-            let entry = *zero::read::<u64>(&entry) >> 12 << 12;
+    fn translate_virtual_to_physical_address(&mut self, address: u64) -> u64 {
+        self.translate(address).0
+    }
 
-            entry + page_address
+    /// The physical address of a linear address and whether instructions can
+    /// be fetched from it
+    fn translate(&mut self, address: u64) -> (u64, bool) {
+        let cr3 = self.cr3 as u64;
+        // the loaders run programs without page tables
+        if self.cr0 & CR0_PG == 0 || cr3 == 0 {
+            (address, true)
+        } else if self.msrs.efer & EFER_LMA != 0 {
+            // four levels with 1 GB and 2 MB pages
+            self.walk_page_tables(address, cr3 & ENTRY_ADDRESS_MASK, &[(39, false), (30, true), (21, true), (12, false)], 8)
+        } else if self.cr4 & CR4_PAE != 0 {
+            // a page directory pointer table with four entries, 2 MB pages
+            self.walk_page_tables(address, cr3 & 0xFFFFFFE0, &[(30, false), (21, true), (12, false)], 8)
+        } else {
+            // two levels of 4 byte entries, 4 MB pages with CR4.PSE
+            let large_pages = self.cr4 & CR4_PSE != 0;
+            self.walk_page_tables(address, cr3 & 0xFFFFF000, &[(22, large_pages), (12, false)], 4)
         }
     }
 
     pub fn mem_read_byte(&mut self, address: u64) -> u8 {
-        let address = self.translate_virtual_to_physical_address(address);
-        self.mem_read_byte_phys(address)
-    }
-
-    fn mem_read_byte_phys(&mut self, address: u64) -> u8 {
-        let page_number = address / PAGE_SIZE;
-        let page = self.get_page(page_number);
-        let page_offset = address % PAGE_SIZE;
-        page[page_offset as usize]
+        let physical_address = self.translate_virtual_to_physical_address(address);
+        let value = self.mem_read_phys(physical_address, 1)[0];
+        self.trace.memory_access(address, &[value], false);
+        value
     }
 
     pub fn mem_read(&mut self, address: u64, length: u64) -> Vec<u8> {
-        let address = self.translate_virtual_to_physical_address(address);
-        self.mem_read_phys(address, length)
+        let physical_address = self.translate_virtual_to_physical_address(address);
+        let data = self.mem_read_phys(physical_address, length);
+        self.trace.memory_access(address, &data, false);
+        data
     }
 
+    /// Reads RAM, ROM, devices and unbacked memory, see memory_map.rs
     fn mem_read_phys(&mut self, address: u64, length: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(length as usize);
+        while (data.len() as u64) < length {
+            // accesses at the end of the address space wrap around
+            let address = address.wrapping_add(data.len() as u64);
+            let (backing, available) = self.memory_map.lookup(address);
+            let length = available.min(length - data.len() as u64);
+            match backing {
+                Backing::Ram | Backing::Rom => self.read_pages(address, length, &mut data),
+                Backing::Mmio(handler, offset) => {
+                    // devices polled by the driver get to run first, e.g. to receive packets
+                    self.process_pci_functions();
+                    for chunk in (0..length).step_by(MMIO_ACCESS_SIZE as usize) {
+                        let size = MMIO_ACCESS_SIZE.min(length - chunk);
+                        let value = handler.borrow_mut().read(offset + chunk, size);
+                        data.extend((0..size).map(|index| (value >> (index * 8)) as u8));
+                    }
+                }
+                Backing::Unbacked => {
+                    self.unbacked_access("read", address);
+                    data.extend((0..length).map(|_| 0xFF));
+                }
+            }
+        }
+        data
+    }
+
+    fn read_pages(&mut self, address: u64, length: u64, data: &mut Vec<u8>) {
         let mut page_number = address / PAGE_SIZE;
         let mut page_offset = address % PAGE_SIZE;
         let mut data_offset = 0;
-        let mut data = Vec::new();
         loop {
             let page = self.get_page(page_number);
 
             loop {
                 if data_offset >= length {
-                    return data;
+                    return;
                 }
                 if page_offset >= PAGE_SIZE {
                     page_number += 1;
@@ -85,6 +160,7 @@ impl MachineState {
     }
 
     pub fn mem_write(&mut self, address: u64, data: &[u8]) {
+        self.trace.memory_access(address, data, true);
         let address = self.translate_virtual_to_physical_address(address);
         self.mem_write_phys(address, data)
     }
@@ -95,10 +171,43 @@ impl MachineState {
             println!("VIDEO: {}", data[0] as char);
         }
 
+        let mut written = 0;
+        while written < data.len() {
+            let address = address.wrapping_add(written as u64);
+            let (backing, available) = self.memory_map.lookup(address);
+            let length = available.min((data.len() - written) as u64) as usize;
+            let chunk = &data[written..written + length];
+            match backing {
+                Backing::Ram => self.write_pages(address, chunk),
+                // ROMs ignore writes
+                Backing::Rom => (),
+                Backing::Mmio(handler, offset) => {
+                    for (index, bytes) in chunk.chunks(MMIO_ACCESS_SIZE as usize).enumerate() {
+                        let value = bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64);
+                        let offset = offset + index as u64 * MMIO_ACCESS_SIZE;
+                        handler.borrow_mut().write(offset, bytes.len() as u64, value);
+                    }
+                    self.process_pci_functions();
+                }
+                Backing::Unbacked => self.unbacked_access("write", address),
+            }
+            written += length;
+        }
+    }
+
+    /// Writes physical memory behind the back of the memory map, loads ROMs
+    pub fn load_rom(&mut self, address: u64, data: &[u8]) {
+        self.write_pages(address, data);
+    }
+
+    fn write_pages(&mut self, address: u64, data: &[u8]) {
         let mut page_number = address / PAGE_SIZE;
         let mut page_offset = address % PAGE_SIZE;
         let mut data_offset = 0;
         loop {
+            if self.code_pages.contains(&page_number) {
+                self.invalidate_instructions();
+            }
             let page = self.get_page(page_number);
 
             loop {
@@ -118,4 +227,44 @@ impl MachineState {
             }
         }
     }
+
+    /// Remembers the physical pages of an instruction the decoder caches,
+    /// writes to them invalidate the cache. Pages with execute disable set
+    /// (EFER.NXE) raise a page fault instead.
+    pub fn add_code_pages(&mut self, address: u64, length: u64) -> Result<(), Exception> {
+        for &address in [address, address + length - 1].iter() {
+            let (physical_address, executable) = self.translate(address);
+            if !executable {
+                self.cr2 = address as i64;
+                let user = if self.cpl() == 3 { PAGE_FAULT_USER } else { 0 };
+                let reason = format!("instruction fetch from {:x}, which is not executable", address);
+                return Err(Exception::page_fault(PAGE_FAULT_PRESENT | PAGE_FAULT_FETCH | user, reason));
+            }
+            self.code_pages.insert(physical_address / PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// The cached instructions are stale: their code was overwritten or the
+    /// page tables which map it changed
+    pub fn invalidate_instructions(&mut self) {
+        self.code_modified = true;
+        self.code_pages.clear();
+    }
+
+    fn unbacked_access(&self, access: &str, address: u64) {
+        if self.memory_map.unbacked_access() == UnbackedAccess::Fault {
+            panic!("Machine check (#MC): {} of unbacked physical address {:#x}", access, address);
+        }
+    }
+}
+
+impl GuestMemory for MachineState {
+    fn read(&mut self, address: u64, length: u64) -> Vec<u8> {
+        self.mem_read_phys(address, length)
+    }
+
+    fn write(&mut self, address: u64, data: &[u8]) {
+        self.mem_write_phys(address, data)
+    }
 }
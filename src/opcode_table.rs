@@ -0,0 +1,181 @@
+// not every encoding the table format supports is used by tables/opcodes.txt yet
+#![allow(dead_code)]
+
+use instruction_set::Instruction;
+
+/// One row of tables/opcodes.txt
+pub struct OpcodeEntry {
+    pub instruction: Instruction,
+    /// ModRM reg field if it extends the opcode
+    pub extension: Option<u8>,
+    /// the whole ModRM byte for opcodes like 0F 01 D0 (xgetbv)
+    pub modrm: Option<u8>,
+    pub prefix: MandatoryPrefix,
+    pub vex: Option<VexEncoding>,
+    pub operands: Operands,
+    pub size: OperandSize,
+    pub immediate: Immediate,
+}
+
+impl OpcodeEntry {
+    /// true if the ModRM r/m operand cannot be a register
+    pub fn memory_only(&self) -> bool {
+        match self.operands {
+            Operands::Memory => true,
+            Operands::Explicit(specs) => specs.iter().any(|spec| match spec.kind {
+                OperandKind::M => true,
+                _ => false,
+            }),
+            _ => false,
+        }
+    }
+
+    /// true if the ModRM r/m operand cannot be memory
+    pub fn register_only(&self) -> bool {
+        match self.operands {
+            Operands::Explicit(specs) => specs.iter().any(|spec| match spec.kind {
+                OperandKind::U | OperandKind::R => true,
+                _ => false,
+            }),
+            _ => false,
+        }
+    }
+
+    /// true if VEX.vvvv encodes an operand, it has to be 1111 otherwise
+    pub fn vex_register(&self) -> bool {
+        match self.operands {
+            Operands::Explicit(specs) => specs.iter().any(|spec| match spec.kind {
+                OperandKind::H | OperandKind::B => true,
+                _ => false,
+            }),
+            _ => false,
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum MandatoryPrefix {
+    None,
+    OperandSize,
+    RepeatNotEqual,
+    RepeatEqual,
+}
+
+pub struct VexEncoding {
+    pub evex: bool,
+    pub length: VectorLength,
+    /// None if W is ignored
+    pub w: Option<bool>,
+}
+
+pub enum VectorLength {
+    Ignored,
+    Bit128,
+    Bit256,
+    Bit512,
+}
+
+/// Operand encodings, see tables/opcodes.txt for a description of each.
+pub enum Operands {
+    None,
+    ModRm,
+    Rm,
+    RmNoSize,
+    RmReg,
+    RegRm,
+    RegRm8,
+    RegRm16,
+    RegRm32,
+    RegRmImm,
+    RmOne,
+    RmCl,
+    RmCr,
+    CrRm,
+    Memory,
+    AlImm,
+    RaxImm,
+    RaxReg,
+    Reg,
+    RegImm,
+    Imm,
+    RcxImm,
+    Enter,
+    Cbw,
+    Cwd,
+    String,
+    SegmentRegister,
+    FarPointer,
+    Port,
+    /// Intel style operand list like Vx,Hx,Wx, destination first
+    Explicit(&'static [OperandSpec]),
+}
+
+/// One operand of an Operands::Explicit list, named like the operand
+/// abbreviations in appendix A of the Intel manual.
+pub struct OperandSpec {
+    pub kind: OperandKind,
+    pub size: OperandSize,
+}
+
+pub enum OperandKind {
+    /// general purpose register in ModRM reg
+    G,
+    /// vector register in ModRM reg
+    V,
+    /// vector register in VEX.vvvv
+    H,
+    /// general purpose register in VEX.vvvv
+    B,
+    /// general purpose register or memory in ModRM r/m
+    E,
+    /// general purpose register in ModRM r/m, no memory
+    R,
+    /// vector register or memory in ModRM r/m
+    W,
+    /// vector register in ModRM r/m, no memory
+    U,
+    /// memory in ModRM r/m, no register
+    M,
+    /// immediate
+    I,
+}
+
+#[derive(PartialEq)]
+pub enum OperandSize {
+    None,
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    /// 16, 32 or 64 bit depending on the operand size prefix and REX.W
+    Full,
+    /// like Full, but 64 bit by default in 64 bit mode
+    Full64,
+    Segment,
+    /// 128 bit
+    DoubleQuadword,
+    /// 256 bit
+    QuadQuadword,
+    /// 128 or 256 bit depending on VEX.L
+    Vector,
+    /// 32 or 64 bit depending on REX.W or VEX.W
+    DwordOrQword,
+}
+
+#[derive(PartialEq)]
+pub enum Immediate {
+    None,
+    Bit8,
+    UnsignedBit8,
+    UnsignedBit16,
+    /// 16 bit with operand size prefix, 32 bit otherwise
+    Bit16Or32,
+    Bit32,
+    /// 16, 32 or 64 bit, like OperandSize::Full
+    Full,
+    /// branch displacement, 32 bit or 16 bit with the operand size prefix
+    /// outside of 64 bit mode
+    Relative,
+}
+
+include!(concat!(env!("OUT_DIR"), "/opcode_maps.rs"));
@@ -0,0 +1,25 @@
+use pci::{PciFunction, PciConfig};
+
+/// The host bridge at 00:00.0, guests look for it before they trust
+/// configuration mechanism #1
+pub struct HostBridge;
+
+impl PciFunction for HostBridge {
+    fn config(&self) -> PciConfig {
+        PciConfig {
+            // QEMU PCIe host bridge
+            vendor_id: 0x1B36,
+            device_id: 0x0008,
+            class_code: 0x060000,
+            ..PciConfig::default()
+        }
+    }
+
+    fn bar_read(&mut self, _bar: usize, _offset: u64, _size: u64) -> u64 {
+        unreachable!("the host bridge has no BARs")
+    }
+
+    fn bar_write(&mut self, _bar: usize, _offset: u64, _size: u64, _value: u64) {
+        unreachable!("the host bridge has no BARs")
+    }
+}
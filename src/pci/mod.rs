@@ -0,0 +1,594 @@
+use std::fmt;
+use std::cmp::Reverse;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use machine_state::MachineState;
+use memory_map::{MmioHandler, RegionType, GuestMemory};
+use io_ports::PortHandler;
+
+pub mod host_bridge;
+pub mod test_device;
+
+use self::host_bridge::HostBridge;
+
+/* A PCI host bridge with the functions of the emulated devices (PciFunction).
+ * Guests reach the configuration space with mechanism #1, an address written
+ * to port 0xCF8 and the data at 0xCFC to 0xCFF, or through ECAM, which maps
+ * 4 KB of configuration space per function at
+ *
+ *     ECAM_BASE + (bus << 20 | device << 15 | function << 12 | register)
+ *
+ * The bus does what the firmware of a PC would: it gives the BARs addresses
+ * in the PCI hole (memory) and from IO_BASE on (i/o ports), enables them in
+ * the command register and routes the INTx pins to IRQs of the interrupt
+ * controller (pic.rs). Guests can size and move the BARs, they are decoded as
+ * long as they stay in the PCI hole or the i/o range. The PCI hole from
+ * ECAM_BASE up to the I/O APIC must not be RAM.
+ */
+
+pub const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+pub const CONFIG_DATA_PORT: u16 = 0xCFC;
+/// ECAM, 1 MB of configuration space per bus
+pub const ECAM_BASE: u64 = 0xB0000000;
+const ECAM_SIZE: u64 = 256 << 20;
+/// memory BARs are assigned from here up to the I/O APIC
+pub const MMIO_BASE: u64 = ECAM_BASE + ECAM_SIZE;
+const MMIO_END: u64 = 0xFEC00000;
+/// i/o BARs are assigned from here up to the last port
+pub const IO_BASE: u16 = 0xC000;
+
+/// registers of the type 0 configuration header
+const VENDOR_ID: usize = 0x00;
+const DEVICE_ID: usize = 0x02;
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const REVISION_ID: usize = 0x08;
+const CLASS_CODE: usize = 0x09;
+const CACHE_LINE_SIZE: usize = 0x0C;
+const LATENCY_TIMER: usize = 0x0D;
+const HEADER_TYPE: usize = 0x0E;
+const BAR0: usize = 0x10;
+const SUBSYSTEM_VENDOR_ID: usize = 0x2C;
+const SUBSYSTEM_ID: usize = 0x2E;
+const CAPABILITIES_POINTER: usize = 0x34;
+const INTERRUPT_LINE: usize = 0x3C;
+const INTERRUPT_PIN: usize = 0x3D;
+/// the capabilities and the device specific registers follow the header
+const DEVICE_SPECIFIC: usize = 0x40;
+const CONFIG_SPACE_SIZE: usize = 256;
+const BARS: usize = 6;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_INTERRUPT: u16 = 1 << 3;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+/// bit 31 of the config address enables the data port
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// IRQs of the PIRQA to PIRQD lines the INTx pins are swizzled to
+const PIRQ_IRQS: [u8; 4] = [10, 11, 10, 11];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    /// takes two BAR registers, the next one holds the upper half of the address
+    Memory64,
+}
+
+/// A base address register, the size is a power of two
+#[derive(Clone, Copy, Debug)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+/// An entry of the capability list, data follows the id and the next pointer
+#[derive(Clone, Debug)]
+pub struct Capability {
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+/// What a function shows in its configuration header
+#[derive(Clone, Debug, Default)]
+pub struct PciConfig {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// base class, sub class and programming interface
+    pub class_code: u32,
+    pub revision_id: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub bars: [Option<Bar>; BARS],
+    /// 1 to 4 for INTA to INTD, 0 if the function does not interrupt
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+}
+
+/// A device function on the PCI bus
+pub trait PciFunction {
+    /// The configuration header, asked once when the function is added to the bus
+    fn config(&self) -> PciConfig;
+
+    /// Accesses of 1, 2, 4 or 8 bytes at an offset into a memory or i/o BAR
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u64) -> u64;
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u64, value: u64);
+
+    /// Writes to the capabilities and the device specific registers from 0x40 on,
+    /// which are read only unless the function changes config
+    fn config_write(&mut self, _config: &mut [u8], _offset: usize, _size: u64, _value: u64) {}
+
+    /// Whether the function asserts its INTx pin
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Called after the guest wrote to a device and before it reads from an
+    /// MMIO window, does the work that needs guest memory, e.g. processes
+    /// virtqueues
+    fn process(&mut self, _memory: &mut dyn GuestMemory) {}
+}
+
+struct Function {
+    address: PciAddress,
+    handler: Rc<RefCell<dyn PciFunction>>,
+    config: Vec<u8>,
+    /// None for the upper half of a 64 bit BAR
+    bars: [Option<Bar>; BARS],
+}
+
+impl Function {
+    fn read(&self, offset: usize, size: u64) -> u64 {
+        (0..size as usize).rev().fold(0, |value, index| value << 8 | self.config[offset + index] as u64)
+    }
+
+    fn write(&mut self, offset: usize, size: u64, value: u64) {
+        for index in 0..size as usize {
+            let writable = self.writable(offset + index);
+            let byte = &mut self.config[offset + index];
+            *byte = *byte & !writable | (value >> (index * 8)) as u8 & writable;
+        }
+    }
+
+    fn command(&self) -> u16 {
+        self.read(COMMAND, 2) as u16
+    }
+
+    /// Bits of the header the guest can write
+    fn writable(&self, offset: usize) -> u8 {
+        match offset {
+            // i/o, memory and bus master enable, INTx disable
+            COMMAND => (COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER) as u8,
+            _ if offset == COMMAND + 1 => (COMMAND_INTX_DISABLE >> 8) as u8,
+            CACHE_LINE_SIZE | LATENCY_TIMER | INTERRUPT_LINE => 0xFF,
+            _ if (BAR0..BAR0 + BARS * 4).contains(&offset) => {
+                let bar = (offset - BAR0) / 4;
+                (self.bar_mask(bar) >> ((offset % 4) * 8)) as u8
+            }
+            _ => 0,
+        }
+    }
+
+    /// The address bits of a BAR register, writing all ones and reading them
+    /// back gives the size
+    fn bar_mask(&self, bar: usize) -> u32 {
+        match self.bars[bar] {
+            Some(Bar { kind: BarKind::Io, size, .. }) => !(size as u32 - 1) & !0x3,
+            Some(Bar { size, .. }) => !(size as u32).wrapping_sub(1) & !0xF,
+            None if bar > 0 && self.bars[bar - 1].map(|bar| bar.kind) == Some(BarKind::Memory64) => {
+                (!(self.bars[bar - 1].unwrap().size - 1) >> 32) as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn bar_address(&self, bar: usize) -> u64 {
+        let register = BAR0 + bar * 4;
+        match self.bars[bar] {
+            Some(Bar { kind: BarKind::Io, .. }) => self.read(register, 4) & !0x3,
+            Some(Bar { kind: BarKind::Memory32, .. }) => self.read(register, 4) & !0xF,
+            Some(Bar { kind: BarKind::Memory64, .. }) => self.read(register, 8) & !0xF,
+            None => 0,
+        }
+    }
+
+    fn set_bar_address(&mut self, bar: usize, address: u64) {
+        let register = BAR0 + bar * 4;
+        let flags = self.read(register, 1) & 0xF;
+        let size = if self.bars[bar].unwrap().kind == BarKind::Memory64 { 8 } else { 4 };
+        let value = address | flags;
+        for index in 0..size {
+            self.config[register + index] = (value >> (index * 8)) as u8;
+        }
+    }
+
+    /// The BAR of the kind (i/o or memory) decoding address and the offset into it
+    fn decode(&self, io: bool, address: u64) -> Option<(usize, u64)> {
+        let enable = if io { COMMAND_IO } else { COMMAND_MEMORY };
+        if self.command() & enable == 0 {
+            return None;
+        }
+        (0..BARS).filter_map(|index| self.bars[index].map(|bar| (index, bar)))
+            .filter(|&(_, bar)| (bar.kind == BarKind::Io) == io)
+            .map(|(index, bar)| (index, self.bar_address(index), bar.size))
+            .find(|&(_, base, size)| base != 0 && address >= base && address - base < size)
+            .map(|(index, base, _)| (index, address - base))
+    }
+}
+
+/// Builds the configuration space of a function from its header, the
+/// capabilities are linked from 0x40 on
+fn config_space(config: &PciConfig) -> Vec<u8> {
+    let mut space = vec![0; CONFIG_SPACE_SIZE];
+    {
+        let mut put = |offset: usize, size: usize, value: u64| {
+            for index in 0..size {
+                space[offset + index] = (value >> (index * 8)) as u8;
+            }
+        };
+        put(VENDOR_ID, 2, config.vendor_id as u64);
+        put(DEVICE_ID, 2, config.device_id as u64);
+        put(REVISION_ID, 1, config.revision_id as u64);
+        put(CLASS_CODE, 3, config.class_code as u64);
+        put(SUBSYSTEM_VENDOR_ID, 2, config.subsystem_vendor_id as u64);
+        put(SUBSYSTEM_ID, 2, config.subsystem_id as u64);
+        put(INTERRUPT_PIN, 1, config.interrupt_pin as u64);
+        for (index, bar) in config.bars.iter().enumerate() {
+            let flags = match *bar {
+                Some(Bar { kind: BarKind::Io, .. }) => 0x1,
+                Some(Bar { kind: BarKind::Memory32, prefetchable, .. }) => (prefetchable as u64) << 3,
+                Some(Bar { kind: BarKind::Memory64, prefetchable, .. }) => 0x4 | (prefetchable as u64) << 3,
+                None => 0,
+            };
+            put(BAR0 + index * 4, 1, flags);
+        }
+    }
+    let mut offset = DEVICE_SPECIFIC;
+    let mut pointer = CAPABILITIES_POINTER;
+    for capability in &config.capabilities {
+        let length = (2 + capability.data.len() + 3) & !3;
+        if offset + length > CONFIG_SPACE_SIZE {
+            panic!("The capabilities of PCI device {:04x}:{:04x} do not fit into the configuration space",
+                   config.vendor_id, config.device_id);
+        }
+        space[pointer] = offset as u8;
+        space[offset] = capability.id;
+        space[offset + 2..offset + 2 + capability.data.len()].copy_from_slice(&capability.data);
+        pointer = offset + 1;
+        offset += length;
+    }
+    if !config.capabilities.is_empty() {
+        space[STATUS] |= STATUS_CAPABILITIES as u8;
+    }
+    space
+}
+
+fn check_bars(config: &PciConfig) {
+    for (index, bar) in config.bars.iter().enumerate() {
+        let bar = match *bar {
+            Some(bar) => bar,
+            None => continue,
+        };
+        let minimum = if bar.kind == BarKind::Io { 4 } else { 16 };
+        let maximum = match bar.kind {
+            BarKind::Io => 256,
+            BarKind::Memory32 => 1 << 31,
+            BarKind::Memory64 => 1 << 63,
+        };
+        if !bar.size.is_power_of_two() || bar.size < minimum || bar.size > maximum {
+            panic!("Invalid size {:#x} of BAR {} of PCI device {:04x}:{:04x}",
+                   bar.size, index, config.vendor_id, config.device_id);
+        }
+        if bar.kind == BarKind::Memory64 && (index == BARS - 1 || config.bars[index + 1].is_some()) {
+            panic!("64 bit BAR {} of PCI device {:04x}:{:04x} needs the next BAR for the upper half",
+                   index, config.vendor_id, config.device_id);
+        }
+    }
+}
+
+pub struct PciBus {
+    functions: Vec<Function>,
+    /// the address register of configuration mechanism #1
+    config_address: u32,
+    /// where the next BARs go
+    next_memory: u64,
+    next_io: u64,
+}
+
+impl PciBus {
+    /// A bus with the host bridge at 00:00.0
+    pub fn new() -> PciBus {
+        let mut bus = PciBus {
+            functions: Vec::new(),
+            config_address: 0,
+            next_memory: MMIO_BASE,
+            next_io: IO_BASE as u64,
+        };
+        bus.add_at(PciAddress { bus: 0, device: 0, function: 0 }, Rc::new(RefCell::new(HostBridge)));
+        bus
+    }
+
+    /// Adds the function as function 0 of the next free device on bus 0
+    pub fn add(&mut self, handler: Rc<RefCell<dyn PciFunction>>) -> PciAddress {
+        let device = (0..32).find(|&device| !self.functions.iter().any(|function| {
+            function.address.bus == 0 && function.address.device == device
+        }));
+        let device = match device {
+            Some(device) => device,
+            None => panic!("All 32 devices of PCI bus 0 are taken"),
+        };
+        let address = PciAddress { bus: 0, device: device, function: 0 };
+        self.add_at(address, handler);
+        address
+    }
+
+    /// Adds the function at address, assigns its BARs and routes its interrupt pin
+    pub fn add_at(&mut self, address: PciAddress, handler: Rc<RefCell<dyn PciFunction>>) {
+        if address.device >= 32 || address.function >= 8 {
+            panic!("Invalid PCI address {}", address);
+        }
+        if self.function(address).is_some() {
+            panic!("PCI address {} is already taken", address);
+        }
+        let config = handler.borrow().config();
+        check_bars(&config);
+        if config.interrupt_pin > 4 {
+            panic!("Invalid interrupt pin {} of PCI device {}", config.interrupt_pin, address);
+        }
+        let mut function = Function {
+            address: address,
+            handler: handler,
+            config: config_space(&config),
+            bars: config.bars,
+        };
+        self.assign_bars(&mut function);
+        if config.interrupt_pin != 0 {
+            function.config[INTERRUPT_LINE] = intx_irq(address, config.interrupt_pin);
+        }
+        self.functions.push(function);
+        self.update_header_types(address);
+    }
+
+    /// Gives the BARs naturally aligned addresses, largest first, and enables them
+    fn assign_bars(&mut self, function: &mut Function) {
+        let mut bars: Vec<(usize, Bar)> = (0..BARS)
+            .filter_map(|index| function.bars[index].map(|bar| (index, bar)))
+            .collect();
+        bars.sort_by_key(|&(_, bar)| Reverse(bar.size));
+        let mut command = function.command();
+        for (index, bar) in bars {
+            let (next, end) = match bar.kind {
+                BarKind::Io => (&mut self.next_io, 0x10000),
+                _ => (&mut self.next_memory, MMIO_END),
+            };
+            let base = (*next + bar.size - 1) & !(bar.size - 1);
+            if base + bar.size > end {
+                panic!("BAR {} of PCI device {} with {:#x} bytes does not fit", index, function.address, bar.size);
+            }
+            *next = base + bar.size;
+            function.set_bar_address(index, base);
+            command |= if bar.kind == BarKind::Io { COMMAND_IO } else { COMMAND_MEMORY };
+        }
+        function.config[COMMAND] = command as u8;
+    }
+
+    /// Function 0 of a device with more functions has the multi function bit set
+    fn update_header_types(&mut self, address: PciAddress) {
+        let functions = self.functions.iter()
+            .filter(|function| function.address.bus == address.bus && function.address.device == address.device)
+            .count();
+        for function in &mut self.functions {
+            if function.address.bus == address.bus && function.address.device == address.device {
+                if functions > 1 {
+                    function.config[HEADER_TYPE] |= HEADER_TYPE_MULTI_FUNCTION;
+                } else {
+                    function.config[HEADER_TYPE] &= !HEADER_TYPE_MULTI_FUNCTION;
+                }
+            }
+        }
+    }
+
+    fn function(&self, address: PciAddress) -> Option<usize> {
+        self.functions.iter().position(|function| function.address == address)
+    }
+
+    /// Reads of functions which do not exist return all ones
+    pub fn config_read(&self, address: PciAddress, offset: usize, size: u64) -> u64 {
+        let function = match self.function(address) {
+            Some(index) => &self.functions[index],
+            None => return u64::MAX >> (64 - size * 8),
+        };
+        // there are no extended capabilities
+        if offset + size as usize > CONFIG_SPACE_SIZE {
+            return 0;
+        }
+        let mut value = function.read(offset, size);
+        if offset <= STATUS && STATUS < offset + size as usize && function.handler.borrow().interrupt() {
+            value |= (STATUS_INTERRUPT as u64) << ((STATUS - offset) * 8);
+        }
+        value
+    }
+
+    pub fn config_write(&mut self, address: PciAddress, offset: usize, size: u64, value: u64) {
+        let function = match self.function(address) {
+            Some(index) => &mut self.functions[index],
+            None => return,
+        };
+        if offset + size as usize > CONFIG_SPACE_SIZE {
+            return;
+        }
+        if offset >= DEVICE_SPECIFIC {
+            let handler = function.handler.clone();
+            handler.borrow_mut().config_write(&mut function.config, offset, size, value);
+        } else {
+            function.write(offset, size, value);
+        }
+    }
+
+    /// IRQs of the asserted INTx pins
+    pub fn interrupts(&self) -> Vec<u8> {
+        let mut irqs: Vec<u8> = self.functions.iter()
+            .filter(|function| function.config[INTERRUPT_PIN] != 0)
+            .filter(|function| function.command() & COMMAND_INTX_DISABLE == 0)
+            .filter(|function| function.handler.borrow().interrupt())
+            .map(|function| intx_irq(function.address, function.config[INTERRUPT_PIN]))
+            .collect();
+        irqs.sort();
+        irqs.dedup();
+        irqs
+    }
+
+    /// Connects the configuration ports, ECAM and the BARs to the machine
+    pub fn attach(bus: Rc<RefCell<PciBus>>, machine_state: &mut MachineState) {
+        for region in machine_state.memory_map.e820() {
+            if region.region_type == RegionType::Ram && region.base < MMIO_END && region.end() > ECAM_BASE {
+                panic!("RAM at {:#x} to {:#x} overlaps the PCI hole from {:#x} to {:#x}",
+                       region.base, region.end(), ECAM_BASE, MMIO_END);
+            }
+        }
+        machine_state.io_ports.add(CONFIG_ADDRESS_PORT, 8, bus.clone());
+        machine_state.io_ports.add(IO_BASE, 0x10000 - IO_BASE as u32, bus.clone());
+        machine_state.memory_map.add_window(ECAM_BASE, MMIO_END - ECAM_BASE, bus.clone());
+        machine_state.pci = Some(bus);
+    }
+
+    fn bar_read(&self, io: bool, address: u64, size: u64) -> u64 {
+        for function in &self.functions {
+            if let Some((bar, offset)) = function.decode(io, address) {
+                return function.handler.borrow_mut().bar_read(bar, offset, size);
+            }
+        }
+        u64::MAX >> (64 - size * 8)
+    }
+
+    fn bar_write(&self, io: bool, address: u64, size: u64, value: u64) {
+        for function in &self.functions {
+            if let Some((bar, offset)) = function.decode(io, address) {
+                function.handler.borrow_mut().bar_write(bar, offset, size, value);
+                return;
+            }
+        }
+    }
+
+    /// The function and register of the config address, None if the data port is disabled
+    fn config_data_address(&self, port: u16) -> Option<(PciAddress, usize)> {
+        if self.config_address & CONFIG_ENABLE == 0 {
+            return None;
+        }
+        let address = PciAddress {
+            bus: (self.config_address >> 16) as u8,
+            device: (self.config_address >> 11) as u8 & 0x1F,
+            function: (self.config_address >> 8) as u8 & 0x7,
+        };
+        let offset = (self.config_address & 0xFC) as usize + (port - CONFIG_DATA_PORT) as usize;
+        Some((address, offset))
+    }
+}
+
+impl Default for PciBus {
+    fn default() -> PciBus {
+        PciBus::new()
+    }
+}
+
+impl MachineState {
+    /// Lets the PCI functions do their DMA, see PciFunction::process
+    pub fn process_pci_functions(&mut self) {
+        let functions: Vec<Rc<RefCell<dyn PciFunction>>> = match self.pci {
+            Some(ref bus) => bus.borrow().functions.iter().map(|function| function.handler.clone()).collect(),
+            None => return,
+        };
+        for function in functions {
+            // DMA into an MMIO window gets here again while the function is busy
+            if let Ok(mut function) = function.try_borrow_mut() {
+                function.process(self);
+            }
+        }
+    }
+}
+
+/// Pin 1 to 4 of a device goes to PIRQA to PIRQD rotated by the device number
+fn intx_irq(address: PciAddress, pin: u8) -> u8 {
+    PIRQ_IRQS[(address.device as usize + pin as usize - 1) % 4]
+}
+
+/// ECAM offsets select the function by bus, device and function number
+fn ecam_address(offset: u64) -> (PciAddress, usize) {
+    let address = PciAddress {
+        bus: (offset >> 20) as u8,
+        device: (offset >> 15) as u8 & 0x1F,
+        function: (offset >> 12) as u8 & 0x7,
+    };
+    (address, (offset & 0xFFF) as usize)
+}
+
+/// The configuration ports and the i/o BARs
+impl PortHandler for PciBus {
+    fn read(&mut self, port: u16, size: u64) -> u64 {
+        match port {
+            CONFIG_ADDRESS_PORT if size == 4 => self.config_address as u64,
+            CONFIG_DATA_PORT...0xCFF => {
+                match self.config_data_address(port) {
+                    Some((address, offset)) => self.config_read(address, offset, size),
+                    None => u64::MAX >> (64 - size * 8),
+                }
+            }
+            _ if port >= IO_BASE => self.bar_read(true, port as u64, size),
+            _ => u64::MAX >> (64 - size * 8),
+        }
+    }
+
+    fn write(&mut self, port: u16, size: u64, value: u64) {
+        match port {
+            // byte writes to 0xCF8 to 0xCFB are not config addresses
+            CONFIG_ADDRESS_PORT if size == 4 => self.config_address = value as u32 & 0x80FFFFFC,
+            CONFIG_DATA_PORT...0xCFF => {
+                if let Some((address, offset)) = self.config_data_address(port) {
+                    self.config_write(address, offset, size, value);
+                }
+            }
+            _ if port >= IO_BASE => self.bar_write(true, port as u64, size, value),
+            _ => (),
+        }
+    }
+}
+
+/// ECAM and the memory BARs in the PCI hole
+impl MmioHandler for PciBus {
+    fn read(&mut self, offset: u64, size: u64) -> u64 {
+        if offset < ECAM_SIZE {
+            let (address, register) = ecam_address(offset);
+            self.config_read(address, register, size)
+        } else {
+            self.bar_read(false, ECAM_BASE + offset, size)
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) {
+        if offset < ECAM_SIZE {
+            let (address, register) = ecam_address(offset);
+            self.config_write(address, register, size, value)
+        } else {
+            self.bar_write(false, ECAM_BASE + offset, size, value)
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use pci::{PciFunction, PciConfig, Bar, BarKind, Capability};
+
+const SCRATCH_SIZE: u64 = 4096;
+const LARGE_BAR_SIZE: u64 = 1 << 20;
+const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+/// the power management capability is the first one at 0x40, its control
+/// and status register follows the capabilities register
+const POWER_MANAGEMENT_CONTROL: usize = 0x44;
+
+/* A PCI function for testing guests and the bus (--pci-test-device), there
+ * is no driver for it:
+ *
+ *     BAR0  4 KB of memory, scratch RAM
+ *     BAR1  16 i/o ports, writing 1 or 0 to the first one asserts or
+ *           deasserts INTA, reading it returns the state of the pin
+ *     BAR2  1 MB of 64 bit prefetchable memory, reads return the offset
+ *
+ * The capability list has power management, whose power state is writable,
+ * and a vendor specific capability with the name of the emulator.
+ */
+pub struct TestDevice {
+    scratch: Vec<u8>,
+    interrupt: bool,
+}
+
+impl TestDevice {
+    pub fn new() -> TestDevice {
+        TestDevice {
+            scratch: vec![0; SCRATCH_SIZE as usize],
+            interrupt: false,
+        }
+    }
+}
+
+impl Default for TestDevice {
+    fn default() -> TestDevice {
+        TestDevice::new()
+    }
+}
+
+impl PciFunction for TestDevice {
+    fn config(&self) -> PciConfig {
+        // the length counts the id and the next pointer too
+        let mut vendor_specific = vec![9];
+        vendor_specific.extend_from_slice(b"x86emu");
+        PciConfig {
+            // QEMU PCI test device
+            vendor_id: 0x1B36,
+            device_id: 0x0005,
+            class_code: 0xFF0000,
+            bars: [
+                Some(Bar { kind: BarKind::Memory32, size: SCRATCH_SIZE, prefetchable: false }),
+                Some(Bar { kind: BarKind::Io, size: 16, prefetchable: false }),
+                Some(Bar { kind: BarKind::Memory64, size: LARGE_BAR_SIZE, prefetchable: true }),
+                None,
+                None,
+                None,
+            ],
+            interrupt_pin: 1,
+            capabilities: vec![
+                // version 3
+                Capability { id: CAPABILITY_POWER_MANAGEMENT, data: vec![0x03, 0x00, 0x00, 0x00] },
+                Capability { id: CAPABILITY_VENDOR_SPECIFIC, data: vendor_specific },
+            ],
+            ..PciConfig::default()
+        }
+    }
+
+    fn bar_read(&mut self, bar: usize, offset: u64, size: u64) -> u64 {
+        match bar {
+            0 => {
+                (0..size).rev().fold(0, |value, index| {
+                    let byte = self.scratch.get((offset + index) as usize).cloned().unwrap_or(0);
+                    value << 8 | byte as u64
+                })
+            }
+            1 if offset == 0 => self.interrupt as u64,
+            1 => 0,
+            _ => offset,
+        }
+    }
+
+    fn bar_write(&mut self, bar: usize, offset: u64, size: u64, value: u64) {
+        match bar {
+            0 => {
+                for index in 0..size {
+                    if let Some(byte) = self.scratch.get_mut((offset + index) as usize) {
+                        *byte = (value >> (index * 8)) as u8;
+                    }
+                }
+            }
+            1 if offset == 0 => self.interrupt = value & 1 != 0,
+            _ => (),
+        }
+    }
+
+    fn config_write(&mut self, config: &mut [u8], offset: usize, size: u64, value: u64) {
+        // the power state in the low two bits of the control register
+        if offset <= POWER_MANAGEMENT_CONTROL && POWER_MANAGEMENT_CONTROL < offset + size as usize {
+            let state = (value >> ((POWER_MANAGEMENT_CONTROL - offset) * 8)) as u8 & 0x3;
+            config[POWER_MANAGEMENT_CONTROL] = config[POWER_MANAGEMENT_CONTROL] & !0x3 | state;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+}
@@ -0,0 +1,253 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use machine_state::MachineState;
+use instruction_set::Flags;
+use io_ports::PortHandler;
+use cpu::msr::EFER_LMA;
+
+/* The two 8259A interrupt controllers of a PC. The master at ports 0x20 and
+ * 0x21 takes IRQ 0 to 7, the slave at 0xA0 and 0xA1 IRQ 8 to 15 and signals
+ * them on IRQ 2 of the master. Guests initialize each controller with ICW1 to
+ * ICW4, which set the vector of its first IRQ, then mask IRQs with OCW1 and
+ * end their handlers with an EOI (OCW2). OCW3 selects whether the command
+ * port reads the requests or the IRQs in service.
+ *
+ * The IRQs are level triggered, the PCI INTx pins of the devices drive them.
+ * Between instructions the decoder delivers the request with the highest
+ * priority, the lowest IRQ, through the IDT if rflags.IF allows it. Without a
+ * 64 bit IDT requests stay pending. Rotating priorities, special mask mode and
+ * the edge triggered mode are not emulated. Until the guest initializes them
+ * the controllers mask all IRQs, at the vectors a BIOS would use.
+ */
+
+const MASTER_COMMAND_PORT: u16 = 0x20;
+const SLAVE_COMMAND_PORT: u16 = 0xA0;
+/// the IRQ of the master the slave is connected to
+const CASCADE_IRQ: u8 = 2;
+
+/// command port writes
+const ICW1: u8 = 1 << 4;
+const ICW1_ICW4_NEEDED: u8 = 1 << 0;
+const OCW3: u8 = 1 << 3;
+const OCW3_READ_REGISTER: u8 = 1 << 1;
+const OCW3_READ_ISR: u8 = 1 << 0;
+const OCW2_EOI: u8 = 1 << 5;
+const OCW2_SPECIFIC: u8 = 1 << 6;
+const ICW4_AUTO_EOI: u8 = 1 << 1;
+
+/// The next initialization word the data port expects
+#[derive(Clone, Copy, PartialEq)]
+enum Initialization {
+    Done,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+struct Controller {
+    /// interrupt request register, the levels of the IRQ lines
+    irr: u8,
+    /// in service register
+    isr: u8,
+    /// interrupt mask register
+    imr: u8,
+    /// vector of IRQ 0 of the controller
+    vector_base: u8,
+    initialization: Initialization,
+    icw4_needed: bool,
+    auto_eoi: bool,
+    /// the command port reads the ISR instead of the IRR
+    read_isr: bool,
+}
+
+impl Controller {
+    fn new(vector_base: u8) -> Controller {
+        Controller {
+            irr: 0,
+            isr: 0,
+            imr: 0xFF,
+            vector_base: vector_base,
+            initialization: Initialization::Done,
+            icw4_needed: false,
+            auto_eoi: false,
+            read_isr: false,
+        }
+    }
+
+    /// The requested IRQ with the highest priority, if no IRQ of the same or
+    /// a higher priority is in service
+    fn pending(&self) -> Option<u8> {
+        let requests = self.irr & !self.imr;
+        if requests == 0 {
+            return None;
+        }
+        let irq = requests.trailing_zeros() as u8;
+        if self.isr & ((2u16 << irq) - 1) as u8 != 0 {
+            return None;
+        }
+        Some(irq)
+    }
+
+    fn acknowledge(&mut self, irq: u8) -> u8 {
+        if !self.auto_eoi {
+            self.isr |= 1 << irq;
+        }
+        self.vector_base.wrapping_add(irq)
+    }
+
+    fn read(&self, command: bool) -> u8 {
+        match (command, self.read_isr) {
+            (true, true) => self.isr,
+            (true, false) => self.irr,
+            (false, _) => self.imr,
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & ICW1 != 0 {
+            self.isr = 0;
+            self.imr = 0;
+            self.read_isr = false;
+            self.auto_eoi = false;
+            self.icw4_needed = value & ICW1_ICW4_NEEDED != 0;
+            self.initialization = Initialization::Icw2;
+        } else if value & OCW3 != 0 {
+            if value & OCW3_READ_REGISTER != 0 {
+                self.read_isr = value & OCW3_READ_ISR != 0;
+            }
+        } else if value & OCW2_EOI != 0 {
+            let irq = if value & OCW2_SPECIFIC != 0 {
+                value & 0x7
+            } else if self.isr != 0 {
+                self.isr.trailing_zeros() as u8
+            } else {
+                return;
+            };
+            self.isr &= !(1 << irq);
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.initialization = match self.initialization {
+            Initialization::Done => {
+                self.imr = value;
+                Initialization::Done
+            }
+            Initialization::Icw2 => {
+                self.vector_base = value & 0xF8;
+                Initialization::Icw3
+            }
+            // the slave is always on IRQ 2
+            Initialization::Icw3 if self.icw4_needed => Initialization::Icw4,
+            Initialization::Icw3 => Initialization::Done,
+            Initialization::Icw4 => {
+                self.auto_eoi = value & ICW4_AUTO_EOI != 0;
+                Initialization::Done
+            }
+        };
+    }
+}
+
+pub struct Pic {
+    master: Controller,
+    slave: Controller,
+}
+
+impl Pic {
+    pub fn new() -> Pic {
+        Pic {
+            master: Controller::new(0x08),
+            slave: Controller::new(0x70),
+        }
+    }
+
+    /// Sets the IRQ lines, irqs are the asserted ones
+    pub fn set_irqs(&mut self, irqs: &[u8]) {
+        let lines = irqs.iter().fold(0u16, |lines, &irq| lines | 1 << irq);
+        self.slave.irr = (lines >> 8) as u8;
+        let cascade = if self.slave.pending().is_some() { 1 << CASCADE_IRQ } else { 0 };
+        self.master.irr = lines as u8 & !(1 << CASCADE_IRQ) | cascade;
+    }
+
+    /// The vector of the pending IRQ with the highest priority, which is in
+    /// service from now on
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master.pending()?;
+        if irq != CASCADE_IRQ {
+            return Some(self.master.acknowledge(irq));
+        }
+        let slave_irq = self.slave.pending()?;
+        self.master.acknowledge(irq);
+        Some(self.slave.acknowledge(slave_irq))
+    }
+
+    /// Connects the ports of both controllers to the machine
+    pub fn attach(pic: Rc<RefCell<Pic>>, machine_state: &mut MachineState) {
+        machine_state.io_ports.add(MASTER_COMMAND_PORT, 2, pic.clone());
+        machine_state.io_ports.add(SLAVE_COMMAND_PORT, 2, pic.clone());
+        machine_state.pic = Some(pic);
+    }
+
+    fn controller(&mut self, port: u16) -> &mut Controller {
+        if port >= SLAVE_COMMAND_PORT {
+            &mut self.slave
+        } else {
+            &mut self.master
+        }
+    }
+}
+
+impl Default for Pic {
+    fn default() -> Pic {
+        Pic::new()
+    }
+}
+
+impl PortHandler for Pic {
+    fn read(&mut self, port: u16, _size: u64) -> u64 {
+        self.controller(port).read(port & 1 == 0) as u64
+    }
+
+    fn write(&mut self, port: u16, _size: u64, value: u64) {
+        let controller = self.controller(port);
+        if port & 1 == 0 {
+            controller.write_command(value as u8);
+        } else {
+            controller.write_data(value as u8);
+        }
+    }
+}
+
+impl MachineState {
+    /// Passes the INTx pins of the PCI bus to the interrupt controller and
+    /// delivers the pending interrupt if the cpu takes interrupts
+    pub fn deliver_interrupts(&mut self) {
+        let pic = match self.pic {
+            Some(ref pic) => pic.clone(),
+            None => return,
+        };
+        let irqs = match self.pci {
+            Some(ref bus) => bus.borrow().interrupts(),
+            None => Vec::new(),
+        };
+        pic.borrow_mut().set_irqs(&irqs);
+        if !self.takes_interrupts() {
+            return;
+        }
+        let vector = match pic.borrow_mut().acknowledge() {
+            Some(vector) => vector,
+            None => return,
+        };
+        self.halted = false;
+        if let Err(exception) = self.interrupt(vector, None, false) {
+            self.deliver_exception(exception);
+        }
+    }
+
+    /// Whether an interrupt can reach the cpu: there is an interrupt controller,
+    /// rflags.IF is set and there is a 64 bit IDT
+    pub fn takes_interrupts(&self) -> bool {
+        self.pic.is_some() && self.get_flag(Flags::Interrupt) && self.msrs.efer & EFER_LMA != 0 && self.idtr.limit != 0
+    }
+}
@@ -0,0 +1,192 @@
+use std::io::Write;
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use symbols::SymbolTable;
+use trace::{TraceSink, TraceRecord};
+
+/* Deterministic guest profiler: every executed instruction is a sample.
+ * The call stack is reconstructed from the executed call and ret instructions,
+ * the functions are looked up in the elf symbol table.
+ */
+
+enum Pending {
+    Nothing,
+    Call(u64),
+    Ret,
+}
+
+pub struct Profiler<W: Write> {
+    writer: W,
+    symbols: SymbolTable,
+    top: usize,
+    /// call stack tree, node -> (parent node, function), node 0 is the root
+    nodes: Vec<(usize, Option<usize>)>,
+    children: FnvHashMap<(usize, Option<usize>), usize>,
+    /// instructions executed with exactly this stack
+    counts: Vec<u64>,
+    /// callers of the current function and their return addresses
+    stack: Vec<(usize, u64)>,
+    callers: usize,
+    node: usize,
+    pending: Pending,
+    instructions: FnvHashMap<u64, (u64, String)>,
+}
+
+impl<W: Write> Profiler<W> {
+    /// Writes collapsed stacks for flamegraph.pl to writer and prints the top
+    /// functions and instructions to stdout.
+    pub fn new(writer: W, program: &str, top: usize) -> Profiler<W> {
+        Profiler {
+            writer: writer,
+            symbols: SymbolTable::from_file(program),
+            top: top,
+            nodes: vec![(0, None)],
+            children: FnvHashMap::default(),
+            counts: vec![0],
+            stack: Vec::new(),
+            callers: 0,
+            node: 0,
+            pending: Pending::Nothing,
+            instructions: FnvHashMap::default(),
+        }
+    }
+
+    fn child(&mut self, parent: usize, function: Option<usize>) -> usize {
+        let next_node = self.nodes.len();
+        let node = *self.children.entry((parent, function)).or_insert(next_node);
+        if node == next_node {
+            self.nodes.push((parent, function));
+            self.counts.push(0);
+        }
+        node
+    }
+
+    fn function_name(&self, function: Option<usize>) -> &str {
+        match function {
+            Some(index) => &self.symbols.get(index).name,
+            None => "[unknown]",
+        }
+    }
+
+    /// Functions from the outermost caller to node.
+    fn functions(&self, mut node: usize) -> Vec<Option<usize>> {
+        let mut functions = Vec::new();
+        while node != 0 {
+            let (parent, function) = self.nodes[node];
+            functions.push(function);
+            node = parent;
+        }
+        functions.reverse();
+        functions
+    }
+
+    fn location(&self, address: u64) -> String {
+        match self.symbols.lookup(address) {
+            Some(index) => {
+                let symbol = self.symbols.get(index);
+                format!("{}+{:#x}", symbol.name, address - symbol.address)
+            }
+            None => "[unknown]".to_string(),
+        }
+    }
+
+    fn print_top_functions(&self) {
+        // self: instructions in the function itself, total: including everything it called
+        let mut functions: FnvHashMap<Option<usize>, (u64, u64)> = FnvHashMap::default();
+        for (node, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let stack = self.functions(node);
+            functions.entry(*stack.last().unwrap()).or_insert((0, 0)).0 += count;
+            let distinct: FnvHashSet<&Option<usize>> = stack.iter().collect();
+            for function in distinct {
+                functions.entry(*function).or_insert((0, 0)).1 += count;
+            }
+        }
+        let mut functions: Vec<(&str, u64, u64)> = functions.into_iter()
+            .map(|(function, (self_count, total))| (self.function_name(function), self_count, total))
+            .collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        println!("Top {} functions (instructions executed)", self.top);
+        println!("{:>12} {:>12}  {}", "self", "total", "function");
+        for &(name, self_count, total) in functions.iter().take(self.top) {
+            println!("{:>12} {:>12}  {}", self_count, total, name);
+        }
+    }
+
+    fn print_top_instructions(&self) {
+        let mut instructions: Vec<(&u64, &(u64, String))> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+
+        println!("Top {} instructions", self.top);
+        println!("{:>12}  {:<18} {:<24} {}", "count", "address", "location", "instruction");
+        for &(&address, &(count, ref text)) in instructions.iter().take(self.top) {
+            println!("{:>12}  {:<#18x} {:<24} {}", count, address, self.location(address), text);
+        }
+    }
+}
+
+impl<W: Write> TraceSink for Profiler<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        match self.pending {
+            Pending::Nothing => (),
+            Pending::Call(return_address) => {
+                self.stack.push((self.callers, return_address));
+                self.callers = self.node;
+            }
+            Pending::Ret => {
+                // normally the innermost frame, but the guest may have skipped frames (longjmp)
+                match self.stack.iter().rposition(|&(_, return_address)| return_address == record.rip) {
+                    Some(position) => {
+                        self.callers = self.stack[position].0;
+                        self.stack.truncate(position);
+                    }
+                    None => {
+                        if let Some((callers, _)) = self.stack.pop() {
+                            self.callers = callers;
+                        }
+                    }
+                }
+            }
+        }
+
+        let function = self.symbols.lookup(record.rip);
+        let callers = self.callers;
+        self.node = self.child(callers, function);
+        self.counts[self.node] += 1;
+        self.instructions.entry(record.rip).or_insert_with(|| (0, record.text())).0 += 1;
+
+        self.pending = if record.mnemonic.starts_with("call") {
+            Pending::Call(record.rip + record.bytes.len() as u64)
+        } else if record.mnemonic.starts_with("ret") || record.mnemonic.starts_with("lret") {
+            Pending::Ret
+        } else {
+            Pending::Nothing
+        };
+    }
+
+    fn finish(&mut self) {
+        let mut stacks = Vec::new();
+        for (node, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let names: Vec<&str> = self.functions(node)
+                .into_iter()
+                .map(|function| self.function_name(function))
+                .collect();
+            stacks.push(format!("{} {}", names.join(";"), count));
+        }
+        stacks.sort();
+        for stack in stacks {
+            writeln!(self.writer, "{}", stack).expect("Failed to write profile");
+        }
+        self.writer.flush().expect("Failed to write profile");
+
+        self.print_top_functions();
+        self.print_top_instructions();
+    }
+}
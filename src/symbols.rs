@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::Read;
+
+use zero::read_str;
+
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Binding, Entry, Type};
+
+pub struct Symbol {
+    pub address: u64,
+    /// zero for assembler labels, they extend to the next symbol
+    pub size: u64,
+    pub name: String,
+}
+
+/// Function symbols of an elf file, sorted by address.
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Reads the functions and global labels (e.g. _start in assembler programs) from .symtab.
+    /// Files which are not elf files or are stripped give an empty table.
+    pub fn from_file(filename: &str) -> SymbolTable {
+        let mut file = File::open(filename).expect("Cannot open file");
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).expect("Failed to read file.");
+        if !buffer.starts_with(b"\x7fELF") {
+            return SymbolTable::default();
+        }
+        let elf_file = ElfFile::new(&buffer);
+
+        let (symbol_string_table, symbol_table) = match (elf_file.find_section_by_name(".strtab"),
+                                                         elf_file.find_section_by_name(".symtab")) {
+            (Some(strtab), Some(symtab)) => (strtab.raw_data(&elf_file), symtab),
+            _ => return SymbolTable::default(),
+        };
+        let mut symbols = Vec::new();
+        if let Ok(SectionData::SymbolTable64(data)) = symbol_table.get_data(&elf_file) {
+            for symbol in data {
+                let function = match (symbol.get_type(), symbol.get_binding()) {
+                    (Ok(Type::Func), _) => true,
+                    (Ok(Type::NoType), Ok(Binding::Global)) => true,
+                    _ => false,
+                };
+                if function && symbol.shndx() != 0 && symbol.value() != 0 {
+                    symbols.push(Symbol {
+                        address: symbol.value(),
+                        size: symbol.size(),
+                        name: read_str(&symbol_string_table[symbol.name() as usize..]).to_string(),
+                    });
+                }
+            }
+        }
+        symbols.sort_by_key(|symbol| symbol.address);
+        symbols.dedup_by_key(|symbol| symbol.address);
+        SymbolTable { symbols: symbols }
+    }
+
+    /// Index of the symbol containing address.
+    pub fn lookup(&self, address: u64) -> Option<usize> {
+        let index = match self.symbols.binary_search_by_key(&address, |symbol| symbol.address) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        if symbol.size != 0 && address >= symbol.address + symbol.size {
+            None
+        } else {
+            Some(index)
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &Symbol {
+        &self.symbols[index]
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::{Read, Write};
+
+use bincode::{serialize, deserialize_from, Infinite};
+use serde_json;
+
+use machine_state::MachineState;
+
+/// A memory access done by an instruction (instruction fetches are not included).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryAccess {
+    pub address: u64,
+    pub data: Vec<u8>,
+    pub write: bool,
+}
+
+/// A register which was changed by an instruction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterDelta {
+    pub register: String,
+    pub old: i64,
+    pub new: i64,
+}
+
+/// Everything we know about one executed instruction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraceRecord {
+    pub counter: u64,
+    pub rip: u64,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub operands: String,
+    pub registers: Vec<RegisterDelta>,
+    pub memory: Vec<MemoryAccess>,
+}
+
+impl TraceRecord {
+    /// AT&T syntax line, the same format --print-instructions always used.
+    pub fn text(&self) -> String {
+        if self.operands.is_empty() {
+            format!("{:<6}", self.mnemonic)
+        } else {
+            format!("{:<6} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+/// Per instruction state collected by MachineState while an instruction is executed,
+/// the mmu appends all memory accesses.
+#[derive(Default)]
+pub struct InstructionTrace {
+    pub memory: Vec<MemoryAccess>,
+    pub record_memory: bool,
+}
+
+impl InstructionTrace {
+    pub fn clear(&mut self) {
+        self.memory.clear();
+    }
+
+    pub fn memory_access(&mut self, address: u64, data: &[u8], write: bool) {
+        if self.record_memory {
+            self.memory.push(MemoryAccess {
+                address: address,
+                data: data.to_vec(),
+                write: write,
+            });
+        }
+    }
+}
+
+const REGISTER_NAMES: [&'static str; 17] = ["rax", "rbx", "rcx", "rdx", "rsp", "rbp", "rsi", "rdi",
+                                             "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+                                             "rflags"];
+
+pub struct RegisterSnapshot {
+    values: [i64; 17],
+}
+
+impl RegisterSnapshot {
+    pub fn new(machine_state: &MachineState) -> RegisterSnapshot {
+        RegisterSnapshot {
+            values: [machine_state.rax, machine_state.rbx, machine_state.rcx, machine_state.rdx,
+                     machine_state.rsp, machine_state.rbp, machine_state.rsi, machine_state.rdi,
+                     machine_state.r8, machine_state.r9, machine_state.r10, machine_state.r11,
+                     machine_state.r12, machine_state.r13, machine_state.r14, machine_state.r15,
+                     machine_state.rflags],
+        }
+    }
+
+    pub fn delta(&self, after: &RegisterSnapshot) -> Vec<RegisterDelta> {
+        let mut deltas = Vec::new();
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            if self.values[i] != after.values[i] {
+                deltas.push(RegisterDelta {
+                    register: name.to_string(),
+                    old: self.values[i],
+                    new: after.values[i],
+                });
+            }
+        }
+        deltas
+    }
+}
+
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord);
+
+    /// Called once after the emulation has stopped.
+    fn finish(&mut self) {}
+}
+
+/// Plain AT&T text, one instruction per line.
+pub struct TextTraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TextTraceWriter<W> {
+    pub fn new(writer: W) -> TextTraceWriter<W> {
+        TextTraceWriter { writer: writer }
+    }
+}
+
+impl<W: Write> TraceSink for TextTraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        writeln!(self.writer, "{}", record.text()).expect("Failed to write trace");
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().expect("Failed to write trace");
+    }
+}
+
+/// One JSON object per instruction and line.
+pub struct JsonTraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonTraceWriter<W> {
+    pub fn new(writer: W) -> JsonTraceWriter<W> {
+        JsonTraceWriter { writer: writer }
+    }
+}
+
+impl<W: Write> TraceSink for JsonTraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        serde_json::to_writer(&mut self.writer, record).expect("Failed to write trace");
+        writeln!(self.writer).expect("Failed to write trace");
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().expect("Failed to write trace");
+    }
+}
+
+/// Start of every binary trace, followed by the format version as a little
+/// endian u32.
+pub const BINARY_TRACE_MAGIC: &'static [u8; 8] = b"X86TRACE";
+pub const BINARY_TRACE_VERSION: u32 = 1;
+
+/// Compact binary trace: the magic and version, then a stream of bincode
+/// encoded TraceRecords.
+pub struct BinaryTraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> BinaryTraceWriter<W> {
+    pub fn new(mut writer: W) -> BinaryTraceWriter<W> {
+        writer.write_all(BINARY_TRACE_MAGIC).expect("Failed to write trace");
+        writer.write_all(&[BINARY_TRACE_VERSION as u8,
+                           (BINARY_TRACE_VERSION >> 8) as u8,
+                           (BINARY_TRACE_VERSION >> 16) as u8,
+                           (BINARY_TRACE_VERSION >> 24) as u8])
+            .expect("Failed to write trace");
+        BinaryTraceWriter { writer: writer }
+    }
+}
+
+impl<W: Write> TraceSink for BinaryTraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        let encoded: Vec<u8> = serialize(record, Infinite).unwrap();
+        self.writer.write_all(&encoded).expect("Failed to write trace");
+    }
+
+    fn finish(&mut self) {
+        self.writer.flush().expect("Failed to write trace");
+    }
+}
+
+/// Reads the records of a binary trace back.
+pub struct BinaryTraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> BinaryTraceReader<R> {
+    /// Checks the header, fails for other files and other versions of the format.
+    pub fn new(mut reader: R) -> Result<BinaryTraceReader<R>, String> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header).map_err(|_| "Not a binary trace".to_string())?;
+        if &header[0..8] != BINARY_TRACE_MAGIC {
+            return Err("Not a binary trace".to_string());
+        }
+        let version = header[8..12].iter().rev().fold(0, |version, byte| version << 8 | *byte as u32);
+        if version != BINARY_TRACE_VERSION {
+            return Err(format!("Unsupported binary trace version {}", version));
+        }
+        Ok(BinaryTraceReader { reader: reader })
+    }
+}
+
+impl<R: Read> Iterator for BinaryTraceReader<R> {
+    type Item = TraceRecord;
+
+    /// The next record, None at the end of the trace
+    fn next(&mut self) -> Option<TraceRecord> {
+        deserialize_from(&mut self.reader, Infinite).ok()
+    }
+}
+
+/// Only forwards instructions inside the address range [start, end) and
+/// whose instruction counter is inside the window [first, last).
+pub struct TraceFilter {
+    sink: Box<dyn TraceSink>,
+    address_range: Option<(u64, u64)>,
+    window: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    pub fn new(sink: Box<dyn TraceSink>,
+               address_range: Option<(u64, u64)>,
+               window: Option<(u64, u64)>)
+               -> TraceFilter {
+        TraceFilter {
+            sink: sink,
+            address_range: address_range,
+            window: window,
+        }
+    }
+}
+
+impl TraceSink for TraceFilter {
+    fn record(&mut self, record: &TraceRecord) {
+        if let Some((start, end)) = self.address_range {
+            if record.rip < start || record.rip >= end {
+                return;
+            }
+        }
+        if let Some((first, last)) = self.window {
+            if record.counter < first || record.counter >= last {
+                return;
+            }
+        }
+        self.sink.record(record);
+    }
+
+    fn finish(&mut self) {
+        self.sink.finish();
+    }
+}
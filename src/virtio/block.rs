@@ -0,0 +1,174 @@
+use memory_map::GuestMemory;
+use virtio::{VirtioDevice, le_bytes, from_le_bytes, config_read};
+use virtio::disk::Disk;
+use virtio::queue::{Virtqueue, Chain};
+
+/* virtio-blk with a single request queue. A request is a 16 byte header
+ * (type, reserved, sector), the data, and a status byte the device writes
+ * at the end of the writable descriptors. Discarded sectors and write zeroes
+ * requests both write zeros.
+ */
+
+const SECTOR_SIZE: u64 = 512;
+const SERIAL: &[u8] = b"x86emu-virtio-blk";
+
+/// feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+/// request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+const REQUEST_HEADER_SIZE: usize = 16;
+/// sector, number of sectors and flags of discard and write zeroes requests
+const SEGMENT_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+/// request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// limits of discard and write zeroes requests in the configuration
+const MAX_SECTORS: u64 = 0x10000;
+const MAX_SEGMENTS: u64 = 1;
+
+pub struct BlockDevice {
+    disk: Disk,
+}
+
+impl BlockDevice {
+    pub fn new(disk: Disk) -> BlockDevice {
+        BlockDevice {
+            disk: disk,
+        }
+    }
+
+    /// Handles one request, returns the number of bytes written to the driver
+    fn request(&mut self, chain: &Chain, memory: &mut dyn GuestMemory) -> u32 {
+        let request = chain.read(memory);
+        let writable = chain.writable_length();
+        // without a header or room for the status there is nothing to answer
+        if request.len() < REQUEST_HEADER_SIZE || writable == 0 {
+            return 0;
+        }
+        let request_type = from_le_bytes(&request[0..4]) as u32;
+        let sector = from_le_bytes(&request[8..16]);
+        let data = &request[REQUEST_HEADER_SIZE..];
+        // everything but the status byte
+        let length = writable - 1;
+
+        let (status, written) = match request_type {
+            VIRTIO_BLK_T_IN => {
+                match self.range(sector, length).and_then(|offset| self.disk.read(offset, length).ok()) {
+                    Some(contents) => {
+                        chain.write(memory, 0, &contents);
+                        (VIRTIO_BLK_S_OK, length)
+                    }
+                    None => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_OUT => {
+                let result = self.range(sector, data.len() as u64)
+                    .and_then(|offset| self.disk.write(offset, data).ok());
+                (result_status(result), 0)
+            }
+            VIRTIO_BLK_T_FLUSH => (result_status(self.disk.flush().ok()), 0),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = SERIAL.to_vec();
+                id.resize(ID_SIZE, 0);
+                chain.write(memory, 0, &id[..(length as usize).min(ID_SIZE)]);
+                (VIRTIO_BLK_S_OK, length.min(ID_SIZE as u64))
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if !self.disk.read_only() => {
+                let mut result = Some(());
+                for segment in data.chunks(SEGMENT_SIZE).filter(|segment| segment.len() == SEGMENT_SIZE) {
+                    let sectors = from_le_bytes(&segment[8..12]);
+                    result = result
+                        .and_then(|_| self.range(from_le_bytes(&segment[0..8]), sectors * SECTOR_SIZE))
+                        .and_then(|offset| self.disk.zero(offset, sectors * SECTOR_SIZE).ok());
+                }
+                (result_status(result), 0)
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => (VIRTIO_BLK_S_IOERR, 0),
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        chain.write(memory, length, &[status]);
+        written as u32 + 1
+    }
+
+    /// The disk offset of length bytes starting at sector, None if they are
+    /// not all on the disk
+    fn range(&self, sector: u64, length: u64) -> Option<u64> {
+        let offset = sector.checked_mul(SECTOR_SIZE)?;
+        match offset.checked_add(length) {
+            Some(end) if end <= self.disk.size() => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+fn result_status<T>(result: Option<T>) -> u8 {
+    match result {
+        Some(_) => VIRTIO_BLK_S_OK,
+        None => VIRTIO_BLK_S_IOERR,
+    }
+}
+
+impl VirtioDevice for BlockDevice {
+    fn device_id(&self) -> u16 {
+        2
+    }
+
+    fn class_code(&self) -> u32 {
+        // mass storage, other
+        0x018000
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.disk.read_only() {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES
+        }
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config_read(&mut self, offset: u64, size: u64) -> u64 {
+        // struct virtio_blk_config up to the write zeroes fields
+        let mut config = le_bytes(self.disk.size() / SECTOR_SIZE, 8);
+        config.resize(0x14, 0);
+        config.extend(le_bytes(SECTOR_SIZE, 4));
+        config.resize(0x24, 0);
+        // max_discard_sectors, max_discard_seg, discard_sector_alignment
+        config.extend(le_bytes(MAX_SECTORS, 4));
+        config.extend(le_bytes(MAX_SEGMENTS, 4));
+        config.extend(le_bytes(1, 4));
+        // max_write_zeroes_sectors, max_write_zeroes_seg, write_zeroes_may_unmap
+        config.extend(le_bytes(MAX_SECTORS, 4));
+        config.extend(le_bytes(MAX_SEGMENTS, 4));
+        config.push(1);
+        config_read(&config, offset, size)
+    }
+
+    fn notify(&mut self, _queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(memory) {
+            let length = self.request(&chain, memory);
+            virtqueue.push(memory, chain.head, length);
+            used = true;
+        }
+        used
+    }
+}
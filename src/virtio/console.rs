@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use libc;
+
+use memory_map::GuestMemory;
+use virtio::{VirtioDevice, le_bytes, from_le_bytes, config_read};
+use virtio::queue::Virtqueue;
+
+/* virtio-console with VIRTIO_CONSOLE_F_MULTIPORT. Port 0 is the console, the
+ * others show up in linux as /dev/vport0p<n> and by name in
+ * /dev/virtio-ports. The queues are:
+ *
+ *     0, 1          receive and transmit queue of port 0
+ *     2, 3          control receive and transmit queue
+ *     2n+2, 2n+3    receive and transmit queue of port n > 0
+ *
+ * The driver announces itself and its ports on the control queue, the device
+ * replies with the ports and opens them. Drivers without multiport only see
+ * port 0.
+ */
+
+/// feature bits
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// events of the control messages (struct virtio_console_control)
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+const CONTROL_SIZE: usize = 8;
+
+const CONTROL_RECEIVE_QUEUE: usize = 2;
+const CONTROL_TRANSMIT_QUEUE: usize = 3;
+/// the driver writes characters for port 0 there, before the queues work
+const EMERGENCY_WRITE: u64 = 8;
+const INPUT_BUFFER_SIZE: usize = 4096;
+
+/// A port with its name and the host files behind it
+pub struct ConsolePort {
+    name: String,
+    output: Box<dyn Write>,
+    input: Option<File>,
+    /// input waiting for a receive buffer
+    pending: Vec<u8>,
+}
+
+impl ConsolePort {
+    pub fn new(name: &str, output: Box<dyn Write>, input: Option<File>) -> ConsolePort {
+        ConsolePort {
+            name: name.to_string(),
+            output: output,
+            input: input,
+            pending: Vec::new(),
+        }
+    }
+
+    /// A port from name:output[:input], output - is stdout. Pipes work as
+    /// output and input, opening a pipe for writing waits for its reader.
+    pub fn from_spec(spec: &str) -> ConsolePort {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            panic!("Invalid console port, expected name:output[:input]: {}", spec);
+        }
+        let output: Box<dyn Write> = match parts[1] {
+            "-" => Box::new(io::stdout()),
+            filename => Box::new(File::create(filename).expect("Cannot create console port output")),
+        };
+        // reading waits for nothing, a pipe without writers reads as empty
+        let input = parts.get(2).map(|filename| {
+            OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(filename)
+                .expect("Cannot open console port input")
+        });
+        ConsolePort::new(parts[0], output, input)
+    }
+
+    /// Input from the host, empty if there is none right now
+    fn read(&mut self) -> Vec<u8> {
+        let mut data = vec![0; INPUT_BUFFER_SIZE];
+        let count = match self.input {
+            Some(ref mut input) => input.read(&mut data).unwrap_or(0),
+            None => 0,
+        };
+        data.truncate(count);
+        data
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.output.write_all(data).expect("Cannot write console port output");
+        self.output.flush().expect("Cannot write console port output");
+    }
+}
+
+pub struct ConsoleDevice {
+    ports: Vec<ConsolePort>,
+    multiport: bool,
+    /// messages for the control receive queue
+    control: VecDeque<Vec<u8>>,
+}
+
+impl ConsoleDevice {
+    pub fn new(ports: Vec<ConsolePort>) -> ConsoleDevice {
+        if ports.is_empty() {
+            panic!("A console needs at least one port");
+        }
+        ConsoleDevice {
+            ports: ports,
+            multiport: false,
+            control: VecDeque::new(),
+        }
+    }
+
+    /// The port of a receive or transmit queue, None for the control queues
+    fn port(&self, queue: usize) -> Option<usize> {
+        match queue {
+            0 | 1 => Some(0),
+            CONTROL_RECEIVE_QUEUE | CONTROL_TRANSMIT_QUEUE => None,
+            _ => Some(queue / 2 - 1),
+        }
+    }
+
+    /// Whether the driver uses the queue, without multiport there is only port 0
+    fn active(&self, queue: usize) -> bool {
+        match self.port(queue) {
+            Some(0) => true,
+            _ => self.multiport,
+        }
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[u8]) {
+        let mut message = le_bytes(id as u64, 4);
+        message.extend(le_bytes(event as u64, 2));
+        message.extend(le_bytes(value as u64, 2));
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    fn control_message(&mut self, message: &[u8]) {
+        // too short for a struct virtio_console_control, there is nothing to answer
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+        let id = from_le_bytes(&message[0..4]) as usize;
+        let event = from_le_bytes(&message[4..6]) as u16;
+        let value = from_le_bytes(&message[6..8]) as u16;
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.send_control(id, DEVICE_ADD, 0, &[]);
+                }
+            }
+            PORT_READY if value == 1 && id < self.ports.len() => {
+                if id == 0 {
+                    self.send_control(id, CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports[id].name.clone().into_bytes();
+                if !name.is_empty() {
+                    self.send_control(id, PORT_NAME, 1, &name);
+                }
+                self.send_control(id, PORT_OPEN, 1, &[]);
+            }
+            // the driver failed to add a port or a program opened or closed
+            // one, the host side stays as it is
+            _ => (),
+        }
+    }
+
+    fn receive_control(&mut self, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let mut used = false;
+        while !self.control.is_empty() {
+            let chain = match virtqueue.pop(memory) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let message = self.control.pop_front().unwrap();
+            chain.write(memory, 0, &message);
+            virtqueue.push(memory, chain.head, message.len() as u32);
+            used = true;
+        }
+        used
+    }
+
+    fn receive_input(&mut self, port: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let port = &mut self.ports[port];
+        let mut used = false;
+        loop {
+            if port.pending.is_empty() {
+                port.pending = port.read();
+                if port.pending.is_empty() {
+                    return used;
+                }
+            }
+            let chain = match virtqueue.pop(memory) {
+                Some(chain) => chain,
+                None => return used,
+            };
+            let count = (chain.writable_length() as usize).min(port.pending.len());
+            chain.write(memory, 0, &port.pending[..count]);
+            virtqueue.push(memory, chain.head, count as u32);
+            port.pending.drain(..count);
+            used = true;
+        }
+    }
+}
+
+impl VirtioDevice for ConsoleDevice {
+    fn device_id(&self) -> u16 {
+        3
+    }
+
+    fn class_code(&self) -> u32 {
+        // simple communication controller, other
+        0x078000
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn queues(&self) -> usize {
+        2 * self.ports.len() + 2
+    }
+
+    fn config_read(&mut self, offset: u64, size: u64) -> u64 {
+        // no size, max_nr_ports, emerg_wr
+        let mut config = le_bytes(0, 4);
+        config.extend(le_bytes(self.ports.len() as u64, 4));
+        config.extend(le_bytes(0, 4));
+        config_read(&config, offset, size)
+    }
+
+    fn config_write(&mut self, offset: u64, _size: u64, value: u64) {
+        if offset == EMERGENCY_WRITE {
+            self.ports[0].write(&[value as u8]);
+        }
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn notify(&mut self, queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        // receive queues are filled by poll()
+        if queue & 1 == 0 || !self.active(queue) {
+            return false;
+        }
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(memory) {
+            let data = chain.read(memory);
+            match self.port(queue) {
+                Some(port) => self.ports[port].write(&data),
+                None => self.control_message(&data),
+            }
+            virtqueue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    fn poll(&mut self, queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        if queue & 1 != 0 || !self.active(queue) {
+            return false;
+        }
+        match self.port(queue) {
+            Some(port) => self.receive_input(port, virtqueue, memory),
+            None => self.receive_control(virtqueue, memory),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        for port in self.ports.iter_mut() {
+            port.pending.clear();
+        }
+    }
+}
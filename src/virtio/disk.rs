@@ -0,0 +1,193 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+
+use virtio::{le_bytes, from_le_bytes};
+
+/* The host side of a disk: a raw image, optionally with a copy-on-write
+ * overlay in front of it so the image itself is never written. The overlay
+ * is a stripped down qcow2:
+ *
+ *     0x0000  header, "X86ECOW1", the cluster size and the disk size as
+ *             little endian u64s, the rest of the 4 KB is zero
+ *     0x1000  cluster table, one little endian u64 per cluster of the disk:
+ *             the file offset of the cluster in the overlay or 0 if it is
+ *             still in the image
+ *             clusters, appended to the file when they are first written
+ *
+ * A missing or empty overlay file is created.
+ */
+
+const OVERLAY_MAGIC: &[u8; 8] = b"X86ECOW1";
+const OVERLAY_HEADER_SIZE: u64 = 0x1000;
+const CLUSTER_SIZE: u64 = 64 * 1024;
+
+struct Overlay {
+    file: File,
+    /// file offsets of the clusters, 0 for clusters read from the image
+    clusters: Vec<u64>,
+    /// where the next cluster goes
+    end: u64,
+}
+
+pub struct Disk {
+    image: File,
+    size: u64,
+    read_only: bool,
+    overlay: Option<Overlay>,
+}
+
+impl Disk {
+    /// Opens the image, writes go to the overlay if there is one
+    pub fn open(filename: &str, read_only: bool, overlay: Option<&str>) -> Disk {
+        let image = OpenOptions::new().read(true).write(!read_only && overlay.is_none()).open(filename)
+            .expect("Cannot open disk image");
+        let size = image.metadata().expect("Cannot open disk image").len();
+        Disk {
+            image: image,
+            size: size,
+            read_only: read_only,
+            overlay: overlay.map(|filename| Overlay::open(filename, size)),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length as usize);
+        let mut offset = offset;
+        let end = offset + length;
+        while offset < end {
+            let count = (CLUSTER_SIZE - offset % CLUSTER_SIZE).min(end - offset);
+            let file_offset = self.overlay.as_ref().map_or(0, |overlay| overlay.offset(offset));
+            if file_offset != 0 {
+                data.extend(read_at(&mut self.overlay.as_mut().unwrap().file, file_offset, count)?);
+            } else {
+                data.extend(read_at(&mut self.image, offset, count)?);
+            }
+            offset += count;
+        }
+        Ok(data)
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "read only disk"));
+        }
+        if self.overlay.is_none() {
+            return write_at(&mut self.image, offset, data);
+        }
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let count = (CLUSTER_SIZE - offset % CLUSTER_SIZE).min(data.len() as u64) as usize;
+            let mut file_offset = self.overlay.as_ref().unwrap().offset(offset);
+            if file_offset == 0 {
+                // copy the cluster from the image before it is modified
+                let cluster = offset - offset % CLUSTER_SIZE;
+                let mut contents = read_at(&mut self.image, cluster, CLUSTER_SIZE.min(self.size - cluster))?;
+                contents.resize(CLUSTER_SIZE as usize, 0);
+                self.overlay.as_mut().unwrap().allocate(cluster, &contents)?;
+                file_offset = self.overlay.as_ref().unwrap().offset(offset);
+            }
+            write_at(&mut self.overlay.as_mut().unwrap().file, file_offset, &data[..count])?;
+            offset += count as u64;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Writes zeros, discarded sectors read as zeros too
+    pub fn zero(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let mut offset = offset;
+        let end = offset + length;
+        while offset < end {
+            let count = (CLUSTER_SIZE - offset % CLUSTER_SIZE).min(end - offset);
+            self.write(offset, &vec![0; count as usize])?;
+            offset += count;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.overlay {
+            Some(ref mut overlay) => overlay.file.sync_data(),
+            None if !self.read_only => self.image.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Overlay {
+    fn open(filename: &str, size: u64) -> Overlay {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(filename)
+            .expect("Cannot open disk overlay");
+        // the last cluster may be partial
+        let clusters = (size / CLUSTER_SIZE + (size % CLUSTER_SIZE != 0) as u64) as usize;
+        let table_size = clusters as u64 * 8;
+        let length = file.metadata().expect("Cannot open disk overlay").len();
+        if length == 0 {
+            let mut header = OVERLAY_MAGIC.to_vec();
+            header.extend(le_bytes(CLUSTER_SIZE, 8));
+            header.extend(le_bytes(size, 8));
+            header.resize((OVERLAY_HEADER_SIZE + table_size) as usize, 0);
+            write_at(&mut file, 0, &header).expect("Cannot write disk overlay");
+            return Overlay {
+                file: file,
+                clusters: vec![0; clusters],
+                end: OVERLAY_HEADER_SIZE + table_size,
+            };
+        }
+
+        let header = read_at(&mut file, 0, 24).expect("Cannot read disk overlay");
+        if &header[0..8] != OVERLAY_MAGIC {
+            panic!("{} is not a disk overlay", filename);
+        }
+        if from_le_bytes(&header[8..16]) != CLUSTER_SIZE || from_le_bytes(&header[16..24]) != size {
+            panic!("Disk overlay {} does not match the disk image", filename);
+        }
+        let table = read_at(&mut file, OVERLAY_HEADER_SIZE, table_size).expect("Cannot read disk overlay");
+        Overlay {
+            file: file,
+            clusters: table.chunks(8).map(from_le_bytes).collect(),
+            end: length,
+        }
+    }
+
+    /// The file offset of a disk offset, 0 if its cluster is not in the overlay
+    fn offset(&self, offset: u64) -> u64 {
+        match self.clusters[(offset / CLUSTER_SIZE) as usize] {
+            0 => 0,
+            cluster => cluster + offset % CLUSTER_SIZE,
+        }
+    }
+
+    fn allocate(&mut self, cluster: u64, contents: &[u8]) -> io::Result<()> {
+        let index = (cluster / CLUSTER_SIZE) as usize;
+        let end = self.end;
+        write_at(&mut self.file, end, contents)?;
+        // the table entry is written last so a crash never points at garbage
+        write_at(&mut self.file, OVERLAY_HEADER_SIZE + index as u64 * 8, &le_bytes(end, 8))?;
+        self.clusters[index] = end;
+        self.end += contents.len() as u64;
+        Ok(())
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn write_at(file: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
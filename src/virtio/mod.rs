@@ -0,0 +1,80 @@
+use memory_map::GuestMemory;
+
+pub mod queue;
+pub mod pci;
+pub mod disk;
+pub mod block;
+pub mod net;
+pub mod net_backend;
+pub mod console;
+pub mod rng;
+
+use self::queue::Virtqueue;
+
+/* Virtio 1.x devices. A VirtioDevice has feature bits, a device specific
+ * configuration and virtqueues (queue.rs) the driver puts buffers into.
+ * VirtioPci (pci.rs) makes it a PCI function with the modern virtio-pci
+ * transport, legacy (virtio 0.9) drivers are not supported.
+ */
+
+/// the transport offers VIRTIO_F_VERSION_1 for every device
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// device status bits the driver sets while it initialises the device
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+/// set by the device when it cannot go on until the driver resets it
+pub const STATUS_DEVICE_NEEDS_RESET: u8 = 1 << 6;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+pub trait VirtioDevice {
+    /// virtio device id, e.g. 2 for block devices
+    fn device_id(&self) -> u16;
+
+    /// class code of the PCI function
+    fn class_code(&self) -> u32;
+
+    /// feature bits of the device, without VIRTIO_F_VERSION_1
+    fn features(&self) -> u64;
+
+    fn queues(&self) -> usize;
+
+    /// Accesses to the device specific configuration
+    fn config_read(&mut self, offset: u64, size: u64) -> u64;
+    fn config_write(&mut self, _offset: u64, _size: u64, _value: u64) {}
+
+    /// The features the driver accepted, before it sets DRIVER_OK
+    fn set_features(&mut self, _features: u64) {}
+
+    /// Processes the buffers the driver notified the device of, returns true
+    /// if it used buffers
+    fn notify(&mut self, queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool;
+
+    /// Called for every ready queue whenever the device gets to run, for work
+    /// the driver does not ask for like received packets, returns true if it
+    /// used buffers
+    fn poll(&mut self, _queue: usize, _virtqueue: &mut Virtqueue, _memory: &mut dyn GuestMemory) -> bool {
+        false
+    }
+
+    /// The driver reset the device by writing 0 to the device status
+    fn reset(&mut self) {}
+}
+
+/// The size bytes of a little endian number
+pub fn le_bytes(value: u64, size: usize) -> Vec<u8> {
+    (0..size).map(|index| (value >> (index * 8)) as u8).collect()
+}
+
+pub fn from_le_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+/// Reads size bytes at offset of a device configuration, bytes past its end are 0
+pub fn config_read(config: &[u8], offset: u64, size: u64) -> u64 {
+    (0..size).rev().fold(0, |value, index| {
+        value << 8 | config.get((offset + index) as usize).cloned().unwrap_or(0) as u64
+    })
+}
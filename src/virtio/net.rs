@@ -0,0 +1,140 @@
+use memory_map::GuestMemory;
+use virtio::{VirtioDevice, le_bytes, config_read};
+use virtio::net_backend::NetBackend;
+use virtio::queue::Virtqueue;
+
+/* virtio-net with a receive and a transmit queue and no offloads. Every
+ * frame is preceded by a 12 byte header (struct virtio_net_hdr), it is all
+ * zeros on received frames but for num_buffers, which is 1 because
+ * VIRTIO_NET_F_MRG_RXBUF is not offered. Received frames that do not fit
+ * into a buffer are truncated, transmitted ones without a header dropped.
+ */
+
+/// feature bits
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u64 = 1;
+const HEADER_SIZE: usize = 12;
+/// offset of num_buffers in the header
+const HEADER_NUM_BUFFERS: usize = 10;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+/// locally administered, the prefix QEMU uses
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+pub struct NetDevice {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    /// a frame from the backend waiting for a receive buffer
+    pending: Option<Vec<u8>>,
+}
+
+impl NetDevice {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> NetDevice {
+        NetDevice {
+            mac: mac,
+            backend: backend,
+            pending: None,
+        }
+    }
+
+    /// Parses a MAC address like 52:54:00:12:34:56
+    pub fn parse_mac(mac: &str) -> [u8; 6] {
+        let bytes: Vec<u8> = mac.split(':')
+            .map(|byte| u8::from_str_radix(byte, 16).expect("Invalid MAC address"))
+            .collect();
+        if bytes.len() != 6 {
+            panic!("Invalid MAC address: {}", mac);
+        }
+        let mut result = [0; 6];
+        result.copy_from_slice(&bytes);
+        result
+    }
+
+    fn transmit(&mut self, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(memory) {
+            let packet = chain.read(memory);
+            // a packet without a header is dropped
+            if packet.len() >= HEADER_SIZE {
+                self.backend.send(&packet[HEADER_SIZE..]);
+            }
+            virtqueue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    fn receive(&mut self, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let mut used = false;
+        loop {
+            let frame = match self.pending.take().or_else(|| self.backend.receive()) {
+                Some(frame) => frame,
+                None => return used,
+            };
+            let chain = match virtqueue.pop(memory) {
+                Some(chain) => chain,
+                None => {
+                    self.pending = Some(frame);
+                    return used;
+                }
+            };
+            let mut packet = vec![0; HEADER_SIZE];
+            packet[HEADER_NUM_BUFFERS] = 1;
+            packet.extend(frame);
+            packet.truncate(chain.writable_length() as usize);
+            chain.write(memory, 0, &packet);
+            virtqueue.push(memory, chain.head, packet.len() as u32);
+            used = true;
+        }
+    }
+}
+
+impl VirtioDevice for NetDevice {
+    fn device_id(&self) -> u16 {
+        1
+    }
+
+    fn class_code(&self) -> u32 {
+        // network controller, ethernet
+        0x020000
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn config_read(&mut self, offset: u64, size: u64) -> u64 {
+        // mac, status and max_virtqueue_pairs of struct virtio_net_config
+        let mut config = self.mac.to_vec();
+        config.extend(le_bytes(VIRTIO_NET_S_LINK_UP, 2));
+        config.extend(le_bytes(1, 2));
+        config_read(&config, offset, size)
+    }
+
+    fn notify(&mut self, queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        match queue {
+            TRANSMIT_QUEUE => self.transmit(virtqueue, memory),
+            // new receive buffers are filled by poll()
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        match queue {
+            RECEIVE_QUEUE => self.receive(virtqueue, memory),
+            _ => false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
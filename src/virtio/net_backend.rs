@@ -0,0 +1,219 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use virtio::{le_bytes, from_le_bytes};
+
+/* Where the ethernet frames of a virtio-net device come from and go to.
+ * Backends never block, a frame that cannot be sent is dropped like on a
+ * cable nobody listens on.
+ */
+
+/// the largest frame backends receive, frames are not segmented so the MTU
+/// limits them to much less
+const MAX_FRAME_SIZE: usize = 65536;
+
+const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xA1B23C4D;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const LINKTYPE_ETHERNET: u64 = 1;
+
+pub trait NetBackend {
+    /// The next frame for the guest, None if there is none right now
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    /// A frame the guest sent
+    fn send(&mut self, frame: &[u8]);
+}
+
+/// Drops what the guest sends and has nothing to receive
+pub struct NullBackend;
+
+impl NetBackend for NullBackend {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn send(&mut self, _frame: &[u8]) {}
+}
+
+/// Replays the frames of a pcap file to the guest as fast as it takes them,
+/// the timestamps are ignored
+pub struct PcapReader {
+    file: BufReader<File>,
+    big_endian: bool,
+}
+
+impl PcapReader {
+    pub fn open(filename: &str) -> PcapReader {
+        let mut file = BufReader::new(File::open(filename).expect("Cannot open pcap file"));
+        let mut header = [0; PCAP_HEADER_SIZE];
+        file.read_exact(&mut header).expect("Cannot read pcap file");
+        let big_endian = match from_le_bytes(&header[0..4]) as u32 {
+            PCAP_MAGIC | PCAP_MAGIC_NANOSECONDS => false,
+            magic if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NANOSECONDS => true,
+            _ => panic!("{} is not a pcap file", filename),
+        };
+        if pcap_number(&header[20..24], big_endian) != LINKTYPE_ETHERNET {
+            panic!("{} does not contain ethernet frames", filename);
+        }
+        PcapReader {
+            file: file,
+            big_endian: big_endian,
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = [0; PCAP_RECORD_HEADER_SIZE];
+        self.file.read_exact(&mut header)?;
+        let length = pcap_number(&header[8..12], self.big_endian) as usize;
+        // a broken file must not make us allocate gigabytes
+        if length > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "pcap record is larger than a frame"));
+        }
+        let mut frame = vec![0; length];
+        self.file.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+}
+
+fn pcap_number(bytes: &[u8], big_endian: bool) -> u64 {
+    if big_endian {
+        bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+    } else {
+        from_le_bytes(bytes)
+    }
+}
+
+/// Writes the frames the guest sends to a pcap file, there is no host time
+/// for them so all timestamps are 0
+pub struct PcapWriter {
+    file: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn create(filename: &str) -> PcapWriter {
+        let mut file = BufWriter::new(File::create(filename).expect("Cannot create pcap file"));
+        let mut header = le_bytes(PCAP_MAGIC as u64, 4);
+        // version 2.4, no time zone and accuracy
+        header.extend(le_bytes(2, 2));
+        header.extend(le_bytes(4, 2));
+        header.extend(le_bytes(0, 8));
+        header.extend(le_bytes(MAX_FRAME_SIZE as u64 - 1, 4));
+        header.extend(le_bytes(LINKTYPE_ETHERNET, 4));
+        file.write_all(&header).expect("Cannot write pcap file");
+        PcapWriter {
+            file: file,
+        }
+    }
+
+    fn write_record(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut header = le_bytes(0, 8);
+        header.extend(le_bytes(frame.len() as u64, 4));
+        header.extend(le_bytes(frame.len() as u64, 4));
+        self.file.write_all(&header)?;
+        self.file.write_all(frame)?;
+        // the guest may never stop, a capture has to be usable while it runs
+        self.file.flush()
+    }
+}
+
+/// A pcap file to replay and one to capture into, either is optional
+pub struct PcapBackend {
+    replay: Option<PcapReader>,
+    capture: Option<PcapWriter>,
+}
+
+impl PcapBackend {
+    pub fn new(replay: Option<&str>, capture: Option<&str>) -> PcapBackend {
+        PcapBackend {
+            replay: replay.map(PcapReader::open),
+            capture: capture.map(PcapWriter::create),
+        }
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let frame = match self.replay {
+            Some(ref mut replay) => replay.read_record().ok(),
+            None => None,
+        };
+        if frame.is_none() {
+            // the end of the file, a truncated or an oversized record
+            self.replay = None;
+        }
+        frame
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(ref mut capture) = self.capture {
+            capture.write_record(frame).expect("Cannot write pcap file");
+        }
+    }
+}
+
+/// A Unix datagram socket with one frame per datagram, for connecting two
+/// emulators (each one's peer is the other's socket) or a userspace switch
+pub struct SocketBackend {
+    socket: UnixDatagram,
+    path: PathBuf,
+    peer: PathBuf,
+}
+
+impl SocketBackend {
+    /// Binds the socket at path, a stale socket file from an earlier run is
+    /// removed. Any other file at path is an error, it is never removed.
+    pub fn new(path: &str, peer: &str) -> io::Result<SocketBackend> {
+        match fs::symlink_metadata(path) {
+            Ok(ref metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", path))),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(SocketBackend {
+            socket: socket,
+            path: PathBuf::from(path),
+            peer: PathBuf::from(peer),
+        })
+    }
+}
+
+/// Whether path is a socket, symbolic links are not followed
+fn is_socket(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false)
+}
+
+impl NetBackend for SocketBackend {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let mut frame = vec![0; MAX_FRAME_SIZE];
+        match self.socket.recv(&mut frame) {
+            Ok(length) => {
+                frame.truncate(length);
+                Some(frame)
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        // dropped if the peer is not there (yet)
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+}
+
+impl Drop for SocketBackend {
+    fn drop(&mut self) {
+        // something else may have replaced the socket in the meantime
+        if is_socket(&self.path) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
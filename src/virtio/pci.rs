@@ -0,0 +1,308 @@
+use memory_map::GuestMemory;
+use pci::{PciFunction, PciConfig, Bar, BarKind, Capability};
+use virtio::{VirtioDevice, VIRTIO_F_VERSION_1, STATUS_FEATURES_OK, STATUS_DRIVER_OK, STATUS_DEVICE_NEEDS_RESET,
+             le_bytes, config_read};
+use virtio::queue::{Virtqueue, MAX_QUEUE_SIZE};
+
+/* The modern virtio-pci transport. BAR0 has four regions the driver finds
+ * through vendor specific capabilities:
+ *
+ *     0x0000  common configuration: features, device status and the queues
+ *     0x1000  ISR status, reading it acknowledges the interrupt
+ *     0x2000  device specific configuration
+ *     0x3000  notifications, the driver writes to 0x3000 + queue * 4 when it
+ *             made buffers available
+ *
+ * Interrupts are INTx only, there is no MSI-X. A broken virtqueue sets
+ * DEVICE_NEEDS_RESET in the device status, the device stops until the driver
+ * resets it.
+ */
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// modern devices have 0x1040 plus the virtio device id as PCI device id
+const VIRTIO_PCI_DEVICE_ID: u16 = 0x1040;
+const VIRTIO_SUBSYSTEM_ID: u16 = 0x1100;
+
+const COMMON_CONFIG: u64 = 0x0000;
+const ISR_STATUS: u64 = 0x1000;
+const DEVICE_CONFIG: u64 = 0x2000;
+const NOTIFY: u64 = 0x3000;
+const REGION_SIZE: u64 = 0x1000;
+const BAR_SIZE: u64 = 0x4000;
+const NOTIFY_OFFSET_MULTIPLIER: u32 = 4;
+
+/// cfg_type of the virtio capabilities
+const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+const COMMON_CONFIG_TYPE: u8 = 1;
+const NOTIFY_CONFIG_TYPE: u8 = 2;
+const ISR_CONFIG_TYPE: u8 = 3;
+const DEVICE_CONFIG_TYPE: u8 = 4;
+
+/// the writable registers of the common configuration, common_read() builds
+/// all of it
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0C;
+const DEVICE_STATUS: u64 = 0x14;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const QUEUE_ENABLE: u64 = 0x1C;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+const COMMON_CONFIG_SIZE: u64 = 0x38;
+const NO_VECTOR: u64 = 0xFFFF;
+
+/// ISR status bits
+const ISR_QUEUE: u8 = 1 << 0;
+const ISR_CONFIG: u8 = 1 << 1;
+
+pub struct VirtioPci<D: VirtioDevice> {
+    device: D,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    status: u8,
+    queue_select: u16,
+    queues: Vec<Virtqueue>,
+    /// queues the driver notified since the last process()
+    notified: Vec<bool>,
+    isr: u8,
+}
+
+impl<D: VirtioDevice> VirtioPci<D> {
+    pub fn new(device: D) -> VirtioPci<D> {
+        let queues = device.queues();
+        VirtioPci {
+            device: device,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            status: 0,
+            queue_select: 0,
+            queues: vec![Virtqueue::new(); queues],
+            notified: vec![false; queues],
+            isr: 0,
+        }
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        let queues = self.queues.len();
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_select = 0;
+        self.queues = vec![Virtqueue::new(); queues];
+        self.notified = vec![false; queues];
+        self.isr = 0;
+        self.device.reset();
+    }
+
+    fn set_status(&mut self, status: u8) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        let mut status = status;
+        if status & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+            // the device refuses features it does not have and drivers without VIRTIO_F_VERSION_1
+            if self.driver_features & !self.features() != 0 || self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                status &= !STATUS_FEATURES_OK;
+            } else {
+                self.device.set_features(self.driver_features);
+            }
+        }
+        // only a reset clears DEVICE_NEEDS_RESET
+        self.status = status | self.status & STATUS_DEVICE_NEEDS_RESET;
+    }
+
+    fn common_read(&self, offset: u64, size: u64) -> u64 {
+        let mut config = Vec::with_capacity(COMMON_CONFIG_SIZE as usize);
+        config.extend(le_bytes(self.device_feature_select as u64, 4));
+        config.extend(le_bytes(feature_word(self.features(), self.device_feature_select), 4));
+        config.extend(le_bytes(self.driver_feature_select as u64, 4));
+        config.extend(le_bytes(feature_word(self.driver_features, self.driver_feature_select), 4));
+        config.extend(le_bytes(NO_VECTOR, 2));
+        config.extend(le_bytes(self.queues.len() as u64, 2));
+        config.push(self.status);
+        // the device configuration never changes under the driver
+        config.push(0);
+        config.extend(le_bytes(self.queue_select as u64, 2));
+        match self.queues.get(self.queue_select as usize) {
+            Some(queue) => {
+                config.extend(le_bytes(queue.size as u64, 2));
+                config.extend(le_bytes(NO_VECTOR, 2));
+                config.extend(le_bytes(queue.ready as u64, 2));
+                config.extend(le_bytes(self.queue_select as u64, 2));
+                config.extend(le_bytes(queue.descriptor_table, 8));
+                config.extend(le_bytes(queue.driver_area, 8));
+                config.extend(le_bytes(queue.device_area, 8));
+            }
+            // size 0 tells the driver the queue does not exist
+            None => config.resize(COMMON_CONFIG_SIZE as usize, 0),
+        }
+        config_read(&config, offset, size)
+    }
+
+    fn common_write(&mut self, offset: u64, size: u64, value: u64) {
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_feature_select = value as u32,
+            DRIVER_FEATURE_SELECT => self.driver_feature_select = value as u32,
+            DRIVER_FEATURE if self.driver_feature_select < 2 && self.status & STATUS_FEATURES_OK == 0 => {
+                let shift = self.driver_feature_select * 32;
+                self.driver_features = self.driver_features & !(0xFFFFFFFF << shift) | (value & 0xFFFFFFFF) << shift;
+            }
+            DEVICE_STATUS => self.set_status(value as u8),
+            QUEUE_SELECT => self.queue_select = value as u16,
+            _ => {
+                let queue = match self.queues.get_mut(self.queue_select as usize) {
+                    Some(queue) => queue,
+                    None => return,
+                };
+                // queues are set up before they are enabled
+                if queue.ready && offset != QUEUE_MSIX_VECTOR {
+                    return;
+                }
+                match offset {
+                    QUEUE_SIZE if (value as u16).is_power_of_two() && value as u16 <= MAX_QUEUE_SIZE => {
+                        queue.size = value as u16;
+                    }
+                    QUEUE_ENABLE => queue.ready = value & 1 != 0,
+                    _ if (QUEUE_DESC..QUEUE_DESC + 8).contains(&offset) => {
+                        set_bytes(&mut queue.descriptor_table, offset - QUEUE_DESC, size, value);
+                    }
+                    _ if (QUEUE_DRIVER..QUEUE_DRIVER + 8).contains(&offset) => {
+                        set_bytes(&mut queue.driver_area, offset - QUEUE_DRIVER, size, value);
+                    }
+                    _ if (QUEUE_DEVICE..QUEUE_DEVICE + 8).contains(&offset) => {
+                        set_bytes(&mut queue.device_area, offset - QUEUE_DEVICE, size, value);
+                    }
+                    // MSI-X vectors stay NO_VECTOR, the rest is read only
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/// The 32 feature bits the select register points at
+fn feature_word(features: u64, select: u32) -> u64 {
+    match select {
+        0...1 => features >> (select * 32) & 0xFFFFFFFF,
+        _ => 0,
+    }
+}
+
+/// Writes size bytes at offset of a 64 bit register, drivers may write the
+/// halves of the queue addresses separately
+fn set_bytes(register: &mut u64, offset: u64, size: u64, value: u64) {
+    for index in 0..size.min(8 - offset) {
+        let shift = (offset + index) * 8;
+        *register = *register & !(0xFF << shift) | (value >> (index * 8) & 0xFF) << shift;
+    }
+}
+
+/// A virtio_pci_cap pointing at length bytes at offset of BAR0
+fn capability(config_type: u8, offset: u64, length: u64, extra: &[u8]) -> Capability {
+    let mut data = vec![(4 + 12 + extra.len()) as u8, config_type, 0, 0, 0, 0];
+    data.extend(le_bytes(offset, 4));
+    data.extend(le_bytes(length, 4));
+    data.extend_from_slice(extra);
+    Capability {
+        id: CAPABILITY_VENDOR_SPECIFIC,
+        data: data,
+    }
+}
+
+impl<D: VirtioDevice> PciFunction for VirtioPci<D> {
+    fn config(&self) -> PciConfig {
+        let notify_length = self.queues.len() as u64 * NOTIFY_OFFSET_MULTIPLIER as u64;
+        PciConfig {
+            vendor_id: VIRTIO_VENDOR_ID,
+            device_id: VIRTIO_PCI_DEVICE_ID + self.device.device_id(),
+            class_code: self.device.class_code(),
+            revision_id: 1,
+            subsystem_vendor_id: VIRTIO_VENDOR_ID,
+            subsystem_id: VIRTIO_SUBSYSTEM_ID,
+            bars: [Some(Bar { kind: BarKind::Memory32, size: BAR_SIZE, prefetchable: false }), None, None, None, None, None],
+            interrupt_pin: 1,
+            capabilities: vec![
+                capability(COMMON_CONFIG_TYPE, COMMON_CONFIG, COMMON_CONFIG_SIZE, &[]),
+                capability(NOTIFY_CONFIG_TYPE, NOTIFY, notify_length, &le_bytes(NOTIFY_OFFSET_MULTIPLIER as u64, 4)),
+                capability(ISR_CONFIG_TYPE, ISR_STATUS, 1, &[]),
+                capability(DEVICE_CONFIG_TYPE, DEVICE_CONFIG, REGION_SIZE, &[]),
+            ],
+        }
+    }
+
+    fn bar_read(&mut self, _bar: usize, offset: u64, size: u64) -> u64 {
+        match offset & !(REGION_SIZE - 1) {
+            COMMON_CONFIG => self.common_read(offset, size),
+            ISR_STATUS => {
+                let isr = self.isr;
+                self.isr = 0;
+                isr as u64
+            }
+            DEVICE_CONFIG => self.device.config_read(offset - DEVICE_CONFIG, size),
+            _ => 0,
+        }
+    }
+
+    fn bar_write(&mut self, _bar: usize, offset: u64, size: u64, value: u64) {
+        match offset & !(REGION_SIZE - 1) {
+            COMMON_CONFIG => self.common_write(offset, size, value),
+            DEVICE_CONFIG => self.device.config_write(offset - DEVICE_CONFIG, size, value),
+            NOTIFY => {
+                let queue = ((offset - NOTIFY) / NOTIFY_OFFSET_MULTIPLIER as u64) as usize;
+                if queue < self.notified.len() {
+                    self.notified[queue] = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.isr != 0
+    }
+
+    fn process(&mut self, memory: &mut dyn GuestMemory) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+        // all notifications first, they may give work to other queues, e.g.
+        // replies on the control queue of a console
+        let mut used = Vec::with_capacity(self.queues.len());
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            let notified = self.notified[index] && queue.ready;
+            if notified {
+                self.notified[index] = false;
+            }
+            used.push(notified && self.device.notify(index, queue, memory));
+        }
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            let polled = self.device.poll(index, queue, memory);
+            if (used[index] || polled) && queue.interrupt_wanted(memory) {
+                self.isr |= ISR_QUEUE;
+            }
+        }
+        // the driver learns about it through a configuration change interrupt
+        if self.queues.iter().any(|queue| queue.broken()) {
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.isr |= ISR_CONFIG;
+        }
+    }
+}
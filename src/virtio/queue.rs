@@ -0,0 +1,179 @@
+use memory_map::GuestMemory;
+use virtio::{le_bytes, from_le_bytes};
+
+/* A split virtqueue. The driver owns three areas in guest memory:
+ *
+ *     descriptor table  16 bytes per descriptor: address, length, flags and
+ *                       the index of the next descriptor of the chain
+ *     driver area       (available ring) flags, index and the heads of the
+ *                       chains the driver made available
+ *     device area       (used ring) flags, index and the heads of the chains
+ *                       the device is done with, with the bytes it wrote
+ *
+ * Chains start with the descriptors the device reads and end with the ones
+ * it writes. Indirect descriptors and event indexes are not offered. A chain
+ * with descriptors outside of the table, a loop or indirect descriptors breaks
+ * the queue, the transport asks the driver to reset the device then.
+ */
+
+/// largest queue size the device offers, drivers may choose smaller ones
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+const DESCRIPTOR_INDIRECT: u16 = 1 << 2;
+/// flag of the available ring, the driver does not want interrupts
+const AVAILABLE_NO_INTERRUPT: u16 = 1 << 0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptor {
+    pub address: u64,
+    pub length: u32,
+    /// written by the device
+    pub writable: bool,
+}
+
+/// The descriptors of a request, the device reads the readable ones and
+/// writes its response into the writable ones
+pub struct Chain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    /// The data of the readable descriptors
+    pub fn read(&self, memory: &mut dyn GuestMemory) -> Vec<u8> {
+        let mut data = Vec::new();
+        for descriptor in self.descriptors.iter().filter(|descriptor| !descriptor.writable) {
+            data.extend(memory.read(descriptor.address, descriptor.length as u64));
+        }
+        data
+    }
+
+    /// How many bytes fit into the writable descriptors
+    pub fn writable_length(&self) -> u64 {
+        self.descriptors.iter()
+            .filter(|descriptor| descriptor.writable)
+            .map(|descriptor| descriptor.length as u64)
+            .sum()
+    }
+
+    /// Writes data at offset into the writable descriptors, as much as fits
+    pub fn write(&self, memory: &mut dyn GuestMemory, offset: u64, data: &[u8]) {
+        let mut offset = offset;
+        let mut data = data;
+        for descriptor in self.descriptors.iter().filter(|descriptor| descriptor.writable) {
+            if data.is_empty() {
+                break;
+            }
+            let length = descriptor.length as u64;
+            if offset >= length {
+                offset -= length;
+                continue;
+            }
+            let count = (length - offset).min(data.len() as u64) as usize;
+            memory.write(descriptor.address + offset, &data[..count]);
+            data = &data[count..];
+            offset = 0;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Virtqueue {
+    pub size: u16,
+    pub ready: bool,
+    pub descriptor_table: u64,
+    pub driver_area: u64,
+    pub device_area: u64,
+    /// index of the next available entry the device looks at
+    next_available: u16,
+    /// index of the next used entry the device writes
+    next_used: u16,
+    /// the driver made an invalid chain available
+    broken: bool,
+}
+
+impl Virtqueue {
+    pub fn new() -> Virtqueue {
+        Virtqueue {
+            size: MAX_QUEUE_SIZE,
+            ..Virtqueue::default()
+        }
+    }
+
+    /// The next chain the driver made available, None if there is none or
+    /// the queue is broken
+    pub fn pop(&mut self, memory: &mut dyn GuestMemory) -> Option<Chain> {
+        if self.broken {
+            return None;
+        }
+        let available_index = read_u16(memory, self.driver_area + 2);
+        if available_index == self.next_available {
+            return None;
+        }
+        let slot = (self.next_available % self.size) as u64;
+        let head = read_u16(memory, self.driver_area + 4 + slot * 2);
+        self.next_available = self.next_available.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.size || descriptors.len() >= self.size as usize {
+                self.broken = true;
+                return None;
+            }
+            let address = self.descriptor_table + index as u64 * DESCRIPTOR_SIZE;
+            let flags = read_u16(memory, address + 12);
+            if flags & DESCRIPTOR_INDIRECT != 0 {
+                self.broken = true;
+                return None;
+            }
+            descriptors.push(Descriptor {
+                address: read_u64(memory, address),
+                length: read_u32(memory, address + 8),
+                writable: flags & DESCRIPTOR_WRITE != 0,
+            });
+            if flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            index = read_u16(memory, address + 14);
+        }
+        Some(Chain {
+            head: head,
+            descriptors: descriptors,
+        })
+    }
+
+    /// Gives the chain back to the driver, length is the number of bytes written
+    pub fn push(&mut self, memory: &mut dyn GuestMemory, head: u16, length: u32) {
+        let slot = (self.next_used % self.size) as u64;
+        let entry = self.device_area + 4 + slot * 8;
+        memory.write(entry, &le_bytes(head as u64, 4));
+        memory.write(entry + 4, &le_bytes(length as u64, 4));
+        self.next_used = self.next_used.wrapping_add(1);
+        memory.write(self.device_area + 2, &le_bytes(self.next_used as u64, 2));
+    }
+
+    pub fn broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Whether the driver wants an interrupt for used buffers
+    pub fn interrupt_wanted(&self, memory: &mut dyn GuestMemory) -> bool {
+        read_u16(memory, self.driver_area) & AVAILABLE_NO_INTERRUPT == 0
+    }
+}
+
+fn read_u16(memory: &mut dyn GuestMemory, address: u64) -> u16 {
+    from_le_bytes(&memory.read(address, 2)) as u16
+}
+
+fn read_u32(memory: &mut dyn GuestMemory, address: u64) -> u32 {
+    from_le_bytes(&memory.read(address, 4)) as u32
+}
+
+fn read_u64(memory: &mut dyn GuestMemory, address: u64) -> u64 {
+    from_le_bytes(&memory.read(address, 8))
+}
@@ -0,0 +1,61 @@
+use cpu::random::Random;
+use memory_map::GuestMemory;
+use virtio::{VirtioDevice, le_bytes};
+use virtio::queue::Virtqueue;
+
+/* virtio-rng (entropy device) with a single request queue, the device fills
+ * the buffers the driver gives it. The bytes come from the seeded generator
+ * of rdrand (--seed), so a guest gets the same ones in every run, but from a
+ * different stream than rdrand.
+ */
+
+pub struct RngDevice {
+    random: Random,
+}
+
+impl RngDevice {
+    pub fn new(seed: u64) -> RngDevice {
+        RngDevice {
+            random: Random::new(Random::new(seed).next_u64()),
+        }
+    }
+}
+
+impl VirtioDevice for RngDevice {
+    fn device_id(&self) -> u16 {
+        4
+    }
+
+    fn class_code(&self) -> u32 {
+        // unclassified device
+        0xFF0000
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config_read(&mut self, _offset: u64, _size: u64) -> u64 {
+        0
+    }
+
+    fn notify(&mut self, _queue: usize, virtqueue: &mut Virtqueue, memory: &mut dyn GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = virtqueue.pop(memory) {
+            let length = chain.writable_length() as usize;
+            let mut data = Vec::with_capacity(length + 8);
+            while data.len() < length {
+                data.extend(le_bytes(self.random.next_u64(), 8));
+            }
+            data.truncate(length);
+            chain.write(memory, 0, &data);
+            virtqueue.push(memory, chain.head, length as u32);
+            used = true;
+        }
+        used
+    }
+}
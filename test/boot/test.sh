@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+mkdir -p tmp/
+as $1 -o tmp/boot.o
+ld -Ttext 0x7c00 --oformat binary -o tmp/boot.bin tmp/boot.o
+cargo run -- --loader boot tmp/boot.bin
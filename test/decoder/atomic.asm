@@ -0,0 +1,14 @@
+.text
+.global  _start
+_start:
+xadd    %al, %bl
+xadd    %eax, %ebx
+xadd    %r8, %r9
+xadd    %ax, (%rsp)
+cmpxchg8b (%rsp)
+cmpxchg16b (%rsp)
+lock xadd %eax, (%rsp)
+lock add %rax, (%rsp)
+lock cmpxchg8b (%rsp)
+
+int     $0x80
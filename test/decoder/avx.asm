@@ -0,0 +1,54 @@
+.text
+.global  _start
+_start:
+// moves
+vmovdqu (%rax), %ymm0
+vmovdqu %ymm1, 0x20(%rbx)
+vmovdqa %xmm2, %xmm3
+vmovups %ymm8, %ymm12
+vmovapd 0x10(%rsp), %xmm15
+vmovd   %eax, %xmm1
+vmovd   %xmm1, (%rcx)
+vmovq   %rax, %xmm9
+vmovq   %xmm9, %r10
+vmovq   (%rdx), %xmm4
+vmovq   %xmm4, 0x8(%rdx)
+
+// integer
+vpaddb  %ymm1, %ymm2, %ymm3
+vpaddq  (%rax), %xmm2, %xmm3
+vpsubd  %ymm9, %ymm10, %ymm11
+vpxor   %ymm0, %ymm0, %ymm0
+vpandn  %xmm1, %xmm2, %xmm3
+vpcmpeqb %ymm1, %ymm2, %ymm3
+vpcmpgtq %xmm1, %xmm2, %xmm3
+vpminub %ymm1, %ymm2, %ymm3
+vpmovmskb %ymm3, %eax
+vptest  %ymm1, %ymm2
+vpshufb %ymm1, %ymm2, %ymm3
+vpshufd $0x1b, %xmm1, %xmm2
+vpbroadcastb %xmm1, %ymm2
+vpbroadcastq (%rax), %xmm2
+vinserti128 $0x1, %xmm1, %ymm2, %ymm3
+vextracti128 $0x1, %ymm1, %xmm2
+vperm2i128 $0x20, %ymm1, %ymm2, %ymm3
+
+// floating point
+vaddps  %ymm1, %ymm2, %ymm3
+vaddsd  %xmm1, %xmm2, %xmm3
+vmulss  (%rax), %xmm2, %xmm3
+vdivpd  %ymm1, %ymm2, %ymm3
+vsqrtps %ymm1, %ymm2
+vmaxpd  %xmm1, %xmm2, %xmm3
+vxorps  %ymm1, %ymm2, %ymm3
+vbroadcastss (%rax), %ymm1
+vbroadcastsd %xmm1, %ymm2
+
+// state
+vzeroupper
+vzeroall
+xgetbv
+xsave   (%rcx)
+xrstor  0x40(%rcx)
+
+int     $0x80
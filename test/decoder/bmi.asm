@@ -0,0 +1,30 @@
+.text
+.global  _start
+_start:
+// bit scans and counts
+bsf     %rax, %rbx
+bsr     0x8(%rsp), %ecx
+bswap   %eax
+bswap   %r9
+popcnt  %rax, %rbx
+popcnt  (%rcx), %dx
+lzcnt   %r8, %r10
+tzcnt   %eax, %ebx
+
+// BMI1 and BMI2
+andn    %rax, %rbx, %rcx
+andn    (%rsp), %r8d, %r9d
+bextr   %rax, %rbx, %rcx
+blsi    %rax, %rbx
+blsmsk  %r12, %r13
+blsr    (%rsp), %ecx
+bzhi    %rax, %rbx, %rcx
+pdep    %rax, %rbx, %rcx
+pext    %eax, %ebx, %ecx
+mulx    %rax, %rbx, %rcx
+sarx    %rax, %rbx, %rcx
+shlx    %eax, %ebx, %ecx
+shrx    %r8, (%rsp), %r15
+rorx    $0x3, %rax, %rbx
+
+int     $0x80
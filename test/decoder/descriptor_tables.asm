@@ -0,0 +1,13 @@
+.text
+.global  _start
+_start:
+sldt    %ax
+sldt    %eax
+sldt    %rcx
+sldt    (%rsp)
+str     %ax
+str     %edx
+str     %r9
+str     0x8(%rsp)
+
+int     $0x80
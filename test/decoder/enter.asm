@@ -0,0 +1,11 @@
+.text
+.global  _start
+_start:
+mov     %rsp, %rbp
+enter   $0x10, $0x0
+mov     %rbp, %rsp
+pop     %rbp
+enter   $0x20, $0x2
+enter   $0xffff, $0x1f
+
+int     $0x80
@@ -0,0 +1,23 @@
+.text
+.global  _start
+_start:
+// port i/o needs iopl 3 in user space
+mov     $172, %eax
+mov     $3, %edi
+syscall
+mov     $0x80, %edx
+
+in      $0x60, %al
+in      $0x60, %ax
+in      $0x60, %eax
+in      (%dx), %al
+in      (%dx), %ax
+in      (%dx), %eax
+out     %al, $0x80
+out     %ax, $0x80
+out     %eax, $0x80
+out     %al, (%dx)
+out     %ax, (%dx)
+out     %eax, (%dx)
+
+int     $0x80
@@ -0,0 +1,13 @@
+.text
+.global  _start
+_start:
+// random numbers
+rdrand  %rcx
+rdrand  %edx
+rdrand  %si
+rdrand  %r9
+rdseed  %rcx
+rdseed  %r10d
+rdseed  %di
+
+int     $0x80
@@ -0,0 +1,47 @@
+.text
+.global  _start
+_start:
+// port i/o needs iopl 3 in user space
+mov     $172, %eax
+mov     $3, %edi
+syscall
+xor     %ecx, %ecx
+mov     %rsp, %rsi
+mov     %rsp, %rdi
+
+movsb
+movsw
+movsl
+movsq
+rep movsq
+cmpsb
+repz cmpsq
+repnz cmpsw
+lodsb
+lodsw
+lodsl
+lodsq
+scasb
+scasw
+scasq
+repz scasb
+repnz scasl
+stosb
+stosw
+rep stosq
+insb
+insw
+rep insl
+rep outsb
+rep outsw
+
+// address size prefix
+addr32 rep movsb
+addr32 stosb
+addr32 lodsb
+addr32 scasb
+addr32 cmpsb
+addr32 insb
+addr32 rep outsb
+
+int     $0x80
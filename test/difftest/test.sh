@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+mkdir -p tmp/
+as $1 -o tmp/out.o
+ld -o tmp/out tmp/out.o
+cargo run --bin x86emu-difftest -- tmp/out
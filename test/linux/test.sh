@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+mkdir -p tmp/
+as $1 -o tmp/bzimage.o
+ld -Ttext 0 --oformat binary -o tmp/bzImage tmp/bzimage.o
+cargo run -- --loader linux --append "console=ttyS0" --initrd test/linux/initrd.txt --memory 64M tmp/bzImage
@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+mkdir -p tmp/
+as --32 $1 -o tmp/kernel.o
+ld -m elf_i386 -Ttext 0x100000 -o tmp/kernel tmp/kernel.o
+cargo run -- --loader multiboot --append "console=ttyS0" --module "test/multiboot/module.txt first module" tmp/kernel
@@ -0,0 +1,21 @@
+#!/usr/bin/env bash
+# Runs the driver on a copy of disk.img, then with a copy-on-write overlay and
+# read only, where the image has to stay unchanged
+set -e
+mkdir -p tmp/
+as --32 $1 -o tmp/kernel.o
+ld -m elf_i386 -Ttext 0x100000 -o tmp/kernel tmp/kernel.o
+
+cp test/virtio_blk/disk.img tmp/disk.img
+cargo run -- --loader multiboot --memory 64M --disk tmp/disk.img tmp/kernel
+[ "$(dd if=tmp/disk.img bs=1 skip=512 count=8 2>/dev/null)" = "written!" ]
+cmp -n 512 tmp/disk.img /dev/zero
+
+cp test/virtio_blk/disk.img tmp/disk.img
+rm -f tmp/disk.cow
+cargo run -- --loader multiboot --memory 64M --disk tmp/disk.img --disk-overlay tmp/disk.cow tmp/kernel
+cmp tmp/disk.img test/virtio_blk/disk.img
+grep -q "written!" tmp/disk.cow
+
+cargo run -- --loader multiboot --memory 64M --disk tmp/disk.img --disk-read-only tmp/kernel
+cmp tmp/disk.img test/virtio_blk/disk.img
@@ -0,0 +1,23 @@
+#!/usr/bin/env bash
+# Runs the driver with a console port and a named port that reads input.txt,
+# the random bytes after the echoed input only depend on the seed
+set -e
+mkdir -p tmp/
+as --32 $1 -o tmp/kernel.o
+ld -m elf_i386 -Ttext 0x100000 -o tmp/kernel tmp/kernel.o
+
+run() {
+    cargo run -- --loader multiboot --memory 64M --console-port console:tmp/console.txt \
+        --console-port org.x86emu.test:tmp/port1.txt:test/virtio_console/input.txt --rng --seed $1 tmp/kernel
+}
+
+run 42
+printf 'hello from port 0\n!\n' | cmp - tmp/console.txt
+head -c 17 tmp/port1.txt | cmp - test/virtio_console/input.txt
+[ $(stat -c %s tmp/port1.txt) = 33 ]
+mv tmp/port1.txt tmp/port1.first
+
+run 42
+cmp tmp/port1.txt tmp/port1.first
+run 43
+! cmp -s tmp/port1.txt tmp/port1.first
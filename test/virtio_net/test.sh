@@ -0,0 +1,12 @@
+#!/usr/bin/env bash
+# Runs the driver with frame.pcap replayed and the sent frames captured, which
+# has to give frame.pcap again, then with a socket that is its own peer
+set -e
+mkdir -p tmp/
+as --32 $1 -o tmp/kernel.o
+ld -m elf_i386 -Ttext 0x100000 -o tmp/kernel tmp/kernel.o
+
+cargo run -- --loader multiboot --memory 64M --net-replay test/virtio_net/frame.pcap --net-capture tmp/capture.pcap tmp/kernel
+cmp tmp/capture.pcap test/virtio_net/frame.pcap
+
+cargo run -- --loader multiboot --memory 64M --net-socket tmp/net.sock --net-peer tmp/net.sock tmp/kernel